@@ -0,0 +1,70 @@
+//! A build script that bundles a binary and hands the script to the crate being built, which
+//! embeds it with `include_str!(env!("TOOL_BUNDLE"))`.
+//!
+//! Run with `cargo run --example export_env`. This plays both cargo and the build script: it runs
+//! itself as the build script of the `simple` test fixture, with `OUT_DIR` and
+//! `CARGO_MANIFEST_DIR` set the way cargo sets them, and then reads the variable back from what
+//! the build script printed, like cargo does before compiling the crate.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use rust_script_bundler::Bundler;
+
+fn main() -> Result<()> {
+    match env::args().nth(1).as_deref() {
+        Some("--build-script") => build_script(),
+        _ => cargo(),
+    }
+}
+
+/// What `build.rs` would do
+fn build_script() -> Result<()> {
+    let report = Bundler::new("src/main.rs")?
+        .with_lib()
+        .bundle(Path::new("tool.rs"))?;
+    report.export_env("TOOL_BUNDLE")
+}
+
+/// What cargo does around the build script, and what `include_str!(env!("TOOL_BUNDLE"))` then
+/// reads
+fn cargo() -> Result<()> {
+    let out_dir = env::temp_dir().join(format!("bundler-export-env-{}", std::process::id()));
+    fs::create_dir_all(&out_dir)?;
+    let package = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/simple");
+    let output = Command::new(env::current_exe()?)
+        .arg("--build-script")
+        .env("OUT_DIR", &out_dir)
+        .env("CARGO_MANIFEST_DIR", &package)
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "The build script failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let directives = String::from_utf8(output.stdout)?;
+    let script: PathBuf = directives
+        .lines()
+        .find_map(|line| line.strip_prefix("cargo:rustc-env=TOOL_BUNDLE="))
+        .context("The build script did not set TOOL_BUNDLE")?
+        .into();
+    let rerun = directives
+        .lines()
+        .filter(|line| line.starts_with("cargo:rerun-if-changed="))
+        .count();
+    let embedded = fs::read_to_string(&script)
+        .with_context(|| format!("Failed to read {}", script.display()))?;
+    println!(
+        "TOOL_BUNDLE={}, {} bytes, rebuilt when any of {} inputs changes",
+        script.display(),
+        embedded.len(),
+        rerun
+    );
+    fs::remove_dir_all(&out_dir)?;
+    Ok(())
+}
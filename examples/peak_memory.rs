@@ -0,0 +1,105 @@
+//! Peak memory of bundling a large generated package, with and without streaming the crates.
+//!
+//! Run with `cargo run --release --example peak_memory [<crates> [<fns per crate>]]`. Each mode
+//! runs in a process of its own, which reports its max RSS from `/proc/self/status`, so this only
+//! works on Linux.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use rust_script_bundler::Bundler;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("--child") => child(Path::new(&args[1]), &args[2], args[3] == "stream"),
+        _ => {
+            let crates: usize = args.first().map_or(Ok(40), |n| n.parse())?;
+            let fns: usize = args.get(1).map_or(Ok(2000), |n| n.parse())?;
+            parent(crates, fns)
+        }
+    }
+}
+
+fn parent(crates: usize, fns: usize) -> Result<()> {
+    let dir = env::temp_dir().join(format!("bundler-peak-memory-{}", std::process::id()));
+    generate(&dir, crates, fns)?;
+    println!("{} crates of {} functions each", crates, fns);
+    for mode in ["whole", "stream"] {
+        let output = Command::new(env::current_exe()?)
+            .arg("--child")
+            .arg(&dir)
+            .arg(crates.to_string())
+            .arg(mode)
+            .output()?;
+        if !output.status.success() {
+            bail!(
+                "{} failed: {}",
+                mode,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        print!("{:>6}: {}", mode, String::from_utf8_lossy(&output.stdout));
+    }
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+fn child(dir: &Path, crates: &str, stream: bool) -> Result<()> {
+    let mut bundler =
+        Bundler::new_with_dir("src/main.rs", env::temp_dir(), dir)?.stream_crates(stream);
+    for i in 0..crates.parse::<usize>()? {
+        let name = format!("gen{}", i);
+        let root: PathBuf = dir.join(&name).join("lib.rs");
+        bundler = bundler.with_crate_at(name, root);
+    }
+    let report = bundler.check()?;
+    println!(
+        "max RSS {} for {} bytes of script",
+        max_rss()?,
+        report.output_size
+    );
+    Ok(())
+}
+
+/// `VmHWM` of this process
+fn max_rss() -> Result<String> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    let line = status
+        .lines()
+        .find(|line| line.starts_with("VmHWM:"))
+        .context("No VmHWM in /proc/self/status")?;
+    Ok(line["VmHWM:".len()..].trim().to_string())
+}
+
+/// A package at `dir` whose binary uses `crates` crates of `fns` functions each
+fn generate(dir: &Path, crates: usize, fns: usize) -> Result<()> {
+    fs::create_dir_all(dir.join("src"))?;
+    fs::write(
+        dir.join("Cargo.toml"),
+        "[package]\nname = \"peak\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+    )?;
+    let mut main = String::from("fn main() {\n");
+    for i in 0..crates {
+        let mut lib = String::new();
+        for j in 0..fns {
+            writeln!(
+                lib,
+                "/// Function {0}\npub fn f{0}(x: u64) -> u64 {{\n    \
+                 let v: Vec<u64> = (0..x).map(|i| i * {0}).collect();\n    \
+                 v.iter().sum::<u64>() + x\n}}\n",
+                j
+            )?;
+        }
+        fs::create_dir_all(dir.join(format!("gen{}", i)))?;
+        fs::write(dir.join(format!("gen{}/lib.rs", i)), lib)?;
+        writeln!(main, "    println!(\"{{}}\", gen{}::f0(1));", i)?;
+    }
+    main.push_str("}\n");
+    fs::write(dir.join("src/main.rs"), main)?;
+    Ok(())
+}
@@ -0,0 +1,364 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{bail, Result};
+use quote::ToTokens;
+
+/// Crate level attributes that only have an effect at the true crate root
+const ROOT_ONLY: &[&str] = &["recursion_limit", "type_length_limit", "windows_subsystem"];
+
+/// Whether `attr` only has an effect at the crate root, and thus must be hoisted out of a crate
+/// being inlined as a module.
+pub fn is_root_only(attr: &syn::Attribute) -> bool {
+    ROOT_ONLY.iter().any(|name| attr.path.is_ident(name))
+}
+
+fn attr_name(attr: &syn::Attribute) -> String {
+    attr.path.to_token_stream().to_string()
+}
+
+/// The string value of `#![name = "value"]`
+fn attr_value(attr: &syn::Attribute) -> Option<String> {
+    match attr.parse_meta().ok()? {
+        syn::Meta::NameValue(syn::MetaNameValue {
+            lit: syn::Lit::Str(lit),
+            ..
+        }) => Some(lit.value()),
+        _ => None,
+    }
+}
+
+/// The text of a `#[doc = "..."]` attribute
+pub fn doc_value(attr: &syn::Attribute) -> Option<String> {
+    if attr.path.is_ident("doc") {
+        attr_value(attr)
+    } else {
+        None
+    }
+}
+
+/// The content of the first ```` ```cargo ```` fenced block in the doc attributes `attrs`
+pub fn embedded_manifest(attrs: &[syn::Attribute]) -> Option<String> {
+    let lines = attrs
+        .iter()
+        .filter_map(doc_value)
+        .flat_map(|doc| doc.lines().map(String::from).collect::<Vec<_>>())
+        .map(|line| line.strip_prefix(' ').map(String::from).unwrap_or(line))
+        .skip_while(|line| line.trim() != "```cargo")
+        .skip(1);
+    let mut manifest = String::new();
+    for line in lines {
+        if line.trim() == "```" {
+            return Some(manifest);
+        }
+        manifest.push_str(&line);
+        manifest.push('\n');
+    }
+    None
+}
+
+/// Remove the first ```` ```cargo ```` fenced block from the doc attributes `attrs`, returning its
+/// content. The doc lines around it stay, rewritten without the block if they shared an
+/// attribute with it.
+pub fn take_embedded_manifest(attrs: &mut Vec<syn::Attribute>) -> Option<String> {
+    let manifest = embedded_manifest(attrs)?;
+    // 0 before the block, 1 in it, 2 after it
+    let mut phase = 0;
+    let mut kept = Vec::with_capacity(attrs.len());
+    for attr in attrs.drain(..) {
+        let doc = match doc_value(&attr) {
+            Some(doc) if phase < 2 => doc,
+            _ => {
+                kept.push(attr);
+                continue;
+            }
+        };
+        let mut lines = vec![];
+        for line in doc.lines() {
+            match (phase, line.trim()) {
+                (0, "```cargo") => phase = 1,
+                (1, "```") => phase = 2,
+                (1, _) => {}
+                _ => lines.push(line),
+            }
+        }
+        if lines.len() == doc.lines().count() {
+            kept.push(attr);
+        } else if !lines.is_empty() {
+            let doc = lines.join("\n");
+            kept.push(syn::parse_quote!(#![doc = #doc]));
+        }
+    }
+    *attrs = kept;
+    Some(manifest)
+}
+
+/// Collects root-only attributes hoisted from inlined crates, respecting those already present
+/// on the binary.
+pub struct RootAttrs {
+    /// names of root-only attributes the binary sets itself
+    binary: BTreeSet<String>,
+    /// attribute name to the crate it came from and the attribute itself
+    hoisted: BTreeMap<String, (String, syn::Attribute)>,
+}
+
+impl RootAttrs {
+    pub fn new(binary_attrs: &[syn::Attribute]) -> Self {
+        RootAttrs {
+            binary: binary_attrs
+                .iter()
+                .filter(|a| is_root_only(a))
+                .map(attr_name)
+                .collect(),
+            hoisted: Default::default(),
+        }
+    }
+
+    /// Hoist `attr` from the inlined crate `krate`. Numeric limits take the maximum over all
+    /// crates, other values must agree.
+    pub fn hoist(&mut self, krate: &str, attr: syn::Attribute) -> Result<()> {
+        let name = attr_name(&attr);
+        if self.binary.contains(&name) {
+            log::info!(
+                "Not hoisting #![{}] from {}, the binary sets its own",
+                name,
+                krate
+            );
+            return Ok(());
+        }
+
+        let (prev_krate, prev) = match self.hoisted.get(&name) {
+            None => {
+                log::info!("Hoisting #![{}] from {} to the bundle root", name, krate);
+                self.hoisted.insert(name, (krate.into(), attr));
+                return Ok(());
+            }
+            Some(prev) => prev,
+        };
+
+        let (prev_value, value) = (attr_value(prev), attr_value(&attr));
+        let numbers = prev_value
+            .as_ref()
+            .and_then(|v| v.parse::<u64>().ok())
+            .zip(value.as_ref().and_then(|v| v.parse::<u64>().ok()));
+        match numbers {
+            Some((prev_limit, limit)) => {
+                if limit > prev_limit {
+                    log::info!(
+                        "Hoisting #![{} = \"{}\"] from {}, raising the limit set by {}",
+                        name,
+                        limit,
+                        krate,
+                        prev_krate
+                    );
+                    self.hoisted.insert(name, (krate.into(), attr));
+                }
+            }
+            None if prev_value == value => {}
+            None => bail!(
+                "Conflicting #![{}] in inlined crates: {:?} from {} and {:?} from {}",
+                name,
+                prev_value.unwrap_or_default(),
+                prev_krate,
+                value.unwrap_or_default(),
+                krate
+            ),
+        }
+        Ok(())
+    }
+
+    /// Append the hoisted attributes to the bundle root's `attrs`.
+    pub fn finish(self, attrs: &mut Vec<syn::Attribute>) {
+        attrs.extend(self.hoisted.into_values().map(|(_, attr)| attr));
+    }
+}
+
+const LINT_LEVELS: &[&str] = &["allow", "warn", "deny", "forbid"];
+
+/// Whether minifying drops `attr`: doc strings, lint levels and deprecations, none of which
+/// change what the code does. `#[doc(hidden)]` and the like are kept.
+pub fn is_minifiable(attr: &syn::Attribute) -> bool {
+    if attr.path.is_ident("doc") {
+        // `#[doc = ...]` rather than `#[doc(hidden)]`
+        let first = attr.tokens.clone().into_iter().next();
+        return matches!(first, Some(proc_macro2::TokenTree::Punct(p)) if p.as_char() == '=');
+    }
+    ["expect", "deprecated"]
+        .iter()
+        .chain(LINT_LEVELS)
+        .any(|name| attr.path.is_ident(name))
+}
+
+/// The lints listed in a lint level attribute like `#![allow(a, b)]`
+fn lint_list(attr: &syn::Attribute) -> Option<Vec<syn::Path>> {
+    if !LINT_LEVELS.iter().any(|level| attr.path.is_ident(level)) {
+        return None;
+    }
+    match attr.parse_meta().ok()? {
+        syn::Meta::List(list) => list
+            .nested
+            .into_iter()
+            .map(|nested| match nested {
+                syn::NestedMeta::Meta(syn::Meta::Path(path)) => Some(path),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Normalize the bundle root's inner attributes: drop exact duplicates, and if `merge_lints` is
+/// set, fold lint lists of the same level into the first attribute of that level. Doc attributes
+/// are never touched since their order is the document.
+pub fn dedup(attrs: &mut Vec<syn::Attribute>, merge_lints: bool) {
+    let mut seen = BTreeSet::new();
+    // lint level to index into `kept` and the lints it lists
+    let mut levels: BTreeMap<String, (usize, Vec<syn::Path>)> = BTreeMap::new();
+    let mut kept: Vec<syn::Attribute> = vec![];
+    for attr in attrs.drain(..) {
+        if attr.path.is_ident("doc") {
+            kept.push(attr);
+            continue;
+        }
+        if !seen.insert(attr.to_token_stream().to_string()) {
+            continue;
+        }
+        let lints = match lint_list(&attr) {
+            Some(lints) if merge_lints => lints,
+            _ => {
+                kept.push(attr);
+                continue;
+            }
+        };
+        let level = attr_name(&attr);
+        match levels.get_mut(&level) {
+            None => {
+                levels.insert(level, (kept.len(), lints));
+                kept.push(attr);
+            }
+            Some((idx, merged)) => {
+                for lint in lints {
+                    let name = lint.to_token_stream().to_string();
+                    if !merged
+                        .iter()
+                        .any(|m| m.to_token_stream().to_string() == name)
+                    {
+                        merged.push(lint);
+                    }
+                }
+                let level = &attr.path;
+                let lints = merged.iter();
+                kept[*idx] = syn::parse_quote!(#![#level(#(#lints),*)]);
+            }
+        }
+    }
+    *attrs = kept;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse::Parser;
+
+    fn inner(tokens: proc_macro2::TokenStream) -> Vec<syn::Attribute> {
+        syn::Attribute::parse_inner.parse2(tokens).unwrap()
+    }
+
+    #[test]
+    fn manifest_block_is_taken_out() {
+        let mut attrs = inner(quote::quote! {
+            #![doc = " A script"]
+            #![doc = " ```cargo"]
+            #![doc = " [dependencies]"]
+            #![doc = " ```"]
+            #![doc = " More text\n ```cargo\n [package]\n ```"]
+            #![allow(dead_code)]
+        });
+        let manifest = take_embedded_manifest(&mut attrs);
+        assert_eq!(manifest.as_deref(), Some("[dependencies]\n"));
+        let expected = inner(quote::quote! {
+            #![doc = " A script"]
+            #![doc = " More text\n ```cargo\n [package]\n ```"]
+            #![allow(dead_code)]
+        });
+        assert_eq!(attrs, expected);
+
+        let doc = " Intro\n ```cargo\n [package]\n ```\n Outro";
+        let mut attrs = inner(quote::quote!(#![doc = #doc]));
+        assert!(take_embedded_manifest(&mut attrs).is_some());
+        let docs: Vec<_> = attrs.iter().filter_map(doc_value).collect();
+        assert_eq!(docs, [" Intro\n Outro"]);
+        assert!(take_embedded_manifest(&mut attrs).is_none());
+    }
+
+    #[test]
+    fn numeric_limits_take_maximum() {
+        let mut root = RootAttrs::new(&[]);
+        for attr in inner(quote::quote! {
+            #![recursion_limit = "256"]
+            #![recursion_limit = "512"]
+            #![recursion_limit = "128"]
+        }) {
+            root.hoist("lib", attr).unwrap();
+        }
+        let mut attrs = vec![];
+        root.finish(&mut attrs);
+        assert_eq!(attrs, inner(quote::quote!(#![recursion_limit = "512"])));
+    }
+
+    #[test]
+    fn binary_takes_precedence() {
+        let binary = inner(quote::quote!(#![recursion_limit = "64"]));
+        let mut root = RootAttrs::new(&binary);
+        for attr in inner(quote::quote!(#![recursion_limit = "256"])) {
+            root.hoist("lib", attr).unwrap();
+        }
+        let mut attrs = vec![];
+        root.finish(&mut attrs);
+        assert!(attrs.is_empty());
+    }
+
+    #[test]
+    fn conflicting_values_error() {
+        let mut root = RootAttrs::new(&[]);
+        let attrs = inner(quote::quote! {
+            #![windows_subsystem = "windows"]
+            #![windows_subsystem = "console"]
+        });
+        let mut attrs = attrs.into_iter();
+        root.hoist("a", attrs.next().unwrap()).unwrap();
+        let err = root.hoist("b", attrs.next().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("windows_subsystem"));
+    }
+
+    #[test]
+    fn dedup_drops_exact_duplicates() {
+        let mut attrs = inner(quote::quote! {
+            #![allow(dead_code)]
+            #![doc = " a"]
+            #![allow(dead_code)]
+            #![doc = " a"]
+        });
+        dedup(&mut attrs, false);
+        let expected = inner(quote::quote! {
+            #![allow(dead_code)]
+            #![doc = " a"]
+            #![doc = " a"]
+        });
+        assert_eq!(attrs, expected);
+    }
+
+    #[test]
+    fn dedup_merges_lint_lists() {
+        let mut attrs = inner(quote::quote! {
+            #![allow(clippy::all)]
+            #![deny(unsafe_code)]
+            #![allow(clippy::pedantic, clippy::all)]
+        });
+        dedup(&mut attrs, true);
+        let expected = inner(quote::quote! {
+            #![allow(clippy::all, clippy::pedantic)]
+            #![deny(unsafe_code)]
+        });
+        assert_eq!(attrs, expected);
+    }
+}
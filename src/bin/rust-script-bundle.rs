@@ -1,28 +1,896 @@
+use std::cell::RefCell;
 use std::env;
+use std::fs;
+use std::io;
+use std::ops::ControlFlow;
 use std::path::{PathBuf, Path};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
-use anyhow::{Result, bail};
-use rust_script_bundler::Bundler;
+use anyhow::{anyhow, bail, Context, Result};
+use rust_script_bundler::{
+    BundleReport, Bundler, ConfigFile, Edition, Formatting, Freshness, GraphFormat, OutputFlavor,
+    Resolution, RunExpectation, Settings, SharedCrates, TargetTool,
+};
+
+const USAGE: &str = "\
+Usage:
+    rust-script-bundle [--manifest-dir <dir>] [--bin <name> | --bin-path <path> | --example <name>]
+                       [--lib] [--crate <name>=<lib.rs>]... [--output <file>] [--no-format]
+                       [--module-path <dir>]... [--shebang <line>] [--target <tool>]
+                       [--watch] [<options>]
+    rust-script-bundle <crate dir> <bin path> <output | ->  [<options>]
+    rust-script-bundle --all-bins <crate dir> <out dir>      [<options>]
+    rust-script-bundle --config <bundle.toml> [--profile <name>] [--print-config]
+                       [--out-dir <dir>] [--bin <name>]... [--lib]
+    rust-script-bundle check <crate dir> <bin path>
+    rust-script-bundle diff <old script> <new script> [--json]
+    rust-script-bundle unbundle <script> <out dir>
+    rust-script-bundle verify <script>
+    rust-script-bundle init [--force] [--dry-run]
+
+Bundling flags:
+    --manifest-dir <dir>      the package to bundle, the current directory by default
+    --bin <name>              the bin target to bundle, the one `cargo run` picks by default
+    --bin-path <path>         the binary's crate root, relative to the package
+    --example <name>          bundle the example target <name>, with the features it requires
+    --lib                     inline the package's own lib target
+    --crate <name>=<lib.rs>   inline the crate rooted at <lib.rs> as <name>, repeatable
+    --module-path <dir>       also look for module files in <dir>, e.g. OUT_DIR, repeatable
+    --output <file>           where to write the script, stdout by default or for -
+    --no-format               leave the script unformatted
+    --shebang <line>          start the script with <line> instead of the default shebang
+    --target <tool>           follow the conventions of rust-script (default), cargo or plain
+    --watch                   bundle again whenever an input changes, until interrupted
+
+Options:
+    --force                   overwrite files that do not look generated
+    --playground              produce a script for the Rust playground
+    --embed-inputs-hash       record the inputs in the script to check its freshness later
+    --trim-deps               drop dependencies the bundle does not refer to
+    --cache-dir <dir>         reuse processed crates across runs
+    --edition <edition>       bundle for this edition rather than the package's
+    --emit <what>[=<path>]    also produce deps-graph or depfile, repeatable
+    --report <file>           write what went into a single script, as JSON
+    --jobs <n>                bundle this many scripts at once
+    --fail-fast               stop at the first script that fails
+    --verify-run -- <args>... run the script with <args>, failing unless it succeeds
+    -v, -vv                   log what is done, and also every file read
+    -h, --help                print this help
+";
 
 fn main() {
     if let Err(e) = try_main() {
         eprintln!("{:?}", e);
+        process::exit(1);
     }
 }
 
 fn try_main() -> Result<()> {
-    let args = env::args_os()
+    let mut args = env::args_os()
         .skip(1)
         .map(PathBuf::from)
         .collect::<Vec<_>>();
+    // `--verify-run -- <args>...` ends the arguments, the rest are for the script
+    let verify_run = match args.iter().position(|a| a == Path::new("--verify-run")) {
+        Some(idx) => {
+            let script_args = args.split_off(idx);
+            if script_args.get(1).map_or(true, |a| a != Path::new("--")) {
+                bail!("--verify-run must be followed by -- and the script's arguments");
+            }
+            let script_args: Vec<_> = script_args[2..]
+                .iter()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect();
+            Some(script_args)
+        }
+        None => None,
+    };
+    if args
+        .iter()
+        .any(|a| a == Path::new("--help") || a == Path::new("-h"))
+    {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    let verbosity = args
+        .iter()
+        .map(|a| match a.to_str() {
+            Some("-v") | Some("--verbose") => 1,
+            Some("-vv") => 2,
+            Some("-vvv") => 3,
+            _ => 0,
+        })
+        .sum();
+    args.retain(|a| {
+        !["-v", "--verbose", "-vv", "-vvv"]
+            .iter()
+            .any(|f| a == Path::new(f))
+    });
+    init_logging(verbosity);
+    // write even over files that look like sources, for every command that writes a bundle
+    let force = args.iter().any(|a| a == Path::new("--force"));
+    args.retain(|a| a != Path::new("--force"));
+    let playground = args.iter().any(|a| a == Path::new("--playground"));
+    args.retain(|a| a != Path::new("--playground"));
+    let embed_hash = args.iter().any(|a| a == Path::new("--embed-inputs-hash"));
+    args.retain(|a| a != Path::new("--embed-inputs-hash"));
+    let trim_deps = args.iter().any(|a| a == Path::new("--trim-deps"));
+    args.retain(|a| a != Path::new("--trim-deps"));
+    let cache_dir = take_value(&mut args, "--cache-dir")?;
+    let edition = match take_value(&mut args, "--edition")? {
+        Some(edition) => Some(edition.to_string_lossy().parse::<Edition>()?),
+        None => None,
+    };
+    let report_path = take_value(&mut args, "--report")?;
+    let mut emit = Emit::default();
+    while let Some(value) = take_value(&mut args, "--emit")? {
+        emit.parse(&value.to_string_lossy())?;
+    }
+    let jobs = Jobs {
+        jobs: match take_value(&mut args, "--jobs")? {
+            Some(n) => n
+                .to_string_lossy()
+                .parse()
+                .map_err(|_| anyhow!("Invalid number of jobs {}", n.display()))?,
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        },
+        fail_fast: args.iter().any(|a| a == Path::new("--fail-fast")),
+    };
+    args.retain(|a| a != Path::new("--fail-fast"));
+    let dry_run = args.iter().any(|a| a == Path::new("--dry-run"));
+    args.retain(|a| a != Path::new("--dry-run"));
+    let options = Options {
+        force,
+        playground,
+        embed_hash,
+        trim_deps,
+        cache_dir,
+        edition,
+        report: report_path,
+        verify_run,
+        emit,
+    };
+
+    if args.first().map_or(false, |a| a == Path::new("--config")) {
+        return bundle_from_config(&args, &options, &jobs);
+    }
+    let mut flags = BundleFlags {
+        manifest_dir: take_value(&mut args, "--manifest-dir")?,
+        bin: take_value(&mut args, "--bin")?.map(|b| b.to_string_lossy().into_owned()),
+        bin_path: take_value(&mut args, "--bin-path")?,
+        example: take_value(&mut args, "--example")?.map(|e| e.to_string_lossy().into_owned()),
+        lib: args.iter().any(|a| a == Path::new("--lib")),
+        crates: vec![],
+        module_paths: vec![],
+        output: take_value(&mut args, "--output")?,
+        no_format: args.iter().any(|a| a == Path::new("--no-format")),
+        watch: args.iter().any(|a| a == Path::new("--watch")),
+        shebang: take_value(&mut args, "--shebang")?.map(|s| s.to_string_lossy().into_owned()),
+        target: match take_value(&mut args, "--target")? {
+            Some(tool) => Some(tool.to_string_lossy().parse::<TargetTool>()?),
+            None => None,
+        },
+    };
+    args.retain(|a| {
+        !["--lib", "--no-format", "--watch"]
+            .iter()
+            .any(|f| a == Path::new(f))
+    });
+    while let Some(spec) = take_value(&mut args, "--crate")? {
+        let spec = spec.to_string_lossy();
+        match spec.split_once('=') {
+            Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+                flags.crates.push((name.to_string(), PathBuf::from(path)))
+            }
+            _ => bail!("Invalid --crate {}, expected <name>=<path to lib.rs>", spec),
+        }
+    }
+    while let Some(dir) = take_value(&mut args, "--module-path")? {
+        flags.module_paths.push(dir);
+    }
+    if !args.is_empty() {
+        flags.reject_positional()?;
+    }
 
-    let (crate_path, bin_path, target_path) = match args[..] {
-        [ref c, ref b, ref t] => (c, b, t),
-        _ => bail!("Incorrect usage"),
+    match args[..] {
+        [] => bundle_from_flags(&flags, &options),
+        [ref flag, ref crate_path, ref out_dir] if flag == Path::new("--all-bins") => {
+            bundle_all_bins(crate_path, out_dir, &flags, &options, &jobs)
+        }
+        [ref cmd, ref script, ref out_dir] if cmd == Path::new("unbundle") => {
+            rust_script_bundler::unbundle(script, out_dir)?;
+            Ok(())
+        }
+        [ref cmd, ref script] if cmd == Path::new("verify") => verify(script),
+        [ref cmd] if cmd == Path::new("init") => init(options.force, dry_run),
+        [ref cmd, ref crate_path, ref bin_path] if cmd == Path::new("check") => {
+            check(crate_path, bin_path, options.edition)
+        }
+        [ref cmd, ref old, ref new] if cmd == Path::new("diff") => print_diff(old, new, false),
+        [ref cmd, ref old, ref new, ref flag]
+            if cmd == Path::new("diff") && flag == Path::new("--json") =>
+        {
+            print_diff(old, new, true)
+        }
+        [ref crate_path, ref bin_path, ref target_path] if target_path == Path::new("-") => {
+            if options.report.is_some() {
+                bail!("--report needs the script written to a file");
+            }
+            if options.embed_hash {
+                bail!("--embed-inputs-hash needs an --output file");
+            }
+            if options.verify_run.is_some() {
+                bail!("--verify-run needs an --output file");
+            }
+            let bundler =
+                flags.apply(options.apply(Bundler::new_in_memory(bin_path, crate_path)?))?;
+            bundler.bundle_to_writer(io::stdout().lock())
+        }
+        [ref crate_path, ref bin_path, ref target_path] => {
+            if target_path.file_name().is_none() {
+                bail!(
+                    "{} does not name a file to write the script to",
+                    target_path.display()
+                );
+            }
+            // relative targets are relative to the working directory, like the other arguments
+            let mut bundler =
+                flags.apply(options.apply(Bundler::new_in_memory(bin_path, crate_path)?))?;
+            if let Some((format, _)) = &options.emit.deps_graph {
+                bundler = bundler.emit_deps_graph(*format, false);
+            }
+            let report = bundler.bundle(target_path)?;
+            finish_bundle(report, &options.emit, options.report.as_deref())
+        }
+        _ => bail!("Incorrect usage, see --help:\n\n{}", USAGE),
+    }
+}
+
+/// Log warnings and errors, and with a `verbosity` of 1 what is done, 2 every file read, 3
+/// everything. `RUST_LOG` takes precedence.
+fn init_logging(verbosity: usize) {
+    let level = match verbosity {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
     };
+    let logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level))
+        .format_timestamp(None)
+        .build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(JobLogger(logger))).expect("logging is set up once");
+}
+
+thread_local! {
+    /// The name of the job running on this thread, if jobs run concurrently
+    static JOB: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Prefixes what concurrent jobs log with the name of the job, as their lines interleave
+struct JobLogger(env_logger::Logger);
+
+impl log::Log for JobLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        JOB.with(|job| match &*job.borrow() {
+            Some(job) => self.0.log(
+                &record
+                    .to_builder()
+                    .args(format_args!("{}: {}", job, record.args()))
+                    .build(),
+            ),
+            None => self.0.log(record),
+        })
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// The options of every mode that bundles
+struct Options {
+    /// write even over files that look like sources
+    force: bool,
+    playground: bool,
+    embed_hash: bool,
+    trim_deps: bool,
+    cache_dir: Option<PathBuf>,
+    edition: Option<Edition>,
+    /// where to write the report of the bundle as JSON
+    report: Option<PathBuf>,
+    /// the arguments to run the script with after bundling, from `--verify-run`
+    verify_run: Option<Vec<String>>,
+    emit: Emit,
+}
+
+impl Options {
+    /// Set up `bundler` the way the options say
+    fn apply(&self, bundler: Bundler) -> Bundler {
+        let mut bundler = bundler
+            .allow_overwrite(self.force)
+            .output_flavor(flavor(self.playground))
+            .embed_inputs_hash(self.embed_hash)
+            .trim_dependencies(self.trim_deps);
+        if let Some(dir) = &self.cache_dir {
+            bundler = bundler.cache_dir(dir);
+        }
+        if let Some(script_args) = &self.verify_run {
+            let script_args: Vec<_> = script_args.iter().map(String::as_str).collect();
+            bundler = bundler.verify_run(&script_args, RunExpectation::new());
+        }
+        if let Some(edition) = self.edition {
+            bundler = bundler.edition(edition);
+        }
+        bundler
+    }
+}
+
+/// How to bundle, from the flags. Only what to inline, how to format it and for which tool
+/// applies with positional arguments too.
+struct BundleFlags {
+    manifest_dir: Option<PathBuf>,
+    bin: Option<String>,
+    bin_path: Option<PathBuf>,
+    example: Option<String>,
+    lib: bool,
+    crates: Vec<(String, PathBuf)>,
+    module_paths: Vec<PathBuf>,
+    /// stdout if none or `-`
+    output: Option<PathBuf>,
+    no_format: bool,
+    shebang: Option<String>,
+    target: Option<TargetTool>,
+    watch: bool,
+}
+
+impl BundleFlags {
+    /// Inline, format and follow the conventions of a tool as the flags say
+    fn apply(&self, mut bundler: Bundler) -> Result<Bundler> {
+        if self.lib {
+            bundler = bundler.with_lib();
+        }
+        for (name, path) in &self.crates {
+            bundler = bundler.with_crate_at(name, path);
+        }
+        for dir in &self.module_paths {
+            bundler = bundler.add_module_search_path(dir);
+        }
+        if self.no_format {
+            bundler = bundler.formatting(Formatting::None);
+        }
+        if let Some(tool) = self.target {
+            bundler = bundler.target_tool(tool);
+        }
+        if let Some(line) = &self.shebang {
+            bundler = bundler.shebang(line)?;
+        }
+        Ok(bundler)
+    }
 
-    Bundler::new_with_dir(bin_path, target_path.parent().unwrap(), crate_path)?
-        .bundle(Path::new(target_path.file_name().unwrap()))?;
+    /// Fail for the flags picking what to bundle and where to, which positional arguments do
+    fn reject_positional(&self) -> Result<()> {
+        let given = [
+            ("--manifest-dir", self.manifest_dir.is_some()),
+            ("--bin", self.bin.is_some()),
+            ("--bin-path", self.bin_path.is_some()),
+            ("--example", self.example.is_some()),
+            ("--output", self.output.is_some()),
+            ("--watch", self.watch),
+        ];
+        match given.iter().find(|(_, given)| *given) {
+            Some((flag, _)) => bail!("{} can not be used with positional arguments", flag),
+            None => Ok(()),
+        }
+    }
+}
+
+fn bundle_from_flags(flags: &BundleFlags, options: &Options) -> Result<()> {
+    let manifest_dir = flags
+        .manifest_dir
+        .as_deref()
+        .unwrap_or_else(|| Path::new("."));
+    let new = |bin_path: &Path| Bundler::new_in_memory(bin_path, manifest_dir);
+    let bundler = match (&flags.bin, &flags.bin_path, &flags.example) {
+        (None, Some(path), None) => new(path)?,
+        (Some(name), None, None) => new(Path::new("src/main.rs"))?.with_bin(name),
+        (None, None, Some(name)) => new(Path::new("src/main.rs"))?.with_example(name),
+        (None, None, None) => new(Path::new("src/main.rs"))?.default_bin(),
+        _ => bail!("Only one of --bin, --bin-path and --example can be used"),
+    };
+    let mut bundler = flags.apply(options.apply(bundler))?;
+    if let Some((format, _)) = &options.emit.deps_graph {
+        bundler = bundler.emit_deps_graph(*format, false);
+    }
+    let emit = &options.emit;
+    match &flags.output {
+        Some(output) if flags.watch && output != Path::new("-") => {
+            // runs until interrupted
+            bundler.watch(output, Duration::from_millis(200), |result| {
+                match result {
+                    Ok(report) => {
+                        eprintln!(
+                            "ok: {} from {} inputs",
+                            report.output.display(),
+                            report.inputs.len()
+                        );
+                        let finished =
+                            finish_bundle(report.clone(), emit, options.report.as_deref());
+                        if let Err(e) = finished {
+                            eprintln!("failed: {:?}", e);
+                        }
+                    }
+                    Err(e) => eprintln!("failed: {:?}", e),
+                }
+                ControlFlow::Continue(())
+            });
+            Ok(())
+        }
+        _ if flags.watch => bail!("--watch needs an --output file"),
+        Some(output) if output != Path::new("-") => {
+            let report = bundler.bundle(output)?;
+            finish_bundle(report, emit, options.report.as_deref())
+        }
+        _ if options.report.is_some() => bail!("--report needs an --output file"),
+        _ if emit.depfile.is_some() => bail!("--emit depfile needs an --output file"),
+        _ if emit.deps_graph.is_some() => bail!("--emit deps-graph needs an --output file"),
+        _ if options.embed_hash => bail!("--embed-inputs-hash needs an --output file"),
+        _ if options.verify_run.is_some() => bail!("--verify-run needs an --output file"),
+        _ => bundler.bundle_to_writer(io::stdout().lock()),
+    }
+}
 
+/// Write what goes with the script of `report`: the report itself if asked to with `--report`,
+/// the depfile and dependency graph of `--emit`, the playground URL and the trimmed dependencies
+fn finish_bundle(report: BundleReport, emit: &Emit, report_path: Option<&Path>) -> Result<()> {
+    write_report(&report, report_path)?;
+    if let Some(url) = &report.playground_url {
+        eprintln!("{}", url);
+    }
+    emit.write_depfile(&report, false)?;
+    print_deps_trim(&report);
+    match (report.deps_graph, &emit.deps_graph) {
+        (Some(graph), Some((_, Some(path)))) => {
+            fs::write(path, graph).with_context(|| format!("Failed to write {}", path.display()))?
+        }
+        (Some(graph), _) => print!("{}", graph),
+        _ => {}
+    }
     Ok(())
 }
+
+fn flavor(playground: bool) -> OutputFlavor {
+    if playground {
+        OutputFlavor::Playground
+    } else {
+        OutputFlavor::RustScript
+    }
+}
+
+/// Remove `flag` and the value following it from `args`, returning the value
+fn take_value(args: &mut Vec<PathBuf>, flag: &str) -> Result<Option<PathBuf>> {
+    match args.iter().position(|a| a == Path::new(flag)) {
+        Some(idx) if idx + 1 < args.len() => {
+            let value = args.remove(idx + 1);
+            args.remove(idx);
+            Ok(Some(value))
+        }
+        Some(_) => bail!("Missing value for {}", flag),
+        None => Ok(None),
+    }
+}
+
+/// What to produce besides the scripts, from any number of `--emit`
+#[derive(Default)]
+struct Emit {
+    /// `deps-graph[=<path>]`, Mermaid for `.mmd` and `.mermaid` paths and DOT otherwise.
+    /// Without a path the graph goes to stdout.
+    deps_graph: Option<(GraphFormat, Option<PathBuf>)>,
+    /// `depfile[=<path>]`, written next to the script as `<script>.d` without a path
+    depfile: Option<Option<PathBuf>>,
+}
+
+impl Emit {
+    fn parse(&mut self, emit: &str) -> Result<()> {
+        let (kind, path) = match emit.split_once('=') {
+            Some((kind, path)) => (kind, Some(PathBuf::from(path))),
+            None => (emit, None),
+        };
+        match kind {
+            "deps-graph" => {
+                let mermaid = path
+                    .as_ref()
+                    .and_then(|p| p.extension())
+                    .map_or(false, |ext| ext == "mmd" || ext == "mermaid");
+                let format = if mermaid {
+                    GraphFormat::Mermaid
+                } else {
+                    GraphFormat::Dot
+                };
+                self.deps_graph = Some((format, path));
+            }
+            "depfile" => self.depfile = Some(path),
+            _ => bail!(
+                "Unknown --emit {}, only deps-graph[=<path>] and depfile[=<path>] are supported",
+                emit
+            ),
+        }
+        Ok(())
+    }
+
+    /// Write the depfile of `report` if asked to. An explicit path only makes sense with a
+    /// single script, so it is an error if `several`.
+    fn write_depfile(&self, report: &BundleReport, several: bool) -> Result<()> {
+        match &self.depfile {
+            Some(Some(_)) if several => {
+                bail!("--emit depfile=<path> can not be used for several scripts, drop the path")
+            }
+            Some(Some(path)) => report.write_depfile(path),
+            Some(None) => {
+                let mut path = report.output.clone().into_os_string();
+                path.push(".d");
+                report.write_depfile(path)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// How to run several bundling jobs, from `--jobs N` and `--fail-fast`
+struct Jobs {
+    jobs: usize,
+    fail_fast: bool,
+}
+
+impl Jobs {
+    /// Run `job` for each of `names`, which produces a file. A single job runs them in order and
+    /// stops at the first error. Otherwise they run concurrently, with a summary in the order of
+    /// `names` at the end.
+    fn bundle_all(
+        &self,
+        names: &[String],
+        job: impl Fn(&str) -> Result<PathBuf> + Sync,
+    ) -> Result<()> {
+        if self.jobs <= 1 {
+            for name in names {
+                job(name)?;
+            }
+            return Ok(());
+        }
+        let results = self.run(names, job);
+        self.summarize(names, results)
+    }
+
+    /// Run `job` for each of `names` on up to `self.jobs` threads. Results are in the order of
+    /// `names`, and jobs not started because an earlier one failed with `--fail-fast` have none.
+    fn run<T: Send>(
+        &self,
+        names: &[String],
+        job: impl Fn(&str) -> Result<T> + Sync,
+    ) -> Vec<Option<Result<T>>> {
+        let results: Mutex<Vec<Option<Result<T>>>> =
+            Mutex::new(names.iter().map(|_| None).collect());
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let worker = || loop {
+            if self.fail_fast && failed.load(Ordering::SeqCst) {
+                break;
+            }
+            let idx = next.fetch_add(1, Ordering::SeqCst);
+            let name = match names.get(idx) {
+                Some(name) => name,
+                None => break,
+            };
+            JOB.with(|job| *job.borrow_mut() = Some(name.clone()));
+            let result = job(name);
+            JOB.with(|job| *job.borrow_mut() = None);
+            if result.is_err() {
+                failed.store(true, Ordering::SeqCst);
+            }
+            results.lock().unwrap()[idx] = Some(result);
+        };
+        thread::scope(|scope| {
+            for _ in 0..self.jobs.min(names.len()) {
+                scope.spawn(worker);
+            }
+        });
+        results.into_inner().unwrap()
+    }
+
+    /// Print a line per job in order, failing if any did
+    fn summarize(&self, names: &[String], results: Vec<Option<Result<PathBuf>>>) -> Result<()> {
+        let mut failures = 0;
+        for (name, result) in names.iter().zip(results) {
+            match result {
+                Some(Ok(output)) => println!("{}: {}", name, output.display()),
+                Some(Err(e)) => {
+                    failures += 1;
+                    eprintln!("{}: failed: {:?}", name, e);
+                }
+                None => eprintln!("{}: skipped", name),
+            }
+        }
+        if failures > 0 {
+            bail!("{} of {} bundles failed", failures, names.len());
+        }
+        Ok(())
+    }
+}
+
+/// `--config <bundle.toml> [--profile <name>] [--print-config] [--out-dir <dir>] [--bin <name>]...
+/// [--lib]`, where flags override values from the config and its profile, as does `--edition`
+fn bundle_from_config(args: &[PathBuf], options: &Options, jobs: &Jobs) -> Result<()> {
+    if options.report.is_some() {
+        bail!("--report needs a single script");
+    }
+    let mut config = None;
+    let mut profile = None;
+    let mut print_config = false;
+    let mut cli = Settings {
+        edition: options.edition,
+        ..Default::default()
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| anyhow!("Missing value for {}", arg))
+        };
+        match arg.as_ref() {
+            "--config" => config = Some(value()?),
+            "--profile" => profile = Some(value()?.to_string_lossy().into_owned()),
+            "--print-config" => print_config = true,
+            "--out-dir" => cli.out_dir = Some(value()?),
+            "--bin" => {
+                let bin = value()?.to_string_lossy().into_owned();
+                cli.bins.get_or_insert_with(Vec::new).push(bin);
+            }
+            "--lib" => cli.lib = Some(true),
+            _ => bail!("Unknown argument {}", arg),
+        }
+    }
+    let config = config.ok_or_else(|| anyhow!("Missing --config"))?;
+    let settings = ConfigFile::from_path(&config)?
+        .effective(profile.as_deref())?
+        .merge(cli);
+
+    if print_config {
+        print!("{}", toml::to_string(&settings)?);
+        return Ok(());
+    }
+
+    let crate_dir = settings.crate_dir.clone().unwrap_or_default();
+    let out_dir = settings
+        .out_dir
+        .clone()
+        .ok_or_else(|| anyhow!("No out-dir in the config or on the command line"))?;
+    let bins = match &settings.bins {
+        Some(bins) => bins.clone(),
+        None => Bundler::new_with_dir("src/main.rs", &out_dir, &crate_dir)?.bin_names()?,
+    };
+    let bundler =
+        options.apply(settings.apply(Bundler::new_with_dir("src/main.rs", &out_dir, &crate_dir)?));
+    let shared = SharedCrates::new();
+    jobs.bundle_all(&bins, |bin| {
+        let mut report = bundler.bundle_bin(bin, Path::new(&format!("{}.rs", bin)), &shared)?;
+        // changing the config changes the script too
+        report.inputs.push(config.clone());
+        options.emit.write_depfile(&report, true)?;
+        if let Some(url) = &report.playground_url {
+            eprintln!("{}: {}", report.output.display(), url);
+        }
+        print_deps_trim(&report);
+        Ok(report.output)
+    })
+}
+
+/// Bundle every bin target of the crate as `<out_dir>/<bin name>.rs`
+fn bundle_all_bins(
+    crate_path: &Path,
+    out_dir: &Path,
+    flags: &BundleFlags,
+    options: &Options,
+    jobs: &Jobs,
+) -> Result<()> {
+    if options.report.is_some() {
+        bail!("--report needs a single script");
+    }
+    let bundler = Bundler::new_with_dir("src/main.rs", out_dir, crate_path)?;
+    let bundler = flags.apply(options.apply(bundler))?;
+    let bins = bundler.bin_names()?;
+    // the crates the bins share are only processed once
+    let shared = SharedCrates::new();
+    jobs.bundle_all(&bins, |bin| {
+        let report = bundler.bundle_bin(bin, Path::new(&format!("{}.rs", bin)), &shared)?;
+        options.emit.write_depfile(&report, true)?;
+        if let Some(url) = &report.playground_url {
+            eprintln!("{}: {}", report.output.display(), url);
+        }
+        print_deps_trim(&report);
+        Ok(report.output)
+    })
+}
+
+/// List the dependencies `--trim-deps` kept and dropped for the script of `report`
+fn print_deps_trim(report: &BundleReport) {
+    if let Some(trim) = &report.deps_trim {
+        eprintln!(
+            "{}: kept dependencies [{}], dropped [{}]",
+            report.output.display(),
+            trim.kept.join(", "),
+            trim.dropped.join(", ")
+        );
+    }
+}
+
+/// Write `report` as JSON to `path`, if given with `--report`
+fn write_report(report: &BundleReport, path: Option<&Path>) -> Result<()> {
+    if let Some(path) = path {
+        fs::write(path, serde_json::to_string_pretty(report)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Set up the package in the working directory for bundling from its build script, only
+/// printing the changes with `--dry-run`
+fn init(force: bool, dry_run: bool) -> Result<()> {
+    let plan = rust_script_bundler::plan_init(env::current_dir()?, force, Resolution::default())?;
+    for edit in &plan.edits {
+        if dry_run {
+            println!("would {} in {}:", edit.summary, edit.path.display());
+            print!("{}", edit.content);
+        } else {
+            println!("{}: {}", edit.path.display(), edit.summary);
+        }
+    }
+    for note in &plan.notes {
+        println!("note: {}", note);
+    }
+    if !dry_run {
+        plan.apply()?;
+    }
+    Ok(())
+}
+
+/// Report whether bundling `bin_path` of the crate would succeed, without writing anything
+fn check(crate_path: &Path, bin_path: &Path, edition: Option<Edition>) -> Result<()> {
+    let mut bundler = Bundler::new_with_dir(bin_path, env::temp_dir(), crate_path)?;
+    if let Some(edition) = edition {
+        bundler = bundler.edition(edition);
+    }
+    let report = bundler.check()?;
+    for input in &report.inputs {
+        println!("input: {}", input.display());
+    }
+    for warning in &report.warnings {
+        println!("warning: {}", warning);
+    }
+    println!("ok, {} bytes before formatting", report.output_size);
+    Ok(())
+}
+
+/// Check a bundle against the inputs hash embedded in it, exiting with 0 if it is up to date, 1
+/// if it is stale and 2 if that can not be told
+fn verify(script: &Path) -> Result<()> {
+    let freshness = rust_script_bundler::check_freshness(script)
+        .unwrap_or_else(|e| Freshness::Unverifiable(format!("{:#}", e)));
+    match freshness {
+        Freshness::UpToDate => {
+            println!("up-to-date: {}", script.display());
+            Ok(())
+        }
+        Freshness::Stale(why) => {
+            println!("stale: {}: {}", script.display(), why);
+            std::process::exit(1);
+        }
+        Freshness::Unverifiable(why) => {
+            println!("unverifiable: {}", why);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Print the semantic diff between two scripts, exiting with 1 if there is any
+fn print_diff(old: &Path, new: &Path, json: bool) -> Result<()> {
+    let diff = rust_script_bundler::diff(old, new)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        print!("{}", diff);
+    }
+    if !diff.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn results_are_in_the_order_of_names() {
+        let jobs = Jobs {
+            jobs: 3,
+            fail_fast: false,
+        };
+        let names = names(&["slow", "medium", "fast"]);
+        // the jobs finish in the reverse order they start in
+        let results = jobs.run(&names, |name| {
+            let millis = match name {
+                "slow" => 200,
+                "medium" => 100,
+                _ => 0,
+            };
+            thread::sleep(Duration::from_millis(millis));
+            Ok(name.to_string())
+        });
+        let results: Vec<_> = results.into_iter().map(|r| r.unwrap().unwrap()).collect();
+        assert_eq!(results, names);
+    }
+
+    #[test]
+    fn fail_fast_skips_jobs_not_started() {
+        let jobs = Jobs {
+            jobs: 2,
+            fail_fast: true,
+        };
+        let names = names(&["broken", "slow", "never"]);
+        let results = jobs.run(&names, |name| match name {
+            "broken" => bail!("broken"),
+            _ => {
+                thread::sleep(Duration::from_millis(100));
+                Ok(())
+            }
+        });
+        assert!(matches!(results[0], Some(Err(_))));
+        // the other thread may have taken the slow job before the failure
+        assert!(results[2].is_none());
+
+        let jobs = Jobs {
+            fail_fast: false,
+            ..jobs
+        };
+        let results = jobs.run(&names, |name| match name {
+            "broken" => bail!("broken"),
+            _ => Ok(()),
+        });
+        assert!(matches!(
+            results[..],
+            [Some(Err(_)), Some(Ok(())), Some(Ok(()))]
+        ));
+    }
+
+    #[test]
+    fn a_single_job_runs_in_order_and_stops_at_the_first_error() {
+        let jobs = Jobs {
+            jobs: 1,
+            fail_fast: false,
+        };
+        let started = Mutex::new(vec![]);
+        let result = jobs.bundle_all(&names(&["a", "b", "c", "d"]), |name| {
+            started.lock().unwrap().push(name.to_string());
+            match name {
+                "c" => bail!("c failed"),
+                _ => Ok(PathBuf::from(format!("{}.rs", name))),
+            }
+        });
+        assert_eq!(result.unwrap_err().to_string(), "c failed");
+        assert_eq!(*started.lock().unwrap(), ["a", "b", "c"]);
+    }
+}
@@ -0,0 +1,264 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{Context, Result};
+use quote::ToTokens;
+use serde::{Deserialize, Serialize};
+
+use crate::template;
+use crate::warning::Warning;
+
+/// Makes temporary file names unique within the process
+static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Tells the [`SharedCrates`] apart
+static NEXT_SHARED: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The crates of every [`SharedCrates`], by its id, crate root and options
+    static SHARED: RefCell<BTreeMap<(usize, PathBuf, String), ProcessedCrate>> =
+        RefCell::new(BTreeMap::new());
+}
+
+/// An inlined crate after all the processing that only depends on the crate itself
+#[derive(Clone)]
+pub struct ProcessedCrate {
+    pub file: syn::File,
+    /// files of the crate, as reported in the inputs
+    pub sources: Vec<PathBuf>,
+    /// further files the processing depended on, like manifests
+    pub depends_on: Vec<PathBuf>,
+    pub warnings: Vec<Warning>,
+    /// whether features were resolved for the crate
+    pub featured: bool,
+    /// names the crate may refer to dependencies by, see [`references`](crate::deps::references)
+    pub references: BTreeSet<String>,
+    /// nodes removed for their cfg, see [`cfg::strip`](crate::cfg::strip)
+    pub cfg_stripped: usize,
+}
+
+/// On disk cache of [`ProcessedCrate`]s, keyed by crate root. An entry is only used if it was
+/// written by the same version of the bundler with the same options, and none of the files it
+/// depends on changed since.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    version: String,
+    options: String,
+    sources: Vec<PathBuf>,
+    /// every file with the hash of its content at the time
+    hashes: Vec<(PathBuf, String)>,
+    warnings: Vec<Warning>,
+    featured: bool,
+    references: BTreeSet<String>,
+    cfg_stripped: usize,
+    /// the processed crate as tokens, which is cheap to store and to parse back
+    code: String,
+}
+
+/// Crates processed by one bundle for the next ones, see
+/// [`Bundler::bundle_all`](crate::Bundler::bundle_all) and
+/// [`Bundler::bundle_bin`](crate::Bundler::bundle_bin), by crate root and options. Bundles may run
+/// on several threads at once: a crate is only processed by the first bundle that needs it, and
+/// the others wait for it. Syntax trees can not leave the thread that parsed them, as their spans
+/// point into a source map of that thread, so the crates are kept in a thread local, and other
+/// threads parse them back from their printed form. They go when this is dropped.
+pub struct SharedCrates {
+    id: usize,
+    /// the crates for other threads, or none if processing failed
+    printed: Mutex<BTreeMap<(PathBuf, String), Arc<OnceLock<Option<PrintedCrate>>>>>,
+}
+
+/// A [`ProcessedCrate`] that can be sent to other threads
+struct PrintedCrate {
+    code: String,
+    sources: Vec<PathBuf>,
+    depends_on: Vec<PathBuf>,
+    warnings: Vec<Warning>,
+    featured: bool,
+    references: BTreeSet<String>,
+    cfg_stripped: usize,
+}
+
+impl SharedCrates {
+    pub fn new() -> Self {
+        SharedCrates {
+            id: NEXT_SHARED.fetch_add(1, Ordering::SeqCst),
+            printed: Default::default(),
+        }
+    }
+
+    /// The crate rooted at `path` processed with `options`, by an earlier bundle or else by
+    /// `process`, and whether it was processed before
+    pub(crate) fn get_or_process(
+        &self,
+        path: &Path,
+        options: &str,
+        process: impl FnOnce() -> Result<ProcessedCrate>,
+    ) -> Result<(ProcessedCrate, bool)> {
+        let key = (self.id, path.to_path_buf(), options.to_string());
+        if let Some(processed) = SHARED.with(|shared| shared.borrow().get(&key).cloned()) {
+            return Ok((processed, true));
+        }
+        let slot = self
+            .printed
+            .lock()
+            .unwrap()
+            .entry((key.1.clone(), key.2.clone()))
+            .or_default()
+            .clone();
+        let mut process = Some(process);
+        let mut fresh = None;
+        let printed = slot.get_or_init(|| {
+            fresh = process.take().map(|process| process());
+            let processed = fresh.as_ref().and_then(|result| result.as_ref().ok());
+            processed.map(PrintedCrate::print)
+        });
+        let (processed, hit) = match (fresh, process) {
+            (Some(result), _) => (result?, false),
+            (None, Some(process)) => match printed.as_ref().and_then(PrintedCrate::parse) {
+                Some(processed) => {
+                    log::debug!(
+                        "Using the processing of {} by another bundle",
+                        path.display()
+                    );
+                    (processed, true)
+                }
+                // it failed for the other bundle, so fail the same way
+                None => (process()?, false),
+            },
+            (None, None) => unreachable!("processing ran without a result"),
+        };
+        SHARED.with(|shared| shared.borrow_mut().insert(key, processed.clone()));
+        Ok((processed, hit))
+    }
+}
+
+impl Default for SharedCrates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PrintedCrate {
+    fn print(processed: &ProcessedCrate) -> Self {
+        PrintedCrate {
+            code: processed.file.to_token_stream().to_string(),
+            sources: processed.sources.clone(),
+            depends_on: processed.depends_on.clone(),
+            warnings: processed.warnings.clone(),
+            featured: processed.featured,
+            references: processed.references.clone(),
+            cfg_stripped: processed.cfg_stripped,
+        }
+    }
+
+    fn parse(&self) -> Option<ProcessedCrate> {
+        Some(ProcessedCrate {
+            file: syn::parse_str(&self.code).ok()?,
+            sources: self.sources.clone(),
+            depends_on: self.depends_on.clone(),
+            warnings: self.warnings.clone(),
+            featured: self.featured,
+            references: self.references.clone(),
+            cfg_stripped: self.cfg_stripped,
+        })
+    }
+}
+
+impl Drop for SharedCrates {
+    fn drop(&mut self) {
+        let id = self.id;
+        // the thread local is gone already when dropped at thread exit
+        let _ = SHARED.try_with(|shared| shared.borrow_mut().retain(|key, _| key.0 != id));
+    }
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Cache { dir: dir.into() }
+    }
+
+    fn entry_path(&self, root: &Path) -> PathBuf {
+        let key = root.to_string_lossy();
+        let name = root
+            .file_stem()
+            .map_or("crate".into(), |s| s.to_string_lossy());
+        self.dir
+            .join(format!("{}-{}.json", name, template::fnv1a(key.as_bytes())))
+    }
+
+    /// The cached crate rooted at `root`, if still valid for `options`
+    pub fn load(&self, root: &Path, options: &str) -> Option<ProcessedCrate> {
+        let text = fs::read_to_string(self.entry_path(root)).ok()?;
+        let entry: Entry = serde_json::from_str(&text).ok()?;
+        if entry.version != env!("CARGO_PKG_VERSION") || entry.options != options {
+            return None;
+        }
+        for (path, hash) in &entry.hashes {
+            if template::inputs_hash(std::slice::from_ref(path)).ok()? != *hash {
+                return None;
+            }
+        }
+        let file = syn::parse_str(&entry.code).ok()?;
+        log::debug!("Using the cached processing of {}", root.display());
+        Some(ProcessedCrate {
+            file,
+            depends_on: entry
+                .hashes
+                .into_iter()
+                .map(|(path, _)| path)
+                .filter(|path| !entry.sources.contains(path))
+                .collect(),
+            sources: entry.sources,
+            warnings: entry.warnings,
+            featured: entry.featured,
+            references: entry.references,
+            cfg_stripped: entry.cfg_stripped,
+        })
+    }
+
+    pub fn store(&self, root: &Path, options: &str, processed: &ProcessedCrate) -> Result<()> {
+        let hashes = processed
+            .sources
+            .iter()
+            .chain(&processed.depends_on)
+            .map(|path| {
+                Ok((
+                    path.clone(),
+                    template::inputs_hash(std::slice::from_ref(path))?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        let entry = Entry {
+            version: env!("CARGO_PKG_VERSION").into(),
+            options: options.into(),
+            sources: processed.sources.clone(),
+            hashes,
+            warnings: processed.warnings.clone(),
+            featured: processed.featured,
+            references: processed.references.clone(),
+            cfg_stripped: processed.cfg_stripped,
+            code: processed.file.to_token_stream().to_string(),
+        };
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create cache dir {}", self.dir.display()))?;
+        // concurrent bundles may store the same crate, so never leave a half written entry
+        let path = self.entry_path(root);
+        let tmp = path.with_extension(format!(
+            "json.{}-{}",
+            std::process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, serde_json::to_string(&entry)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
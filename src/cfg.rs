@@ -0,0 +1,516 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::parse_quote;
+use syn::punctuated::Punctuated;
+use syn::visit_mut::{self, VisitMut};
+
+/// A set of cfg options to evaluate `cfg(...)` predicates against.
+///
+/// Only keys the set knows about are decided: a predicate on any other key, say `target_os` in a
+/// set made of features, stays undecided and is left for rustc.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CfgSet {
+    names: BTreeSet<String>,
+    values: BTreeSet<(String, String)>,
+    known: BTreeSet<String>,
+}
+
+/// What is left of a predicate once the keys of a [`CfgSet`] are decided
+#[derive(Clone)]
+pub enum Cfg {
+    True,
+    False,
+    Undecided(syn::NestedMeta),
+}
+
+impl CfgSet {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The cfgs of the machine running the bundler, deciding `unix`, `windows`, `target_os`,
+    /// `target_family` and `target_arch`.
+    pub fn host() -> Self {
+        let family = std::env::consts::FAMILY;
+        let mut set = CfgSet::new()
+            .with_known("unix")
+            .with_known("windows")
+            .with_value("target_os", std::env::consts::OS)
+            .with_value("target_family", family)
+            .with_value("target_arch", std::env::consts::ARCH);
+        if family == "unix" || family == "windows" {
+            set = set.with_name(family);
+        }
+        set
+    }
+
+    /// Set a name option like `unix`
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        self.known.insert(name.clone());
+        self.names.insert(name);
+        self
+    }
+
+    /// Set a key-value option like `target_os = "linux"`. Keys may have several values.
+    pub fn with_value(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let key = key.into();
+        self.known.insert(key.clone());
+        self.values.insert((key, value.into()));
+        self
+    }
+
+    /// Enable the feature `feature`, deciding all `feature = "..."` predicates
+    pub fn with_feature(self, feature: impl Into<String>) -> Self {
+        self.with_value("feature", feature)
+    }
+
+    /// Decide predicates on `key` even with nothing set for it, making them false.
+    pub fn with_known(mut self, key: impl Into<String>) -> Self {
+        self.known.insert(key.into());
+        self
+    }
+
+    /// Everything set or known in either set
+    pub fn union(mut self, other: &CfgSet) -> Self {
+        self.names.extend(other.names.iter().cloned());
+        self.values.extend(other.values.iter().cloned());
+        self.known.extend(other.known.iter().cloned());
+        self
+    }
+
+    /// Evaluate a predicate given either as `cfg(...)` or as its content, `None` if undecided.
+    pub fn eval_str(&self, predicate: &str) -> Result<Option<bool>> {
+        let meta = syn::parse_str::<syn::NestedMeta>(predicate)
+            .with_context(|| format!("Invalid cfg predicate {}", predicate))?;
+        let meta = match meta {
+            syn::NestedMeta::Meta(syn::Meta::List(list))
+                if list.path.is_ident("cfg") && list.nested.len() == 1 =>
+            {
+                list.nested.into_iter().next().unwrap()
+            }
+            meta => meta,
+        };
+        Ok(match self.simplify(&meta) {
+            Cfg::True => Some(true),
+            Cfg::False => Some(false),
+            Cfg::Undecided(_) => None,
+        })
+    }
+
+    /// Decide as much of `predicate` as this set knows about.
+    pub(crate) fn simplify(&self, predicate: &syn::NestedMeta) -> Cfg {
+        let undecided = || Cfg::Undecided(predicate.clone());
+        let meta = match predicate {
+            syn::NestedMeta::Meta(meta) => meta,
+            syn::NestedMeta::Lit(_) => return undecided(),
+        };
+        match meta {
+            syn::Meta::Path(path) => {
+                let name = match path.get_ident() {
+                    Some(ident) => ident.to_string(),
+                    None => return undecided(),
+                };
+                if self.names.contains(&name) {
+                    Cfg::True
+                } else if self.known.contains(&name) {
+                    Cfg::False
+                } else {
+                    undecided()
+                }
+            }
+            syn::Meta::NameValue(syn::MetaNameValue {
+                path,
+                lit: syn::Lit::Str(value),
+                ..
+            }) => {
+                let key = match path.get_ident() {
+                    Some(ident) => ident.to_string(),
+                    None => return undecided(),
+                };
+                if self.values.contains(&(key.clone(), value.value())) {
+                    Cfg::True
+                } else if self.known.contains(&key) {
+                    Cfg::False
+                } else {
+                    undecided()
+                }
+            }
+            syn::Meta::NameValue(_) => undecided(),
+            syn::Meta::List(list) => {
+                let nested: Vec<_> = list.nested.iter().map(|p| self.simplify(p)).collect();
+                if list.path.is_ident("all") {
+                    combine(nested, false, |rest| parse_quote!(all(#(#rest),*)))
+                } else if list.path.is_ident("any") {
+                    combine(nested, true, |rest| parse_quote!(any(#(#rest),*)))
+                } else if list.path.is_ident("not") && nested.len() == 1 {
+                    match nested.into_iter().next().unwrap() {
+                        Cfg::True => Cfg::False,
+                        Cfg::False => Cfg::True,
+                        Cfg::Undecided(p) => Cfg::Undecided(parse_quote!(not(#p))),
+                    }
+                } else {
+                    undecided()
+                }
+            }
+        }
+    }
+}
+
+/// Combine the operands of `all` (`short` false) or `any` (`short` true): an operand equal to
+/// `short` decides the whole, others equal to `!short` drop out.
+fn combine(
+    nested: Vec<Cfg>,
+    short: bool,
+    rebuild: impl FnOnce(&[syn::NestedMeta]) -> syn::NestedMeta,
+) -> Cfg {
+    let decided = |value: bool| if value { Cfg::True } else { Cfg::False };
+    let mut rest = vec![];
+    for cfg in nested {
+        match cfg {
+            Cfg::True if short => return Cfg::True,
+            Cfg::False if !short => return Cfg::False,
+            Cfg::True | Cfg::False => {}
+            Cfg::Undecided(p) => rest.push(p),
+        }
+    }
+    match rest.len() {
+        0 => decided(!short),
+        1 => Cfg::Undecided(rest.pop().unwrap()),
+        _ => Cfg::Undecided(rebuild(&rest)),
+    }
+}
+
+/// Evaluate `#[cfg]` and `#[cfg_attr]` in `file` as far as `set` decides them: nodes whose cfg
+/// is false are removed, attributes whose cfg is true are dropped or unwrapped, and undecided
+/// parts are kept for rustc. Covers items, impl and trait items, statements, fields, variants and
+/// match arms. Returns how many nodes were removed, not counting those inside them.
+pub fn strip(file: &mut syn::File, set: &CfgSet) -> usize {
+    match resolve_attrs(set, std::mem::take(&mut file.attrs)) {
+        Some(attrs) => file.attrs = attrs,
+        // the whole crate is cfg'd out
+        None => return std::mem::take(&mut file.items).len(),
+    }
+    let mut stripper = Stripper { set, removed: 0 };
+    stripper.visit_file_mut(file);
+    stripper.removed
+}
+
+/// Remove `mod name;` declarations that are cfg'd out, including in inline modules, before
+/// anything looks for their files. Returns the names of the removed modules.
+pub fn drop_disabled_mod_decls(file: &mut syn::File, set: &CfgSet) -> Vec<String> {
+    fn drop_in(items: &mut Vec<syn::Item>, set: &CfgSet, dropped: &mut Vec<String>) {
+        items.retain_mut(|item| match item {
+            syn::Item::Mod(module) => match &mut module.content {
+                None if resolve_attrs(set, module.attrs.clone()).is_none() => {
+                    dropped.push(module.ident.to_string());
+                    false
+                }
+                None => true,
+                Some((_, content)) => {
+                    drop_in(content, set, dropped);
+                    true
+                }
+            },
+            _ => true,
+        });
+    }
+    let mut dropped = vec![];
+    drop_in(&mut file.items, set, &mut dropped);
+    dropped
+}
+
+/// Resolve the cfg attributes of one node, `None` if the node is cfg'd out.
+fn resolve_attrs(set: &CfgSet, attrs: Vec<syn::Attribute>) -> Option<Vec<syn::Attribute>> {
+    let mut kept = vec![];
+    for attr in attrs {
+        if attr.path.is_ident("cfg") {
+            let predicate = match attr.parse_args::<syn::NestedMeta>() {
+                Ok(predicate) => predicate,
+                Err(_) => {
+                    kept.push(attr);
+                    continue;
+                }
+            };
+            match set.simplify(&predicate) {
+                Cfg::True => {}
+                Cfg::False => return None,
+                Cfg::Undecided(p) => kept.push(syn::Attribute {
+                    tokens: quote!((#p)),
+                    ..attr
+                }),
+            }
+        } else if attr.path.is_ident("cfg_attr") {
+            let parser = Punctuated::<syn::NestedMeta, syn::Token![,]>::parse_terminated;
+            let (predicate, wrapped) = match attr.parse_args_with(parser) {
+                Ok(args) if args.len() >= 2 => {
+                    let mut args = args.into_iter();
+                    (args.next().unwrap(), args.collect::<Vec<_>>())
+                }
+                _ => {
+                    kept.push(attr);
+                    continue;
+                }
+            };
+            match set.simplify(&predicate) {
+                Cfg::True => {
+                    let unwrapped = wrapped
+                        .into_iter()
+                        .map(|meta| {
+                            let (path, tokens) = split_meta(meta);
+                            syn::Attribute {
+                                path,
+                                tokens,
+                                ..attr.clone()
+                            }
+                        })
+                        .collect();
+                    // the unwrapped attributes may be cfgs themselves
+                    kept.extend(resolve_attrs(set, unwrapped)?);
+                }
+                Cfg::False => {}
+                Cfg::Undecided(p) => kept.push(syn::Attribute {
+                    tokens: quote!((#p, #(#wrapped),*)),
+                    ..attr
+                }),
+            }
+        } else {
+            kept.push(attr);
+        }
+    }
+    Some(kept)
+}
+
+/// Split `path`, `path(args)` or `path = value` into an attribute's path and tokens
+fn split_meta(meta: syn::NestedMeta) -> (syn::Path, TokenStream) {
+    match meta {
+        syn::NestedMeta::Meta(syn::Meta::Path(path)) => (path, TokenStream::new()),
+        syn::NestedMeta::Meta(syn::Meta::List(list)) => {
+            let nested = list.nested;
+            (list.path, quote!((#nested)))
+        }
+        syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) => {
+            let lit = nv.lit;
+            (nv.path, quote!(= #lit))
+        }
+        // not a valid attribute to begin with, keep it around as a doc string
+        syn::NestedMeta::Lit(lit) => (parse_quote!(doc), quote!(= #lit)),
+    }
+}
+
+/// Attributes of an item, `None` for verbatim items
+pub fn item_attrs(item: &mut syn::Item) -> Option<&mut Vec<syn::Attribute>> {
+    use syn::Item::*;
+    Some(match item {
+        Const(i) => &mut i.attrs,
+        Enum(i) => &mut i.attrs,
+        ExternCrate(i) => &mut i.attrs,
+        Fn(i) => &mut i.attrs,
+        ForeignMod(i) => &mut i.attrs,
+        Impl(i) => &mut i.attrs,
+        Macro(i) => &mut i.attrs,
+        Macro2(i) => &mut i.attrs,
+        Mod(i) => &mut i.attrs,
+        Static(i) => &mut i.attrs,
+        Struct(i) => &mut i.attrs,
+        Trait(i) => &mut i.attrs,
+        TraitAlias(i) => &mut i.attrs,
+        Type(i) => &mut i.attrs,
+        Union(i) => &mut i.attrs,
+        Use(i) => &mut i.attrs,
+        // never look into verbatim tokens, they are reported by `verbatim::check`
+        Verbatim(_) => return None,
+        _ => return None,
+    })
+}
+
+struct Stripper<'a> {
+    set: &'a CfgSet,
+    /// nodes removed so far
+    removed: usize,
+}
+
+impl Stripper<'_> {
+    /// Keep the nodes not cfg'd out, resolving their attributes. Nodes `attrs` returns `None`
+    /// for are kept as they are.
+    fn retain<T>(
+        &mut self,
+        nodes: impl IntoIterator<Item = T>,
+        attrs: fn(&mut T) -> Option<&mut Vec<syn::Attribute>>,
+    ) -> Vec<T> {
+        let set = self.set;
+        let removed = &mut self.removed;
+        nodes
+            .into_iter()
+            .filter_map(|mut node| {
+                if let Some(attrs) = attrs(&mut node) {
+                    match resolve_attrs(set, std::mem::take(attrs)) {
+                        Some(resolved) => *attrs = resolved,
+                        None => {
+                            *removed += 1;
+                            return None;
+                        }
+                    }
+                }
+                Some(node)
+            })
+            .collect()
+    }
+}
+
+impl VisitMut for Stripper<'_> {
+    fn visit_file_mut(&mut self, file: &mut syn::File) {
+        file.items = self.retain(std::mem::take(&mut file.items), item_attrs);
+        visit_mut::visit_file_mut(self, file);
+    }
+
+    fn visit_item_mod_mut(&mut self, module: &mut syn::ItemMod) {
+        if let Some((_, items)) = &mut module.content {
+            *items = self.retain(std::mem::take(items), item_attrs);
+        }
+        visit_mut::visit_item_mod_mut(self, module);
+    }
+
+    fn visit_block_mut(&mut self, block: &mut syn::Block) {
+        block.stmts = self.retain(std::mem::take(&mut block.stmts), |stmt| match stmt {
+            syn::Stmt::Local(local) => Some(&mut local.attrs),
+            syn::Stmt::Item(item) => item_attrs(item),
+            syn::Stmt::Expr(syn::Expr::Macro(m)) | syn::Stmt::Semi(syn::Expr::Macro(m), _) => {
+                Some(&mut m.attrs)
+            }
+            _ => None,
+        });
+        visit_mut::visit_block_mut(self, block);
+    }
+
+    fn visit_item_impl_mut(&mut self, item: &mut syn::ItemImpl) {
+        item.items = self.retain(std::mem::take(&mut item.items), |item| match item {
+            syn::ImplItem::Const(i) => Some(&mut i.attrs),
+            syn::ImplItem::Method(i) => Some(&mut i.attrs),
+            syn::ImplItem::Type(i) => Some(&mut i.attrs),
+            syn::ImplItem::Macro(i) => Some(&mut i.attrs),
+            _ => None,
+        });
+        visit_mut::visit_item_impl_mut(self, item);
+    }
+
+    fn visit_item_trait_mut(&mut self, item: &mut syn::ItemTrait) {
+        item.items = self.retain(std::mem::take(&mut item.items), |item| match item {
+            syn::TraitItem::Const(i) => Some(&mut i.attrs),
+            syn::TraitItem::Method(i) => Some(&mut i.attrs),
+            syn::TraitItem::Type(i) => Some(&mut i.attrs),
+            syn::TraitItem::Macro(i) => Some(&mut i.attrs),
+            _ => None,
+        });
+        visit_mut::visit_item_trait_mut(self, item);
+    }
+
+    fn visit_fields_named_mut(&mut self, fields: &mut syn::FieldsNamed) {
+        let named = std::mem::take(&mut fields.named);
+        fields.named = self
+            .retain(named, |f| Some(&mut f.attrs))
+            .into_iter()
+            .collect();
+        visit_mut::visit_fields_named_mut(self, fields);
+    }
+
+    fn visit_fields_unnamed_mut(&mut self, fields: &mut syn::FieldsUnnamed) {
+        let unnamed = std::mem::take(&mut fields.unnamed);
+        fields.unnamed = self
+            .retain(unnamed, |f| Some(&mut f.attrs))
+            .into_iter()
+            .collect();
+        visit_mut::visit_fields_unnamed_mut(self, fields);
+    }
+
+    fn visit_item_enum_mut(&mut self, item: &mut syn::ItemEnum) {
+        let variants = std::mem::take(&mut item.variants);
+        item.variants = self
+            .retain(variants, |v| Some(&mut v.attrs))
+            .into_iter()
+            .collect();
+        visit_mut::visit_item_enum_mut(self, item);
+    }
+
+    fn visit_expr_match_mut(&mut self, expr: &mut syn::ExprMatch) {
+        expr.arms = self.retain(std::mem::take(&mut expr.arms), |arm| Some(&mut arm.attrs));
+        visit_mut::visit_expr_match_mut(self, expr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::ToTokens;
+
+    #[test]
+    fn decides_known_keys_only() {
+        let set = CfgSet::new()
+            .with_name("unix")
+            .with_known("windows")
+            .with_value("target_os", "linux");
+        assert_eq!(set.eval_str("cfg(unix)").unwrap(), Some(true));
+        assert_eq!(set.eval_str("cfg(windows)").unwrap(), Some(false));
+        assert_eq!(set.eval_str("target_os = \"macos\"").unwrap(), Some(false));
+        assert_eq!(set.eval_str("cfg(debug_assertions)").unwrap(), None);
+        assert_eq!(
+            set.eval_str("cfg(any(windows, all(unix, not(target_os = \"macos\"))))")
+                .unwrap(),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn simplifies_undecided_parts() {
+        let set = CfgSet::new().with_feature("tls");
+        let pred: syn::NestedMeta = parse_quote!(all(feature = "tls", unix));
+        match set.simplify(&pred) {
+            Cfg::Undecided(p) => assert_eq!(p.to_token_stream().to_string(), "unix"),
+            _ => panic!("expected an undecided predicate"),
+        }
+        let pred: syn::NestedMeta = parse_quote!(any(feature = "cli", unix, windows));
+        match set.simplify(&pred) {
+            Cfg::Undecided(p) => {
+                assert_eq!(p.to_token_stream().to_string(), "any (unix , windows)")
+            }
+            _ => panic!("expected an undecided predicate"),
+        }
+    }
+
+    #[test]
+    fn strip_resolves_feature_cfgs() {
+        let mut file: syn::File = parse_quote! {
+            #[cfg(feature = "tls")]
+            fn tls() {}
+            #[cfg(not(feature = "tls"))]
+            fn plain() {}
+            #[cfg(all(feature = "tls", unix))]
+            fn tls_unix() {}
+            #[cfg_attr(feature = "tls", derive(Clone))]
+            struct Conn {
+                #[cfg(feature = "cli")]
+                args: Vec<String>,
+            }
+            impl Conn {
+                #[cfg(feature = "cli")]
+                fn parse() {}
+            }
+        };
+        assert_eq!(strip(&mut file, &CfgSet::new().with_feature("tls")), 3);
+        let expected: syn::File = parse_quote! {
+            fn tls() {}
+            #[cfg(unix)]
+            fn tls_unix() {}
+            #[derive(Clone)]
+            struct Conn {}
+            impl Conn {}
+        };
+        assert_eq!(
+            file.to_token_stream().to_string(),
+            expected.to_token_stream().to_string()
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+use crate::srcmap::SourceMap;
+use crate::verify;
+
+/// Whether and how to run clippy over the bundle, see
+/// [`Bundler::clippy_check`](crate::Bundler::clippy_check).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClippyMode {
+    Off,
+    /// List the diagnostics in the report
+    Report,
+    /// Fail bundling on any diagnostic
+    Fail,
+}
+
+impl Default for ClippyMode {
+    fn default() -> Self {
+        ClippyMode::Off
+    }
+}
+
+/// What clippy had to say about a bundle
+#[derive(Debug, Clone, Default)]
+pub struct ClippyOutcome {
+    /// Rendered warnings and errors, pointed at the original sources
+    pub diagnostics: Vec<String>,
+    /// Lints that fired, like `dead_code` or `clippy::needless_lifetimes`
+    pub lints: BTreeSet<String>,
+}
+
+/// Run `cargo clippy` with `lint_args` on `script`, in a temporary cargo project. As with
+/// `CompileCheck`, dependencies have to be in the local registry cache already.
+pub fn run(script: &Path, lint_args: &[String], map: &SourceMap) -> Result<ClippyOutcome> {
+    let project = verify::temp_project(script, "clippy")?;
+    let output = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+        .arg("clippy")
+        .arg("--offline")
+        .arg("--message-format=json")
+        .arg("--manifest-path")
+        .arg(project.join("Cargo.toml"))
+        .arg("--")
+        .args(lint_args)
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run cargo clippy")?;
+    let text = fs::read_to_string(project.join("src/main.rs"))?;
+    fs::remove_dir_all(&project)?;
+
+    let outcome = parse_messages(&String::from_utf8_lossy(&output.stdout), &|rendered| {
+        map.translate(rendered, "src/main.rs", &text)
+    });
+    // no diagnostics to blame, so cargo or clippy itself failed
+    if !output.status.success() && outcome.diagnostics.is_empty() {
+        bail!(
+            "Failed to run clippy on {}:\n{}",
+            script.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(outcome)
+}
+
+/// Collect the diagnostics in cargo's JSON messages
+fn parse_messages(stdout: &str, translate: &dyn Fn(&str) -> String) -> ClippyOutcome {
+    let mut outcome = ClippyOutcome::default();
+    for line in stdout.lines() {
+        let message: serde_json::Value = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        if message["reason"] != "compiler-message" {
+            continue;
+        }
+        let message = &message["message"];
+        let relevant = matches!(message["level"].as_str(), Some("warning" | "error"))
+            // summaries like "2 warnings emitted" point nowhere
+            && message["spans"].as_array().map_or(false, |spans| !spans.is_empty());
+        if !relevant {
+            continue;
+        }
+        if let Some(rendered) = message["rendered"].as_str() {
+            outcome.diagnostics.push(translate(rendered));
+        }
+        // compile errors have codes like E0425, which can not be allowed
+        match message["code"]["code"].as_str() {
+            Some(code) if !is_error_code(code) => {
+                outcome.lints.insert(code.to_string());
+            }
+            _ => {}
+        }
+    }
+    outcome
+}
+
+fn is_error_code(code: &str) -> bool {
+    code.len() == 5 && code.starts_with('E') && code[1..].bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The inner attribute allowing every one of `lints`
+pub fn allow_attr(lints: &BTreeSet<String>) -> String {
+    let lints: Vec<_> = lints.iter().map(String::as_str).collect();
+    format!("#![allow({})]", lints.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lints_from_json_messages() {
+        let stdout = r#"{"reason":"compiler-artifact","package_id":"x"}
+{"reason":"compiler-message","message":{"level":"warning","code":{"code":"clippy::needless_lifetimes"},"spans":[{}],"rendered":"warning: explicit lifetimes\n --> src/main.rs:3:1\n"}}
+{"reason":"compiler-message","message":{"level":"warning","code":{"code":"dead_code"},"spans":[{}],"rendered":"warning: unused\n --> src/main.rs:9:4\n"}}
+{"reason":"compiler-message","message":{"level":"error","code":{"code":"E0425"},"spans":[{}],"rendered":"error: not found\n"}}
+{"reason":"compiler-message","message":{"level":"warning","code":null,"spans":[],"rendered":"warning: 2 warnings emitted\n"}}
+{"reason":"build-finished","success":false}"#;
+        let outcome = parse_messages(stdout, &|rendered| rendered.trim_end().to_string());
+        assert_eq!(outcome.diagnostics.len(), 3);
+        assert_eq!(
+            outcome.diagnostics[1],
+            "warning: unused\n --> src/main.rs:9:4"
+        );
+        assert_eq!(
+            allow_attr(&outcome.lints),
+            "#![allow(clippy::needless_lifetimes, dead_code)]"
+        );
+    }
+}
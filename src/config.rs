@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{Bundler, Edition, LauncherKind};
+
+/// Bundling settings, as read from a `bundle.toml` or given on the command line. Every field is
+/// optional so that layers can be merged, see [`Settings::merge`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Settings {
+    /// Directory of the cargo project to bundle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crate_dir: Option<PathBuf>,
+    /// Directory the scripts are written to, as `<bin>.rs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub out_dir: Option<PathBuf>,
+    /// Bin targets to bundle, all of them if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bins: Option<Vec<String>>,
+    /// Whether to inline the package's own lib target
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lib: Option<bool>,
+    /// See [`Bundler::nest_under`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nest_under: Option<String>,
+    /// See [`Bundler::with_inner_attr`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inner_attrs: Option<Vec<String>>,
+    /// See [`Bundler::windows_launcher`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub windows_launcher: Option<LauncherKind>,
+    /// Extra crates to inline, name to crate root file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crates: Option<BTreeMap<String, PathBuf>>,
+    /// See [`Bundler::with_rename`], crate name to module name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renames: Option<BTreeMap<String, String>>,
+    /// See [`Bundler::edition`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edition: Option<Edition>,
+}
+
+impl Settings {
+    /// Layer `over` on top of `self`: every field set in `over` wins, whole lists and tables
+    /// included.
+    pub fn merge(self, over: Settings) -> Settings {
+        Settings {
+            crate_dir: over.crate_dir.or(self.crate_dir),
+            out_dir: over.out_dir.or(self.out_dir),
+            bins: over.bins.or(self.bins),
+            lib: over.lib.or(self.lib),
+            nest_under: over.nest_under.or(self.nest_under),
+            inner_attrs: over.inner_attrs.or(self.inner_attrs),
+            windows_launcher: over.windows_launcher.or(self.windows_launcher),
+            crates: over.crates.or(self.crates),
+            renames: over.renames.or(self.renames),
+            edition: over.edition.or(self.edition),
+        }
+    }
+
+    /// Make relative paths relative to `base` instead of the working directory.
+    fn rebase(mut self, base: &Path) -> Settings {
+        let join = |path: PathBuf| base.join(path);
+        self.crate_dir = self.crate_dir.map(join);
+        self.out_dir = self.out_dir.map(join);
+        self.crates = self
+            .crates
+            .map(|crates| crates.into_iter().map(|(k, v)| (k, join(v))).collect());
+        self
+    }
+
+    /// Apply the settings that map directly onto a [`Bundler`].
+    pub fn apply(&self, mut bundler: Bundler) -> Bundler {
+        if self.lib == Some(true) {
+            bundler = bundler.with_lib();
+        }
+        if let Some(name) = &self.nest_under {
+            bundler = bundler.nest_under(name);
+        }
+        for attr in self.inner_attrs.iter().flatten() {
+            bundler = bundler.with_inner_attr(attr);
+        }
+        if let Some(kind) = self.windows_launcher {
+            bundler = bundler.windows_launcher(kind);
+        }
+        for (name, root) in self.crates.iter().flatten() {
+            bundler = bundler.with_crate_at(name, root);
+        }
+        for (name, module) in self.renames.iter().flatten() {
+            bundler = bundler.with_rename(name, module);
+        }
+        if let Some(edition) = self.edition {
+            bundler = bundler.edition(edition);
+        }
+        bundler
+    }
+}
+
+/// Key of the table under `[package.metadata]` that can hold the same as a `bundle.toml`
+pub const METADATA_KEY: &str = "rust-script-bundle";
+
+/// A `bundle.toml`: top-level settings, plus named profiles layered on top of them.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConfigFile {
+    #[serde(flatten)]
+    pub settings: Settings,
+    #[serde(default)]
+    pub profile: BTreeMap<String, Settings>,
+}
+
+impl ConfigFile {
+    /// Read the config at `path`. Relative paths in it are taken relative to the file, and
+    /// `crate-dir` defaults to the file's directory.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config at {}", path.display()))?;
+        let config = Self::parse(&content)
+            .with_context(|| format!("Invalid config at {}", path.display()))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        Ok(config.rooted_at(base))
+    }
+
+    /// Read the config in the `[package.metadata.rust-script-bundle]` table of the manifest in
+    /// `manifest_dir`, if it has one. Paths are relative to `manifest_dir`, as is `crate-dir` by
+    /// default.
+    pub fn from_manifest(manifest_dir: impl AsRef<Path>) -> Result<Option<Self>> {
+        let manifest_dir = manifest_dir.as_ref();
+        let path = manifest_dir.join("Cargo.toml");
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read manifest at {}", path.display()))?;
+        let manifest: toml::Value = toml::from_str(&content)
+            .with_context(|| format!("Invalid manifest at {}", path.display()))?;
+        let table = match manifest
+            .get("package")
+            .and_then(|p| p.get("metadata"))
+            .and_then(|m| m.get(METADATA_KEY))
+        {
+            Some(table) => table.clone(),
+            None => return Ok(None),
+        };
+        let config: ConfigFile = table.try_into().with_context(|| {
+            format!(
+                "Invalid [package.metadata.{}] in {}",
+                METADATA_KEY,
+                path.display()
+            )
+        })?;
+        Ok(Some(config.rooted_at(manifest_dir)))
+    }
+
+    /// Make relative paths relative to `base`, which `crate-dir` defaults to
+    fn rooted_at(mut self, base: &Path) -> Self {
+        self.settings.crate_dir.get_or_insert_with(PathBuf::new);
+        self.settings = self.settings.rebase(base);
+        for settings in self.profile.values_mut() {
+            *settings = std::mem::take(settings).rebase(base);
+        }
+        self
+    }
+
+    /// Parse a config from its content, leaving relative paths alone.
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// The settings in effect with `profile` selected, if any.
+    pub fn effective(&self, profile: Option<&str>) -> Result<Settings> {
+        let settings = self.settings.clone();
+        match profile {
+            None => Ok(settings),
+            Some(name) => {
+                let profile = self.profile.get(name).ok_or_else(|| {
+                    let known: Vec<_> = self.profile.keys().map(String::as_str).collect();
+                    anyhow!(
+                        "No profile {} in the config, available: {}",
+                        name,
+                        known.join(", ")
+                    )
+                })?;
+                Ok(settings.merge(profile.clone()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        out-dir = "scripts"
+        lib = true
+        bins = ["a", "b"]
+
+        [crates]
+        helpers = "../helpers/src/lib.rs"
+
+        [profile.judge]
+        bins = ["a"]
+        nest-under = "__bundled"
+
+        [profile.release-script]
+        windows-launcher = "both"
+        edition = "2018"
+    "#;
+
+    #[test]
+    fn profiles_override_top_level() {
+        let config = ConfigFile::parse(CONFIG).unwrap();
+        let judge = config.effective(Some("judge")).unwrap();
+        assert_eq!(judge.bins, Some(vec!["a".to_string()]));
+        assert_eq!(judge.nest_under.as_deref(), Some("__bundled"));
+        assert_eq!(judge.lib, Some(true));
+        assert_eq!(judge.out_dir, Some(PathBuf::from("scripts")));
+
+        let release = config.effective(Some("release-script")).unwrap();
+        assert_eq!(release.windows_launcher, Some(LauncherKind::Both));
+        assert_eq!(release.edition, Some(Edition::E2018));
+        assert_eq!(release.bins.unwrap().len(), 2);
+
+        assert!(config.effective(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn command_line_overrides_profile() {
+        let config = ConfigFile::parse(CONFIG).unwrap();
+        let cli = Settings {
+            bins: Some(vec!["b".into()]),
+            ..Default::default()
+        };
+        let settings = config.effective(Some("judge")).unwrap().merge(cli);
+        assert_eq!(settings.bins, Some(vec!["b".to_string()]));
+        assert_eq!(settings.nest_under.as_deref(), Some("__bundled"));
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_config() {
+        let dir = std::env::temp_dir().join(format!("bundle-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bundle.toml");
+        fs::write(&path, CONFIG).unwrap();
+
+        let settings = ConfigFile::from_path(&path)
+            .unwrap()
+            .effective(None)
+            .unwrap();
+        assert_eq!(settings.crate_dir, Some(dir.clone()));
+        assert_eq!(settings.out_dir, Some(dir.join("scripts")));
+        assert_eq!(
+            settings.crates.unwrap()["helpers"],
+            dir.join("../helpers/src/lib.rs")
+        );
+    }
+
+    #[test]
+    fn effective_settings_print_as_toml() {
+        let config = ConfigFile::parse(CONFIG).unwrap();
+        let printed = toml::to_string(&config.effective(Some("judge")).unwrap()).unwrap();
+        let reparsed: Settings = toml::from_str(&printed).unwrap();
+        assert_eq!(reparsed, config.effective(Some("judge")).unwrap());
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Context, Result};
+use proc_macro2::{Spacing, TokenStream, TokenTree};
+use quote::ToTokens;
+use serde::Serialize;
+use toml::Value;
+
+/// Dependencies of the package a bundle kept and dropped, see
+/// [`Bundler::trim_dependencies`](crate::Bundler::trim_dependencies).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DependencyTrim {
+    pub kept: Vec<String>,
+    pub dropped: Vec<String>,
+}
+
+/// Names `file` may refer to crates by: every identifier followed by `::`, and whatever a `use`
+/// or `extern crate` starts with. Tokens are scanned rather than the syntax tree, so paths in
+/// macro invocations count too. Local modules and types count as well, which only means a
+/// dependency of the same name is kept.
+pub fn references(file: &syn::File) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    collect(file.to_token_stream(), &mut names);
+    names
+}
+
+fn collect(tokens: TokenStream, names: &mut BTreeSet<String>) {
+    let tokens: Vec<_> = tokens.into_iter().collect();
+    for (idx, token) in tokens.iter().enumerate() {
+        let ident = match token {
+            TokenTree::Group(group) => {
+                collect(group.stream(), names);
+                continue;
+            }
+            TokenTree::Ident(ident) => ident.to_string(),
+            _ => continue,
+        };
+        let path_sep = matches!(
+            (tokens.get(idx + 1), tokens.get(idx + 2)),
+            (Some(TokenTree::Punct(a)), Some(TokenTree::Punct(b)))
+                if a.as_char() == ':' && a.spacing() == Spacing::Joint && b.as_char() == ':'
+        );
+        let is_ident = |i: usize, name: &str| matches!(tokens.get(i), Some(TokenTree::Ident(ident)) if ident == name);
+        let after_use = idx >= 1 && is_ident(idx - 1, "use");
+        let after_extern_crate =
+            idx >= 2 && is_ident(idx - 1, "crate") && is_ident(idx - 2, "extern");
+        if path_sep || after_use || after_extern_crate {
+            names.insert(ident.trim_start_matches("r#").to_string());
+        }
+    }
+}
+
+/// Drop the dependencies in the manifest `text` that none of `references` names, along with
+/// what the `[features]` table says about them. Dev and build dependencies are left alone, as
+/// scripts never build them.
+pub fn trim_manifest(
+    text: &str,
+    references: &BTreeSet<String>,
+) -> Result<(String, DependencyTrim)> {
+    let mut manifest: Value = toml::from_str(text).context("Invalid manifest")?;
+    let root = match manifest.as_table_mut() {
+        Some(root) => root,
+        None => bail!("Manifest is not a table"),
+    };
+    let mut trim = DependencyTrim::default();
+    for (key, value) in root.iter_mut() {
+        match (key.as_str(), value) {
+            ("dependencies", deps) => trim_table(deps, references, &mut trim)?,
+            ("target", Value::Table(targets)) => {
+                for tables in targets.values_mut() {
+                    if let Some(deps) = tables.get_mut("dependencies") {
+                        trim_table(deps, references, &mut trim)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    trim.kept.sort();
+    trim.dropped.sort();
+    if trim.dropped.is_empty() {
+        return Ok((text.into(), trim));
+    }
+
+    if let Some(Value::Table(features)) = root.get_mut("features") {
+        for enables in features.values_mut() {
+            if let Value::Array(enables) = enables {
+                enables.retain(|enable| {
+                    let enable = enable.as_str().unwrap_or_default();
+                    let dep = enable.strip_prefix("dep:").unwrap_or(enable);
+                    let dep = dep.split('/').next().unwrap_or(dep).trim_end_matches('?');
+                    !trim.dropped.iter().any(|dropped| dropped == dep)
+                });
+            }
+        }
+    }
+    Ok((toml::to_string(&manifest)?, trim))
+}
+
+fn trim_table(
+    deps: &mut Value,
+    references: &BTreeSet<String>,
+    trim: &mut DependencyTrim,
+) -> Result<()> {
+    let deps = match deps.as_table_mut() {
+        Some(deps) => deps,
+        None => bail!("Dependencies are not a table"),
+    };
+    let names: Vec<_> = deps.keys().cloned().collect();
+    for name in names {
+        // the key is the name the code uses, even for renamed dependencies
+        if references.contains(&name.replace('-', "_")) {
+            trim.kept.push(name);
+        } else {
+            deps.remove(&name);
+            trim.dropped.push(name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn unreferenced_dependencies_are_dropped() {
+        let file: syn::File = parse_quote! {
+            use anyhow::Result;
+            extern crate log;
+            fn main() -> Result<()> {
+                println!("{}", serde_json::json!({}));
+                Ok(())
+            }
+        };
+        let references = references(&file);
+        let manifest = r#"
+            [package]
+            name = "fetch"
+            version = "0.1.0"
+
+            [dependencies]
+            anyhow = "1.0"
+            log = "0.4"
+            serde_json = "1.0"
+            reqwest = { version = "0.11", optional = true }
+
+            [features]
+            default = ["net"]
+            net = ["dep:reqwest", "reqwest?/json", "log/std"]
+        "#;
+        let (trimmed, trim) = trim_manifest(manifest, &references).unwrap();
+        assert_eq!(trim.kept, ["anyhow", "log", "serde_json"]);
+        assert_eq!(trim.dropped, ["reqwest"]);
+        let trimmed: Value = toml::from_str(&trimmed).unwrap();
+        assert_eq!(
+            trimmed["features"]["net"],
+            Value::Array(vec![Value::String("log/std".into())])
+        );
+    }
+}
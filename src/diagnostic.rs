@@ -0,0 +1,89 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A source file that does not parse, pointing at where and showing the lines around it.
+/// Returned through [`anyhow::Error`], so downcast to inspect it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub file: PathBuf,
+    /// Where parsing stopped, from 1
+    pub line: usize,
+    /// Where parsing stopped in the line, in characters from 1
+    pub column: usize,
+    /// What the parser expected
+    pub message: String,
+    /// The lines around the error, numbered, the offending one marked with `>`. Empty if the
+    /// file can not be read again.
+    pub snippet: String,
+    /// How the file is reached from the crate root, e.g. `src/main.rs -> mod app -> mod config`,
+    /// empty for the root itself
+    pub chain: Vec<String>,
+}
+
+impl ParseError {
+    /// `err` from parsing `file`, which is reached from the crate root through `chain`
+    pub(crate) fn new(file: &Path, err: &syn::Error, chain: Vec<String>) -> Self {
+        let text = fs::read_to_string(file).unwrap_or_default();
+        Self::in_text(file, &text, err, chain)
+    }
+
+    /// `err` from parsing `text`, the contents of `file`, for files that are not read from disk
+    pub(crate) fn in_text(file: &Path, text: &str, err: &syn::Error, chain: Vec<String>) -> Self {
+        let start = err.span().start();
+        ParseError {
+            file: file.to_path_buf(),
+            line: start.line,
+            column: start.column + 1,
+            message: err.to_string(),
+            snippet: snippet(text, start.line),
+            chain,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}\n{}",
+            self.file.display(),
+            self.line,
+            self.column,
+            self.message,
+            self.snippet
+        )?;
+        if !self.chain.is_empty() {
+            write!(f, "    included through {}", self.chain.join(" -> "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseError {}
+
+/// The lines of `text` around `line`, from 1, numbered and with `line` marked by `>`
+pub fn snippet(text: &str, line: usize) -> String {
+    let lines: Vec<_> = text.lines().collect();
+    let mut snippet = String::new();
+    for number in line.saturating_sub(2).max(1)..=(line + 1).min(lines.len()) {
+        let marker = if number == line { ">" } else { " " };
+        snippet.push_str(&format!(
+            "{} {:>5} | {}\n",
+            marker,
+            number,
+            lines[number - 1]
+        ));
+    }
+    snippet
+}
+
+/// Where `span`, of code from `file`, starts, as `file:line:column`, or just the file for code
+/// that was generated rather than parsed
+pub fn location(file: &Path, span: proc_macro2::Span) -> String {
+    match span.start() {
+        start if start.line == 0 => file.display().to_string(),
+        start => format!("{}:{}:{}", file.display(), start.line, start.column + 1),
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use proc_macro2::{Delimiter, TokenStream, TokenTree};
+use quote::{quote, ToTokens};
+use serde::Serialize;
+
+use crate::attrs::embedded_manifest;
+use crate::rewrite::item_ident;
+
+/// Items larger than this many token pairs are reported as replaced wholesale instead of diffed
+const MAX_TOKEN_DIFF: usize = 4_000_000;
+/// Equal tokens shown before each edit
+const CONTEXT_TOKENS: usize = 4;
+
+/// Semantic differences between two bundled scripts, ignoring formatting.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BundleDiff {
+    /// Items only in the new script, e.g. `mod mylib / fn run`
+    pub added: Vec<String>,
+    /// Items only in the old script
+    pub removed: Vec<String>,
+    /// Items in both scripts with different tokens
+    pub changed: Vec<ItemChange>,
+    /// Differences between the embedded manifests, by dotted key
+    pub manifest: Vec<ManifestChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ItemChange {
+    pub item: String,
+    pub edits: Vec<TokenEdit>,
+}
+
+/// A run of differing tokens, each side joined by spaces
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TokenEdit {
+    /// Equal tokens right before the edit
+    pub context: String,
+    pub removed: String,
+    pub added: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ManifestChange {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl BundleDiff {
+    /// Whether the scripts are semantically the same
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.manifest.is_empty()
+    }
+}
+
+impl Display for BundleDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for item in &self.removed {
+            writeln!(f, "- {}", item)?;
+        }
+        for item in &self.added {
+            writeln!(f, "+ {}", item)?;
+        }
+        for change in &self.changed {
+            writeln!(f, "~ {}", change.item)?;
+            for edit in &change.edits {
+                writeln!(
+                    f,
+                    "    ... {} [-{}-]{{+{}+}}",
+                    edit.context, edit.removed, edit.added
+                )?;
+            }
+        }
+        if !self.manifest.is_empty() {
+            writeln!(f, "manifest:")?;
+        }
+        for change in &self.manifest {
+            match (&change.old, &change.new) {
+                (Some(old), Some(new)) => writeln!(f, "~ {}: {} -> {}", change.key, old, new)?,
+                (Some(old), None) => writeln!(f, "- {} = {}", change.key, old)?,
+                (None, Some(new)) => writeln!(f, "+ {} = {}", change.key, new)?,
+                (None, None) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compare the bundled scripts at `old` and `new`.
+pub fn diff(old: impl AsRef<Path>, new: impl AsRef<Path>) -> Result<BundleDiff> {
+    let read = |path: &Path| {
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))
+    };
+    diff_str(&read(old.as_ref())?, &read(new.as_ref())?)
+}
+
+/// Compare two bundled scripts given as source text.
+pub fn diff_str(old: &str, new: &str) -> Result<BundleDiff> {
+    let old = syn::parse_file(old).context("Failed to parse the old script")?;
+    let new = syn::parse_file(new).context("Failed to parse the new script")?;
+
+    let (mut old_items, mut new_items) = (BTreeMap::new(), BTreeMap::new());
+    collect_items(&old.items, "", &mut old_items);
+    collect_items(&new.items, "", &mut new_items);
+
+    let mut diff = BundleDiff::default();
+    for (key, old_tokens) in &old_items {
+        match new_items.remove(key) {
+            None => diff.removed.push(key.clone()),
+            Some(new_tokens) if new_tokens.to_string() != old_tokens.to_string() => {
+                diff.changed.push(ItemChange {
+                    item: key.clone(),
+                    edits: token_edits(old_tokens.clone(), new_tokens),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    diff.added = new_items.into_keys().collect();
+
+    let manifest = |file: &syn::File| -> Result<BTreeMap<String, String>> {
+        let mut keys = BTreeMap::new();
+        if let Some(manifest) = embedded_manifest(&file.attrs) {
+            let value: toml::Value =
+                toml::from_str(&manifest).context("Failed to parse embedded manifest")?;
+            flatten_toml("", &value, &mut keys);
+        }
+        Ok(keys)
+    };
+    let (old_manifest, mut new_manifest) = (manifest(&old)?, manifest(&new)?);
+    for (key, old_value) in old_manifest {
+        let new_value = new_manifest.remove(&key);
+        if new_value.as_ref() != Some(&old_value) {
+            diff.manifest.push(ManifestChange {
+                key,
+                old: Some(old_value),
+                new: new_value,
+            });
+        }
+    }
+    diff.manifest
+        .extend(new_manifest.into_iter().map(|(key, value)| ManifestChange {
+            key,
+            old: None,
+            new: Some(value),
+        }));
+    diff.manifest.sort_by(|a, b| a.key.cmp(&b.key));
+
+    Ok(diff)
+}
+
+fn tokens(t: &dyn ToTokens) -> String {
+    t.to_token_stream().to_string()
+}
+
+/// A key identifying `item` among its siblings by kind and name
+fn item_key(item: &syn::Item) -> String {
+    use syn::Item;
+    let kind = match item {
+        Item::Impl(i) => {
+            let of_trait = i.trait_.as_ref().map_or(String::new(), |(bang, path, _)| {
+                format!(
+                    "{}{} for ",
+                    if bang.is_some() { "!" } else { "" },
+                    tokens(path)
+                )
+            });
+            return format!("impl {}{}", of_trait, tokens(&i.self_ty));
+        }
+        Item::Use(u) => return format!("use {}", tokens(&u.tree)),
+        Item::ForeignMod(m) => return format!("extern {}", tokens(&m.abi)),
+        Item::Macro(m) if m.ident.is_none() => return format!("{}!", tokens(&m.mac.path)),
+        Item::Const(_) => "const",
+        Item::Enum(_) => "enum",
+        Item::ExternCrate(_) => "extern crate",
+        Item::Fn(_) => "fn",
+        Item::Macro(_) => "macro_rules!",
+        Item::Macro2(_) => "macro",
+        Item::Mod(_) => "mod",
+        Item::Static(_) => "static",
+        Item::Struct(_) => "struct",
+        Item::Trait(_) => "trait",
+        Item::TraitAlias(_) => "trait alias",
+        Item::Type(_) => "type",
+        Item::Union(_) => "union",
+        _ => return tokens(item),
+    };
+    match item_ident(item) {
+        Some(ident) => format!("{} {}", kind, ident),
+        None => kind.into(),
+    }
+}
+
+/// Flatten `items` into their keys, prefixed by the enclosing modules. Inline modules are
+/// recursed into, and only their own attributes and visibility are compared.
+fn collect_items(items: &[syn::Item], prefix: &str, out: &mut BTreeMap<String, TokenStream>) {
+    let mut seen = BTreeMap::new();
+    for item in items {
+        let key = format!("{}{}", prefix, item_key(item));
+        let count = seen.entry(key.clone()).or_insert(0);
+        *count += 1;
+        let key = if *count > 1 {
+            format!("{} #{}", key, count)
+        } else {
+            key
+        };
+
+        match item {
+            syn::Item::Mod(m) if m.content.is_some() => {
+                let (attrs, vis) = (&m.attrs, &m.vis);
+                out.insert(key.clone(), quote!(#(#attrs)* #vis));
+                let (_, nested) = m.content.as_ref().unwrap();
+                collect_items(nested, &format!("{} / ", key), out);
+            }
+            _ => {
+                out.insert(key, item.to_token_stream());
+            }
+        }
+    }
+}
+
+fn flatten_tokens(stream: TokenStream, out: &mut Vec<String>) {
+    for tt in stream {
+        match tt {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => ("", ""),
+                };
+                if !open.is_empty() {
+                    out.push(open.into());
+                }
+                flatten_tokens(group.stream(), out);
+                if !close.is_empty() {
+                    out.push(close.into());
+                }
+            }
+            tt => out.push(tt.to_string()),
+        }
+    }
+}
+
+/// Token level edits turning `old` into `new`, based on their longest common subsequence
+fn token_edits(old: TokenStream, new: TokenStream) -> Vec<TokenEdit> {
+    let (mut a, mut b) = (vec![], vec![]);
+    flatten_tokens(old, &mut a);
+    flatten_tokens(new, &mut b);
+    if a.len().saturating_mul(b.len()) > MAX_TOKEN_DIFF {
+        return vec![TokenEdit {
+            context: String::new(),
+            removed: a.join(" "),
+            added: b.join(" "),
+        }];
+    }
+
+    // lcs[i][j] is the length of the lcs of a[i..] and b[j..]
+    let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut edits = vec![];
+    let mut context: Vec<&str> = vec![];
+    let (mut removed, mut added): (Vec<&str>, Vec<&str>) = (vec![], vec![]);
+    let (mut i, mut j) = (0, 0);
+    let mut flush = |context: &[&str], removed: &mut Vec<&str>, added: &mut Vec<&str>| {
+        if !removed.is_empty() || !added.is_empty() {
+            let start = context.len().saturating_sub(CONTEXT_TOKENS);
+            edits.push(TokenEdit {
+                context: context[start..].join(" "),
+                removed: removed.join(" "),
+                added: added.join(" "),
+            });
+            removed.clear();
+            added.clear();
+        }
+    };
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            flush(&context, &mut removed, &mut added);
+            context.push(&a[i]);
+            i += 1;
+            j += 1;
+        } else if j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            added.push(&b[j]);
+            j += 1;
+        } else {
+            removed.push(&a[i]);
+            i += 1;
+        }
+    }
+    flush(&context, &mut removed, &mut added);
+    edits
+}
+
+fn flatten_toml(prefix: &str, value: &toml::Value, out: &mut BTreeMap<String, String>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_toml(&key, value, out);
+            }
+        }
+        value => {
+            out.insert(prefix.into(), value.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = r#"#!/usr/bin/env -S rust-script
+//! ```cargo
+//! [package]
+//! name = "tool"
+//! version = "0.1.0"
+//! ```
+fn main() { mylib::run(1); }
+fn gone() {}
+pub mod mylib { pub fn run(x: u32) {} }
+"#;
+
+    const NEW: &str = r#"#!/usr/bin/env -S rust-script
+//! ```cargo
+//! [package]
+//! name = "tool"
+//! version = "0.2.0"
+//! ```
+fn main() {
+    mylib::run(2);
+}
+pub mod mylib {
+    pub fn run(x: u32) {}
+    pub fn added() {}
+}
+"#;
+
+    #[test]
+    fn formatting_is_not_a_difference() {
+        let reformatted = OLD.replace(
+            "fn main() { mylib::run(1); }",
+            "fn main()\n{\n    mylib::run(1);\n}",
+        );
+        assert!(diff_str(OLD, &reformatted).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_items_and_manifest() {
+        let diff = diff_str(OLD, NEW).unwrap();
+        assert_eq!(diff.removed, ["fn gone"]);
+        assert_eq!(diff.added, ["mod mylib / fn added"]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].item, "fn main");
+        assert_eq!(diff.changed[0].edits[0].removed, "1");
+        assert_eq!(diff.changed[0].edits[0].added, "2");
+        assert_eq!(
+            diff.manifest,
+            [ManifestChange {
+                key: "package.version".into(),
+                old: Some("\"0.1.0\"".into()),
+                new: Some("\"0.2.0\"".into()),
+            }]
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+use proc_macro2::{TokenStream, TokenTree};
+use serde::{Deserialize, Serialize};
+use syn::visit::{self, Visit};
+
+/// A Rust edition, see [`Bundler::edition`](crate::Bundler::edition).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Edition {
+    #[serde(rename = "2015")]
+    E2015,
+    #[serde(rename = "2018")]
+    E2018,
+    #[serde(rename = "2021")]
+    E2021,
+}
+
+impl Edition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Edition::E2015 => "2015",
+            Edition::E2018 => "2018",
+            Edition::E2021 => "2021",
+        }
+    }
+}
+
+impl fmt::Display for Edition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Edition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "2015" => Edition::E2015,
+            "2018" => Edition::E2018,
+            "2021" => Edition::E2021,
+            _ => bail!("Unknown edition {}, expected 2015, 2018 or 2021", s),
+        })
+    }
+}
+
+impl From<cargo_toml::Edition> for Edition {
+    fn from(edition: cargo_toml::Edition) -> Self {
+        match edition {
+            cargo_toml::Edition::E2015 => Edition::E2015,
+            cargo_toml::Edition::E2018 => Edition::E2018,
+            cargo_toml::Edition::E2021 => Edition::E2021,
+        }
+    }
+}
+
+/// Identifiers that became keywords in 2018, so code of an older edition may use them as names
+const KEYWORDS_2018: &[&str] = &["async", "await", "dyn", "try"];
+
+/// What in a crate does not fit the edition it is bundled for
+#[derive(Debug, Default)]
+pub struct Findings {
+    /// Code the target edition rejects
+    pub errors: Vec<String>,
+    /// Code that may not mean the same in the target edition
+    pub warnings: Vec<String>,
+}
+
+/// Look for syntax in `file`, written for the `source` edition, that does not work the same in
+/// the `target` edition. Only what is visible in the syntax tree is found:
+/// - `async` and `.await`, which need 2018;
+/// - trait objects without `dyn`, which 2021 rejects;
+/// - identifiers in macro bodies that are keywords of the target edition but not of the source
+///   one, and would have to be raw identifiers.
+///
+/// `name` is how messages refer to the crate.
+pub fn check(file: &syn::File, name: &str, source: Edition, target: Edition) -> Findings {
+    let mut checker = Checker {
+        name,
+        source,
+        target,
+        item: None,
+        findings: Findings::default(),
+    };
+    checker.visit_file(file);
+    checker.findings
+}
+
+struct Checker<'a> {
+    name: &'a str,
+    source: Edition,
+    target: Edition,
+    /// innermost named item, to point at
+    item: Option<String>,
+    findings: Findings,
+}
+
+impl Checker<'_> {
+    fn location(&self) -> String {
+        match &self.item {
+            Some(item) => format!("{} in crate {}", item, self.name),
+            None => format!("crate {}", self.name),
+        }
+    }
+
+    /// `what` needs at least `edition`
+    fn require(&mut self, what: &str, edition: Edition) {
+        if self.target < edition {
+            let error = format!(
+                "{} uses {}, which needs edition {} but the bundle is for {}",
+                self.location(),
+                what,
+                edition,
+                self.target
+            );
+            self.findings.errors.push(error);
+        }
+    }
+
+    fn with_item(&mut self, item: String, visit: impl FnOnce(&mut Self)) {
+        let outer = self.item.replace(item);
+        visit(self);
+        self.item = outer;
+    }
+
+    fn check_tokens(&mut self, tokens: TokenStream) {
+        for token in tokens {
+            match token {
+                TokenTree::Group(group) => self.check_tokens(group.stream()),
+                TokenTree::Ident(ident) => {
+                    let ident = ident.to_string();
+                    let newly_reserved = self.source < Edition::E2018
+                        && self.target >= Edition::E2018
+                        && KEYWORDS_2018.contains(&ident.as_str());
+                    if newly_reserved {
+                        let warning = format!(
+                            "{} has `{}` in a macro, which is a keyword in edition {} and \
+                            may have to be written r#{}",
+                            self.location(),
+                            ident,
+                            self.target,
+                            ident
+                        );
+                        self.findings.warnings.push(warning);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for Checker<'_> {
+    fn visit_item_fn(&mut self, i: &'ast syn::ItemFn) {
+        self.with_item(format!("fn {}", i.sig.ident), |c| {
+            visit::visit_item_fn(c, i)
+        });
+    }
+
+    fn visit_impl_item_method(&mut self, i: &'ast syn::ImplItemMethod) {
+        self.with_item(format!("fn {}", i.sig.ident), |c| {
+            visit::visit_impl_item_method(c, i)
+        });
+    }
+
+    fn visit_trait_item_method(&mut self, i: &'ast syn::TraitItemMethod) {
+        self.with_item(format!("fn {}", i.sig.ident), |c| {
+            visit::visit_trait_item_method(c, i)
+        });
+    }
+
+    fn visit_item_macro(&mut self, i: &'ast syn::ItemMacro) {
+        match &i.ident {
+            Some(ident) => self.with_item(format!("macro {}", ident), |c| {
+                visit::visit_item_macro(c, i)
+            }),
+            None => visit::visit_item_macro(self, i),
+        }
+    }
+
+    fn visit_signature(&mut self, i: &'ast syn::Signature) {
+        if i.asyncness.is_some() {
+            self.require("async fn", Edition::E2018);
+        }
+        visit::visit_signature(self, i);
+    }
+
+    fn visit_expr_async(&mut self, i: &'ast syn::ExprAsync) {
+        self.require("an async block", Edition::E2018);
+        visit::visit_expr_async(self, i);
+    }
+
+    fn visit_expr_closure(&mut self, i: &'ast syn::ExprClosure) {
+        if i.asyncness.is_some() {
+            self.require("an async closure", Edition::E2018);
+        }
+        visit::visit_expr_closure(self, i);
+    }
+
+    fn visit_expr_await(&mut self, i: &'ast syn::ExprAwait) {
+        self.require(".await", Edition::E2018);
+        visit::visit_expr_await(self, i);
+    }
+
+    fn visit_type_trait_object(&mut self, i: &'ast syn::TypeTraitObject) {
+        if i.dyn_token.is_none() && self.target >= Edition::E2021 {
+            let error = format!(
+                "{} has a trait object without `dyn`, which edition {} rejects",
+                self.location(),
+                self.target
+            );
+            self.findings.errors.push(error);
+        }
+        visit::visit_type_trait_object(self, i);
+    }
+
+    fn visit_macro(&mut self, i: &'ast syn::Macro) {
+        self.check_tokens(i.tokens.clone());
+        visit::visit_macro(self, i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn newer_syntax_is_found() {
+        let file: syn::File = parse_quote! {
+            async fn fetch() -> u8 { 1 }
+            fn run(f: Box<Fn() + Send>) {
+                let _ = async { fetch().await };
+            }
+            macro_rules! call { ($e:expr) => { $e.try() } }
+        };
+
+        let findings = check(&file, "net", Edition::E2018, Edition::E2015);
+        assert_eq!(findings.errors.len(), 3);
+        assert!(findings.errors[0].starts_with("fn fetch in crate net uses async fn"));
+        assert!(findings.warnings.is_empty());
+
+        let findings = check(&file, "net", Edition::E2015, Edition::E2021);
+        assert_eq!(
+            findings.errors,
+            ["fn run in crate net has a trait object without `dyn`, which edition 2021 rejects"]
+        );
+        assert_eq!(findings.warnings.len(), 1);
+        assert!(findings.warnings[0].starts_with("macro call in crate net has `try`"));
+
+        assert_eq!("2018".parse::<Edition>().unwrap(), Edition::E2018);
+        assert!("2024".parse::<Edition>().is_err());
+    }
+}
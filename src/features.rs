@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::cfg::CfgSet;
+use crate::resolve::{DepKind, Package};
+
+/// The features to build an inlined crate with, as selected through
+/// [`Bundler::with_crate_features`](crate::Bundler::with_crate_features).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureSelection {
+    pub features: Vec<String>,
+    pub default_features: bool,
+}
+
+impl Default for FeatureSelection {
+    fn default() -> Self {
+        FeatureSelection {
+            features: vec![],
+            default_features: true,
+        }
+    }
+}
+
+/// The features `package` asks for when depending on the crate at `crate_dir` as a path
+/// dependency, the way cargo would build it.
+pub fn inferred(package: &Package, crate_dir: &Path) -> Option<FeatureSelection> {
+    let crate_dir = crate_dir.canonicalize().ok()?;
+    let dep = package.dependencies.iter().find(|d| {
+        d.kind == DepKind::Normal
+            && d.path
+                .as_ref()
+                .and_then(|p| p.canonicalize().ok())
+                .map_or(false, |p| p == crate_dir)
+    })?;
+    log::info!(
+        "Inferred features {:?} (default features {}) for {} from the dependency declaration",
+        dep.features,
+        if dep.default_features { "on" } else { "off" },
+        dep.name
+    );
+    Some(FeatureSelection {
+        features: dep.features.clone(),
+        default_features: dep.default_features,
+    })
+}
+
+/// Every feature of `package`, including the implicit ones of its optional dependencies, the
+/// way `--all-features` selects them
+pub fn all(package: &Package) -> FeatureSelection {
+    FeatureSelection {
+        features: package.features.keys().cloned().collect(),
+        default_features: true,
+    }
+}
+
+/// What a [`FeatureSelection`] enables in a package, following its `[features]` table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnabledFeatures {
+    pub features: BTreeSet<String>,
+    /// optional dependencies turned on
+    pub deps: BTreeSet<String>,
+    /// features of dependencies turned on, by dependency name
+    pub dep_features: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl EnabledFeatures {
+    /// The cfgs these features decide, i.e. `feature = "..."`
+    pub fn cfg_set(&self) -> CfgSet {
+        self.features
+            .iter()
+            .fold(CfgSet::new().with_known("feature"), |set, f| {
+                set.with_feature(f)
+            })
+    }
+}
+
+/// Resolve `selection` against the `[features]` of `package` the way cargo does, including the
+/// `dep:name`, `name/feature` and `name?/feature` forms.
+pub fn resolve(package: &Package, selection: &FeatureSelection) -> Result<EnabledFeatures> {
+    let optional = |name: &str| {
+        package
+            .dependencies
+            .iter()
+            .any(|d| d.kind == DepKind::Normal && d.optional && d.name == name)
+    };
+
+    let mut enabled = EnabledFeatures::default();
+    // `name?/feature` waiting for `name` to be enabled
+    let mut weak: Vec<(String, String)> = vec![];
+    let mut pending: Vec<String> = selection.features.clone();
+    if selection.default_features && package.features.contains_key("default") {
+        pending.push("default".into());
+    }
+    while let Some(feature) = pending.pop() {
+        if let Some(dep) = feature.strip_prefix("dep:") {
+            if !optional(dep) {
+                bail!(
+                    "Package {} has no optional dependency {}",
+                    package.name,
+                    dep
+                );
+            }
+            enabled.deps.insert(dep.into());
+        } else if let Some((dep, dep_feature)) = feature.split_once('/') {
+            match dep.strip_suffix('?') {
+                Some(dep) => weak.push((dep.into(), dep_feature.into())),
+                None => {
+                    if optional(dep) {
+                        pending.push(format!("dep:{}", dep));
+                        if package.features.contains_key(dep) {
+                            pending.push(dep.into());
+                        }
+                    }
+                    enabled
+                        .dep_features
+                        .entry(dep.into())
+                        .or_default()
+                        .insert(dep_feature.into());
+                }
+            }
+        } else if enabled.features.insert(feature.clone()) {
+            match package.features.get(&feature) {
+                Some(implied) => pending.extend(implied.iter().cloned()),
+                None => bail!("Package {} has no feature {}", package.name, feature),
+            }
+        }
+    }
+    for (dep, dep_feature) in weak {
+        let active = enabled.deps.contains(&dep) || !optional(&dep);
+        if active {
+            enabled
+                .dep_features
+                .entry(dep)
+                .or_default()
+                .insert(dep_feature);
+        }
+    }
+    Ok(enabled)
+}
+
+/// Check that inlined crates sharing a dependency name mean the same package by it, since the
+/// bundle has a single manifest. `crates` pairs each crate's name with its package.
+pub fn unify<'a>(crates: impl IntoIterator<Item = (&'a str, &'a Package)>) -> Result<()> {
+    let mut seen: BTreeMap<&str, (&str, &str)> = BTreeMap::new();
+    for (krate, package) in crates {
+        for dep in package
+            .dependencies
+            .iter()
+            .filter(|d| d.kind == DepKind::Normal)
+        {
+            match seen.get(dep.name.as_str()) {
+                Some((other, other_package)) if *other_package != dep.package => bail!(
+                    "Inlined crates {} and {} can not be unified: dependency {} is package {} in {} but {} in {}",
+                    other,
+                    krate,
+                    dep.name,
+                    other_package,
+                    other,
+                    dep.package,
+                    krate
+                ),
+                Some(_) => {}
+                None => {
+                    seen.insert(&dep.name, (krate, &dep.package));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolve::Dependency;
+
+    fn package() -> Package {
+        let dep = |name: &str, optional| Dependency {
+            kind: DepKind::Normal,
+            target: None,
+            name: name.into(),
+            package: name.into(),
+            req: "^1".into(),
+            optional,
+            default_features: true,
+            features: vec![],
+            path: None,
+        };
+        let features = [
+            ("default", vec!["fast"]),
+            ("fast", vec!["dep:memchr", "serde?/std"]),
+            ("regex", vec!["dep:regex", "regex/unicode"]),
+            ("serde", vec!["dep:serde"]),
+        ];
+        Package {
+            name: "helpers".into(),
+            version: "0.1.0".into(),
+            edition: "2021".into(),
+            lib: None,
+            bins: vec![],
+            examples: vec![],
+            default_run: None,
+            features: features
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.iter().map(|f| f.to_string()).collect()))
+                .collect(),
+            dependencies: vec![dep("memchr", true), dep("regex", true), dep("serde", true)],
+        }
+    }
+
+    #[test]
+    fn follows_the_feature_graph() {
+        let selection = FeatureSelection {
+            features: vec!["regex".into()],
+            default_features: true,
+        };
+        let enabled = resolve(&package(), &selection).unwrap();
+        let features: Vec<_> = enabled.features.iter().map(String::as_str).collect();
+        assert_eq!(features, ["default", "fast", "regex"]);
+        let deps: Vec<_> = enabled.deps.iter().map(String::as_str).collect();
+        assert_eq!(deps, ["memchr", "regex"]);
+        // serde is not on, so its weak feature is not either
+        assert!(!enabled.dep_features.contains_key("serde"));
+    }
+
+    #[test]
+    fn default_features_can_be_turned_off() {
+        let selection = FeatureSelection {
+            features: vec!["serde".into()],
+            default_features: false,
+        };
+        let enabled = resolve(&package(), &selection).unwrap();
+        let deps: Vec<_> = enabled.deps.iter().map(String::as_str).collect();
+        assert_eq!(deps, ["serde"]);
+
+        let unknown = FeatureSelection {
+            features: vec!["nope".into()],
+            default_features: false,
+        };
+        assert!(resolve(&package(), &unknown).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::ops::Range;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+
+use crate::resolve::{DepKind, Package};
+use crate::shebang::ShebangStyle;
+use crate::warning::{Warning, WarningKind};
+
+/// What kind of script to produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFlavor {
+    /// A rust-script script, with shebang and embedded manifest
+    RustScript,
+    /// A single file for play.rust-lang.org, without shebang or manifest. External dependencies
+    /// have to be among the crates the playground provides.
+    Playground,
+}
+
+impl Default for OutputFlavor {
+    fn default() -> Self {
+        OutputFlavor::RustScript
+    }
+}
+
+/// Where a rust-script bundle keeps its manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestStyle {
+    /// A ```` ```cargo ```` block in the crate's inner doc comment, which rust-script reads
+    DocComment,
+    /// A block fenced by `---` lines right after the shebang, the frontmatter cargo's `-Zscript`
+    /// reads. It is not Rust, so the bundle only parses once it is taken out.
+    Frontmatter,
+    /// `//` comment lines at the top, which no tool reads, for files compiled some other way
+    Comment,
+}
+
+impl Default for ManifestStyle {
+    fn default() -> Self {
+        ManifestStyle::DocComment
+    }
+}
+
+/// What to do with a ```` ```cargo ```` block the binary carries itself, as rust-script files
+/// converted to a package do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingManifest {
+    /// Drop it, the generated manifest takes its place
+    Replace,
+    /// Drop it, but add its dependencies to the generated manifest first
+    Merge,
+}
+
+impl Default for ExistingManifest {
+    fn default() -> Self {
+        ExistingManifest::Replace
+    }
+}
+
+/// The manifest `text` as frontmatter, fences included
+pub fn frontmatter(text: &str) -> String {
+    format!("---\n{}\n---", text.trim_end())
+}
+
+/// The manifest `text` as `//` comment lines
+pub fn manifest_comment(text: &str) -> String {
+    let mut comment = String::from("// The manifest of the bundled package, for reference:\n");
+    for line in text.trim_end().lines() {
+        comment.push_str(format!("// {}", line).trim_end());
+        comment.push('\n');
+    }
+    comment
+}
+
+/// What runs the bundle, which decides the conventions at the top and bottom of the file, see
+/// [`Bundler::target_tool`](crate::Bundler::target_tool).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetTool {
+    /// rust-script: its shebang, the manifest in a doc comment and the vim modeline
+    RustScript,
+    /// cargo's `-Zscript`: a shebang running cargo, the manifest as frontmatter and the vim
+    /// modeline
+    CargoScript,
+    /// Nothing in particular, a plain source file: no shebang, the manifest in a comment for
+    /// reference and no footer
+    Plain,
+}
+
+impl TargetTool {
+    pub fn shebang(self) -> ShebangStyle {
+        match self {
+            TargetTool::RustScript => ShebangStyle::EnvSplit,
+            TargetTool::CargoScript => ShebangStyle::Custom("#!/usr/bin/env cargo".into()),
+            TargetTool::Plain => ShebangStyle::Omitted,
+        }
+    }
+
+    pub fn manifest_style(self) -> ManifestStyle {
+        match self {
+            TargetTool::RustScript => ManifestStyle::DocComment,
+            TargetTool::CargoScript => ManifestStyle::Frontmatter,
+            TargetTool::Plain => ManifestStyle::Comment,
+        }
+    }
+
+    /// Whether the file ends with the vim modeline, for files without a `.rs` extension
+    pub fn modeline(self) -> bool {
+        self != TargetTool::Plain
+    }
+}
+
+impl FromStr for TargetTool {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "rust-script" => TargetTool::RustScript,
+            "cargo" => TargetTool::CargoScript,
+            "plain" => TargetTool::Plain,
+            _ => bail!(
+                "Unknown target tool {}, expected rust-script, cargo or plain",
+                s
+            ),
+        })
+    }
+}
+
+/// The lines of the frontmatter in `lines`, fences included, if there is one. It comes first,
+/// after the shebang and blank lines if any.
+pub fn frontmatter_lines(lines: &[&str]) -> Option<Range<usize>> {
+    let shebang = lines.first().map_or(false, |line| {
+        line.starts_with("#!") && !line.starts_with("#![")
+    });
+    let start = usize::from(shebang);
+    let start = start
+        + lines[start..]
+            .iter()
+            .take_while(|l| l.trim().is_empty())
+            .count();
+    let open = lines.get(start)?.trim_end();
+    let dashes = open.len() - open.trim_start_matches('-').len();
+    if dashes < 3 {
+        return None;
+    }
+    let close = lines[start + 1..]
+        .iter()
+        .position(|line| line.trim_end() == &open[..dashes])?;
+    Some(start..start + close + 2)
+}
+
+/// The script `text` with the lines of its frontmatter, if any, left blank, so that it parses
+/// as Rust and everything stays on its line
+pub fn blank_frontmatter(text: &str) -> Cow<'_, str> {
+    let lines: Vec<_> = text.split_inclusive('\n').collect();
+    match frontmatter_lines(&lines) {
+        Some(range) => lines
+            .iter()
+            .copied()
+            .enumerate()
+            .map(|(idx, line)| if range.contains(&idx) { "\n" } else { line })
+            .collect(),
+        None => Cow::Borrowed(text),
+    }
+}
+
+/// Crates available on the playground, by package name. A snapshot of its top crates list.
+const PLAYGROUND_CRATES: &[&str] = &[
+    "ahash",
+    "aho-corasick",
+    "anyhow",
+    "arrayvec",
+    "async-trait",
+    "base64",
+    "bitflags",
+    "byteorder",
+    "bytes",
+    "cfg-if",
+    "chrono",
+    "clap",
+    "crossbeam",
+    "crossbeam-channel",
+    "crossbeam-utils",
+    "csv",
+    "either",
+    "env_logger",
+    "futures",
+    "getrandom",
+    "hashbrown",
+    "hex",
+    "http",
+    "indexmap",
+    "itertools",
+    "itoa",
+    "lazy_static",
+    "libc",
+    "log",
+    "memchr",
+    "num",
+    "num-bigint",
+    "num-traits",
+    "once_cell",
+    "parking_lot",
+    "percent-encoding",
+    "proc-macro2",
+    "quote",
+    "rand",
+    "rand_chacha",
+    "rayon",
+    "regex",
+    "ryu",
+    "serde",
+    "serde_derive",
+    "serde_json",
+    "sha2",
+    "smallvec",
+    "strum",
+    "syn",
+    "tempfile",
+    "thiserror",
+    "time",
+    "tokio",
+    "tracing",
+    "unicode-segmentation",
+    "url",
+    "uuid",
+];
+
+/// Longest URL the playground reliably accepts
+const MAX_URL_LEN: usize = 8000;
+
+/// Describe the normal dependencies of `package` that are neither inlined (`inlined` holds
+/// crate names) nor available on the playground.
+pub fn unavailable_on_playground(package: &Package, inlined: &[&str]) -> Vec<Warning> {
+    package
+        .dependencies
+        .iter()
+        .filter(|d| d.kind == DepKind::Normal)
+        .filter(|d| {
+            let name = d.name.replace('-', "_");
+            !inlined.iter().any(|c| c.replace('-', "_") == name)
+        })
+        .filter(|d| !PLAYGROUND_CRATES.contains(&d.package.as_str()))
+        .map(|d| {
+            let warning = format!(
+                "Dependency {} is not available on the playground, the shared code will not compile",
+                d.package
+            );
+            Warning::new(WarningKind::Dependency, warning)
+        })
+        .collect()
+}
+
+/// The URL opening `code` on the playground, `None` if it would be too long.
+pub fn playground_url(code: &str, edition: &str) -> Option<String> {
+    let mut url = format!(
+        "https://play.rust-lang.org/?version=stable&mode=debug&edition={}&code=",
+        edition
+    );
+    for byte in code.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                url.push(byte as char)
+            }
+            _ => write!(url, "%{:02X}", byte).expect("Writing to a String can not fail"),
+        }
+    }
+    (url.len() <= MAX_URL_LEN).then(|| url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_encoded_and_limited() {
+        let url = playground_url("fn main() {}\n", "2021").unwrap();
+        assert!(url.ends_with("&code=fn%20main%28%29%20%7B%7D%0A"));
+        assert!(playground_url(&"x".repeat(MAX_URL_LEN), "2021").is_none());
+    }
+}
@@ -0,0 +1,228 @@
+use std::path::{Path, PathBuf};
+
+use crate::paths;
+
+/// The `include`/`exclude` lists of a package, deciding which files `cargo package` would ship.
+/// Patterns use gitignore semantics, relative to the package root.
+pub struct PackageFiles {
+    root: PathBuf,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl PackageFiles {
+    pub fn new(package: &cargo_toml::Package, root: &Path) -> Self {
+        let parse = |patterns: &[String]| patterns.iter().map(|p| Pattern::new(p)).collect();
+        PackageFiles {
+            root: paths::normalize(root),
+            include: parse(&package.include),
+            exclude: parse(&package.exclude),
+        }
+    }
+
+    /// Whether `cargo package` would leave out `file`. As with cargo, `include` wins over
+    /// `exclude` if both are given.
+    pub fn is_excluded(&self, file: &Path) -> bool {
+        let rel = match paths::normalize(file).strip_prefix(&self.root) {
+            Ok(rel) => rel.to_path_buf(),
+            // not part of the package at all
+            Err(_) => return false,
+        };
+        let segments: Vec<_> = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        if self.include.is_empty() {
+            last_match(&self.exclude, &segments)
+        } else {
+            !last_match(&self.include, &segments)
+        }
+    }
+}
+
+/// Whether the last of `patterns` that matches `path` or one of its parent directories is a
+/// positive one, which is how gitignore combines patterns
+fn last_match(patterns: &[Pattern], path: &[String]) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        let hit = (1..=path.len()).any(|len| pattern.matches(&path[..len], len == path.len()));
+        if hit {
+            matched = !pattern.negated;
+        }
+    }
+    matched
+}
+
+/// A single gitignore style pattern
+struct Pattern {
+    negated: bool,
+    /// only matches directories, from a trailing `/`
+    dir_only: bool,
+    /// matched against the whole path rather than just the last segment, from a `/` anywhere
+    /// but at the end
+    anchored: bool,
+    segments: Vec<String>,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Self {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        Pattern {
+            negated,
+            dir_only,
+            anchored,
+            segments: pattern.split('/').map(String::from).collect(),
+        }
+    }
+
+    /// `is_file` tells whether `path` is the file itself or one of its parent directories
+    fn matches(&self, path: &[String], is_file: bool) -> bool {
+        if self.dir_only && is_file {
+            return false;
+        }
+        if self.anchored {
+            match_segments(&self.segments, path)
+        } else {
+            path.last()
+                .map_or(false, |last| match_segment(&self.segments[0], last))
+        }
+    }
+}
+
+fn match_segments(pattern: &[String], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| match_segments(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, path)) => match_segment(first, segment) && match_segments(rest, path),
+            None => false,
+        },
+    }
+}
+
+/// Match one path segment against `*`, `?` and literal characters
+fn match_segment(pattern: &str, segment: &str) -> bool {
+    fn go(pattern: &[char], segment: &[char]) -> bool {
+        match pattern.split_first() {
+            None => segment.is_empty(),
+            Some(('*', rest)) => (0..=segment.len()).any(|skip| go(rest, &segment[skip..])),
+            Some(('?', rest)) => !segment.is_empty() && go(rest, &segment[1..]),
+            Some((c, rest)) => segment.first() == Some(c) && go(rest, &segment[1..]),
+        }
+    }
+    let pattern: Vec<_> = pattern.chars().collect();
+    let segment: Vec<_> = segment.chars().collect();
+    go(&pattern, &segment)
+}
+
+/// Remove the modules of `file`, the crate rooted at `root`, whose files `package` excludes,
+/// `mod` declaration and all. `sources` are the files the crate was inlined from. Returns the
+/// files of the removed modules.
+pub fn drop_excluded(
+    file: &mut syn::File,
+    root: &Path,
+    sources: &[PathBuf],
+    package: &PackageFiles,
+) -> Vec<PathBuf> {
+    let mut dropped = vec![];
+    if let Some(dir) = paths::normalize(root).parent() {
+        drop_in(&mut file.items, dir, sources, package, &mut dropped);
+    }
+    dropped
+}
+
+/// `dir` is where the files of modules declared in `items` are looked up
+fn drop_in(
+    items: &mut Vec<syn::Item>,
+    dir: &Path,
+    sources: &[PathBuf],
+    package: &PackageFiles,
+    dropped: &mut Vec<PathBuf>,
+) {
+    items.retain_mut(|item| {
+        let module = match item {
+            syn::Item::Mod(module) => module,
+            _ => return true,
+        };
+        let name = module.ident.to_string();
+        let candidates = match path_attr(module) {
+            Some(path) => vec![paths::normalize(&dir.join(path))],
+            None => vec![
+                dir.join(format!("{}.rs", name)),
+                dir.join(&name).join("mod.rs"),
+            ],
+        };
+        // modules inline in the source have no file of their own
+        let file = candidates.into_iter().find(|c| sources.contains(c));
+        if let Some(file) = file {
+            if package.is_excluded(&file) {
+                dropped.push(file);
+                return false;
+            }
+        }
+        if let Some((_, content)) = &mut module.content {
+            drop_in(content, &dir.join(&name), sources, package, dropped);
+        }
+        true
+    });
+}
+
+/// The value of a `#[path = "..."]` attribute
+pub fn path_attr(module: &syn::ItemMod) -> Option<String> {
+    module
+        .attrs
+        .iter()
+        .find_map(|attr| match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                path,
+                lit: syn::Lit::Str(lit),
+                ..
+            })) if path.is_ident("path") => Some(lit.value()),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(include: &[&str], exclude: &[&str]) -> PackageFiles {
+        let parse = |patterns: &[&str]| patterns.iter().map(|p| Pattern::new(p)).collect();
+        PackageFiles {
+            root: PathBuf::from("/pkg"),
+            include: parse(include),
+            exclude: parse(exclude),
+        }
+    }
+
+    #[test]
+    fn gitignore_semantics() {
+        let globs = files(
+            &[],
+            &["src/experimental/**", "*.bak", "!keep.bak", "fixtures/"],
+        );
+        let excluded = |p: &str| globs.is_excluded(Path::new(p));
+        assert!(excluded("/pkg/src/experimental/mod.rs"));
+        assert!(excluded("/pkg/src/experimental/deep/x.rs"));
+        assert!(!excluded("/pkg/src/experimental.rs"));
+        assert!(excluded("/pkg/src/old.bak"));
+        assert!(!excluded("/pkg/src/keep.bak"));
+        assert!(excluded("/pkg/src/fixtures/data.rs"));
+        assert!(!excluded("/other/src/experimental/mod.rs"));
+
+        let only_src = files(&["src/**/*.rs", "Cargo.toml"], &["src/main.rs"]);
+        assert!(!only_src.is_excluded(Path::new("/pkg/src/main.rs")));
+        assert!(only_src.is_excluded(Path::new("/pkg/build.rs")));
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+
+/// How to write the structure graph of a bundle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    /// A Mermaid flowchart, which renders in markdown on most forges
+    Mermaid,
+}
+
+/// An inlined crate, as far as the graph is concerned
+pub struct GraphCrate {
+    pub name: String,
+    /// the module it became in the bundle
+    pub module: String,
+    /// names of its normal dependencies in its manifest
+    pub deps: Vec<String>,
+}
+
+/// What the graph is built from besides the bundle itself
+pub struct GraphInput {
+    pub bin_name: String,
+    pub nest: Option<String>,
+    pub crates: Vec<GraphCrate>,
+    /// names of the registry dependencies the binary keeps in its manifest
+    pub deps: Vec<String>,
+    /// also add a node for each inline module
+    pub modules: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum NodeKind {
+    Binary,
+    Crate,
+    Module,
+    Dependency,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum EdgeKind {
+    Inlines,
+    DependsOn,
+    References,
+}
+
+impl EdgeKind {
+    fn label(self) -> &'static str {
+        match self {
+            EdgeKind::Inlines => "inlines",
+            EdgeKind::DependsOn => "depends on",
+            EdgeKind::References => "references",
+        }
+    }
+}
+
+struct Node {
+    label: String,
+    kind: NodeKind,
+    /// lines of the bundle it contributes
+    lines: Option<usize>,
+}
+
+/// The binary, inlined crates, their modules and registry dependencies of a bundle, with what
+/// inlines, depends on and references what. Everything is kept sorted so the output is
+/// deterministic.
+pub struct DepsGraph {
+    nodes: BTreeMap<String, Node>,
+    edges: BTreeSet<(String, String, EdgeKind)>,
+}
+
+impl DepsGraph {
+    /// Build the graph of the bundle `text`, which should be formatted for line counts to mean
+    /// anything. References inside macro invocations are not seen.
+    pub fn build(text: &str, input: &GraphInput) -> Result<Self> {
+        let file = syn::parse_file(text).context("The bundled script does not parse")?;
+        let mut graph = DepsGraph {
+            nodes: BTreeMap::new(),
+            edges: BTreeSet::new(),
+        };
+
+        // where the crate modules are
+        let container = input.nest.as_ref().and_then(|nest| {
+            file.items.iter().find_map(|item| match item {
+                syn::Item::Mod(m) if m.ident == nest => m.content.as_ref().map(|(_, c)| c),
+                _ => None,
+            })
+        });
+        let crate_items = container.unwrap_or(&file.items);
+        let crate_of = |item: &syn::Item| match item {
+            syn::Item::Mod(m) => input.crates.iter().find(|c| m.ident == c.module),
+            _ => None,
+        };
+        let is_binary_item = |item: &syn::Item| match (item, &input.nest) {
+            (syn::Item::Mod(m), Some(nest)) => m.ident != nest,
+            (item, None) => crate_of(item).is_none(),
+            _ => true,
+        };
+
+        let bin = format!("bin:{}", input.bin_name);
+        let resolver = Resolver { input };
+        let mut crate_lines = 0;
+        for item in crate_items {
+            let (module, krate) = match (item, crate_of(item)) {
+                (syn::Item::Mod(m), Some(krate)) => (m, krate),
+                _ => continue,
+            };
+            let id = format!("crate:{}", krate.name);
+            let lines = lines_of(module);
+            crate_lines += lines;
+            graph.node(&id, &krate.name, NodeKind::Crate, Some(lines));
+            graph.edge(&bin, &id, EdgeKind::Inlines);
+            for dep in &krate.deps {
+                let target = resolver.dependency(dep);
+                graph.dependency(&target, dep);
+                graph.edge(&id, &target, EdgeKind::DependsOn);
+            }
+            let items = module.content.as_ref().map_or(&[][..], |(_, c)| c);
+            for target in resolver.references(items.iter()) {
+                if target != id {
+                    graph.edge(&id, &target, EdgeKind::References);
+                }
+            }
+            if input.modules {
+                graph.modules(&id, &krate.name, items);
+            }
+        }
+
+        let binary_lines = text.lines().count().saturating_sub(crate_lines);
+        graph.node(&bin, &input.bin_name, NodeKind::Binary, Some(binary_lines));
+        for dep in &input.deps {
+            let target = resolver.dependency(dep);
+            graph.dependency(&target, dep);
+            graph.edge(&bin, &target, EdgeKind::DependsOn);
+        }
+        let binary_items = file.items.iter().filter(|item| is_binary_item(item));
+        for target in resolver.references(binary_items.clone()) {
+            graph.edge(&bin, &target, EdgeKind::References);
+        }
+        if input.modules {
+            let items: Vec<_> = binary_items.cloned().collect();
+            graph.modules(&bin, &input.bin_name, &items);
+        }
+        Ok(graph)
+    }
+
+    fn node(&mut self, id: &str, label: &str, kind: NodeKind, lines: Option<usize>) {
+        self.nodes.insert(
+            id.into(),
+            Node {
+                label: label.into(),
+                kind,
+                lines,
+            },
+        );
+    }
+
+    /// Add the node of a registry dependency, unless `id` is an inlined crate
+    fn dependency(&mut self, id: &str, name: &str) {
+        if id.starts_with("dep:") {
+            self.node(id, name, NodeKind::Dependency, None);
+        }
+    }
+
+    fn edge(&mut self, from: &str, to: &str, kind: EdgeKind) {
+        self.edges.insert((from.into(), to.into(), kind));
+    }
+
+    /// Add the inline modules in `items` of the node `parent` at `path`, recursively
+    fn modules(&mut self, parent: &str, path: &str, items: &[syn::Item]) {
+        for item in items {
+            if let syn::Item::Mod(m) = item {
+                if let Some((_, content)) = &m.content {
+                    let path = format!("{}::{}", path, m.ident);
+                    let id = format!("mod:{}", path);
+                    self.node(&id, &path, NodeKind::Module, Some(lines_of(m)));
+                    self.edge(parent, &id, EdgeKind::Inlines);
+                    self.modules(&id, &path, content);
+                }
+            }
+        }
+    }
+
+    pub fn render(&self, format: GraphFormat) -> String {
+        let mut out = String::new();
+        match format {
+            GraphFormat::Dot => self.write_dot(&mut out),
+            GraphFormat::Mermaid => self.write_mermaid(&mut out),
+        }
+        .expect("Writing to a String can not fail");
+        out
+    }
+
+    fn write_dot(&self, dot: &mut String) -> std::fmt::Result {
+        dot.push_str("digraph bundle {\n    rankdir=LR;\n");
+        for (id, node) in &self.nodes {
+            let shape = match node.kind {
+                NodeKind::Binary => "doubleoctagon",
+                NodeKind::Crate => "box",
+                NodeKind::Module => "note",
+                NodeKind::Dependency => "ellipse",
+            };
+            match node.lines {
+                // bigger the more lines
+                Some(lines) => writeln!(
+                    dot,
+                    "    \"{}\" [label=\"{}\\n{} lines\", shape={}, width={:.2}];",
+                    id,
+                    node.label,
+                    lines,
+                    shape,
+                    1.0 + (lines as f64).sqrt() / 20.0
+                )?,
+                None => writeln!(
+                    dot,
+                    "    \"{}\" [label=\"{}\", shape={}];",
+                    id, node.label, shape
+                )?,
+            }
+        }
+        for (from, to, kind) in &self.edges {
+            let style = match kind {
+                EdgeKind::Inlines => "solid",
+                EdgeKind::DependsOn => "dotted",
+                EdgeKind::References => "dashed",
+            };
+            writeln!(
+                dot,
+                "    \"{}\" -> \"{}\" [label=\"{}\", style={}];",
+                from,
+                to,
+                kind.label(),
+                style
+            )?;
+        }
+        dot.push_str("}\n");
+        Ok(())
+    }
+
+    fn write_mermaid(&self, mermaid: &mut String) -> std::fmt::Result {
+        // node ids in mermaid can not contain most punctuation
+        let ids: BTreeMap<_, _> = self
+            .nodes
+            .keys()
+            .enumerate()
+            .map(|(i, id)| (id, format!("n{}", i)))
+            .collect();
+        mermaid.push_str("graph LR\n");
+        for (id, node) in &self.nodes {
+            match node.lines {
+                Some(lines) => writeln!(
+                    mermaid,
+                    "    {}[\"{}<br/>{} lines\"]",
+                    ids[id], node.label, lines
+                )?,
+                None => writeln!(mermaid, "    {}[\"{}\"]", ids[id], node.label)?,
+            }
+        }
+        for (from, to, kind) in &self.edges {
+            let arrow = match kind {
+                EdgeKind::Inlines => "-->",
+                EdgeKind::DependsOn => "-.->",
+                EdgeKind::References => "==>",
+            };
+            writeln!(
+                mermaid,
+                "    {} {}|{}| {}",
+                ids[from],
+                arrow,
+                kind.label(),
+                ids[to]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn lines_of(module: &syn::ItemMod) -> usize {
+    let span = module.span();
+    span.end().line + 1 - span.start().line
+}
+
+/// Maps names in code and manifests to the nodes they refer to
+struct Resolver<'a> {
+    input: &'a GraphInput,
+}
+
+impl<'a> Resolver<'a> {
+    /// The node of the dependency `name`, an inlined crate if there is one by that name
+    fn dependency(&self, name: &str) -> String {
+        match self.input.crates.iter().find(|c| c.name == name) {
+            Some(krate) => format!("crate:{}", krate.name),
+            None => format!("dep:{}", name),
+        }
+    }
+
+    /// The nodes the paths in `items` are rooted at
+    fn references<'i>(&self, items: impl Iterator<Item = &'i syn::Item>) -> BTreeSet<String> {
+        let mut paths = Paths::default();
+        for item in items {
+            paths.visit_item(item);
+        }
+        paths
+            .roots
+            .into_iter()
+            .filter_map(|segments| self.root(&segments))
+            .collect()
+    }
+
+    fn root(&self, segments: &[String]) -> Option<String> {
+        let mut segments = segments;
+        let in_crate = segments.first().map_or(false, |s| s == "crate");
+        if in_crate {
+            segments = &segments[1..];
+            if let Some(nest) = &self.input.nest {
+                segments = segments.strip_prefix(std::slice::from_ref(nest))?;
+            }
+        }
+        let first = segments.first()?;
+        if let Some(krate) = self.input.crates.iter().find(|c| c.module == *first) {
+            return Some(format!("crate:{}", krate.name));
+        }
+        let deps = self
+            .input
+            .deps
+            .iter()
+            .chain(self.input.crates.iter().flat_map(|c| &c.deps));
+        for dep in deps {
+            if !in_crate && dep.replace('-', "_") == *first {
+                return Some(self.dependency(dep));
+            }
+        }
+        None
+    }
+}
+
+/// Collects every path with at least two segments, including those in `use` trees
+#[derive(Default)]
+struct Paths {
+    roots: BTreeSet<Vec<String>>,
+}
+
+impl Paths {
+    fn use_tree(&mut self, prefix: &mut Vec<String>, tree: &syn::UseTree) {
+        match tree {
+            syn::UseTree::Path(p) => {
+                prefix.push(p.ident.to_string());
+                self.use_tree(prefix, &p.tree);
+                prefix.pop();
+            }
+            syn::UseTree::Name(syn::UseName { ident })
+            | syn::UseTree::Rename(syn::UseRename { ident, .. }) => {
+                let mut path = prefix.clone();
+                path.push(ident.to_string());
+                self.roots.insert(path);
+            }
+            syn::UseTree::Glob(_) => {
+                self.roots.insert(prefix.clone());
+            }
+            syn::UseTree::Group(g) => {
+                for tree in &g.items {
+                    self.use_tree(prefix, tree);
+                }
+            }
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for Paths {
+    fn visit_path(&mut self, path: &'ast syn::Path) {
+        if path.segments.len() > 1 {
+            self.roots
+                .insert(path.segments.iter().map(|s| s.ident.to_string()).collect());
+        }
+        visit::visit_path(self, path);
+    }
+
+    fn visit_item_use(&mut self, item: &'ast syn::ItemUse) {
+        self.use_tree(&mut vec![], &item.tree);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot_is_deterministic() {
+        let text = "use serde::Serialize;
+
+fn main() {
+    crate::util::helper();
+}
+
+pub mod util {
+    pub fn helper() {
+        log::info!(\"hi\");
+        let _ = regex::Regex::new(\"x\");
+    }
+
+    mod inner {}
+}
+";
+        let input = GraphInput {
+            bin_name: "app".into(),
+            nest: None,
+            crates: vec![GraphCrate {
+                name: "util".into(),
+                module: "util".into(),
+                deps: vec!["regex".into()],
+            }],
+            deps: vec!["serde".into()],
+            modules: true,
+        };
+        let dot = DepsGraph::build(text, &input)
+            .unwrap()
+            .render(GraphFormat::Dot);
+        assert_eq!(
+            dot,
+            DepsGraph::build(text, &input)
+                .unwrap()
+                .render(GraphFormat::Dot)
+        );
+        assert!(dot.contains("\"crate:util\" [label=\"util\\n8 lines\""));
+        assert!(dot.contains("\"bin:app\" -> \"crate:util\" [label=\"inlines\""));
+        assert!(dot.contains("\"bin:app\" -> \"crate:util\" [label=\"references\""));
+        assert!(dot.contains("\"bin:app\" -> \"dep:serde\" [label=\"references\""));
+        assert!(dot.contains("\"crate:util\" -> \"dep:regex\" [label=\"depends on\""));
+        assert!(dot.contains("\"crate:util\" -> \"mod:util::inner\""));
+        // the log macro is not a dependency anyone declared
+        assert!(!dot.contains("dep:log"));
+
+        let mermaid = DepsGraph::build(text, &input)
+            .unwrap()
+            .render(GraphFormat::Mermaid);
+        assert!(mermaid.starts_with("graph LR\n"));
+    }
+}
@@ -0,0 +1,260 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use syn::spanned::Spanned;
+use syn::visit_mut::{self, VisitMut};
+
+use crate::globs::path_attr;
+use crate::paths;
+use crate::warning::{Warning, WarningKind};
+
+/// Default of [`Bundler::include_size_limit`](crate::Bundler::include_size_limit), 1 MiB
+pub const DEFAULT_SIZE_LIMIT: u64 = 1 << 20;
+
+/// Replace `include_str!`, `include_bytes!` and `include!` invocations with a literal relative
+/// path in `file`, the crate rooted at `crate_root` and inlined from `sources`, by what they
+/// include, as the path would be resolved against the bundle otherwise. The strings and bytes
+/// become literals, and `include!` is parsed and spliced in, as items at module level and as an
+/// expression elsewhere.
+///
+/// Paths that are not literals, files that do not exist or are larger than `limit` bytes are
+/// left alone, with a warning logged and added to `warnings`. `root` is what messages name
+/// files relative to. Returns the files included.
+pub fn resolve(
+    file: &mut syn::File,
+    crate_root: &Path,
+    sources: &[PathBuf],
+    limit: u64,
+    root: &Path,
+    warnings: &mut Vec<Warning>,
+) -> Vec<PathBuf> {
+    let crate_root = paths::normalize(crate_root);
+    let mut resolver = Resolver {
+        file: crate_root.clone(),
+        sources,
+        limit,
+        root,
+        included: vec![],
+        warnings,
+    };
+    if let Some(dir) = crate_root.parent() {
+        resolver.resolve_items(&mut file.items, &crate_root, dir);
+    }
+    resolver.included
+}
+
+struct Resolver<'a> {
+    /// the file the code being visited comes from
+    file: PathBuf,
+    sources: &'a [PathBuf],
+    limit: u64,
+    root: &'a Path,
+    included: Vec<PathBuf>,
+    warnings: &'a mut Vec<Warning>,
+}
+
+impl Resolver<'_> {
+    /// `items` come from `file`, and `dir` is where the files of modules declared in them are
+    /// looked up
+    fn resolve_items(&mut self, items: &mut Vec<syn::Item>, file: &Path, dir: &Path) {
+        let mut idx = 0;
+        while idx < items.len() {
+            match &mut items[idx] {
+                syn::Item::Mod(module) => {
+                    let name = module.ident.to_string();
+                    let candidates = match path_attr(module) {
+                        Some(path) => vec![paths::normalize(&dir.join(path))],
+                        None => {
+                            vec![
+                                dir.join(format!("{}.rs", name)),
+                                dir.join(&name).join("mod.rs"),
+                            ]
+                        }
+                    };
+                    // modules inline in the source have no file of their own
+                    let own = candidates.into_iter().find(|c| self.sources.contains(c));
+                    let file = own.as_deref().unwrap_or(file);
+                    if let Some((_, content)) = &mut module.content {
+                        self.resolve_items(content, file, &dir.join(&name));
+                    }
+                    idx += 1;
+                }
+                syn::Item::Macro(item)
+                    if item.ident.is_none() && is_macro(&item.mac, "include") =>
+                {
+                    self.file = file.to_path_buf();
+                    let included = self
+                        .included_text(&item.mac, "include")
+                        .and_then(|(path, text)| self.parse::<syn::File>(&path, &text));
+                    match included {
+                        Some((path, mut included)) => {
+                            let dir = path.parent().unwrap_or(dir).to_path_buf();
+                            self.resolve_items(&mut included.items, &path, &dir);
+                            let count = included.items.len();
+                            let _: Vec<_> = items.splice(idx..=idx, included.items).collect();
+                            idx += count;
+                        }
+                        None => idx += 1,
+                    }
+                }
+                item => {
+                    self.file = file.to_path_buf();
+                    self.visit_item_mut(item);
+                    idx += 1;
+                }
+            }
+        }
+    }
+
+    /// The literal the include `mac` stands for, or what `include!` parses to
+    fn expand(&mut self, mac: &syn::Macro) -> Option<syn::Expr> {
+        let span = mac.span();
+        let lit: syn::Lit = if is_macro(mac, "include_str") {
+            let (_, text) = self.included_text(mac, "include_str")?;
+            syn::LitStr::new(&text, span).into()
+        } else if is_macro(mac, "include_bytes") {
+            let path = self.included_path(mac, "include_bytes")?;
+            let bytes = self.read(&path)?;
+            syn::LitByteStr::new(&bytes, span).into()
+        } else if is_macro(mac, "include") {
+            let (path, text) = self.included_text(mac, "include")?;
+            let (path, mut expr) = self.parse::<syn::Expr>(&path, &text)?;
+            // includes in there are relative to the included file
+            let outer = std::mem::replace(&mut self.file, path);
+            self.visit_expr_mut(&mut expr);
+            self.file = outer;
+            return Some(expr);
+        } else {
+            return None;
+        };
+        Some(syn::Expr::Lit(syn::ExprLit { attrs: vec![], lit }))
+    }
+
+    fn included_text(&mut self, mac: &syn::Macro, kind: &str) -> Option<(PathBuf, String)> {
+        let path = self.included_path(mac, kind)?;
+        match String::from_utf8(self.read(&path)?) {
+            Ok(text) => Some((path, text)),
+            Err(_) => {
+                self.warn(
+                    mac,
+                    format!("{}! of {} which is not UTF-8", kind, path.display()),
+                );
+                None
+            }
+        }
+    }
+
+    /// The file `mac` includes, if it is a relative literal path to a file within the size limit
+    fn included_path(&mut self, mac: &syn::Macro, kind: &str) -> Option<PathBuf> {
+        let path = match mac.parse_body::<syn::LitStr>() {
+            Ok(path) => path.value(),
+            Err(_) => {
+                self.warn(
+                    mac,
+                    format!(
+                        "{}! without a literal path can not be resolved when bundling",
+                        kind
+                    ),
+                );
+                return None;
+            }
+        };
+        // absolute paths mean the same wherever the bundle is
+        if Path::new(&path).is_absolute() {
+            return None;
+        }
+        let dir = self.file.parent().unwrap_or_else(|| Path::new(""));
+        let resolved = paths::normalize(&dir.join(&path));
+        match fs::metadata(&resolved) {
+            Ok(meta) if meta.len() > self.limit => {
+                let message = format!(
+                    "{}!({:?}) is not inlined, as the file is larger than {} bytes",
+                    kind, path, self.limit
+                );
+                self.warn(mac, message);
+                None
+            }
+            Ok(_) => Some(resolved),
+            Err(_) => {
+                self.warn(
+                    mac,
+                    format!("{}!({:?}) does not point at a file", kind, path),
+                );
+                None
+            }
+        }
+    }
+
+    fn read(&mut self, path: &Path) -> Option<Vec<u8>> {
+        match fs::read(path) {
+            Ok(content) => {
+                if !self.included.contains(&path.to_path_buf()) {
+                    self.included.push(path.to_path_buf());
+                }
+                Some(content)
+            }
+            Err(e) => {
+                let warning = format!("Failed to read included file {}: {}", path.display(), e);
+                log::warn!("{}", warning);
+                let relative = paths::relative_to(path, self.root);
+                self.warnings
+                    .push(Warning::new(WarningKind::Include, warning).at(relative, None));
+                None
+            }
+        }
+    }
+
+    fn parse<T: syn::parse::Parse>(&mut self, path: &Path, text: &str) -> Option<(PathBuf, T)> {
+        match syn::parse_str(text) {
+            Ok(parsed) => Some((path.to_path_buf(), parsed)),
+            Err(e) => {
+                let relative = paths::relative_to(path, self.root);
+                let warning = format!(
+                    "Included file {} can not be parsed, leaving include! as is: {}",
+                    relative.display(),
+                    e
+                );
+                log::warn!("{}", warning);
+                self.warnings
+                    .push(Warning::new(WarningKind::Include, warning).at(relative, None));
+                None
+            }
+        }
+    }
+
+    fn warn(&mut self, mac: &syn::Macro, message: String) {
+        let relative = paths::relative_to(&self.file, self.root);
+        let line = mac.span().start().line;
+        let warning = format!("{}:{}: {}", relative.display(), line, message);
+        log::warn!("{}", warning);
+        let warning = Warning::new(WarningKind::Include, warning).at(relative, Some(line));
+        self.warnings.push(warning);
+    }
+}
+
+impl VisitMut for Resolver<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut syn::Expr) {
+        if let syn::Expr::Macro(m) = expr {
+            if let Some(expanded) = self.expand(&m.mac) {
+                *expr = expanded;
+                return;
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+}
+
+/// Whether `mac` invokes the std macro `name`, directly or through `std::` or `core::`
+fn is_macro(mac: &syn::Macro, name: &str) -> bool {
+    let segments: Vec<_> = mac
+        .path
+        .segments
+        .iter()
+        .map(|s| s.ident.to_string())
+        .collect();
+    match &segments[..] {
+        [ident] => ident == name,
+        [krate, ident] => (krate == "std" || krate == "core") && ident == name,
+        _ => false,
+    }
+}
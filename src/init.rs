@@ -0,0 +1,208 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use cargo_toml::Manifest;
+use toml_edit::{value, Array, Document, Item, Table};
+
+use crate::config::METADATA_KEY;
+use crate::resolve::{self, Resolution};
+
+/// The line calling the bundler from `main` of a build script
+const CALL: &str = "    rust_script_bundler::bundle_from_metadata().unwrap();\n";
+
+/// A file [`plan_init`] creates or changes
+#[derive(Debug, Clone)]
+pub struct InitEdit {
+    pub path: PathBuf,
+    /// What changes, in a few words
+    pub summary: String,
+    /// The whole new content
+    pub content: String,
+}
+
+/// What setting up a package for bundling from its build script takes, see [`plan_init`]
+#[derive(Debug, Clone, Default)]
+pub struct InitPlan {
+    pub edits: Vec<InitEdit>,
+    /// What was left alone and why, including anything to do by hand
+    pub notes: Vec<String>,
+}
+
+impl InitPlan {
+    pub fn apply(&self) -> Result<()> {
+        for edit in &self.edits {
+            fs::write(&edit.path, &edit.content)
+                .with_context(|| format!("Failed to write {}", edit.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Work out how to set up the package in `manifest_dir` for bundling with
+/// [`bundle_from_metadata`](crate::bundle_from_metadata), without changing anything yet:
+/// - a `[package.metadata.rust-script-bundle]` table with the package's bin targets and lib;
+/// - `rust-script-bundler` in `[build-dependencies]`;
+/// - a build script calling the bundler. An existing one is only changed with `force`, otherwise
+///   the call to add is in the notes.
+///
+/// The manifest keeps its formatting, and parts already there are left alone, so planning again
+/// after applying finds nothing to do. The bin targets are found with `resolution`.
+pub fn plan_init(
+    manifest_dir: impl AsRef<Path>,
+    force: bool,
+    resolution: Resolution,
+) -> Result<InitPlan> {
+    let manifest_dir = manifest_dir.as_ref();
+    let manifest_path = manifest_dir.join("Cargo.toml");
+    let text = fs::read_to_string(&manifest_path)
+        .with_context(|| format!("Failed to read manifest at {}", manifest_path.display()))?;
+    let mut doc: Document = text
+        .parse()
+        .with_context(|| format!("Invalid manifest at {}", manifest_path.display()))?;
+    let mut plan = InitPlan::default();
+    let mut changed = vec![];
+
+    let package = match doc.get_mut("package").and_then(Item::as_table_mut) {
+        Some(package) => package,
+        None => bail!("{} has no [package]", manifest_path.display()),
+    };
+    let build = match package.get("build") {
+        None => "build.rs".to_string(),
+        Some(build) => match (build.as_str(), build.as_bool()) {
+            (Some(path), _) => path.to_string(),
+            (_, Some(false)) => bail!("The package has no build script, build = false"),
+            _ => bail!("Unsupported build key in {}", manifest_path.display()),
+        },
+    };
+    let metadata = package
+        .entry("metadata")
+        .or_insert_with(|| {
+            let mut metadata = Table::new();
+            metadata.set_implicit(true);
+            Item::Table(metadata)
+        })
+        .as_table_mut()
+        .context("[package.metadata] is not a table")?;
+    if metadata.contains_key(METADATA_KEY) {
+        plan.notes.push(format!(
+            "[package.metadata.{}] is already there",
+            METADATA_KEY
+        ));
+    } else {
+        metadata.insert(
+            METADATA_KEY,
+            Item::Table(stanza(&text, &manifest_path, resolution)?),
+        );
+        changed.push(format!("add [package.metadata.{}]", METADATA_KEY));
+    }
+
+    let build_deps = doc
+        .as_table_mut()
+        .entry("build-dependencies")
+        .or_insert_with(|| Item::Table(Table::new()))
+        .as_table_mut()
+        .context("[build-dependencies] is not a table")?;
+    if build_deps.contains_key("rust-script-bundler") {
+        plan.notes
+            .push("rust-script-bundler is already a build dependency".into());
+    } else {
+        build_deps.insert("rust-script-bundler", value(env!("CARGO_PKG_VERSION")));
+        changed.push("add rust-script-bundler to [build-dependencies]".into());
+    }
+
+    if !changed.is_empty() {
+        plan.edits.push(InitEdit {
+            path: manifest_path,
+            summary: changed.join(", "),
+            content: doc.to_string(),
+        });
+    }
+
+    let build_path = manifest_dir.join(&build);
+    match fs::read_to_string(&build_path) {
+        Err(_) => plan.edits.push(InitEdit {
+            path: build_path,
+            summary: "create the build script".into(),
+            content: format!("fn main() {{\n{}}}\n", CALL),
+        }),
+        Ok(script) if script.contains("bundle_from_metadata") => plan
+            .notes
+            .push(format!("{} already calls the bundler", build)),
+        Ok(script) => match (force, call_in_main(&script)) {
+            (true, Some(content)) => plan.edits.push(InitEdit {
+                path: build_path,
+                summary: "call the bundler from main".into(),
+                content,
+            }),
+            _ => plan.notes.push(format!(
+                "{} exists, add this to its main, or rerun with --force:\n{}",
+                build,
+                CALL.trim_end()
+            )),
+        },
+    }
+    Ok(plan)
+}
+
+/// The metadata table bundling every bin target of the package, and its lib if it has one
+fn stanza(text: &str, manifest_path: &Path, resolution: Resolution) -> Result<Table> {
+    let mut manifest = Manifest::from_str(text)?;
+    manifest.complete_from_path(manifest_path)?;
+    let dir = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+    let package = resolve::resolve_package(resolution, &manifest, dir)?;
+    let mut stanza = Table::new();
+    let bins: Array = package.bins.iter().map(|bin| bin.name.as_str()).collect();
+    stanza.insert("bins", value(bins));
+    if package.lib.is_some() {
+        stanza.insert("lib", value(true));
+    }
+    Ok(stanza)
+}
+
+/// `script` with [`CALL`] first in its `main`, if it can be found
+fn call_in_main(script: &str) -> Option<String> {
+    let main = script.find("fn main(")?;
+    let body = main + script[main..].find('{')? + 1;
+    let rest = &script[body..];
+    let rest = rest.strip_prefix('\n').unwrap_or(rest);
+    Some(format!("{}\n{}{}", &script[..body], CALL, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init_is_idempotent() {
+        let dir = std::env::temp_dir().join(format!("bundler-init-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        let manifest = "[package]\nname = \"tool\" # the name\nversion = \"0.1.0\"\n";
+        fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+        fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(
+            dir.join("build.rs"),
+            "fn main() {\n    println!(\"hi\");\n}\n",
+        )
+        .unwrap();
+
+        // an existing build script is left alone without force
+        let plan = plan_init(&dir, false, Resolution::CargoToml).unwrap();
+        assert_eq!(plan.edits.len(), 1);
+        assert!(plan.notes[0].contains("--force"));
+
+        let plan = plan_init(&dir, true, Resolution::CargoToml).unwrap();
+        assert_eq!(plan.edits.len(), 2);
+        plan.apply().unwrap();
+        let manifest = fs::read_to_string(dir.join("Cargo.toml")).unwrap();
+        assert!(manifest.starts_with("[package]\nname = \"tool\" # the name\n"));
+        assert!(manifest.contains("[package.metadata.rust-script-bundle]\nbins = [\"tool\"]\n"));
+        assert!(manifest.contains("[build-dependencies]\nrust-script-bundler = "));
+        let build = fs::read_to_string(dir.join("build.rs")).unwrap();
+        assert!(build.starts_with(&format!("fn main() {{\n{}", CALL)));
+
+        let again = plan_init(&dir, true, Resolution::CargoToml).unwrap();
+        assert!(again.edits.is_empty());
+        assert_eq!(again.notes.len(), 3);
+    }
+}
@@ -0,0 +1,643 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use syn::ext::IdentExt;
+use syn_inline_mod::InlinerBuilder;
+
+use crate::cfg::{self, CfgSet};
+use crate::diagnostic::ParseError;
+use crate::paths;
+
+/// What [`inline_crate`] does with a module it fails to load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorMode {
+    /// Fail, once every module is attempted, with an error describing all that failed
+    FailFast,
+    /// Leave it as a declaration and go on, describing it in [`InlinedCrate::diagnostics`]
+    Collect,
+    /// Replace it by an empty inline module and go on, describing it in
+    /// [`InlinedCrate::diagnostics`]. The result is then always a complete file.
+    Stub,
+}
+
+impl Default for ErrorMode {
+    fn default() -> Self {
+        ErrorMode::FailFast
+    }
+}
+
+/// Sources of modules besides the file system, e.g. files a build script generates or a crate
+/// kept in memory, see [`InlineOptions::sources`]
+pub trait SourceProvider: Send + Sync {
+    /// The text of the file at `path`, if provided. `path` is normalized but only absolute if
+    /// the crate root is.
+    fn load(&self, path: &Path) -> Option<String>;
+}
+
+impl SourceProvider for BTreeMap<PathBuf, String> {
+    fn load(&self, path: &Path) -> Option<String> {
+        self.get(path).cloned()
+    }
+}
+
+/// Options of [`inline_crate`]
+#[derive(Clone)]
+pub struct InlineOptions {
+    errors: ErrorMode,
+    cfgs: Option<CfgSet>,
+    record_files: bool,
+    sources: Option<Arc<dyn SourceProvider>>,
+    search_paths: Vec<PathBuf>,
+}
+
+impl Default for InlineOptions {
+    fn default() -> Self {
+        InlineOptions {
+            errors: ErrorMode::default(),
+            cfgs: None,
+            record_files: true,
+            sources: None,
+            search_paths: vec![],
+        }
+    }
+}
+
+impl fmt::Debug for InlineOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InlineOptions")
+            .field("errors", &self.errors)
+            .field("cfgs", &self.cfgs)
+            .field("record_files", &self.record_files)
+            .field("sources", &self.sources.is_some())
+            .field("search_paths", &self.search_paths)
+            .finish()
+    }
+}
+
+impl InlineOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// What to do with modules that fail to load, [`ErrorMode::FailFast`] by default
+    pub fn errors(mut self, errors: ErrorMode) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// Drop `mod` declarations `cfgs` rules out, so their files need not exist. Without this,
+    /// every declared module is loaded.
+    pub fn cfgs(mut self, cfgs: CfgSet) -> Self {
+        self.cfgs = Some(cfgs);
+        self
+    }
+
+    /// Record the path of every file loaded in [`InlinedCrate::files`], on by default
+    pub fn record_files(mut self, record: bool) -> Self {
+        self.record_files = record;
+        self
+    }
+
+    /// Ask `sources` for every file, the crate root included, before reading it from disk.
+    /// Files it provides are not in [`InlinedCrate::files`].
+    pub fn sources(mut self, sources: Arc<dyn SourceProvider>) -> Self {
+        self.sources = Some(sources);
+        self
+    }
+
+    /// Look for a module whose file is not next to its parent in `dir` as well, as
+    /// `dir/<name>.rs` or `dir/<name>/mod.rs`, e.g. for modules a build script generates into
+    /// `OUT_DIR`. Its submodules are then looked for in `dir/<name>`.
+    pub fn search_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.search_paths.push(dir.into());
+        self
+    }
+}
+
+/// A crate with all its modules inlined, see [`inline_crate`]
+#[derive(Debug, Clone)]
+pub struct InlinedCrate {
+    pub file: syn::File,
+    /// Every file loaded from disk, the root first unless provided, if
+    /// [`InlineOptions::record_files`] is on
+    pub files: Vec<PathBuf>,
+    /// Modules that failed to load, with [`ErrorMode::Collect`]
+    pub diagnostics: Vec<String>,
+}
+
+/// Inline all modules of the crate rooted at `path` into a single file, the way bundling does
+/// but with nothing else done to it.
+pub fn inline_crate(path: &Path, opts: &InlineOptions) -> Result<InlinedCrate> {
+    let (mut file, loaded, mut failures) = match opts.sources {
+        Some(_) => load_with_sources(path, opts)?,
+        None => load_from_disk(path, opts)?,
+    };
+    let diagnostics: Vec<_> = failures.iter().map(ToString::to_string).collect();
+    match (opts.errors, failures.len()) {
+        (ErrorMode::FailFast, 0) => {}
+        (ErrorMode::FailFast, 1) => return Err(failures.remove(0)),
+        (ErrorMode::FailFast, n) => {
+            bail!("{} modules failed to load:\n{}", n, diagnostics.join("\n"))
+        }
+        (ErrorMode::Collect, _) => {}
+        (ErrorMode::Stub, _) => stub_declarations(&mut file.items),
+    }
+
+    Ok(InlinedCrate {
+        file,
+        files: if opts.record_files { loaded } else { vec![] },
+        diagnostics,
+    })
+}
+
+/// The crate rooted at `path` inlined by syn-inline-mod, with the modules it does not find next
+/// to their parent looked for in the search paths, along with the files loaded and the modules
+/// that failed
+fn load_from_disk(
+    path: &Path,
+    opts: &InlineOptions,
+) -> Result<(syn::File, Vec<PathBuf>, Vec<anyhow::Error>)> {
+    let mut loaded = vec![];
+    // load the file as AST
+    let inlined = InlinerBuilder::default().inline_with_callback(path, |path, _| {
+        let path = paths::normalize(path);
+        let bytes = fs::metadata(&path).map_or(0, |meta| meta.len());
+        log::debug!("Loaded {} ({} bytes)", path.display(), bytes);
+        loaded.push(path);
+    });
+    let (mut file, errors) = match inlined {
+        Ok(output) => output.into_output_and_errors(),
+        Err(syn_inline_mod::Error::Parse(err)) => {
+            return Err(ParseError::new(path, &err, vec![]).into());
+        }
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read the crate root {}", path.display()));
+        }
+    };
+
+    // failed modules are left as declarations, so the disabled ones can still be dropped
+    let mut disabled = match &opts.cfgs {
+        Some(cfgs) => cfg::drop_disabled_mod_decls(&mut file, cfgs),
+        None => vec![],
+    };
+    let root = paths::normalize(path);
+    let mut loader = Loader::new(opts);
+    if !opts.search_paths.is_empty() {
+        let scope = Scope::root(&root);
+        loader.search(&mut file.items, &scope);
+    }
+    loaded.append(&mut loader.files);
+    let mut failures = loader.failures;
+    for err in errors.into_iter() {
+        if loader.found.contains(&paths::normalize(err.path())) {
+            continue;
+        }
+        // each dropped declaration accounts for one error, as cfg-gated variants share a name
+        let name = err.module_name().to_string();
+        if let Some(idx) = disabled.iter().position(|disabled| *disabled == name) {
+            disabled.remove(idx);
+            log::info!(
+                "Skipped mod {} in {}, which is cfg'd out",
+                err.module_name(),
+                err.src_path().display()
+            );
+            continue;
+        }
+        let mut chain = include_chain(&paths::normalize(err.src_path()), &root, &loaded);
+        chain.push(format!("mod {}", name));
+        let failure = match err.kind() {
+            syn_inline_mod::Error::Parse(parse) => ParseError::new(err.path(), parse, chain).into(),
+            kind => anyhow!(
+                "Error when parsing {}, included by {} as mod {}: {}\n    included through {}",
+                err.path().display(),
+                err.src_path().display(),
+                err.module_name(),
+                kind,
+                chain.join(" -> ")
+            ),
+        };
+        failures.push(failure);
+    }
+    Ok((file, loaded, failures))
+}
+
+/// The crate rooted at `path` inlined by asking the source provider for each file before the
+/// disk, along with the files loaded from disk and the modules that failed
+fn load_with_sources(
+    path: &Path,
+    opts: &InlineOptions,
+) -> Result<(syn::File, Vec<PathBuf>, Vec<anyhow::Error>)> {
+    let root = paths::normalize(path);
+    let mut loader = Loader::new(opts);
+    let text = loader
+        .read(&root)
+        .with_context(|| format!("Failed to read the crate root {}", path.display()))?;
+    let mut file =
+        syn::parse_file(&text).map_err(|err| ParseError::in_text(&root, &text, &err, vec![]))?;
+    loader.drop_disabled(&mut file, &root);
+    loader.inline(&mut file.items, &Scope::root(&root));
+    Ok((file, loader.files, loader.failures))
+}
+
+/// Where the module declarations being inlined are
+#[derive(Debug, Clone)]
+struct Scope {
+    /// The file they are in
+    file: PathBuf,
+    /// Where their files are by the usual layout
+    dir: PathBuf,
+    /// What their `#[path]` is relative to
+    path_dir: PathBuf,
+    /// How the crate root reaches them, see [`include_chain`]
+    chain: Vec<String>,
+}
+
+impl Scope {
+    fn root(root: &Path) -> Self {
+        let dir = root.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+        Scope {
+            file: root.to_path_buf(),
+            dir: dir.clone(),
+            path_dir: dir,
+            chain: vec![root.display().to_string()],
+        }
+    }
+
+    /// The scope inside the inline module `name`
+    fn inline_module(&self, name: &str) -> Self {
+        Scope {
+            file: self.file.clone(),
+            dir: self.dir.join(name),
+            path_dir: self.dir.join(name),
+            chain: self.chain_to(name),
+        }
+    }
+
+    /// The scope of the file `file` of the module `name`, whose submodules are in `dir`
+    fn module_file(&self, name: &str, file: &Path, dir: PathBuf) -> Self {
+        Scope {
+            file: file.to_path_buf(),
+            dir,
+            path_dir: file.parent().unwrap_or_else(|| Path::new("")).to_path_buf(),
+            chain: self.chain_to(name),
+        }
+    }
+
+    fn chain_to(&self, name: &str) -> Vec<String> {
+        let mut chain = self.chain.clone();
+        chain.push(format!("mod {}", name));
+        chain
+    }
+}
+
+/// Loads modules through the source provider and the search paths
+struct Loader<'a> {
+    opts: &'a InlineOptions,
+    /// The files read from disk
+    files: Vec<PathBuf>,
+    failures: Vec<anyhow::Error>,
+    /// The files syn-inline-mod looked for of the modules found in the search paths instead
+    found: Vec<PathBuf>,
+}
+
+impl<'a> Loader<'a> {
+    fn new(opts: &'a InlineOptions) -> Self {
+        Loader {
+            opts,
+            files: vec![],
+            failures: vec![],
+            found: vec![],
+        }
+    }
+
+    /// The text of `path`, from the source provider if it has it
+    fn read(&mut self, path: &Path) -> Option<String> {
+        let path = paths::normalize(path);
+        if let Some(text) = self
+            .opts
+            .sources
+            .as_ref()
+            .and_then(|sources| sources.load(&path))
+        {
+            log::debug!("Loaded {} from the source provider", path.display());
+            return Some(text);
+        }
+        let text = fs::read_to_string(&path).ok()?;
+        log::debug!("Loaded {} ({} bytes)", path.display(), text.len());
+        self.files.push(path);
+        Some(text)
+    }
+
+    /// Drop the declarations in `file`, at `path`, the cfgs rule out
+    fn drop_disabled(&self, file: &mut syn::File, path: &Path) {
+        if let Some(cfgs) = &self.opts.cfgs {
+            for name in cfg::drop_disabled_mod_decls(file, cfgs) {
+                log::info!(
+                    "Skipped mod {} in {}, which is cfg'd out",
+                    name,
+                    path.display()
+                );
+            }
+        }
+    }
+
+    /// The first of `candidates` there is, with its text
+    fn first(&mut self, candidates: &[PathBuf]) -> Option<(PathBuf, String)> {
+        candidates
+            .iter()
+            .find_map(|candidate| Some((paths::normalize(candidate), self.read(candidate)?)))
+    }
+
+    /// The module `name` in the search paths, with its text and the directory of its submodules
+    fn from_search_paths(&mut self, name: &str) -> Option<(PathBuf, String, PathBuf)> {
+        let opts = self.opts;
+        for dir in &opts.search_paths {
+            if let Some((path, text)) = self.first(&layout(dir, name)) {
+                return Some((path, text, dir.join(name)));
+            }
+        }
+        None
+    }
+
+    /// Load every module declared in `items`, recording those that fail
+    fn inline(&mut self, items: &mut [syn::Item], scope: &Scope) {
+        for item in items {
+            let module = match item {
+                syn::Item::Mod(module) => module,
+                _ => continue,
+            };
+            let name = module.ident.unraw().to_string();
+            if let Some((_, content)) = &mut module.content {
+                self.inline(content, &scope.inline_module(&name));
+                continue;
+            }
+            let found = match path_attr(&module.attrs) {
+                Some(path) => {
+                    let path = scope.path_dir.join(path);
+                    let dir = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+                    self.first(&[path]).map(|(path, text)| (path, text, dir))
+                }
+                None => match self.first(&layout(&scope.dir, &name)) {
+                    Some((path, text)) => Some((path, text, scope.dir.join(&name))),
+                    None => self.from_search_paths(&name),
+                },
+            };
+            match found {
+                Some((path, text, dir)) => {
+                    self.fill(module, &text, &scope.module_file(&name, &path, dir));
+                }
+                None => self.failures.push(anyhow!(
+                    "Error when parsing {}, included by {} as mod {}: not found on disk, in the \
+                     search paths nor from the source provider\n    included through {}",
+                    layout(&scope.dir, &name)[0].display(),
+                    scope.file.display(),
+                    name,
+                    scope.chain_to(&name).join(" -> ")
+                )),
+            }
+        }
+    }
+
+    /// Load the modules that syn-inline-mod did not find from the search paths. Modules under a
+    /// `#[path]` are skipped, as where syn-inline-mod looked for them is not known.
+    fn search(&mut self, items: &mut [syn::Item], scope: &Scope) {
+        for item in items {
+            let module = match item {
+                syn::Item::Mod(module) if path_attr(&module.attrs).is_none() => module,
+                _ => continue,
+            };
+            let name = module.ident.unraw().to_string();
+            if let Some((_, content)) = &mut module.content {
+                self.search(content, &scope.inline_module(&name));
+                continue;
+            }
+            if let Some((path, text, dir)) = self.from_search_paths(&name) {
+                let looked_for = layout(&scope.dir, &name);
+                self.found
+                    .extend(looked_for.iter().map(|path| paths::normalize(path)));
+                self.fill(module, &text, &scope.module_file(&name, &path, dir));
+            }
+        }
+    }
+
+    /// Make `module` the inline module with the text of its file in `scope`, loading its own
+    /// modules. It is left as it is if the text does not parse.
+    fn fill(&mut self, module: &mut syn::ItemMod, text: &str, scope: &Scope) {
+        let mut file = match syn::parse_file(text) {
+            Ok(file) => file,
+            Err(err) => {
+                let chain = scope.chain.clone();
+                self.failures
+                    .push(ParseError::in_text(&scope.file, text, &err, chain).into());
+                return;
+            }
+        };
+        self.drop_disabled(&mut file, &scope.file);
+        self.inline(&mut file.items, scope);
+        module.attrs.extend(file.attrs);
+        module.content = Some((Default::default(), file.items));
+        module.semi = None;
+    }
+}
+
+/// Where the module `name` declared for `dir` can be by the usual layout
+fn layout(dir: &Path, name: &str) -> [PathBuf; 2] {
+    [
+        dir.join(format!("{}.rs", name)),
+        dir.join(name).join("mod.rs"),
+    ]
+}
+
+/// The value of a `#[path = "..."]` among `attrs`
+fn path_attr(attrs: &[syn::Attribute]) -> Option<String> {
+    attrs.iter().find_map(|attr| match attr.parse_meta() {
+        Ok(syn::Meta::NameValue(syn::MetaNameValue {
+            path,
+            lit: syn::Lit::Str(lit),
+            ..
+        })) if path.is_ident("path") => Some(lit.value()),
+        _ => None,
+    })
+}
+
+/// How `file` is reached from the crate root `root` through module declarations, as the first
+/// file followed by each module, e.g. `src/main.rs -> mod app`. This goes as far as can be told
+/// from the usual module file layout and the `loaded` files; modules found through `#[path]` end
+/// the chain early.
+fn include_chain(file: &Path, root: &Path, loaded: &[PathBuf]) -> Vec<String> {
+    let mut chain = vec![file.to_path_buf()];
+    let mut current = file.to_path_buf();
+    while current != root {
+        // both `a.rs` and `a/mod.rs` declare their submodules in `a`
+        let module_dir = if current.file_name().map_or(false, |name| name == "mod.rs") {
+            current.parent().map(Path::to_path_buf)
+        } else {
+            Some(current.with_extension(""))
+        };
+        let parent_dir = match module_dir.as_deref().and_then(Path::parent) {
+            Some(dir) => dir,
+            None => break,
+        };
+        let parent = if root.parent() == Some(parent_dir) {
+            Some(root.to_path_buf())
+        } else {
+            [parent_dir.join("mod.rs"), parent_dir.with_extension("rs")]
+                .into_iter()
+                .find(|candidate| loaded.contains(candidate))
+        };
+        match parent {
+            Some(parent) => {
+                chain.push(parent.clone());
+                current = parent;
+            }
+            None => break,
+        }
+    }
+    chain.reverse();
+    let modules = chain[1..].iter().map(|file| {
+        let module = match file.file_name() {
+            Some(name) if name == "mod.rs" => file.parent().and_then(Path::file_name),
+            _ => file.file_stem(),
+        };
+        format!("mod {}", module.unwrap_or_default().to_string_lossy())
+    });
+    Some(chain[0].display().to_string())
+        .into_iter()
+        .chain(modules)
+        .collect()
+}
+
+/// Turn the `mod name;` declarations left in `items`, those of modules that failed to load, into
+/// empty inline modules
+fn stub_declarations(items: &mut [syn::Item]) {
+    for item in items {
+        if let syn::Item::Mod(module) = item {
+            match &mut module.content {
+                Some((_, content)) => stub_declarations(content),
+                None => {
+                    module.content = Some((Default::default(), vec![]));
+                    module.semi = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn modules_are_inlined() {
+        let root = fixture("simple").join("src/lib.rs");
+        let krate = inline_crate(&root, &InlineOptions::new()).unwrap();
+        assert_eq!(
+            krate.files,
+            [root.clone(), fixture("simple").join("src/greeting.rs")]
+        );
+        assert!(krate.diagnostics.is_empty());
+        match &krate.file.items[0] {
+            syn::Item::Mod(module) => assert_eq!(module.content.as_ref().unwrap().1.len(), 2),
+            _ => panic!("Expected mod greeting first"),
+        }
+
+        let krate = inline_crate(&root, &InlineOptions::new().record_files(false)).unwrap();
+        assert!(krate.files.is_empty());
+    }
+
+    #[test]
+    fn missing_modules_follow_the_options() {
+        let root = fixture("cfg-mods").join("src/main.rs");
+        // freebsd_impl.rs does not exist
+        assert!(inline_crate(&root, &InlineOptions::new()).is_err());
+
+        let opts = InlineOptions::new().errors(ErrorMode::Collect);
+        let krate = inline_crate(&root, &opts).unwrap();
+        assert_eq!(krate.diagnostics.len(), 1);
+        assert!(krate.diagnostics[0].contains("as mod freebsd_impl"));
+
+        let krate = inline_crate(&root, &InlineOptions::new().errors(ErrorMode::Stub)).unwrap();
+        assert_eq!(krate.diagnostics.len(), 1);
+        let text = quote::ToTokens::to_token_stream(&krate.file).to_string();
+        assert!(text.contains("mod freebsd_impl { }"));
+
+        let linux = CfgSet::new().with_value("target_os", "linux");
+        let krate = inline_crate(&root, &InlineOptions::new().cfgs(linux)).unwrap();
+        assert!(krate.diagnostics.is_empty());
+        assert_eq!(krate.files.len(), 2);
+    }
+
+    #[test]
+    fn every_failure_is_reported_with_its_chain() {
+        let dir = std::env::temp_dir().join(format!("inline-failures-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/main.rs"), "mod a;\nmod b;\nfn main() {}\n").unwrap();
+        std::fs::write(dir.join("src/a.rs"), "mod c;\n").unwrap();
+
+        let err = inline_crate(&dir.join("src/main.rs"), &InlineOptions::new()).unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with("2 modules failed to load:\n"));
+        assert!(message.contains("as mod b"));
+        let main = dir.join("src/main.rs");
+        let chain = format!("{} -> mod a -> mod c", main.display());
+        assert!(message.contains(&format!("included through {}", chain)));
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_source() {
+        let dir = std::env::temp_dir().join(format!("inline-parse-error-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src/app")).unwrap();
+        std::fs::write(dir.join("src/main.rs"), "mod app;\nfn main() {}\n").unwrap();
+        std::fs::write(dir.join("src/app.rs"), "mod config;\n").unwrap();
+        std::fs::write(
+            dir.join("src/app/config.rs"),
+            "fn load() {\n    let x = ;\n}\n",
+        )
+        .unwrap();
+
+        let err = inline_crate(&dir.join("src/main.rs"), &InlineOptions::new()).unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert!(err.file.ends_with("src/app/config.rs"));
+        assert_eq!((err.line, err.column), (2, 13));
+        assert!(err.snippet.contains(">     2 |     let x = ;\n"));
+        let main = dir.join("src/main.rs").display().to_string();
+        assert_eq!(err.chain, [main.as_str(), "mod app", "mod config"]);
+        assert!(err.to_string().ends_with(" -> mod app -> mod config"));
+    }
+
+    #[test]
+    fn sources_come_from_the_provider_first() {
+        let root = PathBuf::from("/in-memory/src/main.rs");
+        let mut sources = BTreeMap::new();
+        sources.insert(root.clone(), "mod app;\nfn main() {}\n".to_string());
+        sources.insert(
+            "/in-memory/src/app.rs".into(),
+            "mod config;\nmod missing;\n".into(),
+        );
+        sources.insert(
+            "/in-memory/src/app/config.rs".into(),
+            "fn load() {}\n".into(),
+        );
+        let opts = InlineOptions::new()
+            .errors(ErrorMode::Collect)
+            .sources(Arc::new(sources));
+        let krate = inline_crate(&root, &opts).unwrap();
+        assert!(krate.files.is_empty());
+        assert_eq!(krate.diagnostics.len(), 1);
+        assert!(krate.diagnostics[0].contains("as mod missing"));
+        assert!(krate.diagnostics[0].ends_with("main.rs -> mod app -> mod missing"));
+        let text = quote::ToTokens::to_token_stream(&krate.file).to_string();
+        assert!(text.contains("mod app { mod config { fn load () { } } mod missing ; }"));
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Which Windows launchers to write next to the script, since shebangs do not work there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LauncherKind {
+    /// `<name>.cmd` for cmd.exe
+    Cmd,
+    /// `<name>.ps1` for PowerShell
+    Ps1,
+    Both,
+}
+
+/// The interpreter command line a shebang runs, e.g. `["rust-script", "--toolchain", "nightly"]`
+/// for `#!/usr/bin/env -S rust-script --toolchain nightly`. Absolute interpreter paths are
+/// reduced to their file name, as they are meaningless on Windows.
+pub fn interpreter_from_shebang(shebang: &str) -> Vec<String> {
+    let mut words = shebang
+        .trim_start_matches("#!")
+        .split_whitespace()
+        .peekable();
+    if words
+        .peek()
+        .map_or(false, |w| Path::new(w).ends_with("env"))
+    {
+        words.next();
+        while words.peek().map_or(false, |w| w.starts_with('-')) {
+            words.next();
+        }
+    }
+    words
+        .enumerate()
+        .map(|(i, w)| match Path::new(w).file_name() {
+            Some(name) if i == 0 => name.to_string_lossy().into_owned(),
+            _ => w.to_string(),
+        })
+        .collect()
+}
+
+/// Write the launchers of `kind` for `script` next to it, returning their paths.
+pub fn write_launchers(kind: LauncherKind, script: &Path, shebang: &str) -> Result<Vec<PathBuf>> {
+    let name = script
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Script path {} has no valid file name", script.display()))?;
+    let interpreter = interpreter_from_shebang(shebang);
+    if interpreter.is_empty() {
+        return Err(anyhow!(
+            "Can not derive an interpreter from shebang {}",
+            shebang
+        ));
+    }
+
+    let mut written = vec![];
+    let mut write = |ext: &str, content: String| -> Result<()> {
+        let path = script.with_extension(ext);
+        fs::write(&path, content)
+            .with_context(|| format!("Failed to write launcher {}", path.display()))?;
+        written.push(path);
+        Ok(())
+    };
+    if matches!(kind, LauncherKind::Cmd | LauncherKind::Both) {
+        write("cmd", cmd_launcher(&interpreter, name))?;
+    }
+    if matches!(kind, LauncherKind::Ps1 | LauncherKind::Both) {
+        write("ps1", ps1_launcher(&interpreter, name))?;
+    }
+    Ok(written)
+}
+
+/// Quote a word for cmd.exe, `%` has to be doubled even inside quotes in batch files
+fn cmd_quote(word: &str) -> String {
+    let word = word.replace('%', "%%");
+    if word.is_empty() || word.contains(|c: char| c.is_whitespace() || "&|<>^()\"".contains(c)) {
+        format!("\"{}\"", word.replace('"', "\"\""))
+    } else {
+        word
+    }
+}
+
+fn cmd_launcher(interpreter: &[String], script_name: &str) -> String {
+    let command: Vec<_> = interpreter.iter().map(|w| cmd_quote(w)).collect();
+    format!(
+        "@{} \"%~dp0{}\" %*\r\n",
+        command.join(" "),
+        script_name.replace('%', "%%")
+    )
+}
+
+/// Quote a word as a PowerShell single quoted string
+fn ps1_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "''"))
+}
+
+fn ps1_launcher(interpreter: &[String], script_name: &str) -> String {
+    let command: Vec<_> = interpreter.iter().map(|w| ps1_quote(w)).collect();
+    format!(
+        "& {} (Join-Path $PSScriptRoot {}) @args\r\nexit $LASTEXITCODE\r\n",
+        command.join(" "),
+        ps1_quote(script_name)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpreter_from_env_shebang() {
+        assert_eq!(
+            interpreter_from_shebang("#!/usr/bin/env -S rust-script --toolchain nightly"),
+            ["rust-script", "--toolchain", "nightly"]
+        );
+        assert_eq!(
+            interpreter_from_shebang("#!/opt/bin/rust-script"),
+            ["rust-script"]
+        );
+    }
+
+    #[test]
+    fn cmd_quotes_spaces_and_percent() {
+        let interpreter = vec!["C:\\Program Files\\rust-script.exe".to_string()];
+        assert_eq!(
+            cmd_launcher(&interpreter, "my tool 100%.rs"),
+            "@\"C:\\Program Files\\rust-script.exe\" \"%~dp0my tool 100%%.rs\" %*\r\n"
+        );
+    }
+
+    #[test]
+    fn ps1_quotes_spaces_and_quotes() {
+        let interpreter = vec!["rust-script".to_string()];
+        assert_eq!(
+            ps1_launcher(&interpreter, "bob's tool.rs"),
+            "& 'rust-script' (Join-Path $PSScriptRoot 'bob''s tool.rs') @args\r\nexit $LASTEXITCODE\r\n"
+        );
+    }
+}
@@ -1,41 +1,202 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
+use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "parallel")]
+use std::sync::Mutex;
+#[cfg(feature = "parallel")]
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[allow(unused_imports)]
 use anyhow::{anyhow, bail, Result, Context};
 use cargo_toml::Manifest;
 use quote::quote;
+use serde::Serialize;
 use syn::parse::Parser;
-use syn_inline_mod::InlinerBuilder;
 
+mod attrs;
+mod cache;
+mod cfg;
+mod clippy;
+mod config;
+mod deps;
+mod diagnostic;
+mod diff;
+mod edition;
+mod features;
+mod flavor;
+mod globs;
+mod graph;
+mod include;
+mod init;
+mod inline;
+mod launcher;
+mod manifest;
+mod order;
+mod pass;
+mod paths;
+mod pkg_env;
+mod pretty;
 mod print;
-use print::SynFilePrint;
+mod provenance;
+mod prune;
+mod resolve;
+mod rewrite;
+mod shebang;
+mod srcmap;
+mod stamp;
+mod stats;
+mod template;
+mod testcode;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+mod unbundle;
+mod verbatim;
+mod verify;
+mod warning;
+mod watch;
+use attrs::RootAttrs;
+pub use cache::SharedCrates;
+use cache::{Cache, ProcessedCrate};
+pub use cfg::CfgSet;
+pub use clippy::ClippyMode;
+pub use config::{ConfigFile, Settings};
+pub use deps::DependencyTrim;
+pub use diagnostic::ParseError;
+pub use diff::{diff, diff_str, BundleDiff, ItemChange, ManifestChange, TokenEdit};
+pub use edition::Edition;
+use features::FeatureSelection;
+pub use flavor::{ExistingManifest, ManifestStyle, OutputFlavor, TargetTool};
+use globs::PackageFiles;
+pub use graph::GraphFormat;
+use graph::{DepsGraph, GraphCrate, GraphInput};
+pub use init::{plan_init, InitEdit, InitPlan};
+pub use inline::{inline_crate, ErrorMode, InlineOptions, InlinedCrate, SourceProvider};
+pub use launcher::LauncherKind;
+pub use order::{ItemOrder, Section};
+use pass::{BuiltinOptions, Pipeline};
+pub use pass::{Pass, PassContext, PassOutcome, PassPosition, PassReport};
+pub use paths::ContainmentPolicy;
+use pkg_env::PkgEnv;
+pub use pretty::Formatting;
+pub use print::{FilePrinter, SynFilePrint};
+pub use provenance::Provenance;
+pub use resolve::Resolution;
+use rewrite::CrateModule;
+pub use rewrite::{CollisionPolicy, ReexportMode};
+pub use shebang::ShebangStyle;
+use srcmap::SourceMap;
+use stamp::Stamp;
+pub use stamp::{check_freshness, Freshness};
+use stats::Phases;
+pub use stats::{BundleStats, CrateStats, StrippedItems};
+use template::Template;
+pub use testcode::Tests;
+pub use unbundle::{unbundle, SOURCE_MARKER};
+pub use verify::{RunExpectation, Verify};
+pub use warning::{Warning, WarningKind};
+// the document `Bundler::map_manifest` edits
+pub use toml_edit;
 
-fn inline_module(path: &Path) -> Result<syn::File> {
-    // load the file as AST
-    let (ast, errors) = InlinerBuilder::default()
-        .parse_and_inline_modules(path)
-        .with_context(|| format!("Failed to parse and inline modules at {}", path.display()))?
-        .into_output_and_errors();
+/// Turn the whole crate `name` into `pub mod module { ... }`, keeping its inner attributes on the
+/// module. Attributes that only work at the crate root are returned, to be hoisted with
+/// [`RootAttrs`].
+fn modulize_crate(
+    name: &str,
+    module: &str,
+    file: syn::File,
+) -> Result<(syn::ItemMod, Vec<syn::Attribute>)> {
+    let ident = syn::parse_str::<syn::Ident>(module)
+        .with_context(|| format!("{} is not a valid module name for crate {}", module, name))?;
+    let (hoisted, attrs): (Vec<_>, Vec<_>) = file.attrs.into_iter().partition(attrs::is_root_only);
+    let lib = syn::ItemMod {
+        attrs,
+        vis: syn::parse_quote!(pub),
+        mod_token: Default::default(),
+        ident,
+        content: Some((Default::default(), file.items)),
+        semi: None,
+    };
+    Ok((lib, hoisted))
+}
 
-    for err in errors.into_iter() {
+/// Wrap the modulized crates `libs` in the container module `name`
+fn container_module(name: &str, libs: Vec<syn::ItemMod>) -> Result<syn::ItemMod> {
+    let ident = syn::parse_str::<syn::Ident>(name)
+        .with_context(|| format!("{} is not a valid container module name", name))?;
+    if let Some(lib) = libs.iter().find(|lib| lib.ident == ident) {
         bail!(
-            "Error when parsing {}, included by {} as mod {}: {}",
-            err.path().display(),
-            err.src_path().display(),
-            err.module_name(),
-            err.kind()
+            "Inlined crate {} has the same name as its container module",
+            lib.ident
         );
     }
-
-    Ok(ast)
+    // textually scoped macros only reach the binary if every enclosing module has #[macro_use]
+    let macro_use = libs
+        .iter()
+        .any(|lib| lib.attrs.iter().any(|a| a.path.is_ident("macro_use")))
+        .then(|| -> syn::Attribute { syn::parse_quote!(#[macro_use]) });
+    let items = libs.into_iter().map(syn::Item::Mod);
+    Ok(syn::parse_quote! {
+        /// Crates inlined by rust-script-bundler, do not edit by hand.
+        #[allow(dead_code, unused_imports, unused_macros)]
+        #macro_use
+        pub mod #ident {
+            #(#items)*
+        }
+    })
 }
 
-fn modulize_crate(name: &str, file: syn::File) -> Result<syn::ItemMod> {
-    todo!()
+/// The module each of `crates` becomes, after giving those rooted at a path in `at` their module
+/// and then applying `renames`
+fn crate_modules(
+    crates: &[(String, PathBuf)],
+    at: &[(PathBuf, String)],
+    renames: &[(String, String)],
+) -> Result<Vec<CrateModule>> {
+    let mut modules: Vec<_> = crates
+        .iter()
+        .map(|(name, _)| CrateModule {
+            name: name.clone(),
+            module: name.clone(),
+        })
+        .collect();
+    let mut renamed = vec![false; modules.len()];
+    for (root, module) in at {
+        let root = paths::normalize(root);
+        let idx = crates
+            .iter()
+            .zip(&renamed)
+            .position(|((_, path), renamed)| !renamed && paths::normalize(path) == root)
+            .ok_or_else(|| anyhow!("No inlined crate at {} to name {}", root.display(), module))?;
+        modules[idx].module = module.clone();
+        renamed[idx] = true;
+    }
+    for (name, module) in renames {
+        let idx = modules
+            .iter()
+            .zip(&renamed)
+            .position(|(m, renamed)| !renamed && m.name == *name)
+            .ok_or_else(|| anyhow!("No inlined crate {} left to rename to {}", name, module))?;
+        modules[idx].module = module.clone();
+        renamed[idx] = true;
+    }
+
+    let mut seen = BTreeSet::new();
+    for m in &modules {
+        if !seen.insert(&m.module) {
+            bail!(
+                "Several inlined crates would become module {}, use Bundler::with_crate_at_as or Bundler::with_rename to tell them apart",
+                m.module
+            );
+        }
+    }
+    Ok(modules)
 }
 
 fn new_manifest_comment(content: &str) -> Vec<syn::Attribute> {
@@ -54,25 +215,591 @@ fn new_manifest_comment(content: &str) -> Vec<syn::Attribute> {
     attr
 }
 
-/// make the file a little readable
-fn format_file(path: &Path) -> Result<()> {
-    let status = Command::new("rustfmt")
-        .arg(path)
-        .stdin(Stdio::null())
-        .status()?;
-    if !status.success() {
-        bail!("Failed to run rustfmt on {}", path.display());
+/// Check the crate `name`, of the `declared` edition, against the `target` edition of the bundle.
+/// Mismatches and what may break are added to `warnings`, what will break to `errors`.
+fn check_edition(
+    file: &syn::File,
+    name: &str,
+    declared: Edition,
+    target: Edition,
+    warnings: &mut Vec<Warning>,
+    errors: &mut Vec<String>,
+) {
+    let mut found = vec![];
+    if declared != target {
+        found.push(format!(
+            "Crate {} declares edition {}, but the bundle is for {}",
+            name, declared, target
+        ));
+    }
+    let findings = edition::check(file, name, declared, target);
+    found.extend(findings.warnings);
+    for warning in found {
+        log::warn!("{}", warning);
+        warnings.push(Warning::new(WarningKind::Edition, warning));
+    }
+    errors.extend(findings.errors);
+}
+
+/// Warn about the module file `file` left out for the package's include/exclude lists
+fn excluded_warning(file: &Path, root: &Path) -> Warning {
+    let relative = paths::relative_to(file, root);
+    let warning = format!(
+        "Skipped module file {}, which the package's include/exclude lists leave out",
+        relative.display()
+    );
+    log::warn!("{}", warning);
+    Warning::new(WarningKind::ExcludedModule, warning).at(relative, None)
+}
+
+/// Short hash of the commit checked out at `dir`, `unknown` outside of a git checkout
+fn git_hash(dir: &Path) -> String {
+    Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--short", "HEAD"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map_or_else(
+            || "unknown".into(),
+            |output| String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        )
+}
+
+/// Tells apart the temporary files of scripts written at the same time
+static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Write `text` to `target` through a temporary file next to it that is renamed over it, so that
+/// readers never see a half written script, making it executable first if asked to
+fn write_atomically(target: &Path, text: &str, executable: bool) -> Result<()> {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = target.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    let write = || -> Result<()> {
+        fs::write(&tmp, text)?;
+        if executable {
+            set_executable(&tmp)?;
+        }
+        fs::rename(&tmp, target)?;
+        Ok(())
+    };
+    let written = write();
+    if written.is_err() {
+        let _ignore = fs::remove_file(&tmp);
+    }
+    written.with_context(|| format!("Failed to write {}", target.display()))
+}
+
+/// Set the permissions of `path` to `rwxr-xr-x`
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("Failed to make {} executable", path.display()))
+}
+
+/// Files have no executable bit outside of Unix
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Refuse to write `target` if it is one of `inputs` or inside the `src/` of the crate at
+/// `manifest_dir`, or if it exists but does not look generated.
+fn check_target(target: &Path, inputs: &[PathBuf], manifest_dir: &Path) -> Result<()> {
+    let canonical = match target.canonicalize() {
+        Ok(canonical) => canonical,
+        // nothing there yet
+        Err(_) => return Ok(()),
+    };
+    let is_input = inputs.iter().any(|input| {
+        input
+            .canonicalize()
+            .map_or(false, |input| input == canonical)
+    });
+    let in_src = manifest_dir
+        .join("src")
+        .canonicalize()
+        .map_or(false, |src| canonical.starts_with(src));
+    if is_input || in_src {
+        bail!(
+            "Refusing to overwrite {}, which is a source of the crate being bundled, use allow_overwrite to force",
+            target.display()
+        );
+    }
+
+    let existing = fs::read_to_string(&canonical).unwrap_or_default();
+    let generated = existing.starts_with("#!")
+        || existing.contains("```cargo")
+        || existing.contains("@generated");
+    if !generated {
+        bail!(
+            "Refusing to overwrite {}, which does not look like a generated script, use allow_overwrite to force",
+            target.display()
+        );
     }
     Ok(())
 }
 
+/// The rustfmt to run: `RUSTFMT` if set, like cargo does, else the one rustup would run, else
+/// whatever is on the `PATH`
+fn rustfmt_command() -> PathBuf {
+    if let Some(rustfmt) = env::var_os("RUSTFMT") {
+        return rustfmt.into();
+    }
+    let rustup = Command::new("rustup")
+        .args(["which", "rustfmt"])
+        .stdin(Stdio::null())
+        .output();
+    match rustup {
+        Ok(output) if output.status.success() => {
+            let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if path.is_empty() {
+                "rustfmt".into()
+            } else {
+                path.into()
+            }
+        }
+        _ => "rustfmt".into(),
+    }
+}
+
+/// make `text`, the script `name`, a little readable with `rustfmt`, pointing any errors at the
+/// original sources with `map`. A missing rustfmt is only warned about, in `warnings`, unless
+/// `required`.
+fn format_text(
+    rustfmt: &Path,
+    text: &str,
+    name: &str,
+    map: &SourceMap,
+    edition: &str,
+    required: bool,
+    warnings: &mut Vec<Warning>,
+) -> Result<String> {
+    let child = Command::new(rustfmt)
+        .arg("--edition")
+        .arg(edition)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) if err.kind() == io::ErrorKind::NotFound && !required => {
+            let warning = format!(
+                "{} not found, leaving {} unformatted",
+                rustfmt.display(),
+                name
+            );
+            log::warn!("{}", warning);
+            warnings.push(Warning::new(WarningKind::Formatting, warning));
+            return Ok(text.into());
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to run {}", rustfmt.display()))
+        }
+    };
+    // rustfmt reads all of its input before writing anything, and a failure to pass it all is
+    // better explained by what rustfmt says
+    let written = child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(text.as_bytes());
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let complaint = format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stderr),
+            String::from_utf8_lossy(&output.stdout)
+        );
+        bail!(
+            "Failed to run {} on {}:\n{}",
+            rustfmt.display(),
+            name,
+            map.translate(complaint.trim_end(), "<stdin>", text)
+        );
+    }
+    written.with_context(|| format!("Failed to pass {} to {}", name, rustfmt.display()))?;
+    String::from_utf8(output.stdout).context("rustfmt wrote invalid UTF-8")
+}
+
+/// Where the binary crate root comes from
+enum Binary {
+    /// Explicit path to the crate root
+    Path(PathBuf),
+    /// Named bin target of the package
+    Target(String),
+    /// The bin target `cargo run` would pick
+    Default,
+    /// Named example target of the package
+    Example(String),
+}
+
+/// Where the license put at the top of the bundle comes from
+enum LicenseHeader {
+    Text(String),
+    File(PathBuf),
+    /// the `license-file` of the package
+    Manifest,
+}
+
+/// What a call to [`Bundler::bundle`] produced. Serializes to JSON for tools to keep track of,
+/// see [`BundleReport::stats`].
+#[derive(Debug, Clone, Serialize)]
+pub struct BundleReport {
+    /// The generated script
+    pub output: PathBuf,
+    /// Every file written, i.e. the script followed by any launchers
+    pub outputs: Vec<PathBuf>,
+    /// Whether the script was written, rather than left alone because it already had the exact
+    /// content, which keeps its modification time
+    pub written: bool,
+    /// Every file read to produce the script, including the manifest and those of crates whose
+    /// dependencies were merged
+    pub inputs: Vec<PathBuf>,
+    /// Link opening the script on the playground, for [`OutputFlavor::Playground`] if it is
+    /// short enough
+    pub playground_url: Option<String>,
+    /// Inlined crates whose module was renamed by [`CollisionPolicy::AutoRename`], as crate name
+    /// and module name. These change what the bundle's modules are called.
+    pub renamed: Vec<(String, String)>,
+    /// The structure of the bundle, if asked for with [`Bundler::emit_deps_graph`]
+    pub deps_graph: Option<String>,
+    /// Every pass run over the bundle, in order, see [`Bundler::with_pass`]
+    pub passes: Vec<PassReport>,
+    /// What clippy reported with [`ClippyMode::Report`], pointed at the original sources
+    pub clippy_diagnostics: Vec<String>,
+    /// Lints allowed in the bundle because they fired, see [`Bundler::clippy_auto_allow`]
+    pub auto_allowed: Vec<String>,
+    /// Dependencies kept in and dropped from the manifest, with
+    /// [`Bundler::trim_dependencies`]
+    pub deps_trim: Option<DependencyTrim>,
+    /// Problems that did not stop bundling, like modules left empty with [`Bundler::lenient`],
+    /// see [`BundleReport::emit_cargo_warnings`]
+    pub warnings: Vec<Warning>,
+    /// What went into the script and how long it took, to follow the size of the bundle
+    pub stats: BundleStats,
+}
+
+/// The outcome of [`Bundler::check`]
+#[derive(Debug, Clone)]
+pub struct CheckReport {
+    /// Every file that would be read to produce the script, including the manifest
+    pub inputs: Vec<PathBuf>,
+    /// Problems that do not stop bundling
+    pub warnings: Vec<Warning>,
+    /// Size in bytes of the script before formatting
+    pub output_size: usize,
+    /// Every pass run over the bundle, in order, see [`Bundler::with_pass`]
+    pub passes: Vec<PassReport>,
+}
+
+/// A script rendered in memory, before formatting
+struct Rendered {
+    /// the script, unless it was printed to a stream instead, see [`Bundler::render_run`]
+    text: String,
+    inputs: Vec<PathBuf>,
+    warnings: Vec<Warning>,
+    /// edition of the bundle
+    edition: String,
+    source_map: SourceMap,
+    /// see [`BundleReport::renamed`]
+    renamed: Vec<(String, String)>,
+    deps_trim: Option<DependencyTrim>,
+    /// what to build the structure graph from, once the script is formatted
+    graph: Option<GraphInput>,
+    passes: Vec<PassReport>,
+    /// everything but the phases, which go on once the script is rendered
+    stats: BundleStats,
+    phases: Phases,
+}
+
+impl BundleReport {
+    /// Print `cargo:rerun-if-changed` for every input, so a build script reruns when any changes.
+    pub fn emit_rerun_if_changed(&self) {
+        for input in &self.inputs {
+            println!("cargo:rerun-if-changed={}", input.display());
+        }
+    }
+
+    /// Print every warning as `cargo:warning` lines, so cargo shows them when a build script
+    /// bundles.
+    pub fn emit_cargo_warnings(&self) {
+        for warning in &self.warnings {
+            print!("{}", warning.cargo_directives());
+        }
+    }
+
+    /// Make the absolute path of the script available to the crate being built as the
+    /// environment variable `name`, and rerun the build script whenever an input changes. This
+    /// lets the crate embed the script with `include_str!(env!("TOOL_BUNDLE"))` for a `name` of
+    /// `TOOL_BUNDLE`. `examples/export_env.rs` runs a build script doing so, and reads the
+    /// variable back the way cargo does.
+    pub fn export_env(&self, name: &str) -> Result<()> {
+        print!("{}", self.env_directives(name)?);
+        Ok(())
+    }
+
+    /// Write a makedep style depfile to `path`, making the script depend on every input like
+    /// [`BundleReport::emit_rerun_if_changed`] does, for build systems like Ninja or make.
+    pub fn write_depfile(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.depfile()?)
+            .with_context(|| format!("Failed to write depfile {}", path.display()))
+    }
+
+    fn depfile(&self) -> Result<String> {
+        let escape = |path: &Path| -> Result<String> {
+            let text = path.to_string_lossy();
+            if text.contains(['\n', '\r']) {
+                bail!("Path {} can not be written to a depfile", path.display());
+            }
+            Ok(text
+                .replace(' ', "\\ ")
+                .replace('#', "\\#")
+                .replace('$', "$$"))
+        };
+        let mut depfile = format!("{}:", escape(&self.output)?);
+        for input in &self.inputs {
+            depfile.push_str(" \\\n  ");
+            depfile.push_str(&escape(input)?);
+        }
+        depfile.push('\n');
+        Ok(depfile)
+    }
+
+    fn env_directives(&self, name: &str) -> Result<String> {
+        let valid = name
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            bail!("{:?} is not a valid environment variable name", name);
+        }
+        let output = fs::canonicalize(&self.output)
+            .with_context(|| format!("Failed to resolve {}", self.output.display()))?;
+        // cargo reads directives line by line
+        let cargo_path = |path: &Path| -> Result<String> {
+            match path.to_str() {
+                Some(p) if !p.contains(['\n', '\r']) => Ok(p.to_string()),
+                _ => bail!("Path {} can not be passed to cargo", path.display()),
+            }
+        };
+        let mut directives = format!("cargo:rustc-env={}={}\n", name, cargo_path(&output)?);
+        for input in &self.inputs {
+            directives.push_str(&format!("cargo:rerun-if-changed={}\n", cargo_path(input)?));
+        }
+        Ok(directives)
+    }
+}
+
+/// What one bundle adds to the settings of the [`Bundler`] making it, so that a single
+/// [`Bundler`] can make several bundles
+struct Run<'a> {
+    /// the binary to bundle, the one the [`Bundler`] was told to unless [`Bundler::bundle_all`]
+    /// picks it
+    binary: &'a Binary,
+    /// inner attributes [`Bundler::clippy_auto_allow`] added
+    inner_attrs: Vec<String>,
+    /// crates processed by earlier bundles of [`Bundler::bundle_all`], by root and options
+    shared: Option<&'a SharedCrates>,
+}
+
+impl<'a> Run<'a> {
+    fn new(binary: &'a Binary) -> Self {
+        Run {
+            binary,
+            inner_attrs: vec![],
+            shared: None,
+        }
+    }
+}
+
+/// What preparing each inlined crate needs besides the crate, see [`Bundler::prepare_crate`]
+struct CrateJob<'a> {
+    /// the package being bundled
+    package: &'a resolve::Package,
+    /// the binary being bundled
+    binary: &'a Binary,
+    root: &'a Path,
+    cache: Option<&'a Cache>,
+    shared: Option<&'a SharedCrates>,
+    /// the package's edition and that of the bundle, to check the crates against
+    editions: Option<(Edition, Edition)>,
+    /// the passes to run on each crate before printing it, if crates are streamed
+    passes: Option<(&'a PassContext, &'a BuiltinOptions)>,
+}
+
+/// An inlined crate ready to be arranged into the bundle, see [`Bundler::prepare_crate`]
+struct PreparedCrate {
+    lib: order::Lib,
+    /// root-only attributes to hoist to the bundle root
+    hoisted: Vec<syn::Attribute>,
+    facts: CrateFacts,
+}
+
+/// What the bundle needs to know about an inlined crate, besides its syntax
+struct CrateFacts {
+    name: String,
+    stats: CrateStats,
+    sources: Vec<PathBuf>,
+    references: BTreeSet<String>,
+    warnings: Vec<Warning>,
+    cfg_stripped: usize,
+    /// its package, if features were resolved for it
+    featured: Option<(String, resolve::Package)>,
+    edition_errors: Vec<String>,
+    /// the reports of the passes and the printed crate, if streamed
+    streamed: Option<(Vec<PassReport>, String)>,
+}
+
+/// A [`PreparedCrate`] with its syntax printed, which unlike syntax trees can be sent to another
+/// thread
+#[cfg(feature = "parallel")]
+struct SentCrate {
+    /// see [`order::Lib::into_parts`]
+    lib: (String, BTreeSet<String>),
+    hoisted: Vec<String>,
+    facts: CrateFacts,
+}
+
+#[cfg(feature = "parallel")]
+impl PreparedCrate {
+    fn send(self) -> SentCrate {
+        SentCrate {
+            lib: self.lib.into_parts(),
+            hoisted: self
+                .hoisted
+                .iter()
+                .map(|attr| quote!(#attr).to_string())
+                .collect(),
+            facts: self.facts,
+        }
+    }
+
+    /// The streamed crate `sent`, printed as fragment `idx`, back
+    fn receive(sent: SentCrate, idx: usize) -> Result<Self> {
+        let (module, textual_macros) = sent.lib;
+        let lib = order::Lib::from_parts(&module, textual_macros, vec![print::placeholder(idx)])?;
+        let mut hoisted = vec![];
+        for attr in &sent.hoisted {
+            hoisted.extend(syn::Attribute::parse_inner.parse_str(attr)?);
+        }
+        Ok(PreparedCrate {
+            lib,
+            hoisted,
+            facts: sent.facts,
+        })
+    }
+}
+
+/// An edit of the embedded manifest, see [`Bundler::map_manifest`]
+type ManifestMap = Box<dyn Fn(&mut toml_edit::Document) -> Result<()> + Send + Sync>;
+
 pub struct Bundler {
-    binary_path: PathBuf,
+    binary: Binary,
     crates: Vec<(String, PathBuf)>,
+    /// crate name to module name, applied in order to the first crate of that name not yet renamed
+    renames: Vec<(String, String)>,
+    /// crate root to module name, see [`Bundler::with_crate_at_as`]
+    modules_at: Vec<(PathBuf, String)>,
+    /// container module for all inlined crates
+    nest: Option<String>,
+    collisions: CollisionPolicy,
+    /// format of the structure graph to produce, and whether it shows modules
+    deps_graph: Option<(GraphFormat, bool)>,
+    /// features to inline crates with, by crate name
+    crate_features: BTreeMap<String, FeatureSelection>,
+    /// features to build the package with, instead of whatever the script is run with
+    features: Option<FeatureSelection>,
+    all_features: bool,
+    /// crates whose dependencies go into the bundle's manifest
+    merged_manifests: Vec<String>,
+    /// inline path dependencies, recursively
+    path_deps: bool,
+    /// fail on code only passed through verbatim
+    strict_verbatim: bool,
+    inline_includes: bool,
+    include_size_limit: u64,
+    lenient: bool,
+    /// asked for module files before the disk, see [`Bundler::source_provider`]
+    sources: Option<Arc<dyn SourceProvider>>,
+    module_search_paths: Vec<PathBuf>,
+    preserve_comments: bool,
+    minify: bool,
+    /// write the target even if it looks like a source file
+    allow_overwrite: bool,
+    flavor: OutputFlavor,
+    manifest_style: ManifestStyle,
+    existing_manifest: ExistingManifest,
+    /// leave the binary's own shebang alone
+    keep_shebang: bool,
+    executable: Option<bool>,
+    containment: ContainmentPolicy,
+    /// where to cache processed crates across runs
+    cache_dir: Option<PathBuf>,
+    /// leave out module files the package's include/exclude lists do not ship
+    package_globs: bool,
+    tests: Tests,
+    prune_unused: bool,
+    pipeline: Pipeline,
+    /// see [`Bundler::map_manifest`]
+    manifest_maps: Vec<ManifestMap>,
+    clippy: ClippyMode,
+    clippy_args: Vec<String>,
+    clippy_auto_allow: bool,
+    embed_inputs_hash: bool,
+    formatting: Formatting,
+    formatting_required: bool,
+    /// the rustfmt to run instead of looking for one, see [`rustfmt_command`]
+    rustfmt: Option<PathBuf>,
+    /// comment lines after the manifest, defaults depend on the flavor
+    header: Option<Template>,
+    /// comment lines before the manifest
+    header_comment: Option<Template>,
+    license: Option<LicenseHeader>,
+    provenance: Option<Provenance>,
+    /// comment lines at the end, the vim modeline unless replaced or removed
+    footer: Option<Template>,
+    /// arguments to run the written script with, and how it has to behave
+    verify_run: Option<(Vec<String>, RunExpectation)>,
+    verify: Verify,
+    /// parse the script back before writing it
+    self_check: bool,
+    /// whether to inline the package's own lib target
+    with_lib: bool,
 
     manifest: Manifest,
     /// also save content for later writing
     manifest_str: String,
+    manifest_dir: PathBuf,
+    resolution: Resolution,
+    reexport: ReexportMode,
+    item_order: ItemOrder,
+    /// extra inner attributes for the bundle root, as source text
+    inner_attrs: Vec<String>,
+    merge_lint_attrs: bool,
+    launcher: Option<LauncherKind>,
+    shebang: ShebangStyle,
+    /// cfgs to resolve target specific dependency tables with
+    target_cfgs: Option<CfgSet>,
+    /// edition of the bundle, instead of the package's
+    edition: Option<Edition>,
+    /// print each crate before parsing the next
+    stream_crates: bool,
+    /// drop dependencies the bundle does not refer to from its manifest
+    trim_deps: bool,
+    /// remove what only makes sense for a package from the manifest
+    scrub_manifest: bool,
 
     out_dir: PathBuf,
 }
@@ -85,6 +812,27 @@ impl Bundler {
         Self::new_with_dir(binary, out_dir, manifest_dir)
     }
 
+    /// Like [`Bundler::new`], for a binary picked from the manifest with [`Bundler::with_bin`]
+    /// rather than by path. Until then the binary is `src/main.rs`.
+    ///
+    /// ```ignore
+    /// // build.rs
+    /// Bundler::new_from_manifest()?.with_bin("tool").bundle(Path::new("tool.rs"))?;
+    /// ```
+    pub fn new_from_manifest() -> Result<Self> {
+        Self::new("src/main.rs")
+    }
+
+    /// Like [`Bundler::new_with_dir`] without an out dir, for [`Bundler::bundle_to_string`] and
+    /// [`Bundler::bundle_to_writer`]. [`Bundler::bundle`] then writes relative to the current
+    /// directory.
+    pub fn new_in_memory(
+        binary: impl AsRef<Path>,
+        manifest_dir: impl Into<PathBuf>,
+    ) -> Result<Self> {
+        Self::new_with_dir(binary, PathBuf::new(), manifest_dir)
+    }
+
     pub fn new_with_dir(
         binary: impl AsRef<Path>,
         out_dir: impl Into<PathBuf>,
@@ -95,95 +843,3957 @@ impl Bundler {
         let manifest_path = manifest_dir.join("Cargo.toml");
         let manifest_str = fs::read_to_string(&manifest_path)
             .with_context(|| format!("Failed to read manifest at {}", &manifest_path.display()))?;
+        let manifest_str = manifest::inherit_workspace(&manifest_str, &manifest_dir)?;
         let mut manifest = Manifest::from_str(&manifest_str)?;
         manifest.complete_from_path(&manifest_path)?;
 
         Ok(Bundler {
-            binary_path: manifest_dir.join(binary.as_ref()),
+            binary: Binary::Path(manifest_dir.join(binary.as_ref())),
             crates: Default::default(),
+            renames: Default::default(),
+            modules_at: Default::default(),
+            nest: None,
+            collisions: Default::default(),
+            deps_graph: None,
+            crate_features: Default::default(),
+            features: None,
+            all_features: false,
+            merged_manifests: vec![],
+            path_deps: false,
+            strict_verbatim: false,
+            inline_includes: true,
+            include_size_limit: include::DEFAULT_SIZE_LIMIT,
+            lenient: false,
+            sources: None,
+            module_search_paths: vec![],
+            preserve_comments: false,
+            minify: false,
+            allow_overwrite: false,
+            flavor: Default::default(),
+            manifest_style: Default::default(),
+            existing_manifest: Default::default(),
+            keep_shebang: false,
+            executable: None,
+            containment: Default::default(),
+            cache_dir: None,
+            package_globs: false,
+            tests: Tests::default(),
+            prune_unused: false,
+            pipeline: Default::default(),
+            manifest_maps: vec![],
+            clippy: Default::default(),
+            clippy_args: vec![],
+            clippy_auto_allow: false,
+            embed_inputs_hash: false,
+            formatting: Formatting::default(),
+            formatting_required: false,
+            rustfmt: None,
+            header: None,
+            header_comment: None,
+            license: None,
+            provenance: None,
+            footer: Some(Template::literal(template::DEFAULT_FOOTER)),
+            verify_run: None,
+            verify: Verify::default(),
+            self_check: true,
+            with_lib: false,
 
             manifest,
             manifest_str,
+            manifest_dir,
+            resolution: Default::default(),
+            reexport: Default::default(),
+            item_order: Default::default(),
+            inner_attrs: Default::default(),
+            merge_lint_attrs: false,
+            launcher: None,
+            shebang: ShebangStyle::default(),
+            target_cfgs: None,
+            edition: None,
+            stream_crates: true,
+            trim_deps: false,
+            scrub_manifest: true,
 
             out_dir: out_dir.into(),
         })
     }
 
+    /// Choose how targets and dependencies are resolved. Defaults to [`Resolution::CargoToml`],
+    /// which does not need cargo on PATH. It applies to the package, the inlined crates and the
+    /// path dependencies found with [`Bundler::with_path_deps`], so choose it before
+    /// [`Bundler::with_crate_manifest`].
+    pub fn resolution(mut self, resolution: Resolution) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// Choose how the binary's references to inlined crates are kept resolving. Defaults to
+    /// [`ReexportMode::Rewrite`].
+    pub fn reexport_mode(mut self, reexport: ReexportMode) -> Self {
+        self.reexport = reexport;
+        self
+    }
+
+    /// Choose where the inlined crates go relative to the binary's items. Defaults to
+    /// [`ItemOrder::LibsFirst`], after the binary's imports and before its other items.
+    pub fn item_order(mut self, order: ItemOrder) -> Self {
+        self.item_order = order;
+        self
+    }
+
+    /// Add an inner attribute like `#![allow(dead_code)]` to the bundle root.
+    pub fn with_inner_attr(mut self, attr: impl Into<String>) -> Self {
+        self.inner_attrs.push(attr.into());
+        self
+    }
+
+    /// Merge lint attributes of the same level at the bundle root, e.g. `#![allow(a)]` and
+    /// `#![allow(b)]` into `#![allow(a, b)]`. Exact duplicates are always removed.
+    pub fn merge_lint_attrs(mut self, merge: bool) -> Self {
+        self.merge_lint_attrs = merge;
+        self
+    }
+
+    /// Also write Windows launchers next to the script, running the interpreter from the shebang.
+    pub fn windows_launcher(mut self, kind: LauncherKind) -> Self {
+        self.launcher = Some(kind);
+        self
+    }
+
+    /// How the script runs rust-script, see [`ShebangStyle`]. Fails for a `Direct` interpreter
+    /// that is not an absolute path.
+    pub fn shebang_style(mut self, style: ShebangStyle) -> Result<Self> {
+        style.validate()?;
+        self.shebang = style;
+        Ok(self)
+    }
+
+    /// Start the script with the line `line` instead of the default shebang, see
+    /// [`ShebangStyle::Custom`]. Fails unless it is a single line starting with `#!`.
+    pub fn shebang(self, line: impl Into<String>) -> Result<Self> {
+        self.shebang_style(ShebangStyle::Custom(line.into()))
+    }
+
+    /// Write no shebang, so the script starts with its manifest.
+    pub fn no_shebang(mut self) -> Self {
+        self.shebang = ShebangStyle::Omitted;
+        self
+    }
+
+    /// Resolve `[target.'cfg(...)'.dependencies]` tables in the embedded manifest with `cfgs`,
+    /// e.g. [`CfgSet::host`]: tables for false predicates are dropped, those for true ones are
+    /// merged into the plain `[dependencies]`. Tables for undecided predicates and target triples
+    /// are kept as they are, which rust-script handles fine.
+    pub fn resolve_target_cfgs(mut self, cfgs: CfgSet) -> Self {
+        self.target_cfgs = Some(cfgs);
+        self
+    }
+
+    /// Use the package's bin target `name` as the binary, instead of the path given at construction.
+    /// Its path comes from the `[[bin]]` table, or the `src/bin/<name>.rs` and
+    /// `src/bin/<name>/main.rs` conventions without one. Bundling fails for unknown names.
+    pub fn with_bin(mut self, name: impl Into<String>) -> Self {
+        self.binary = Binary::Target(name.into());
+        self
+    }
+
+    /// Use the package's example target `name` as the binary, instead of the path given at
+    /// construction. Its path comes from the `[[example]]` table, or the `examples/<name>.rs` and
+    /// `examples/<name>/main.rs` conventions without one, and the features it requires are
+    /// selected on top of [`Bundler::features`]. Examples use the package's lib, which
+    /// [`Bundler::with_lib`] inlines. Bundling fails for unknown names.
+    pub fn with_example(mut self, name: impl Into<String>) -> Self {
+        self.binary = Binary::Example(name.into());
+        self
+    }
+
+    /// Pick the binary from the manifest the way `cargo run` does, instead of the path given at
+    /// construction: the `default-run` bin target, the only bin target, or `src/main.rs`.
+    /// Bundling fails if that leaves several candidates, listing them.
+    pub fn default_bin(mut self) -> Self {
+        self.binary = Binary::Default;
+        self
+    }
+
+    /// Names of all bin targets of the package.
+    pub fn bin_names(&self) -> Result<Vec<String>> {
+        let package =
+            resolve::resolve_package(self.resolution, &self.manifest, &self.manifest_dir)?;
+        Ok(package.bins.into_iter().map(|bin| bin.name).collect())
+    }
+
+    /// Also inline the package's own lib target. The target is looked up at bundle time using the
+    /// configured [`Resolution`], following cargo's conventions for what the manifest leaves out:
+    /// `src/lib.rs`, named after the package. Bundling fails if there is none.
     pub fn with_lib(mut self) -> Self {
-        if let Some((name, path)) = self
-            .manifest
-            .lib
-            .as_ref()
-            .and_then(|lib| lib.name.as_ref().zip(lib.path.as_ref()))
-        {
-            self.crates.push((name.into(), path.into()));
-        }
+        self.with_lib = true;
         self
     }
 
+    /// Inline the crate `name` whose root module is the file `root`. Crates are emitted in the
+    /// order they are added, after the package's own lib (see [`Bundler::with_lib`]) and before
+    /// the path dependencies found with [`Bundler::with_path_deps`], so the same builder calls
+    /// always give the same bundle.
     pub fn with_crate_at(mut self, name: impl Into<String>, root: impl Into<PathBuf>) -> Self {
         self.crates.push((name.into(), root.into()));
         self
     }
 
-    /// Expand a binary rs file to `target`, which is relative to `OUT_DIR`.
-    /// Also write a rust-script compatible header and vim file type footer.
-    pub fn bundle(self, target: &Path) -> Result<PathBuf> {
-        let target = self.out_dir.join(target);
-        if let Some(p) = target.parent() {
-            fs::create_dir_all(p).context("failed to create out dir")?;
-        }
+    /// Like [`Bundler::with_crate_at`], emitting the crate as `mod module` rather than a module
+    /// named after the crate, e.g. when the binary has a module of that name already. The
+    /// binary's references to the crate are mapped to the new module, as with
+    /// [`Bundler::with_rename`], which only ever applies to other crates.
+    pub fn with_crate_at_as(
+        mut self,
+        name: impl Into<String>,
+        root: impl Into<PathBuf>,
+        module: impl Into<String>,
+    ) -> Self {
+        let root = root.into();
+        self.modules_at.push((root.clone(), module.into()));
+        self.with_crate_at(name, root)
+    }
 
-        // parse the binary
-        let mut binary = inline_module(&self.binary_path)?;
+    /// Inline the lib of the package in `crate_dir` as the crate `name`, like
+    /// [`Bundler::with_crate_at`], and merge the dependencies its manifest declares into the
+    /// bundle's manifest. A dependency the bundle already has must have the same requirement, and
+    /// gets the union of the features. Optional dependencies are only merged if the features the
+    /// crate is inlined with turn them on.
+    pub fn with_crate_manifest(
+        self,
+        name: impl Into<String>,
+        crate_dir: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let crate_dir = crate_dir.as_ref();
+        let manifest_path = crate_dir.join("Cargo.toml");
+        let text = fs::read_to_string(&manifest_path)
+            .with_context(|| format!("Failed to read manifest at {}", manifest_path.display()))?;
+        let text = manifest::inherit_workspace(&text, crate_dir)?;
+        let mut manifest = Manifest::from_str(&text)
+            .with_context(|| format!("Invalid manifest at {}", manifest_path.display()))?;
+        manifest.complete_from_path(&manifest_path)?;
+        let package = resolve::resolve_package(self.resolution, &manifest, crate_dir)?;
+        let lib = package.lib.ok_or_else(|| {
+            anyhow!(
+                "Package {} at {} has no lib target",
+                package.name,
+                crate_dir.display()
+            )
+        })?;
+        let name = name.into();
+        let mut bundler = self.with_crate_at(name.clone(), lib.path);
+        bundler.merged_manifests.push(name);
+        Ok(bundler)
+    }
 
-        // parse any crate, also modulize them
-        let libs = self
-            .crates
-            .into_iter()
-            .map(|(name, path)| {
-                let lib = inline_module(&path)?;
-                let lib = modulize_crate(&name, lib)?;
-                Ok(lib)
-            })
-            .collect::<Result<Vec<_>>>()?;
+    /// Inline the libs of the package's path dependencies, and of their path dependencies in
+    /// turn, each once however many crates depend on it. Their other dependencies are merged
+    /// into the bundle's manifest as with [`Bundler::with_crate_manifest`], and the entries of
+    /// the inlined crates removed. Crates added explicitly are not added again.
+    ///
+    /// Path dependencies for a specific target, like `[target.'cfg(windows)'.dependencies]`,
+    /// that also have a version are not inlined, but depended on from the registry in the same
+    /// target table of the bundle's manifest.
+    pub fn with_path_deps(mut self) -> Self {
+        self.path_deps = true;
+        self
+    }
 
-        // add libs to binary
-        binary.items.extend(libs.into_iter().map(Into::into));
+    /// Inline the crate `name` with `features` enabled, on top of its default features unless
+    /// turned off with [`Bundler::with_crate_default_features`]. `#[cfg(feature = ...)]` in the
+    /// crate is then resolved against its own features, which the bundle's manifest knows
+    /// nothing about.
+    ///
+    /// Without a selection, crates the package depends on by path are inlined with the features
+    /// that dependency declaration asks for.
+    pub fn with_crate_features(mut self, name: impl Into<String>, features: &[&str]) -> Self {
+        self.crate_features
+            .entry(name.into())
+            .or_default()
+            .features
+            .extend(features.iter().map(|f| f.to_string()));
+        self
+    }
 
-        // add rust-script shebang
-        binary.shebang = Some("#!/usr/bin/env -S rust-script".into());
-        // add doc attribute for cargo manifest, make sure we add to the head
-        let _: Vec<_> = binary
-            .attrs
-            .splice(..0, new_manifest_comment(&self.manifest_str))
-            .collect();
+    /// Whether to enable the default features of the inlined crate `name`, see
+    /// [`Bundler::with_crate_features`].
+    pub fn with_crate_default_features(mut self, name: impl Into<String>, enabled: bool) -> Self {
+        self.crate_features
+            .entry(name.into())
+            .or_default()
+            .default_features = enabled;
+        self
+    }
 
-        // print the file
-        {
-            let mut bundle = fs::File::create(&target)?;
+    /// Build the bundle with the package's `features` enabled, on top of its default features
+    /// unless turned off with [`Bundler::no_default_features`]. `#[cfg(feature = ...)]` in the
+    /// binary and the package's lib is resolved against them, and the embedded manifest fixed to
+    /// them: optional dependencies left off are dropped, the others become plain dependencies
+    /// with the features enabled on them, and `[features]` is removed.
+    ///
+    /// Without a selection, the manifest is embedded with its features, and rust-script builds
+    /// the script with the default ones.
+    pub fn features(mut self, features: &[&str]) -> Self {
+        self.features
+            .get_or_insert_with(Default::default)
+            .features
+            .extend(features.iter().map(|f| f.to_string()));
+        self
+    }
 
-            writeln!(bundle, "{}", binary.print())?;
+    /// Build the bundle with every feature of the package enabled, see [`Bundler::features`].
+    pub fn all_features(mut self) -> Self {
+        self.all_features = true;
+        self
+    }
 
-            // write the footer
-            writeln!(bundle, "// vim: ft=rust syntax=rust")?;
-        }
+    /// Build the bundle without the default features of the package, see
+    /// [`Bundler::features`].
+    pub fn no_default_features(mut self) -> Self {
+        self.features
+            .get_or_insert_with(Default::default)
+            .default_features = false;
+        self
+    }
 
-        // make it readable
-        format_file(&target)?;
+    /// Fail instead of warning on code syn can not parse into a syntax tree, which is bundled
+    /// verbatim without any of the rewriting the code around it gets.
+    pub fn strict_verbatim(mut self, strict: bool) -> Self {
+        self.strict_verbatim = strict;
+        self
+    }
 
-        Ok(target)
+    /// Whether to resolve `include_str!`, `include_bytes!` and `include!` with a literal relative
+    /// path, which would otherwise be relative to the bundle: the string and bytes become
+    /// literals, and the included code is spliced in. Includes that can not be resolved, like
+    /// `include!(concat!(env!("OUT_DIR"), ...))`, are warned about. On by default.
+    pub fn inline_includes(mut self, inline: bool) -> Self {
+        self.inline_includes = inline;
+        self
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Leave included files larger than `bytes` as includes, with a warning. 1 MiB by default.
+    pub fn include_size_limit(mut self, bytes: u64) -> Self {
+        self.include_size_limit = bytes;
+        self
+    }
 
-    #[test]
-    fn new_manifest_comment_works() {
-        let attrs = new_manifest_comment("abc\n  def");
+    /// Bundle even if some modules fail to load, replacing each by an empty module and adding a
+    /// warning to [`BundleReport::warnings`], to look at what a partially broken tree bundles
+    /// to. Otherwise bundling fails with every module that failed to load. Off by default.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
 
-        dbg!(attrs);
+    /// Ask `provider` for the files of the binary, the crates inlined with it and their path
+    /// dependencies before reading them from disk, e.g. for modules a build script generates, or
+    /// to bundle a package kept in memory. Files it provides are not
+    /// [inputs](BundleReport::inputs), and the [cache](Bundler::cache_dir) is not used.
+    pub fn source_provider(mut self, provider: impl SourceProvider + 'static) -> Self {
+        self.sources = Some(Arc::new(provider));
+        self
+    }
+
+    /// Look for a module whose file is not next to its parent in `dir` as well, as
+    /// `dir/<name>.rs` or `dir/<name>/mod.rs`, e.g. the `OUT_DIR` a build script generates
+    /// modules into. Directories are tried in the order they were added.
+    pub fn add_module_search_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.module_search_paths.push(dir.into());
+        self
+    }
+
+    /// Copy the items no step changed from the original sources, which keeps their plain `//`
+    /// and `/* */` comments and those between them, rather than printing every item from the
+    /// syntax tree. Items the bundler rewrote, like paths into inlined crates, lose their
+    /// comments still. [`Formatting::PrettyPlease`] would drop the comments, so it is skipped
+    /// with this; [`Formatting::Rustfmt`] keeps them. This bypasses the cache, which does not
+    /// keep comments. Off by default.
+    pub fn preserve_comments(mut self, preserve: bool) -> Self {
+        self.preserve_comments = preserve;
+        self
+    }
+
+    /// Make the bundle smaller at the cost of readability: drop doc comments, lint attributes
+    /// like `#[allow(...)]` and `#[deprecated]`, the blank lines between items and the footer.
+    /// The shebang and the manifest stay, as do doc attributes that change the item, like
+    /// `#[doc(hidden)]`. Implies [`Formatting::None`] and turns off
+    /// [`Bundler::preserve_comments`]. Off by default.
+    pub fn minify(mut self, minify: bool) -> Self {
+        self.minify = minify;
+        self
+    }
+
+    /// Write the bundle even if the target is one of the crate's sources, or an existing file
+    /// that was not generated by a bundler.
+    pub fn allow_overwrite(mut self, allow: bool) -> Self {
+        self.allow_overwrite = allow;
+        self
+    }
+
+    /// Choose what kind of script to produce. Defaults to [`OutputFlavor::RustScript`].
+    pub fn output_flavor(mut self, flavor: OutputFlavor) -> Self {
+        self.flavor = flavor;
+        self
+    }
+
+    /// Choose how a rust-script bundle embeds its manifest. Defaults to
+    /// [`ManifestStyle::DocComment`]. Bundles with [`ManifestStyle::Frontmatter`] can not use
+    /// [`ShebangStyle::PolyglotTrampoline`], as the frontmatter has to come right after the
+    /// shebang.
+    pub fn manifest_style(mut self, style: ManifestStyle) -> Self {
+        self.manifest_style = style;
+        self
+    }
+
+    /// Choose what happens to a ```` ```cargo ```` block in the binary's own doc comment, as
+    /// binaries that started out as rust-script files have. It is always taken out, so the
+    /// bundle has a single manifest, and the rest of the doc comment follows the generated one.
+    /// Defaults to [`ExistingManifest::Replace`].
+    pub fn existing_manifest(mut self, existing: ExistingManifest) -> Self {
+        self.existing_manifest = existing;
+        self
+    }
+
+    /// Keep the shebang the binary starts with, if any, instead of writing the one of
+    /// [`Bundler::shebang_style`]. Off by default.
+    pub fn keep_shebang(mut self, keep: bool) -> Self {
+        self.keep_shebang = keep;
+        self
+    }
+
+    /// Make the written script executable, setting its permissions to `rwxr-xr-x`. Defaults to
+    /// whether the script starts with a shebang. Only Unix has the permission, elsewhere this
+    /// does nothing.
+    pub fn executable(mut self, executable: bool) -> Self {
+        self.executable = Some(executable);
+        self
+    }
+
+    /// Set the shebang, manifest style and footer the tool meant to run the bundle expects, see
+    /// [`TargetTool`]. Later calls to [`Bundler::shebang_style`], [`Bundler::manifest_style`]
+    /// and the footer methods change these again.
+    pub fn target_tool(mut self, tool: TargetTool) -> Self {
+        self.shebang = tool.shebang();
+        self.manifest_style = tool.manifest_style();
+        self.footer = tool
+            .modeline()
+            .then(|| Template::literal(template::DEFAULT_FOOTER));
+        self
+    }
+
+    /// Emit `template` as `//` comment lines between the manifest and the code, replacing the
+    /// default header. Templates can use the variables `{version}`, `{bin_name}`, `{date}`,
+    /// `{git_hash}` and `{inputs_hash}`; using any other is an error.
+    pub fn with_header_template(mut self, template: &str) -> Result<Self> {
+        self.header = Some(Template::parse(template)?);
+        Ok(self)
+    }
+
+    /// Emit `template` as `//` comment lines at the end, replacing the vim modeline. See
+    /// [`Bundler::with_header_template`] for the variables.
+    pub fn with_footer_template(mut self, template: &str) -> Result<Self> {
+        self.footer = Some(Template::parse(template)?);
+        Ok(self)
+    }
+
+    /// Emit `footer` as is as `//` comment lines at the end, one per line, in place of the vim
+    /// modeline. `None` leaves the footer out.
+    pub fn footer(mut self, footer: Option<String>) -> Self {
+        self.footer = footer.map(Template::literal);
+        self
+    }
+
+    /// Emit `comment` as is as `//` comment lines right after the shebang, one per line. Unlike
+    /// the header, this comes before the manifest, so it does not end up in the crate docs. `None`,
+    /// the default, emits nothing.
+    pub fn header_comment(mut self, comment: Option<String>) -> Self {
+        self.header_comment = comment.map(Template::literal);
+        self
+    }
+
+    /// Emit the license `text` as `//` comment lines right after the shebang, before the header
+    /// comment and the manifest, for scripts handed out on their own. Each line of the text
+    /// becomes one comment line, as it is.
+    pub fn license_header(mut self, text: impl Into<String>) -> Self {
+        self.license = Some(LicenseHeader::Text(text.into()));
+        self
+    }
+
+    /// Like [`Bundler::license_header`], with the text of the file at `path`, relative to the
+    /// manifest directory. The file is read when bundling and counts as an input.
+    pub fn license_header_from_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.license = Some(LicenseHeader::File(path.into()));
+        self
+    }
+
+    /// Like [`Bundler::license_header_from_file`], with the `license-file` of the package.
+    /// Bundling fails if the manifest has none.
+    pub fn license_header_from_manifest(mut self) -> Self {
+        self.license = Some(LicenseHeader::Manifest);
+        self
+    }
+
+    /// Record where the bundle comes from, as a comment line right after the shebang and, if
+    /// `provenance` asks for them, as consts the script can use. The package and its version come
+    /// from the manifest, and the commit from git, which is left out without a checkout. `None`,
+    /// the default, records nothing.
+    pub fn provenance(mut self, provenance: Option<Provenance>) -> Self {
+        self.provenance = provenance;
+        self
+    }
+
+    /// Drop modules whose files the package's `include`/`exclude` lists leave out, the way
+    /// `cargo package` would, along with their `mod` declarations. Skipped files are warned about.
+    pub fn respect_package_globs(mut self, respect: bool) -> Self {
+        self.package_globs = respect;
+        self
+    }
+
+    /// What to do with the test code of the binary and the inlined crates, [`Tests::Keep`] by
+    /// default. Stripping removes `#[cfg(test)]` items, `#[test]` functions and
+    /// `cfg_attr(test, ...)` attributes, along with private imports only the removed code used.
+    /// Kept tests have their `crate::` paths rewritten like any other code, so they run with
+    /// `rust-script --test`, given their dev-dependencies with [`Tests::KeepAndMergeDevDeps`].
+    pub fn tests(mut self, tests: Tests) -> Self {
+        self.tests = tests;
+        self
+    }
+
+    /// [`Tests::Strip`] if `strip`, [`Tests::Keep`] otherwise, see [`Bundler::tests`]
+    pub fn strip_tests(self, strip: bool) -> Self {
+        self.tests(if strip { Tests::Strip } else { Tests::Keep })
+    }
+
+    /// Drop the items of the inlined crates the bundle never refers to, which can make bundles of
+    /// scripts using a small part of a large library a lot smaller. The analysis goes by names
+    /// and errs on the side of keeping: an item is kept if anything kept mentions its name, and
+    /// impls stay with their types. The inlined crates are then processed together with the
+    /// binary rather than one at a time, see [`Bundler::stream_crates`]. Off by default.
+    pub fn prune_unused(mut self, prune: bool) -> Self {
+        self.prune_unused = prune;
+        self
+    }
+
+    /// Run `pass` over the bundle at `position` in the pipeline. The built-in passes run in this
+    /// order, which `position` can refer to by name:
+    /// - `strip-tests`, if enabled with [`Bundler::tests`];
+    /// - `rewrite-paths`, keeping references to the inlined crates resolving, see
+    ///   [`ReexportMode`];
+    /// - `prune-unused`, if enabled with [`Bundler::prune_unused`];
+    /// - `dedupe-uses`, dropping repeated identical imports;
+    /// - `dedupe-attrs`, see [`Bundler::merge_lint_attrs`];
+    /// - `minify`, if enabled with [`Bundler::minify`].
+    ///
+    /// Passes run after the crates are inlined next to the binary's items and before the
+    /// manifest is added. Each pass is timed and listed in the reports.
+    pub fn with_pass(mut self, position: PassPosition, pass: impl Pass + 'static) -> Self {
+        self.pipeline.insert(position, Box::new(pass));
+        self
+    }
+
+    /// Skip the pass called `name`, built-in or not
+    pub fn disable_pass(mut self, name: impl Into<String>) -> Self {
+        self.pipeline.disable(name.into());
+        self
+    }
+
+    /// Cache the inlined and processed crates in `dir`, so unchanged crates are not inlined
+    /// again on the next run. Entries are invalidated by changes to any file a crate was read
+    /// from, to the options for the crate and to the bundler version.
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// [`Bundler::cache_dir`] under the target directory, `$CARGO_TARGET_DIR` or `target` next to
+    /// the manifest, or `$OUT_DIR` when running in a build script.
+    pub fn with_default_cache(self) -> Self {
+        let base = env::var_os("OUT_DIR")
+            .or_else(|| env::var_os("CARGO_TARGET_DIR"))
+            .map_or_else(|| self.manifest_dir.join("target"), PathBuf::from);
+        self.cache_dir(base.join("rust-script-bundler-cache"))
+    }
+
+    /// Choose where module files pulled in through `#[path]` may live. Defaults to
+    /// [`ContainmentPolicy::Anywhere`].
+    pub fn containment(mut self, policy: ContainmentPolicy) -> Self {
+        self.containment = policy;
+        self
+    }
+
+    /// Run the written script with `args`, e.g. a self-test flag, and fail the bundle unless it
+    /// behaves as `expected`. Needs rust-script, or cargo and the dependencies for a temporary
+    /// project.
+    pub fn verify_run(mut self, args: &[&str], expected: RunExpectation) -> Self {
+        let args = args.iter().map(|a| a.to_string()).collect();
+        self.verify_run = Some((args, expected));
+        self
+    }
+
+    /// Make sure the written script compiles, failing the bundle with the compiler's output
+    /// otherwise, see [`Verify`]. Off by default, as building the dependencies may need network
+    /// access.
+    pub fn verify(mut self, verify: Verify) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Do not parse the script back before writing it. By default a script that does not parse
+    /// fails the bundle, pointing at where the printer went wrong, rather than whoever runs it.
+    pub fn skip_self_check(mut self) -> Self {
+        self.self_check = false;
+        self
+    }
+
+    /// Run `cargo clippy` over the written bundle, in a temporary cargo project like
+    /// [`Bundler::verify_run`] uses. Diagnostics are pointed at the original sources.
+    pub fn clippy_check(mut self, mode: ClippyMode) -> Self {
+        self.clippy = mode;
+        self
+    }
+
+    /// Arguments for clippy's lint settings, e.g. `["-W", "clippy::pedantic"]`.
+    pub fn clippy_args(mut self, args: &[&str]) -> Self {
+        self.clippy_args = args.iter().map(|a| a.to_string()).collect();
+        self
+    }
+
+    /// With [`ClippyMode::Report`], allow every lint that fired with `#![allow(...)]` at the
+    /// bundle root, so the written bundle passes clippy cleanly. The source crates are left
+    /// alone, and the allowed lints are listed in [`BundleReport::auto_allowed`].
+    pub fn clippy_auto_allow(mut self, auto_allow: bool) -> Self {
+        self.clippy_auto_allow = auto_allow;
+        self
+    }
+
+    /// Embed the bundle's inputs and a SHA-256 over them in its header, so
+    /// [`check_freshness`] can later tell whether it is stale without bundling again. The hash
+    /// is defined to stay the same across releases, see [`Stamp`](stamp::Stamp).
+    pub fn embed_inputs_hash(mut self, embed: bool) -> Self {
+        self.embed_inputs_hash = embed;
+        self
+    }
+
+    /// How to make the written script readable, see [`Formatting`]. Defaults to
+    /// [`Formatting::PrettyPlease`], which unlike rustfmt needs nothing installed, but has the
+    /// whole script parsed at once even with [`Bundler::stream_crates`].
+    pub fn formatting(mut self, formatting: Formatting) -> Self {
+        self.formatting = formatting;
+        self
+    }
+
+    /// Whether bundling with [`Formatting::Rustfmt`] fails when rustfmt can not be found. Off by
+    /// default, which leaves the script unformatted with a warning. rustfmt is taken from the
+    /// `RUSTFMT` environment variable, else from rustup, else from the `PATH`.
+    pub fn formatting_required(mut self, required: bool) -> Self {
+        self.formatting_required = required;
+        self
+    }
+
+    /// Run `rustfmt` for [`Formatting::Rustfmt`], whatever the environment says
+    #[cfg(test)]
+    pub(crate) fn rustfmt(mut self, rustfmt: impl Into<PathBuf>) -> Self {
+        self.rustfmt = Some(rustfmt.into());
+        self
+    }
+
+    /// Produce a bundle for `edition` rather than the package's own. The embedded manifest and
+    /// rustfmt use it, and the binary and inlined crates are checked for syntax the edition does
+    /// not accept, see [`Edition`]. Bundling fails on what the edition rejects, while crates
+    /// declaring another edition and code that may change meaning are only warned about.
+    pub fn edition(mut self, edition: Edition) -> Self {
+        self.edition = Some(edition);
+        self
+    }
+
+    /// Whether to process the inlined crates one at a time, printing each before parsing the
+    /// next, which keeps at most one crate's syntax tree in memory. On by default. Passes added
+    /// with [`Bundler::with_pass`] get the whole bundle at once, so they turn it off. With the
+    /// `parallel` feature, streamed crates are prepared on several threads, which leaves the
+    /// bundle the same.
+    pub fn stream_crates(mut self, stream: bool) -> Self {
+        self.stream_crates = stream;
+        self
+    }
+
+    /// Drop the dependencies neither the binary nor the inlined crates refer to from the
+    /// bundle's manifest, so each bin of a package only pulls in what it uses. What is kept and
+    /// dropped is in [`BundleReport::deps_trim`]. References are found by name, so a
+    /// dependency whose library is named differently from its key in the manifest is dropped.
+    pub fn trim_dependencies(mut self, trim: bool) -> Self {
+        self.trim_deps = trim;
+        self
+    }
+
+    /// Edit the manifest embedded in the script with `map`, for what the bundler has no option
+    /// for, e.g. adding `[profile.release]` or pinning a dependency to a git revision for the
+    /// script only. The document keeps the formatting of the manifest, and what `map` leaves in
+    /// it is what the script gets. Failing fails bundling.
+    ///
+    /// Maps run in the order they were added, after everything else done to the manifest:
+    /// [scrubbing](Bundler::scrub_manifest), dropping optional dependencies for the
+    /// [selected features](Bundler::features), merging the dependencies of inlined crates,
+    /// embedding path and workspace dependencies, resolving
+    /// [target cfgs](Bundler::resolve_target_cfgs), setting the [edition](Bundler::edition) and
+    /// [trimming dependencies](Bundler::trim_dependencies). The document is that of the
+    /// re-exported [`toml_edit`].
+    pub fn map_manifest(
+        mut self,
+        map: impl Fn(&mut toml_edit::Document) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.manifest_maps.push(Box::new(map));
+        self
+    }
+
+    /// Whether to remove what only makes sense for a package from the embedded manifest: targets
+    /// like `[lib]` and `[[bin]]`, dev and build dependencies, `[workspace]`, and all of
+    /// `[package]` but its name, version and edition. On by default.
+    pub fn scrub_manifest(mut self, scrub: bool) -> Self {
+        self.scrub_manifest = scrub;
+        self
+    }
+
+    /// Place all inlined crates in the container module `name`, e.g. `__bundled`, instead of at the
+    /// bundle root, so they can not collide with the binary's own items. References to the crates
+    /// then go through `crate::<name>::<crate>`.
+    ///
+    /// The container is a single crate as far as [`ItemOrder`] is concerned, so place it with
+    /// [`Section::Libs`] or [`Section::Lib`] with the container's name.
+    pub fn nest_under(mut self, name: impl Into<String>) -> Self {
+        self.nest = Some(name.into());
+        self
+    }
+
+    /// Emit the inlined crate `crate_name` as `mod module_name` instead of a module named after
+    /// the crate. The binary's references to the crate are mapped to the new module.
+    ///
+    /// Each call renames the first crate of that name not renamed by an earlier call, in the
+    /// order crates were added with the package's own lib (see [`Bundler::with_lib`]) first. This
+    /// allows inlining two crates of the same name, though the binary can then only refer to one
+    /// of them.
+    pub fn with_rename(
+        mut self,
+        crate_name: impl Into<String>,
+        module_name: impl Into<String>,
+    ) -> Self {
+        self.renames.push((crate_name.into(), module_name.into()));
+        self
+    }
+
+    /// What to do when an inlined crate would become a module with the name of an item the
+    /// binary already has at its root. [`CollisionPolicy::Error`] by default; renames
+    /// [`CollisionPolicy::AutoRename`] applies are logged and listed in
+    /// [`BundleReport::renamed`].
+    pub fn collision_policy(mut self, policy: CollisionPolicy) -> Self {
+        self.collisions = policy;
+        self
+    }
+
+    /// Also produce a graph of the bundle's structure in [`BundleReport::deps_graph`]: the
+    /// binary, inlined crates, registry dependencies, and each inline module if `modules`, with
+    /// what inlines, depends on and references what. Nodes are sized by the lines they
+    /// contribute, and the output is deterministic so it can be committed next to the bundle.
+    pub fn emit_deps_graph(mut self, format: GraphFormat, modules: bool) -> Self {
+        self.deps_graph = Some((format, modules));
+        self
+    }
+
+    /// Bundle the binary into a string, with nothing written. This is [`Bundler::bundle`] up to
+    /// formatting: no launchers, no clippy or verify run, and no inputs hash, which is relative
+    /// to where the script is written.
+    pub fn bundle_to_string(&self) -> Result<String> {
+        let rendered = self.render(None)?;
+        self.format(&rendered, "the bundle", &mut vec![])
+    }
+
+    /// Like [`Bundler::bundle_to_string`], writing the script to `w`. With [`Formatting::None`],
+    /// or [`Bundler::minify`], and [`Bundler::skip_self_check`], the script is printed to `w` as
+    /// it goes. Otherwise it is formatted or parsed back first, so the whole script is buffered
+    /// before any of it is written.
+    pub fn bundle_to_writer(&self, mut w: impl io::Write) -> Result<()> {
+        let unformatted = self.minify || self.formatting == Formatting::None;
+        if unformatted && !self.self_check {
+            let mut w = io::BufWriter::new(w);
+            self.render_run(None, &Run::new(&self.binary), Some(&mut w))?;
+            w.flush()?;
+            return Ok(());
+        }
+        w.write_all(self.bundle_to_string()?.as_bytes())?;
+        Ok(())
+    }
+
+    /// The text of the script to write, formatted as configured. `name` is how messages refer to
+    /// it, and formatting left undone is added to `warnings`.
+    fn format(
+        &self,
+        rendered: &Rendered,
+        name: &str,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<String> {
+        log::info!("Formatting {} with {:?}", name, self.formatting);
+        let text = match self.formatting {
+            _ if self.minify => rendered.text.clone(),
+            // prettyplease prints from the syntax tree, which has no comments
+            Formatting::PrettyPlease if self.preserves_comments() => rendered.text.clone(),
+            Formatting::PrettyPlease => pretty::format(&rendered.text, warnings)?,
+            Formatting::Rustfmt => format_text(
+                &self.rustfmt.clone().unwrap_or_else(rustfmt_command),
+                &rendered.text,
+                name,
+                &rendered.source_map,
+                &rendered.edition,
+                self.formatting_required,
+                warnings,
+            )?,
+            Formatting::None => rendered.text.clone(),
+        };
+        if self.self_check {
+            verify::self_check(&text, name)?;
+        }
+        Ok(text)
+    }
+
+    /// Write the rendered script to `target`, unless it is there already, and make it executable
+    /// if it should be, see [`Bundler::executable`]. Returns whether it was written.
+    fn write_script(&self, target: &Path, rendered: &mut Rendered) -> Result<bool> {
+        let mut warnings = vec![];
+        let text = self.format(rendered, &target.display().to_string(), &mut warnings)?;
+        rendered.warnings.extend(warnings);
+        rendered.phases.end("format");
+        rendered.stats.bytes = text.len();
+        rendered.stats.lines = text.lines().count();
+        let shebang = text.starts_with("#!") && !text.starts_with("#![");
+        let executable = self.executable.unwrap_or(shebang);
+        let written = if fs::read(target).map_or(false, |existing| existing == text.as_bytes()) {
+            log::info!("{} is up to date", target.display());
+            // it may have been written before scripts were made executable
+            if executable {
+                set_executable(target)?;
+            }
+            false
+        } else {
+            write_atomically(target, &text, executable)?;
+            true
+        };
+        rendered.phases.end("write");
+        Ok(written)
+    }
+
+    /// Expand a binary rs file to `target`, which is relative to `OUT_DIR`.
+    /// Also write a rust-script compatible header and vim file type footer.
+    pub fn bundle(&self, target: &Path) -> Result<BundleReport> {
+        self.bundle_once(target, Run::new(&self.binary))
+    }
+
+    /// Bundle each bin target of the package `filter` accepts the name of, like
+    /// [`Bundler::bundle`] with [`Bundler::with_bin`], to `<bin>.rs` in `out_subdir` of the out
+    /// dir. The crates the bins share are only inlined once, for the first of them. A bin that
+    /// fails does not stop the others: the outcome of each is returned with its name, in the
+    /// order of [`Bundler::bin_names`].
+    pub fn bundle_all(
+        &self,
+        out_subdir: &Path,
+        filter: impl Fn(&str) -> bool,
+    ) -> Result<Vec<(String, Result<BundleReport>)>> {
+        let bins: Vec<_> = self
+            .bin_names()?
+            .into_iter()
+            .filter(|bin| filter(bin))
+            .collect();
+        let shared = SharedCrates::new();
+        let mut reports = vec![];
+        for bin in bins {
+            let report = self.bundle_bin(&bin, &out_subdir.join(format!("{}.rs", bin)), &shared);
+            if let Err(e) = &report {
+                log::error!("Failed to bundle bin {}: {:?}", bin, e);
+            }
+            reports.push((bin, report));
+        }
+        Ok(reports)
+    }
+
+    /// Bundle the bin target `bin` to `target` like [`Bundler::bundle`] with
+    /// [`Bundler::with_bin`], taking crates from and adding them to `shared`. Bundles of several
+    /// bins that share crates only process them once, also when they run on several threads at
+    /// once, each with its own `bundle_bin`.
+    pub fn bundle_bin(
+        &self,
+        bin: &str,
+        target: &Path,
+        shared: &SharedCrates,
+    ) -> Result<BundleReport> {
+        let binary = Binary::Target(bin.into());
+        let run = Run {
+            shared: Some(shared),
+            ..Run::new(&binary)
+        };
+        self.bundle_once(target, run)
+    }
+
+    /// Bundle to `target` like [`Bundler::bundle`], and again whenever an input of the last
+    /// successful bundle changes, once no more changes came for `debounce`. New modules are
+    /// picked up, as each bundle finds the inputs anew. Until a bundle succeeds, the package's
+    /// manifest and the Rust files under its `src` are watched.
+    ///
+    /// `callback` gets the outcome of every bundle, failed ones included, and stops watching by
+    /// returning [`ControlFlow::Break`].
+    pub fn watch(
+        &self,
+        target: &Path,
+        debounce: Duration,
+        mut callback: impl FnMut(&Result<BundleReport>) -> ControlFlow<()>,
+    ) {
+        let mut watched = watch::package_files(&self.manifest_dir);
+        if let Binary::Path(path) = &self.binary {
+            watched.push(path.clone());
+        }
+        loop {
+            let result = self.bundle_once(target, Run::new(&self.binary));
+            if let Ok(report) = &result {
+                watched = report.inputs.clone();
+            }
+            // before the callback, which may already change something
+            let bundled = watch::snapshot(&watched);
+            if callback(&result).is_break() {
+                return;
+            }
+            watch::wait_for_change(&watched, bundled, debounce);
+        }
+    }
+
+    fn bundle_once(&self, target: &Path, mut run: Run) -> Result<BundleReport> {
+        let start = Instant::now();
+        let target = self.out_dir.join(target);
+        let target_dir = target.parent().unwrap_or(&self.out_dir).to_path_buf();
+        let mut rendered = self.render_run(Some(&target_dir), &run, None)?;
+
+        if !self.allow_overwrite {
+            check_target(&target, &rendered.inputs, &self.manifest_dir)?;
+        }
+        if let Some(p) = target.parent() {
+            fs::create_dir_all(p).context("failed to create out dir")?;
+        }
+        let mut written = self.write_script(&target, &mut rendered)?;
+
+        let mut clippy_diagnostics = vec![];
+        let mut auto_allowed = vec![];
+        if self.clippy != ClippyMode::Off {
+            let outcome = clippy::run(&target, &self.clippy_args, &rendered.source_map)?;
+            if self.clippy == ClippyMode::Fail && !outcome.diagnostics.is_empty() {
+                bail!(
+                    "Clippy found problems in {}:\n{}",
+                    target.display(),
+                    outcome.diagnostics.join("\n")
+                );
+            }
+            if self.clippy_auto_allow && !outcome.lints.is_empty() {
+                let attr = clippy::allow_attr(&outcome.lints);
+                log::warn!("Adding {} to {}", attr, target.display());
+                run.inner_attrs.push(attr);
+                rendered = self.render_run(Some(&target_dir), &run, None)?;
+                written |= self.write_script(&target, &mut rendered)?;
+                auto_allowed = outcome.lints.into_iter().collect();
+            }
+            clippy_diagnostics = outcome.diagnostics;
+            rendered.phases.end("clippy");
+        }
+
+        verify::compile(&target, self.verify, &rendered.source_map)?;
+        if let Some((args, expected)) = &self.verify_run {
+            verify::run(&target, args, expected, &rendered.source_map)?;
+        }
+        if self.verify != Verify::None || self.verify_run.is_some() {
+            rendered.phases.end("verify");
+        }
+
+        let playground_url = match self.flavor {
+            OutputFlavor::RustScript => None,
+            OutputFlavor::Playground => {
+                let code = fs::read_to_string(&target)?;
+                let url = flavor::playground_url(&code, &rendered.edition);
+                if url.is_none() {
+                    log::warn!("{} is too long to share through a URL", target.display());
+                }
+                url
+            }
+        };
+
+        let deps_graph = match (&rendered.graph, self.deps_graph) {
+            (Some(input), Some((format, _))) => {
+                let code = fs::read_to_string(&target)?;
+                let code = flavor::blank_frontmatter(&code);
+                Some(DepsGraph::build(&code, input)?.render(format))
+            }
+            _ => None,
+        };
+
+        let mut outputs = vec![target.clone()];
+        if let Some(kind) = self.launcher {
+            outputs.extend(launcher::write_launchers(
+                kind,
+                &target,
+                &self.shebang.interpreter(),
+            )?);
+        }
+
+        log::info!(
+            "Bundled {} in {:?}: {} bytes from {} inputs, with {} warnings",
+            target.display(),
+            start.elapsed(),
+            fs::metadata(&target).map_or(0, |meta| meta.len()),
+            rendered.inputs.len(),
+            rendered.warnings.len()
+        );
+        Ok(BundleReport {
+            output: target,
+            outputs,
+            written,
+            inputs: rendered.inputs,
+            playground_url,
+            renamed: rendered.renamed,
+            deps_graph,
+            passes: rendered.passes,
+            clippy_diagnostics,
+            auto_allowed,
+            deps_trim: rendered.deps_trim,
+            warnings: rendered.warnings,
+            stats: BundleStats {
+                phases: rendered.phases.finish(),
+                ..rendered.stats
+            },
+        })
+    }
+
+    /// Run the whole bundling pipeline up to and including re-parsing the rendered script, but
+    /// without writing anything. Answers whether [`Bundler::bundle`] would succeed right now.
+    pub fn check(&self) -> Result<CheckReport> {
+        let rendered = self.render(None)?;
+        syn::parse_file(&flavor::blank_frontmatter(&rendered.text))
+            .context("The bundled script does not parse")?;
+        Ok(CheckReport {
+            inputs: rendered.inputs,
+            warnings: rendered.warnings,
+            output_size: rendered.text.len(),
+            passes: rendered.passes,
+        })
+    }
+
+    /// Produce the unformatted script, to be written to `target_dir` if known
+    fn render(&self, target_dir: Option<&Path>) -> Result<Rendered> {
+        self.render_run(target_dir, &Run::new(&self.binary), None)
+    }
+
+    /// [`Bundler::render`] for `run`. With a `stream`, the script is printed to it as it goes
+    /// rather than into [`Rendered::text`], which stays empty.
+    fn render_run(
+        &self,
+        target_dir: Option<&Path>,
+        run: &Run,
+        stream: Option<&mut dyn Write>,
+    ) -> Result<Rendered> {
+        let mut phases = Phases::start();
+        let mut warnings = vec![];
+        let package =
+            resolve::resolve_package(self.resolution, &self.manifest, &self.manifest_dir)?;
+        let mut crates = self.crates.clone();
+        if self.with_lib {
+            let lib = package.lib.as_ref().ok_or_else(|| {
+                anyhow!(
+                    "Package {} has no lib target to inline: no [lib] path in its manifest and no \
+                    src/lib.rs",
+                    package.name
+                )
+            })?;
+            crates.insert(0, (lib.name.clone(), lib.path.clone()));
+        }
+
+        let mut merged_manifests = self.merged_manifests.clone();
+        if self.path_deps {
+            let path_deps =
+                resolve::path_dependencies(self.resolution, &package, &self.manifest_dir)?;
+            for (name, path) in path_deps {
+                let known = crates
+                    .iter()
+                    .any(|(_, known)| paths::normalize(known) == paths::normalize(&path));
+                if !known {
+                    log::info!("Inlining path dependency {} at {}", name, path.display());
+                    merged_manifests.push(name.clone());
+                    crates.push((name, path));
+                }
+            }
+        }
+
+        if let Some(name) = self
+            .crate_features
+            .keys()
+            .find(|name| !crates.iter().any(|(krate, _)| krate == *name))
+        {
+            bail!(
+                "Features selected for {}, which is not an inlined crate",
+                name
+            );
+        }
+
+        let enabled = match self.package_selection(&package, run.binary) {
+            Some(selection) => {
+                let enabled = features::resolve(&package, &selection)?;
+                log::info!(
+                    "Bundling {} with features {:?} and optional dependencies {:?}",
+                    package.name,
+                    enabled.features,
+                    enabled.deps
+                );
+                Some(enabled)
+            }
+            None => None,
+        };
+
+        let binary_path = match run.binary {
+            Binary::Path(path) => path.clone(),
+            Binary::Target(name) => find_bin(&package, name)?.path.clone(),
+            Binary::Default => resolve::default_bin(&package, &self.manifest_dir)?,
+            Binary::Example(name) => find_example(&package, name)?.path.clone(),
+        };
+        let mut inputs = vec![self.manifest_dir.join("Cargo.toml")];
+        // sources are named relative to this in messages
+        let root = paths::workspace_root(&self.manifest_dir);
+        let license = self.license_text(&mut inputs)?;
+
+        // parse the binary
+        let mut cfgs = self.target_cfgs.clone().unwrap_or_default();
+        if let Some(enabled) = &enabled {
+            cfgs = cfgs.union(&enabled.cfg_set());
+        }
+        log::info!(
+            "Bundling {}",
+            paths::relative_to(&binary_path, &root).display()
+        );
+        let opts = self.inline_options(&cfgs);
+        let InlinedCrate {
+            file: mut binary,
+            files,
+            diagnostics,
+        } = inline_crate(&binary_path, &opts)?;
+        inputs.extend(files);
+        for diagnostic in diagnostics {
+            log::warn!("{}", diagnostic);
+            warnings.push(Warning::new(WarningKind::ModuleNotLoaded, diagnostic));
+        }
+        // a binary that is a script itself has a manifest of its own, the bundle gets a new one
+        let script_manifest = attrs::take_embedded_manifest(&mut binary.attrs);
+        // nodes removed for their cfg, in the binary and the crates
+        let mut cfg_stripped = 0;
+        if enabled.is_some() {
+            cfg_stripped += cfg::strip(&mut binary, &cfgs);
+        }
+        let relative = paths::relative_to(&binary_path, &root);
+        verbatim::check(&binary, &relative, self.strict_verbatim, &mut warnings)?;
+        if let (true, Some(manifest_package)) = (self.package_globs, &self.manifest.package) {
+            let files = PackageFiles::new(manifest_package, &self.manifest_dir);
+            let sources = &inputs[1..];
+            for file in globs::drop_excluded(&mut binary, &binary_path, sources, &files) {
+                warnings.push(excluded_warning(&file, &root));
+            }
+        }
+        if self.inline_includes {
+            let sources = inputs[1..].to_vec();
+            let limit = self.include_size_limit;
+            let included = include::resolve(
+                &mut binary,
+                &binary_path,
+                &sources,
+                limit,
+                &root,
+                &mut warnings,
+            );
+            inputs.extend(included);
+        }
+        let bin_name = match run.binary {
+            Binary::Example(name) => name,
+            _ => package
+                .bins
+                .iter()
+                .find(|bin| bin.path == binary_path)
+                .map_or(&package.name, |bin| &bin.name),
+        };
+        if let Some(manifest_package) = &self.manifest.package {
+            pkg_env::expand(&mut binary, &PkgEnv::new(manifest_package, bin_name));
+        }
+        // the package's edition is only needed to check against another one
+        let editions = match self.edition {
+            Some(target) => Some((package.edition.parse::<Edition>()?, target)),
+            None => None,
+        };
+        let mut edition_errors = vec![];
+        if let Some((declared, target)) = editions {
+            check_edition(
+                &binary,
+                bin_name,
+                declared,
+                target,
+                &mut warnings,
+                &mut edition_errors,
+            );
+        }
+
+        // keep the binary's references to the crates resolving once they become modules
+        let mut modules = crate_modules(&crates, &self.modules_at, &self.renames)?;
+        // nested crates can not collide with the binary's items, only the container can
+        let renamed = match &self.nest {
+            Some(_) => vec![],
+            None => rewrite::resolve_collisions(&binary, &mut modules, self.collisions)?
+                .into_iter()
+                .map(|m| (m.name, m.module))
+                .collect(),
+        };
+
+        let inlined: Vec<_> = crates.iter().map(|(name, _)| name.clone()).collect();
+        let mut merged = vec![];
+        let mut merged_inputs = vec![];
+        for (name, path) in &crates {
+            if merged_manifests.contains(name) {
+                let mut deps = self.crate_dependencies(name, path, &package, run.binary)?;
+                // the inlined crates are not dependencies of the bundle
+                deps.retain(|dep| !inlined.contains(&dep.name.replace('-', "_")));
+                merged.push((name.clone(), deps));
+                if let Some((_, dir)) = resolve::find_crate_manifest(path)? {
+                    merged_inputs.push(paths::normalize(&dir.join("Cargo.toml")));
+                }
+            }
+        }
+        if self.tests == Tests::KeepAndMergeDevDeps {
+            let dev_deps = package
+                .dependencies
+                .iter()
+                .filter(|dep| dep.kind == resolve::DepKind::Dev)
+                .filter(|dep| !inlined.contains(&dep.name.replace('-', "_")))
+                .map(|dep| resolve::Dependency {
+                    kind: resolve::DepKind::Normal,
+                    ..dep.clone()
+                })
+                .collect();
+            merged.push((package.name.clone(), dev_deps));
+        }
+        let graph = match self.deps_graph {
+            Some((_, modules)) => {
+                let normal_deps = |package: &resolve::Package| -> Vec<String> {
+                    package
+                        .dependencies
+                        .iter()
+                        .filter(|dep| dep.kind == resolve::DepKind::Normal)
+                        .map(|dep| dep.name.clone())
+                        .collect()
+                };
+                let mut graph_crates = vec![];
+                for ((name, path), module) in crates.iter().zip(&modules) {
+                    let deps = match resolve::find_crate_manifest(path)? {
+                        Some((manifest, dir)) => normal_deps(&resolve::resolve_package(
+                            self.resolution,
+                            &manifest,
+                            &dir,
+                        )?),
+                        None => vec![],
+                    };
+                    graph_crates.push(GraphCrate {
+                        name: name.clone(),
+                        module: module.module.clone(),
+                        deps,
+                    });
+                }
+                Some(GraphInput {
+                    bin_name: bin_name.clone(),
+                    nest: self.nest.clone(),
+                    crates: graph_crates,
+                    // inlined ones get an edge of their own
+                    deps: normal_deps(&package)
+                        .into_iter()
+                        .filter(|dep| !inlined.contains(dep))
+                        .collect(),
+                    modules,
+                })
+            }
+            None => None,
+        };
+        // where each crate root ends up, for mapping diagnostics about the bundle back
+        let mut source_map = SourceMap::default();
+        source_map.add_root(vec![], paths::normalize(&binary_path));
+        for ((_, path), module) in crates.iter().zip(&modules) {
+            let prefix = self
+                .nest
+                .iter()
+                .chain(Some(&module.module))
+                .cloned()
+                .collect();
+            source_map.add_root(prefix, paths::normalize(path));
+        }
+        // the files of the binary are all read by now, which the passes point errors at
+        source_map.set_sources(inputs.clone());
+
+        // cached crates are stored as tokens, without their comments
+        let cache = self
+            .cache_dir
+            .as_ref()
+            .filter(|_| !self.preserves_comments() && self.sources.is_none())
+            .map(Cache::new);
+        let ctx = PassContext::new(bin_name.clone(), modules.clone(), self.nest.clone())
+            .with_sources(source_map.clone());
+        let options = BuiltinOptions {
+            strip_tests: self.tests == Tests::Strip,
+            reexport: self.reexport,
+            prune_unused: self.prune_unused,
+            merge_lint_attrs: self.merge_lint_attrs,
+            minify: self.minify,
+        };
+        // custom passes and pruning may look at the whole bundle at once
+        let streaming = self.stream_crates && !self.pipeline.has_custom() && !self.prune_unused;
+        let mut passes = vec![];
+        let mut fragments = vec![];
+        // names the bundle may refer to dependencies by
+        let mut references = BTreeSet::new();
+
+        // parse any crate, also modulize them
+        let mut root_attrs = RootAttrs::new(&binary.attrs);
+        let mut featured = vec![];
+        let mut crate_stats = vec![];
+        let job = CrateJob {
+            package: &package,
+            binary: run.binary,
+            root: &root,
+            cache: cache.as_ref(),
+            shared: run.shared,
+            editions,
+            passes: streaming.then(|| (&ctx, &options)),
+        };
+        let mut libs = vec![];
+        for prepared in self.prepare_crates(&crates, &modules, &job)? {
+            let facts = prepared.facts;
+            for attr in prepared.hoisted {
+                root_attrs.hoist(&facts.name, attr)?;
+            }
+            crate_stats.push(facts.stats);
+            cfg_stripped += facts.cfg_stripped;
+            inputs.extend(facts.sources);
+            references.extend(facts.references);
+            warnings.extend(facts.warnings);
+            featured.extend(facts.featured);
+            edition_errors.extend(facts.edition_errors);
+            if let Some((reports, fragment)) = facts.streamed {
+                pass::merge_reports(&mut passes, reports);
+                fragments.push(fragment);
+            }
+            libs.push(prepared.lib);
+        }
+        phases.end("inline");
+        if let (false, Some((_, target))) = (edition_errors.is_empty(), editions) {
+            bail!(
+                "The sources do not fit edition {}:\n{}",
+                target,
+                edition_errors.join("\n")
+            );
+        }
+        // the manifest is always inside, only module files can escape
+        paths::check_containment(self.containment, &inputs[1..], &root)?;
+        // merged manifests decide what the bundle depends on
+        inputs.extend(merged_inputs);
+        features::unify(
+            featured
+                .iter()
+                .map(|(name, package)| (name.as_str(), package)),
+        )?;
+        root_attrs.finish(&mut binary.attrs);
+        for attr in self.inner_attrs.iter().chain(&run.inner_attrs) {
+            let parsed = syn::Attribute::parse_inner
+                .parse_str(attr)
+                .with_context(|| format!("Invalid inner attribute {}", attr))?;
+            binary.attrs.extend(parsed);
+        }
+
+        let libs = match &self.nest {
+            Some(name) => {
+                let modules = libs.into_iter().map(|lib| lib.module).collect();
+                vec![container_module(name, modules)?.into()]
+            }
+            None => libs,
+        };
+
+        let libs = if streaming {
+            // the crates went through the passes already
+            let reports = self.pipeline.run(&options, &ctx, &mut binary)?;
+            pass::merge_reports(&mut passes, reports);
+            libs
+        } else {
+            // run the passes over the binary and the crates together
+            binary
+                .items
+                .extend(libs.into_iter().map(|lib| syn::Item::Mod(lib.module)));
+            passes = self.pipeline.run(&options, &ctx, &mut binary)?;
+            let (libs, items): (Vec<_>, Vec<_>) = std::mem::take(&mut binary.items)
+                .into_iter()
+                .partition(|item| ctx.is_inlined(item));
+            binary.items = items;
+            libs.into_iter()
+                .map(|item| match item {
+                    syn::Item::Mod(lib) => lib.into(),
+                    _ => unreachable!("Only modules are inlined crates"),
+                })
+                .collect()
+        };
+        phases.end("passes");
+        let removed_by = |name: &str| {
+            passes
+                .iter()
+                .find(|report| report.name == name)
+                .map_or(0, |report| report.changes)
+        };
+        let stripped = StrippedItems {
+            tests: removed_by("strip-tests"),
+            cfg: cfg_stripped,
+            pruned: removed_by("prune-unused"),
+        };
+
+        if self.trim_deps {
+            references.extend(deps::references(&binary));
+        }
+
+        // add libs to binary
+        let items = std::mem::take(&mut binary.items);
+        binary.items = order::arrange(&self.item_order, items, libs, &mut warnings)?;
+
+        log::info!("Embedding the manifest of {}", package.name);
+        let manifest = if self.scrub_manifest {
+            manifest::scrub(&self.manifest_str)?
+        } else {
+            self.manifest_str.clone()
+        };
+        let manifest = match &enabled {
+            Some(enabled) => manifest::select_features(&manifest, enabled)?,
+            None => manifest,
+        };
+        let manifest = manifest::merge_dependencies(&manifest, &package.name, &merged)?;
+        let manifest = match self.tests {
+            Tests::KeepAndMergeDevDeps => manifest::drop_dev_dependencies(&manifest)?,
+            _ => manifest,
+        };
+        let manifest = match (&script_manifest, self.existing_manifest) {
+            (Some(script), ExistingManifest::Merge) => {
+                let script = Manifest::from_str(script).with_context(|| {
+                    format!(
+                        "Invalid manifest in the doc comment of {}",
+                        relative.display()
+                    )
+                })?;
+                let dir = binary_path.parent().unwrap_or(&self.manifest_dir);
+                let mut deps = resolve::manifest_dependencies(&script, dir);
+                deps.retain(|dep| dep.kind == resolve::DepKind::Normal);
+                deps.retain(|dep| !inlined.contains(&dep.name.replace('-', "_")));
+                let script = [(bin_name.clone(), deps)];
+                manifest::merge_dependencies(&manifest, &package.name, &script)?
+            }
+            _ => manifest,
+        };
+        let manifest = manifest::embed_dependencies(&manifest, &inlined, &root, &mut warnings)?;
+        let manifest = match &self.target_cfgs {
+            Some(cfgs) => manifest::resolve_target_cfgs(&manifest, cfgs)?,
+            None => manifest,
+        };
+        let manifest = match self.edition {
+            Some(edition) => manifest::set_edition(&manifest, edition)?,
+            None => manifest,
+        };
+        let edition = self
+            .edition
+            .map_or(package.edition.clone(), |e| e.to_string());
+        let (manifest, deps_trim) = if self.trim_deps {
+            let (manifest, trim) = deps::trim_manifest(&manifest, &references)?;
+            log::info!(
+                "Keeping dependencies {:?} of {}, dropping {:?}",
+                trim.kept,
+                bin_name,
+                trim.dropped
+            );
+            (manifest, Some(trim))
+        } else {
+            (manifest, None)
+        };
+        let manifest = if self.manifest_maps.is_empty() {
+            manifest
+        } else {
+            let mut doc: toml_edit::Document = manifest.parse().context("Invalid manifest")?;
+            for map in &self.manifest_maps {
+                map(&mut doc).context("Failed to edit the manifest")?;
+            }
+            doc.to_string()
+        };
+
+        let mut frontmatter = String::new();
+        let mut manifest_comment = String::new();
+        let keep_shebang = self.keep_shebang && binary.shebang.is_some();
+        match self.flavor {
+            OutputFlavor::RustScript => {
+                // add rust-script shebang
+                if !keep_shebang {
+                    binary.shebang = self.shebang.shebang();
+                }
+                match self.manifest_style {
+                    ManifestStyle::DocComment => {
+                        // add doc attribute for cargo manifest, make sure we add to the head
+                        let _: Vec<_> = binary
+                            .attrs
+                            .splice(..0, new_manifest_comment(&manifest))
+                            .collect();
+                    }
+                    ManifestStyle::Frontmatter => {
+                        if self.shebang == ShebangStyle::PolyglotTrampoline {
+                            bail!(
+                                "The manifest can not go in frontmatter with a trampoline \
+                                shebang, both have to come right after the shebang line"
+                            );
+                        }
+                        frontmatter = flavor::frontmatter(&manifest);
+                    }
+                    ManifestStyle::Comment => {
+                        manifest_comment = flavor::manifest_comment(&manifest);
+                    }
+                }
+            }
+            OutputFlavor::Playground => {
+                let inlined: Vec<_> = inlined.iter().map(String::as_str).collect();
+                for warning in flavor::unavailable_on_playground(&package, &inlined) {
+                    log::warn!("{}", warning);
+                    warnings.push(warning);
+                }
+            }
+        }
+        let dependencies = manifest::dependency_requirements(&manifest)?;
+        phases.end("manifest");
+
+        // print the file, with header and footer
+        let default_header = match self.flavor {
+            OutputFlavor::RustScript => None,
+            OutputFlavor::Playground => Some(Template::parse(template::PLAYGROUND_HEADER)?),
+        };
+        let var = |name: &str| -> Result<String> {
+            Ok(match name {
+                "version" => package.version.clone(),
+                "bin_name" => bin_name.clone(),
+                "date" => template::today(),
+                "git_hash" => git_hash(&self.manifest_dir),
+                "inputs_hash" => template::inputs_hash(&inputs)?,
+                _ => unreachable!("Template variables are checked when parsing"),
+            })
+        };
+        let mut header = match self.header.as_ref().or(default_header.as_ref()) {
+            Some(header) => header.render(var)?,
+            None => String::new(),
+        };
+        if let (true, Some(dir)) = (self.embed_inputs_hash, target_dir) {
+            let flavor = match self.flavor {
+                OutputFlavor::RustScript => "rust-script",
+                OutputFlavor::Playground => "playground",
+            };
+            let options = format!(
+                "bin={} flavor={} edition={} trim-deps={} scrub-manifest={} crates={}",
+                bin_name,
+                flavor,
+                edition,
+                self.trim_deps,
+                self.scrub_manifest,
+                inlined.join(",")
+            );
+            header.push_str(&Stamp::new(dir, &self.manifest_dir, &inputs, options)?.render());
+        }
+        let mut banner = match license {
+            Some(license) => Template::literal(license).render(var)?,
+            None => String::new(),
+        };
+        if let Some(comment) = &self.header_comment {
+            if !banner.is_empty() {
+                banner.push('\n');
+            }
+            banner.push_str(&comment.render(var)?);
+        }
+        if let Some(provenance) = &self.provenance {
+            let facts = provenance.facts(&package.name, &package.version, &self.manifest_dir);
+            banner.push_str(&facts.comment());
+            let _: Vec<_> = binary.items.splice(0..0, facts.items()).collect();
+        }
+        if !manifest_comment.is_empty() {
+            if !banner.is_empty() {
+                banner.push('\n');
+            }
+            banner.push_str(&manifest_comment);
+        }
+        let printer = binary
+            .print()
+            .with_preamble(if keep_shebang {
+                ""
+            } else {
+                self.shebang.preamble()
+            })
+            .with_frontmatter(&frontmatter)
+            .with_banner(&banner)
+            .with_header(&header)
+            .with_fragments(&fragments)
+            .preserve_comments(self.preserves_comments())
+            .compact(self.minify);
+        let mut text = vec![];
+        let mut out: &mut dyn Write = match stream {
+            Some(stream) => stream,
+            None => &mut text,
+        };
+        printer
+            .write_to(&mut out)
+            .context("Failed to print the bundle")?;
+        if let Some(footer) = self.footer.as_ref().filter(|_| !self.minify) {
+            write!(out, "\n{}", footer.render(var)?)?;
+        }
+        let text = String::from_utf8(text)?;
+
+        source_map.set_sources(inputs.clone());
+        phases.end("print");
+        Ok(Rendered {
+            text,
+            inputs,
+            warnings,
+            edition,
+            source_map,
+            renamed,
+            deps_trim,
+            graph,
+            passes,
+            stats: BundleStats {
+                crates: crate_stats,
+                dependencies,
+                stripped,
+                ..Default::default()
+            },
+            phases,
+        })
+    }
+
+    /// Run the pipeline over the modulized crate `lib` alone, placed where it is in the bundle
+    fn run_passes_on_crate(
+        &self,
+        lib: syn::ItemMod,
+        ctx: &PassContext,
+        options: &BuiltinOptions,
+    ) -> Result<(syn::ItemMod, Vec<PassReport>)> {
+        let ident = lib.ident.clone();
+        let item = match &self.nest {
+            Some(name) => container_module(name, vec![lib])?,
+            None => lib,
+        };
+        let mut file = syn::File {
+            shebang: None,
+            attrs: vec![],
+            items: vec![syn::Item::Mod(item)],
+        };
+        let reports = self.pipeline.run(options, ctx, &mut file)?;
+        let lib = match (file.items.pop(), &self.nest) {
+            (Some(syn::Item::Mod(container)), Some(_)) => {
+                container.content.and_then(|(_, mut items)| items.pop())
+            }
+            (item, None) => item,
+            _ => None,
+        };
+        match lib {
+            Some(syn::Item::Mod(lib)) => Ok((lib, reports)),
+            _ => bail!("The passes removed inlined crate {}", ident),
+        }
+    }
+
+    /// The text of the license header, if any, adding the file it is read from to `inputs`
+    fn license_text(&self, inputs: &mut Vec<PathBuf>) -> Result<Option<String>> {
+        let path = match &self.license {
+            None => return Ok(None),
+            Some(LicenseHeader::Text(text)) => return Ok(Some(text.trim_end().into())),
+            Some(LicenseHeader::File(path)) => self.manifest_dir.join(path),
+            Some(LicenseHeader::Manifest) => {
+                let license_file = self
+                    .manifest
+                    .package
+                    .as_ref()
+                    .and_then(|package| package.license_file.as_ref())
+                    .ok_or_else(|| anyhow!("The package has no license-file for the header"))?;
+                self.manifest_dir.join(license_file)
+            }
+        };
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read the license at {}", path.display()))?;
+        inputs.push(path);
+        Ok(Some(text.trim_end().into()))
+    }
+
+    /// Whether items are copied from the sources, see [`Bundler::preserve_comments`]
+    fn preserves_comments(&self) -> bool {
+        self.preserve_comments && !self.minify
+    }
+
+    /// How to inline crates with `cfgs`
+    fn inline_options(&self, cfgs: &CfgSet) -> InlineOptions {
+        let mut opts = InlineOptions::new()
+            .cfgs(cfgs.clone())
+            .errors(self.inline_errors());
+        if let Some(sources) = &self.sources {
+            opts = opts.sources(sources.clone());
+        }
+        for dir in &self.module_search_paths {
+            opts = opts.search_path(dir);
+        }
+        opts
+    }
+
+    /// What to do with modules that fail to load, see [`Bundler::lenient`]
+    fn inline_errors(&self) -> ErrorMode {
+        if self.lenient {
+            ErrorMode::Stub
+        } else {
+            ErrorMode::FailFast
+        }
+    }
+
+    /// The features to inline the crate `name` in `crate_dir` with, if any are selected.
+    /// `package` is the package being bundled, for `binary`.
+    fn feature_selection(
+        &self,
+        name: &str,
+        crate_dir: Option<&Path>,
+        package: &resolve::Package,
+        binary: &Binary,
+    ) -> Option<FeatureSelection> {
+        let own = crate_dir
+            .and_then(|dir| dir.canonicalize().ok())
+            .map_or(false, |dir| {
+                self.manifest_dir.canonicalize().ok() == Some(dir)
+            });
+        // explicit selections win over what the package's dependency declaration asks for
+        match (self.crate_features.get(name), crate_dir) {
+            (Some(selection), _) => Some(selection.clone()),
+            // the package's own lib is built with the package's features
+            (None, Some(_)) if own => self.package_selection(package, binary),
+            (None, Some(dir)) => features::inferred(package, dir),
+            (None, None) => None,
+        }
+    }
+
+    /// The features to build the package being bundled, `package`, with for `binary`, if any are
+    /// selected
+    fn package_selection(
+        &self,
+        package: &resolve::Package,
+        binary: &Binary,
+    ) -> Option<FeatureSelection> {
+        let mut selection = match (&self.features, self.all_features) {
+            (_, true) => return Some(features::all(package)),
+            (selection, false) => selection.clone(),
+        };
+        // an example needs the features it requires
+        let required: &[String] = match binary {
+            Binary::Example(name) => package
+                .examples
+                .iter()
+                .find(|example| &example.name == name)
+                .map_or(&[][..], |example| example.required_features.as_slice()),
+            _ => &[],
+        };
+        for feature in required {
+            let selection = selection.get_or_insert_with(Default::default);
+            if !selection.features.contains(feature) {
+                selection.features.push(feature.clone());
+            }
+        }
+        selection
+    }
+
+    /// The dependencies of the crate `name` rooted at `path` to merge into the bundle's
+    /// manifest, with the features its selection enables on them
+    fn crate_dependencies(
+        &self,
+        name: &str,
+        path: &Path,
+        package: &resolve::Package,
+        binary: &Binary,
+    ) -> Result<Vec<resolve::Dependency>> {
+        let (manifest, dir) = resolve::find_crate_manifest(path)?
+            .ok_or_else(|| anyhow!("No Cargo.toml found for crate {}", name))?;
+        let crate_package = resolve::resolve_package(self.resolution, &manifest, &dir)?;
+        let enabled = match self.feature_selection(name, Some(&dir), package, binary) {
+            Some(selection) => Some(features::resolve(&crate_package, &selection)?),
+            None => None,
+        };
+        let dev = self.tests == Tests::KeepAndMergeDevDeps;
+        let deps = crate_package
+            .dependencies
+            .into_iter()
+            .filter(|dep| match dep.kind {
+                resolve::DepKind::Normal => true,
+                resolve::DepKind::Dev => dev,
+                _ => false,
+            })
+            .filter(|dep| {
+                !dep.optional
+                    || enabled
+                        .as_ref()
+                        .map_or(false, |e| e.deps.contains(&dep.name))
+            })
+            .map(|mut dep| {
+                // tests of the crate are bundled, so its dev-dependencies are plain ones
+                dep.kind = resolve::DepKind::Normal;
+                let features = enabled.as_ref().and_then(|e| e.dep_features.get(&dep.name));
+                for feature in features.into_iter().flatten() {
+                    if !dep.features.contains(feature) {
+                        dep.features.push(feature.clone());
+                    }
+                }
+                dep
+            })
+            .collect();
+        Ok(deps)
+    }
+
+    /// Prepare each of `crates`, which become `modules`, in order. With the `parallel` feature,
+    /// streamed crates are prepared on several threads.
+    fn prepare_crates(
+        &self,
+        crates: &[(String, PathBuf)],
+        modules: &[CrateModule],
+        job: &CrateJob,
+    ) -> Result<Vec<PreparedCrate>> {
+        #[cfg(feature = "parallel")]
+        if job.passes.is_some() && crates.len() > 1 {
+            return self.prepare_crates_parallel(crates, modules, job);
+        }
+        crates
+            .iter()
+            .zip(modules)
+            .enumerate()
+            .map(|(idx, ((name, path), module))| self.prepare_crate(name, path, module, idx, job))
+            .collect()
+    }
+
+    /// [`Bundler::prepare_crates`] on as many threads as there are cores. Each crate is printed
+    /// on the thread that parsed it, and the results are put back in order, so the bundle is the
+    /// same as without threads. Every crate is attempted, and all that fail are reported.
+    #[cfg(feature = "parallel")]
+    fn prepare_crates_parallel(
+        &self,
+        crates: &[(String, PathBuf)],
+        modules: &[CrateModule],
+        job: &CrateJob,
+    ) -> Result<Vec<PreparedCrate>> {
+        assert_eq!(crates.len(), modules.len(), "a module for every crate");
+        let next = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<Result<SentCrate>>>> =
+            Mutex::new(crates.iter().map(|_| None).collect());
+        let worker = || loop {
+            let idx = next.fetch_add(1, Ordering::SeqCst);
+            let (name, path) = match crates.get(idx) {
+                Some(krate) => krate,
+                None => break,
+            };
+            let prepared = self.prepare_crate(name, path, &modules[idx], idx, job);
+            results.lock().unwrap()[idx] = Some(prepared.map(PreparedCrate::send));
+        };
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        thread::scope(|scope| {
+            for _ in 0..threads.min(crates.len()) {
+                scope.spawn(worker);
+            }
+        });
+
+        let mut prepared = vec![];
+        let mut failures = vec![];
+        let results = results.into_inner().unwrap();
+        for (idx, result) in results.into_iter().enumerate() {
+            match result.expect("every crate is attempted") {
+                Ok(sent) => prepared.push(PreparedCrate::receive(sent, idx)?),
+                Err(err) => failures.push(err),
+            }
+        }
+        match failures.len() {
+            0 => Ok(prepared),
+            1 => Err(failures.remove(0)),
+            n => {
+                let failures: Vec<_> = failures.iter().map(|err| format!("{:#}", err)).collect();
+                bail!("{} crates failed to inline:\n{}", n, failures.join("\n"))
+            }
+        }
+    }
+
+    /// Inline the crate `name` rooted at `path` as `module` and modulize it, and with streaming
+    /// also run the passes on it and print it as fragment `idx`
+    fn prepare_crate(
+        &self,
+        name: &str,
+        path: &Path,
+        module: &CrateModule,
+        idx: usize,
+        job: &CrateJob,
+    ) -> Result<PreparedCrate> {
+        // everything up to modulizing only depends on the crate, so it can be cached
+        let options = format!(
+            "{} {:?} {:?} {:?} {} {} {} {} {}",
+            name,
+            self.crate_features.get(name),
+            (&self.features, self.all_features),
+            self.target_cfgs,
+            self.strict_verbatim,
+            self.package_globs,
+            self.inline_includes,
+            self.include_size_limit,
+            self.lenient
+        );
+        log::info!(
+            "Inlining crate {} from {} as mod {}",
+            name,
+            paths::relative_to(path, job.root).display(),
+            module.module
+        );
+        let mut loaded = false;
+        let mut process = || -> Result<ProcessedCrate> {
+            if let Some(processed) = job.cache.and_then(|cache| cache.load(path, &options)) {
+                loaded = true;
+                return Ok(processed);
+            }
+            let processed = self.process_crate(name, path, job)?;
+            if let Some(cache) = job.cache {
+                cache.store(path, &options, &processed)?;
+            }
+            Ok(processed)
+        };
+        let (processed, shared_hit) = match job.shared {
+            Some(shared) => shared.get_or_process(path, &options, process)?,
+            None => (process()?, false),
+        };
+        let hit = shared_hit || loaded;
+        let stats = CrateStats::new(
+            name,
+            &module.module,
+            &processed.sources,
+            &processed.file.items,
+            hit,
+        );
+        // fresh ones were logged while processing
+        if hit {
+            for warning in &processed.warnings {
+                log::warn!("{}", warning);
+            }
+        }
+        let mut warnings = processed.warnings;
+        let featured = if processed.featured {
+            let (manifest, dir) = resolve::find_crate_manifest(path)?
+                .ok_or_else(|| anyhow!("No Cargo.toml found for crate {}", name))?;
+            let package = resolve::resolve_package(self.resolution, &manifest, &dir)?;
+            Some((name.to_string(), package))
+        } else {
+            None
+        };
+        let mut edition_errors = vec![];
+        if let Some((package_edition, target)) = job.editions {
+            let declared = resolve::find_crate_manifest(path)?
+                .and_then(|(manifest, _)| manifest.package)
+                .map_or(package_edition, |package| package.edition.into());
+            check_edition(
+                &processed.file,
+                name,
+                declared,
+                target,
+                &mut warnings,
+                &mut edition_errors,
+            );
+        }
+        let (mut lib, hoisted) = modulize_crate(name, &module.module, processed.file)?;
+        let prefix: Vec<_> = self
+            .nest
+            .iter()
+            .chain(Some(&module.module))
+            .cloned()
+            .collect();
+        rewrite::rewrite_self_refs(&mut lib, name, &prefix);
+        rewrite::reexport_macros(&mut lib);
+        let (lib, streamed) = match job.passes {
+            None => (order::Lib::from(lib), None),
+            // only keep the printed crate, and what later steps need to know about it
+            Some((ctx, options)) => {
+                let (lib, reports) = self.run_passes_on_crate(lib, ctx, options)?;
+                let mut lib = order::Lib::from(lib);
+                let fragment = print::take_fragment(
+                    &mut lib.module,
+                    idx,
+                    self.preserves_comments(),
+                    self.minify,
+                );
+                (lib, Some((reports, fragment)))
+            }
+        };
+        Ok(PreparedCrate {
+            lib,
+            hoisted,
+            facts: CrateFacts {
+                name: name.to_string(),
+                stats,
+                sources: processed.sources,
+                references: processed.references,
+                warnings,
+                cfg_stripped: processed.cfg_stripped,
+                featured,
+                edition_errors,
+                streamed,
+            },
+        })
+    }
+
+    /// Inline the crate `name` rooted at `path`, and do all the processing that only depends on
+    /// the crate itself
+    fn process_crate(&self, name: &str, path: &Path, job: &CrateJob) -> Result<ProcessedCrate> {
+        let root = job.root;
+        let crate_manifest = resolve::find_crate_manifest(path)?;
+        let crate_dir = crate_manifest.as_ref().map(|(_, dir)| dir.as_path());
+        let selection = self.feature_selection(name, crate_dir, job.package, job.binary);
+        let featured = selection.is_some();
+        let mut cfgs = self.target_cfgs.clone().unwrap_or_default();
+        if let Some(selection) = selection {
+            let (manifest, dir) = crate_manifest.as_ref().ok_or_else(|| {
+                anyhow!(
+                    "No Cargo.toml found for crate {} to select features from",
+                    name
+                )
+            })?;
+            let package = resolve::resolve_package(self.resolution, manifest, dir)?;
+            let enabled = features::resolve(&package, &selection)?;
+            log::info!(
+                "Inlining {} with features {:?} and optional dependencies {:?}",
+                name,
+                enabled.features,
+                enabled.deps
+            );
+            cfgs = cfgs.union(&enabled.cfg_set());
+        }
+
+        let opts = self.inline_options(&cfgs);
+        let InlinedCrate {
+            file: mut lib,
+            files: mut sources,
+            diagnostics,
+        } = inline_crate(path, &opts)?;
+        // collected separately, as they are cached with the crate
+        let mut warnings = vec![];
+        for diagnostic in diagnostics {
+            log::warn!("{}", diagnostic);
+            warnings.push(Warning::new(WarningKind::ModuleNotLoaded, diagnostic));
+        }
+        let relative = paths::relative_to(path, root);
+        verbatim::check(&lib, &relative, self.strict_verbatim, &mut warnings)?;
+        if let Some((Some(crate_package), dir)) = crate_manifest
+            .as_ref()
+            .filter(|_| self.package_globs)
+            .map(|(manifest, dir)| (manifest.package.as_ref(), dir))
+        {
+            let files = PackageFiles::new(crate_package, dir);
+            for file in globs::drop_excluded(&mut lib, path, &sources, &files) {
+                warnings.push(excluded_warning(&file, root));
+            }
+        }
+        if self.inline_includes {
+            let limit = self.include_size_limit;
+            let included = include::resolve(&mut lib, path, &sources, limit, root, &mut warnings);
+            sources.extend(included);
+        }
+        if let Some(env) = PkgEnv::for_crate_root(path, name)? {
+            pkg_env::expand(&mut lib, &env);
+        }
+        let mut depends_on = vec![self.manifest_dir.join("Cargo.toml")];
+        if let Some((_, dir)) = &crate_manifest {
+            depends_on.push(dir.join("Cargo.toml"));
+        }
+        let cfg_stripped = if featured {
+            cfg::strip(&mut lib, &cfgs)
+        } else {
+            0
+        };
+        rewrite::reexport_extern_crates(&mut lib);
+        Ok(ProcessedCrate {
+            references: deps::references(&lib),
+            file: lib,
+            sources,
+            depends_on,
+            warnings,
+            featured,
+            cfg_stripped,
+        })
+    }
+}
+
+/// Bundle what the `[package.metadata.rust-script-bundle]` table of the package asks for, as in
+/// a `bundle.toml`, from its build script. Scripts go to the table's `out-dir`, or `OUT_DIR` if
+/// it has none, as `<bin>.rs`, and the build script reruns when any of their inputs changes.
+/// Warnings are passed on to cargo to show.
+///
+/// ```ignore
+/// // build.rs
+/// fn main() {
+///     rust_script_bundler::bundle_from_metadata().unwrap();
+/// }
+/// ```
+pub fn bundle_from_metadata() -> Result<Vec<BundleReport>> {
+    let out_dir = env::var_os("OUT_DIR").ok_or_else(|| anyhow!("Missing OUT_DIR env var"))?;
+    let manifest_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .ok_or_else(|| anyhow!("Missing CARGO_MANIFEST_DIR env var"))?;
+    let settings = ConfigFile::from_manifest(&manifest_dir)?
+        .ok_or_else(|| {
+            anyhow!(
+                "No [package.metadata.{}] in the manifest, run rust-script-bundle init",
+                config::METADATA_KEY
+            )
+        })?
+        .effective(None)?;
+    let out_dir = settings.out_dir.clone().unwrap_or_else(|| out_dir.into());
+    let crate_dir = settings
+        .crate_dir
+        .clone()
+        .unwrap_or_else(|| manifest_dir.into());
+    let bins = match &settings.bins {
+        Some(bins) => bins.clone(),
+        None => Bundler::new_with_dir("src/main.rs", &out_dir, &crate_dir)?.bin_names()?,
+    };
+    let mut reports = vec![];
+    for bin in bins {
+        let bundler = Bundler::new_with_dir("src/main.rs", &out_dir, &crate_dir)?.with_bin(&bin);
+        let report = settings
+            .apply(bundler)
+            .bundle(Path::new(&format!("{}.rs", bin)))?;
+        report.emit_rerun_if_changed();
+        report.emit_cargo_warnings();
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+fn find_bin<'a>(package: &'a resolve::Package, name: &str) -> Result<&'a resolve::Target> {
+    package
+        .bins
+        .iter()
+        .find(|bin| bin.name == name)
+        .ok_or_else(|| {
+            let available: Vec<_> = package.bins.iter().map(|bin| bin.name.as_str()).collect();
+            anyhow!(
+                "Package {} has no bin target named {}, available bin targets: {}",
+                package.name,
+                name,
+                available.join(", ")
+            )
+        })
+}
+
+fn find_example<'a>(package: &'a resolve::Package, name: &str) -> Result<&'a resolve::Target> {
+    package
+        .examples
+        .iter()
+        .find(|example| example.name == name)
+        .ok_or_else(|| {
+            let available: Vec<_> = package.examples.iter().map(|e| e.name.as_str()).collect();
+            anyhow!(
+                "Package {} has no example named {}, available examples: {}",
+                package.name,
+                name,
+                available.join(", ")
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_manifest_comment_works() {
+        let attrs = new_manifest_comment("abc\n  def");
+
+        dbg!(attrs);
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn out_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "rust-script-bundler-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn dir_bins_are_listed_once() {
+        let bundler =
+            Bundler::new_with_dir("src/main.rs", out_dir("dir-bins"), fixture("multibin")).unwrap();
+        let mut names = bundler.bin_names().unwrap();
+        names.sort();
+        assert_eq!(names, ["flat", "mytool"]);
+    }
+
+    #[test]
+    fn bins_are_found_by_name() {
+        let dir = out_dir("bin-path");
+        fs::create_dir_all(dir.join("tools")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"tools\"\nversion = \"0.1.0\"\n\n\
+            [[bin]]\nname = \"tool\"\npath = \"tools/tool.rs\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("tools/tool.rs"),
+            "fn main() { println!(\"tool\"); }\n",
+        )
+        .unwrap();
+        let bundler = || Bundler::new_with_dir("src/main.rs", dir.join("out"), &dir).unwrap();
+
+        let report = bundler().with_bin("tool").check().unwrap();
+        assert!(report.inputs.contains(&dir.join("tools/tool.rs")));
+        let err = bundler().with_bin("nope").check().unwrap_err().to_string();
+        assert!(err.ends_with("available bin targets: tool"));
+    }
+
+    #[test]
+    fn all_bins_are_bundled_on_their_own() {
+        let dir = out_dir("all-bins");
+        fs::create_dir_all(dir.join("src/bin")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"tools\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("src/lib.rs"),
+            "pub fn greet() -> &'static str { \"hi\" }\n",
+        )
+        .unwrap();
+        for bin in ["one", "two"] {
+            let main = "fn main() { println!(\"{}\", tools::greet()); }\n";
+            fs::write(dir.join(format!("src/bin/{}.rs", bin)), main).unwrap();
+        }
+        fs::write(dir.join("src/bin/broken.rs"), "fn main( {\n").unwrap();
+        fs::write(dir.join("src/bin/skipped.rs"), "fn main() {}\n").unwrap();
+
+        let mut reports = Bundler::new_with_dir("src/main.rs", dir.join("out"), &dir)
+            .unwrap()
+            .with_lib()
+            .bundle_all(Path::new("bins"), |bin| bin != "skipped")
+            .unwrap();
+        reports.sort_by(|a, b| a.0.cmp(&b.0));
+        let names: Vec<_> = reports.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["broken", "one", "two"]);
+        // the broken bin does not stop the others
+        assert!(reports[0].1.is_err());
+        for (name, report) in &reports[1..] {
+            let report = report.as_ref().unwrap();
+            assert_eq!(
+                report.output,
+                dir.join("out/bins").join(format!("{}.rs", name))
+            );
+            let script = fs::read_to_string(&report.output).unwrap();
+            assert!(script.contains("pub fn greet"));
+            assert!(report.inputs.contains(&dir.join("src/lib.rs")));
+        }
+        // the lib is only inlined for the first bin that gets to it
+        let cached: Vec<_> = reports[1..]
+            .iter()
+            .map(|(_, report)| report.as_ref().unwrap().stats.crates[0].cached)
+            .collect();
+        assert_eq!(cached.iter().filter(|cached| !**cached).count(), 1);
+    }
+
+    #[test]
+    fn bins_bundled_at_once_share_crates() {
+        let dir = out_dir("bins-at-once");
+        fs::create_dir_all(dir.join("src/bin")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"tools\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("src/lib.rs"),
+            "pub fn greet() -> &'static str { \"hi\" }\n",
+        )
+        .unwrap();
+        let bins = ["one", "two", "three", "four"];
+        for bin in bins {
+            let main = "fn main() { println!(\"{}\", tools::greet()); }\n";
+            fs::write(dir.join(format!("src/bin/{}.rs", bin)), main).unwrap();
+        }
+
+        let bundler = Bundler::new_with_dir("src/main.rs", dir.join("out"), &dir)
+            .unwrap()
+            .with_lib();
+        let shared = SharedCrates::new();
+        let reports: Vec<_> = std::thread::scope(|scope| {
+            let threads: Vec<_> = bins
+                .iter()
+                .map(|bin| {
+                    let (bundler, shared) = (&bundler, &shared);
+                    let target = PathBuf::from(format!("{}.rs", bin));
+                    scope.spawn(move || bundler.bundle_bin(bin, &target, shared).unwrap())
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect()
+        });
+        for report in &reports {
+            let script = fs::read_to_string(&report.output).unwrap();
+            assert!(script.contains("pub fn greet"));
+        }
+        // the other threads parse what the first one processed
+        let processed = reports
+            .iter()
+            .filter(|report| !report.stats.crates[0].cached)
+            .count();
+        assert_eq!(processed, 1);
+    }
+
+    #[test]
+    fn output_is_parsed_back() {
+        let dir = out_dir("self-check");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"odd\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        let main = "/// First line\n\
+            ///\n\
+            /// Second \"quoted\" line, with a \\ backslash\n\
+            /** block\n * doc */\n\
+            macro_rules! nested {\n\
+                ($($x:expr),* ; [$($y:tt)*]) => { vec![$($x),*] };\n\
+            }\n\
+            fn main() {\n\
+                let v: Vec<i32> = nested!(1, 2 ; [a { b ( c ) } ]);\n\
+                println!(\"{:?}\", v);\n\
+            }\n";
+        fs::write(dir.join("src/main.rs"), main).unwrap();
+        let bundler = || {
+            Bundler::new_in_memory("src/main.rs", &dir)
+                .unwrap()
+                .formatting(Formatting::None)
+        };
+        bundler().bundle_to_string().unwrap();
+
+        struct Garble;
+        impl Pass for Garble {
+            fn name(&self) -> &str {
+                "garble"
+            }
+
+            fn run(&self, _: &PassContext, file: &mut syn::File) -> Result<PassOutcome> {
+                let broken = "fn broken() { let x = ; }".parse().unwrap();
+                file.items.push(syn::Item::Verbatim(broken));
+                Ok(PassOutcome::changes(1))
+            }
+        }
+        let garbled = || bundler().with_pass(PassPosition::Last, Garble);
+        let err = garbled().bundle_to_string().unwrap_err().to_string();
+        assert!(err.starts_with("the bundle does not parse at line"));
+        assert!(err.contains("broken"));
+        assert!(garbled().skip_self_check().bundle_to_string().is_ok());
+    }
+
+    #[test]
+    fn dir_bin_inlines_sibling_modules() {
+        let report = Bundler::new_with_dir("src/main.rs", out_dir("dir-bin"), fixture("multibin"))
+            .unwrap()
+            .with_bin("mytool")
+            .bundle(Path::new("mytool.rs"))
+            .unwrap();
+
+        let args = fixture("multibin").join("src/bin/mytool/args.rs");
+        assert!(report.inputs.contains(&args));
+
+        let output = fs::read_to_string(&report.output).unwrap();
+        let file = syn::parse_file(&output).unwrap();
+        assert!(file.items.iter().any(|item| matches!(
+            item,
+            syn::Item::Mod(m) if m.ident == "args" && m.content.is_some()
+        )));
+    }
+
+    #[test]
+    fn manifest_can_go_in_frontmatter() {
+        let bundler = || {
+            Bundler::new_in_memory("src/main.rs", fixture("simple"))
+                .unwrap()
+                .manifest_style(ManifestStyle::Frontmatter)
+        };
+        let text = bundler().bundle_to_string().unwrap();
+        assert!(!text.contains("```cargo"));
+        let lines: Vec<_> = text.split_inclusive('\n').collect();
+        assert_eq!(lines[0], "#!/usr/bin/env -S rust-script\n");
+        let range = flavor::frontmatter_lines(&lines).unwrap();
+        assert_eq!(range.start, 1);
+        let manifest = lines[range.start + 1..range.end - 1].concat();
+        let manifest: toml::Value = toml::from_str(&manifest).unwrap();
+        assert_eq!(manifest["package"]["name"].as_str(), Some("simple-fixture"));
+        // without it the script is plain Rust
+        let rest = [&lines[..range.start], &lines[range.end..]]
+            .concat()
+            .concat();
+        let file = syn::parse_file(&rest).unwrap();
+        assert!(attrs::embedded_manifest(&file.attrs).is_none());
+        bundler().check().unwrap();
+
+        let trampoline = bundler()
+            .shebang_style(ShebangStyle::PolyglotTrampoline)
+            .unwrap();
+        assert!(trampoline.bundle_to_string().is_err());
+    }
+
+    #[test]
+    fn target_tools_set_the_conventions() {
+        let bundle = |tool: TargetTool| {
+            Bundler::new_in_memory("src/main.rs", fixture("simple"))
+                .unwrap()
+                .target_tool(tool)
+        };
+        let text = bundle(TargetTool::RustScript).bundle_to_string().unwrap();
+        assert!(text.starts_with("#!/usr/bin/env -S rust-script\n"));
+        assert!(text.contains("//! ```cargo"));
+        assert!(text.ends_with("// vim: ft=rust syntax=rust\n"));
+
+        let text = bundle(TargetTool::CargoScript).bundle_to_string().unwrap();
+        assert!(text.starts_with("#!/usr/bin/env cargo\n---\n[package]\n"));
+        assert!(text.ends_with("// vim: ft=rust syntax=rust\n"));
+
+        let text = bundle(TargetTool::Plain).bundle_to_string().unwrap();
+        assert!(text.starts_with("// The manifest of the bundled package, for reference:\n"));
+        assert!(text.contains("// [package]\n"));
+        assert!(!text.contains("vim:"));
+        let file = syn::parse_file(&text).unwrap();
+        assert!(file.shebang.is_none());
+        assert!(attrs::embedded_manifest(&file.attrs).is_none());
+
+        // the pieces can still be changed
+        let text = bundle(TargetTool::Plain)
+            .manifest_style(ManifestStyle::DocComment)
+            .bundle_to_string()
+            .unwrap();
+        assert!(text.starts_with("//! ```cargo\n"));
+    }
+
+    #[test]
+    fn embedded_manifest_is_scrubbed() {
+        let manifest = |scrub: bool| {
+            let report = Bundler::new_with_dir("src/main.rs", out_dir("scrub"), fixture("simple"))
+                .unwrap()
+                .with_lib()
+                .scrub_manifest(scrub)
+                .bundle(Path::new(&format!("simple-{}.rs", scrub)))
+                .unwrap();
+            let script = fs::read_to_string(&report.output).unwrap();
+            let file = syn::parse_file(&script).unwrap();
+            let manifest = attrs::embedded_manifest(&file.attrs).unwrap();
+            toml::from_str::<toml::Value>(&manifest).unwrap()
+        };
+        let scrubbed = manifest(true);
+        assert!(scrubbed.get("dev-dependencies").is_none());
+        assert_eq!(scrubbed["package"]["name"].as_str(), Some("simple-fixture"));
+        assert!(scrubbed["dependencies"].get("anyhow").is_some());
+        assert!(manifest(false).get("dev-dependencies").is_some());
+    }
+
+    #[test]
+    fn crate_manifests_are_merged() {
+        let bundle = |other: &str| {
+            Bundler::new_with_dir("src/main.rs", out_dir("merge"), fixture("merge"))?
+                .with_crate_manifest("alpha", fixture("merge").join("alpha"))?
+                .with_crate_manifest(other, fixture("merge").join(other))?
+                .bundle(Path::new(&format!("merge-{}.rs", other)))
+        };
+        let report = bundle("beta").unwrap();
+        let script = fs::read_to_string(&report.output).unwrap();
+        let file = syn::parse_file(&script).unwrap();
+        let manifest = attrs::embedded_manifest(&file.attrs).unwrap();
+        let manifest: toml::Value = toml::from_str(&manifest).unwrap();
+        let deps = manifest["dependencies"].as_table().unwrap();
+        let names: Vec<_> = deps.keys().map(String::as_str).collect();
+        assert_eq!(names, ["anyhow", "serde_json"]);
+        assert_eq!(
+            deps["serde_json"]["features"].as_array().unwrap(),
+            &[toml::Value::String("preserve_order".into())]
+        );
+
+        let err = bundle("gamma").unwrap_err().to_string();
+        assert!(err.contains("crate gamma requires serde_json ^0.9"));
+        assert!(err.contains("crate alpha requires serde_json ^1.0"));
+    }
+
+    #[test]
+    fn target_specific_dependencies_keep_their_tables() {
+        let script = Bundler::new_in_memory("src/main.rs", fixture("pathdeps"))
+            .unwrap()
+            .with_path_deps()
+            .bundle_to_string()
+            .unwrap();
+        let file = syn::parse_file(&script).unwrap();
+        let manifest = attrs::embedded_manifest(&file.attrs).unwrap();
+        let manifest: toml::Value = toml::from_str(&manifest).unwrap();
+        let unix = &manifest["target"]["cfg(unix)"];
+        assert_eq!(unix["dependencies"]["libc"].as_str(), Some("0.2"));
+        assert!(unix.get("dev-dependencies").is_none());
+        // the windows-only path dependency of engine is depended on from the registry instead
+        let windows = &manifest["target"]["cfg(windows)"]["dependencies"];
+        assert_eq!(windows["winconsole"]["version"].as_str(), Some("^0.2"));
+        assert!(windows["winconsole"].get("path").is_none());
+        assert!(manifest["dependencies"].get("winconsole").is_none());
+        assert!(!script.contains("mod winconsole"));
+    }
+
+    #[test]
+    fn output_is_reproducible() {
+        fn copy_dir(from: &Path, to: &Path) {
+            fs::create_dir_all(to).unwrap();
+            for entry in fs::read_dir(from).unwrap() {
+                let path = entry.unwrap().path();
+                let target = to.join(path.file_name().unwrap());
+                if path.is_dir() {
+                    copy_dir(&path, &target);
+                } else {
+                    fs::copy(&path, &target).unwrap();
+                }
+            }
+        }
+        let bundle = |name: &str| {
+            let dir = out_dir(name);
+            let _ = fs::remove_dir_all(&dir);
+            copy_dir(&fixture("pathdeps"), &dir.join("checkout"));
+            let out = dir.join("out");
+            let report = Bundler::new_with_dir("src/main.rs", &out, dir.join("checkout"))
+                .unwrap()
+                .with_crate_at("util", dir.join("checkout/util/src/lib.rs"))
+                .with_path_deps()
+                .provenance(Some(Provenance::new().commit("abc1234", false)))
+                .bundle(&out.join("pathdeps.rs"))
+                .unwrap();
+            (dir, fs::read_to_string(report.output).unwrap())
+        };
+        let (first_dir, first) = bundle("reproducible-a");
+        let (second_dir, second) = bundle("reproducible-b");
+        assert_eq!(first, second);
+        for dir in [&first_dir, &second_dir] {
+            assert!(!first.contains(&*dir.to_string_lossy()));
+        }
+        // util was added before engine was found as a path dependency
+        let util = first.find("mod util").unwrap();
+        let engine = first.find("mod engine").unwrap();
+        assert!(util < engine);
+        assert!(!first.contains(&format!(" on {}", template::today())));
+    }
+
+    #[test]
+    fn rewrite_errors_point_at_the_sources() {
+        let dir = out_dir("rewrite-errors");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        let manifest = "[package]\nname = \"diag\"\nversion = \"0.1.0\"\n";
+        fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+        fs::write(
+            dir.join("src/main.rs"),
+            "mod app;\nfn main() { app::run(); }\n",
+        )
+        .unwrap();
+        let app = "pub fn run() {\n    ::simple_fixture::run().unwrap();\n}\n";
+        fs::write(dir.join("src/app.rs"), app).unwrap();
+
+        let err = Bundler::new_in_memory("src/main.rs", dir.clone())
+            .unwrap()
+            .with_crate_at("simple_fixture", fixture("simple").join("src/lib.rs"))
+            .reexport_mode(ReexportMode::Glob)
+            .bundle_to_string()
+            .unwrap_err();
+        let app = paths::normalize(&dir.join("src/app.rs"));
+        let expected = format!(
+            "{}:2:7: absolute path `:: simple_fixture :: run`",
+            app.display()
+        );
+        assert!(format!("{:#}", err).contains(&expected));
+    }
+
+    #[test]
+    fn manifest_can_be_mapped() {
+        let text = Bundler::new_in_memory("src/main.rs", fixture("simple"))
+            .unwrap()
+            .map_manifest(|doc| {
+                doc["profile"]["release"]["lto"] = toml_edit::value(true);
+                Ok(())
+            })
+            .map_manifest(|doc| {
+                doc["package"]["name"] = toml_edit::value("simple-script");
+                Ok(())
+            })
+            .bundle_to_string()
+            .unwrap();
+        let manifest = attrs::embedded_manifest(&syn::parse_file(&text).unwrap().attrs).unwrap();
+        let manifest: toml::Value = toml::from_str(&manifest).unwrap();
+        assert_eq!(manifest["profile"]["release"]["lto"].as_bool(), Some(true));
+        assert_eq!(manifest["package"]["name"].as_str(), Some("simple-script"));
+
+        let err = Bundler::new_in_memory("src/main.rs", fixture("simple"))
+            .unwrap()
+            .map_manifest(|_| bail!("no network"))
+            .bundle_to_string()
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to edit the manifest: no network"
+        );
+    }
+
+    #[test]
+    fn examples_are_bundled() {
+        let bundle = |name: &str| {
+            Bundler::new_in_memory("src/main.rs", fixture("examples"))
+                .unwrap()
+                .with_lib()
+                .with_example(name)
+                .bundle_to_string()
+                .map(|text| text.split_whitespace().collect::<String>())
+        };
+        let plain = bundle("plain").unwrap();
+        assert!(plain.contains("pubmodexamples_fixture{"));
+        assert!(plain.contains("examples_fixture::greet;"));
+        // nothing selects the feature, so it is left for rustc
+        assert!(plain.contains("#[cfg(feature=\"fancy\")]"));
+
+        // the required feature is selected
+        let fancy = bundle("fancy").unwrap();
+        assert!(fancy.contains("examples_fixture::fancy()"));
+        assert!(fancy.contains("pubfnfancy()->String"));
+        assert!(!fancy.contains("feature="));
+
+        let moved = bundle("moved").unwrap();
+        assert!(moved.contains("\"moved:{}\""));
+
+        let err = bundle("nope").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Package examples-fixture has no example named nope, available examples: fancy, moved, \
+            plain"
+        );
+    }
+
+    #[test]
+    fn tests_are_kept_or_stripped() {
+        let bundle = |tests: Tests| {
+            let text = Bundler::new_in_memory("src/main.rs", fixture("testable"))
+                .unwrap()
+                .with_lib()
+                .tests(tests)
+                .bundle_to_string()
+                .unwrap();
+            let file = syn::parse_file(&text).unwrap();
+            let manifest = attrs::embedded_manifest(&file.attrs).unwrap();
+            let manifest = toml::from_str::<toml::Value>(&manifest).unwrap();
+            (text.split_whitespace().collect::<String>(), manifest)
+        };
+        let (kept, manifest) = bundle(Tests::Keep);
+        assert!(kept.contains("#[test]fndoubles_the_answer()"));
+        assert!(kept.contains("modtests{usesuper::*;usepretty_assertions::assert_eq;"));
+        assert!(kept.contains("double(2),crate::testable_fixture::math::times(2,2)"));
+        let deps = manifest.get("dependencies");
+        assert!(deps
+            .and_then(|deps| deps.get("pretty_assertions"))
+            .is_none());
+
+        let (stripped, _) = bundle(Tests::Strip);
+        assert!(!stripped.contains("modtests"));
+        assert!(!stripped.contains("doubles_the_answer"));
+
+        let (merged, manifest) = bundle(Tests::KeepAndMergeDevDeps);
+        assert!(merged.contains("double(2),crate::testable_fixture::math::times(2,2)"));
+        let dev_dep = &manifest["dependencies"]["pretty_assertions"];
+        assert_eq!(dev_dep.as_str(), Some("^1"));
+        assert!(manifest.get("dev-dependencies").is_none());
+    }
+
+    #[test]
+    fn generated_modules_are_provided() {
+        let bundler = || Bundler::new_in_memory("src/main.rs", fixture("generated")).unwrap();
+        let err = bundler().with_lib().bundle_to_string().unwrap_err();
+        assert!(err.to_string().contains("as mod generated"));
+
+        let generated = "pub fn answer() -> u32 { 42 }\n";
+        let mut sources = BTreeMap::new();
+        sources.insert(
+            fixture("generated").join("src/generated.rs"),
+            generated.to_string(),
+        );
+        let text = bundler()
+            .with_lib()
+            .source_provider(sources)
+            .bundle_to_string()
+            .unwrap();
+        let text: String = text.split_whitespace().collect();
+        assert!(text.contains("modgenerated{pubfnanswer()->u32{42}}"));
+
+        let out_dir = out_dir("generated-search-path");
+        fs::write(out_dir.join("generated.rs"), generated).unwrap();
+        let report = bundler()
+            .with_lib()
+            .add_module_search_path(&out_dir)
+            .check()
+            .unwrap();
+        assert!(report.inputs.contains(&out_dir.join("generated.rs")));
+    }
+
+    #[test]
+    fn lib_is_found_by_convention() {
+        // no [lib] table
+        let text = Bundler::new_in_memory("src/main.rs", fixture("bare"))
+            .unwrap()
+            .with_lib()
+            .bundle_to_string()
+            .unwrap();
+        let text: String = text.split_whitespace().collect();
+        assert!(text.contains("pubmodbare_fixture{pubfnanswer()->u32{"));
+
+        let err = Bundler::new_in_memory("src/main.rs", fixture("pathdeps"))
+            .unwrap()
+            .with_lib()
+            .bundle_to_string()
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Package pathdeps-fixture has no lib target"));
+    }
+
+    #[test]
+    fn script_binaries_get_one_manifest() {
+        let bundler = || Bundler::new_in_memory("src/main.rs", fixture("scripted")).unwrap();
+        let deps = |text: &str| {
+            let file = syn::parse_file(text).unwrap();
+            let manifest = attrs::embedded_manifest(&file.attrs).unwrap();
+            let manifest: toml::Value = toml::from_str(&manifest).unwrap();
+            manifest["dependencies"].as_table().unwrap().clone()
+        };
+        let text = bundler().bundle_to_string().unwrap();
+        assert!(text.starts_with("#!/usr/bin/env -S rust-script\n//! ```cargo\n"));
+        assert_eq!(text.matches("```cargo").count(), 1);
+        let fence = text.find("//! ```\n").unwrap();
+        assert!(text.find("Greets, once a script of its own.").unwrap() > fence);
+        assert!(text.find("Run it without arguments.").unwrap() > fence);
+        let replaced = deps(&text);
+        assert!(replaced.contains_key("anyhow"));
+        assert!(!replaced.contains_key("regex"));
+
+        let text = bundler()
+            .existing_manifest(ExistingManifest::Merge)
+            .keep_shebang(true)
+            .bundle_to_string()
+            .unwrap();
+        assert!(text.starts_with("#!/usr/bin/env run-cargo-script\n//! ```cargo\n"));
+        assert_eq!(text.matches("```cargo").count(), 1);
+        let merged = deps(&text);
+        assert_eq!(merged["regex"].as_str(), Some("^1"));
+        assert!(merged.contains_key("anyhow"));
+    }
+
+    #[test]
+    fn selected_features_fix_the_manifest() {
+        let bundle = |bundler: Bundler| {
+            let text = bundler.bundle_to_string().unwrap();
+            let file = syn::parse_file(&text).unwrap();
+            let manifest = attrs::embedded_manifest(&file.attrs).unwrap();
+            (text, toml::from_str::<toml::Value>(&manifest).unwrap())
+        };
+        let features = |dep: &toml::Value| -> Vec<String> {
+            dep["features"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter_map(|f| f.as_str().map(String::from))
+                .collect()
+        };
+        let bundler = || Bundler::new_in_memory("src/main.rs", fixture("features")).unwrap();
+
+        let (text, manifest) = bundle(bundler().features(&["tls"]).no_default_features());
+        assert!(manifest.get("features").is_none());
+        let deps = manifest["dependencies"].as_table().unwrap();
+        let names: Vec<_> = deps.keys().map(String::as_str).collect();
+        assert_eq!(names, ["log", "rustls"]);
+        assert!(deps["rustls"].get("optional").is_none());
+        assert_eq!(features(&deps["log"]), ["std"]);
+        assert!(text.contains("\"plain\""));
+        assert!(!text.contains("\"cli\""));
+        assert!(text.contains("true") && !text.contains("false"));
+
+        let (text, manifest) = bundle(bundler().all_features());
+        let deps = manifest["dependencies"].as_table().unwrap();
+        assert_eq!(deps.len(), 4);
+        assert!(deps.values().all(|dep| dep.get("optional").is_none()));
+        assert_eq!(features(&deps["clap"]), ["derive"]);
+        // serde is on, so the weak feature is too
+        assert_eq!(features(&deps["serde"]), ["derive"]);
+        assert!(text.contains("\"cli\""));
+
+        // without a selection the manifest keeps its features
+        let (_, manifest) = bundle(bundler());
+        assert!(manifest.get("features").is_some());
+        assert!(manifest["dependencies"].get("clap").is_some());
+    }
+
+    #[test]
+    fn selected_features_resolve_cfgs_in_the_source() {
+        // compared without whitespace, which depends on the printer
+        let bundle = |bundler: Bundler| {
+            let text = bundler
+                .formatting(Formatting::None)
+                .bundle_to_string()
+                .unwrap();
+            text.split_whitespace().collect::<String>()
+        };
+        let bundler = || Bundler::new_in_memory("src/main.rs", fixture("features")).unwrap();
+
+        let text = bundle(bundler().features(&["tls"]).no_default_features());
+        assert!(!text.contains("feature="));
+        // only the feature part of the predicate is decided
+        assert!(text.contains("#[cfg(unix)]fnsocket_dir"));
+        assert!(text.contains("cert:Option<String>"));
+        assert!(text.contains("Plain,Tls,"));
+        assert!(text.contains("Transport::Tls=>\"tlstransport\""));
+        // cli is off, so the impl is left empty
+        assert!(text.contains("implConfig{}"));
+
+        let text = bundle(bundler().features(&["cli"]).no_default_features());
+        assert!(!text.contains("socket_dir"));
+        assert!(!text.contains("cert"));
+        assert!(!text.contains("Tls"));
+        assert!(text.contains("fnfrom_args"));
+
+        // without a selection the cfgs are left for rustc
+        let text = bundle(bundler());
+        assert!(text.contains("#[cfg(all(feature=\"tls\",unix))]"));
+    }
+
+    #[test]
+    fn workspace_inheritance_is_resolved() {
+        let member = fixture("workspace").join("apps/tool");
+        let text = Bundler::new_in_memory("src/main.rs", member)
+            .unwrap()
+            .with_path_deps()
+            .bundle_to_string()
+            .unwrap();
+        let file = syn::parse_file(&text).unwrap();
+        let manifest = attrs::embedded_manifest(&file.attrs).unwrap();
+        assert!(!manifest.contains("workspace"));
+        let manifest: toml::Value = toml::from_str(&manifest).unwrap();
+        assert_eq!(manifest["package"]["version"].as_str(), Some("0.3.0"));
+        assert_eq!(manifest["package"]["edition"].as_str(), Some("2021"));
+        let serde = &manifest["dependencies"]["serde"];
+        assert_eq!(serde["version"].as_str(), Some("1.0"));
+        let features: Vec<_> = serde["features"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(toml::Value::as_str)
+            .collect();
+        assert_eq!(features, ["std", "derive"]);
+        // the inherited path dependency is found relative to the member, and inlined
+        assert!(manifest["dependencies"].get("shared").is_none());
+        assert!(text.contains("hello from the workspace"));
+    }
+
+    #[test]
+    fn path_dependencies_are_inlined_recursively() {
+        let report =
+            Bundler::new_with_dir("src/main.rs", out_dir("path-deps"), fixture("pathdeps"))
+                .unwrap()
+                .with_path_deps()
+                .bundle(Path::new("pathdeps.rs"))
+                .unwrap();
+        assert!(report
+            .inputs
+            .contains(&fixture("pathdeps").join("util/src/lib.rs")));
+        // a change to their dependencies changes the bundle too
+        assert!(report
+            .inputs
+            .contains(&fixture("pathdeps").join("util/Cargo.toml")));
+        let script = fs::read_to_string(&report.output).unwrap();
+        let file = syn::parse_file(&script).unwrap();
+        // util is reached twice, but inlined once
+        let modules: Vec<_> = file
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                syn::Item::Mod(m) => Some(m.ident.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(modules, ["util", "engine"]);
+        assert!(script.contains("crate::util::double(n)"));
+
+        let manifest = attrs::embedded_manifest(&file.attrs).unwrap();
+        let manifest: toml::Value = toml::from_str(&manifest).unwrap();
+        let deps = manifest["dependencies"].as_table().unwrap();
+        assert_eq!(deps.keys().collect::<Vec<_>>(), ["serde_json"]);
+    }
+
+    #[test]
+    fn dependencies_are_trimmed_per_bin() {
+        let trim = |bin: &str| {
+            Bundler::new_with_dir("src/main.rs", out_dir("trim-deps"), fixture("multibin"))
+                .unwrap()
+                .with_bin(bin)
+                .trim_dependencies(true)
+                .bundle(Path::new(&format!("{}.rs", bin)))
+                .unwrap()
+        };
+        let flat = trim("flat");
+        let trim_of_flat = flat.deps_trim.unwrap();
+        assert_eq!(trim_of_flat.kept, ["anyhow"]);
+        assert!(trim_of_flat.dropped.is_empty());
+
+        let mytool = trim("mytool");
+        assert_eq!(mytool.deps_trim.unwrap().dropped, ["anyhow"]);
+        let script = fs::read_to_string(&mytool.output).unwrap();
+        assert!(!script.contains("anyhow"));
+    }
+
+    #[test]
+    fn all_item_orders_bundle_the_fixture() {
+        let orders = [
+            ItemOrder::LibsFirst,
+            ItemOrder::LibsLast,
+            ItemOrder::Interleaved(vec![
+                Section::BinaryImports,
+                Section::Libs,
+                Section::BinaryItems,
+            ]),
+        ];
+        for (i, order) in orders.into_iter().enumerate() {
+            let report = Bundler::new_with_dir("src/main.rs", out_dir("orders"), fixture("simple"))
+                .unwrap()
+                .with_lib()
+                .item_order(order)
+                .bundle(Path::new(&format!("simple-{}.rs", i)))
+                .unwrap();
+            let output = fs::read_to_string(&report.output).unwrap();
+            let file = syn::parse_file(&output).unwrap();
+            assert_eq!(file.items.len(), 2);
+        }
+    }
+
+    #[test]
+    fn renames_apply_in_crate_order() {
+        let crates = vec![
+            ("common".to_string(), PathBuf::from("a")),
+            ("common".to_string(), PathBuf::from("b")),
+        ];
+        assert!(crate_modules(&crates, &[], &[]).is_err());
+
+        let renames = vec![("common".to_string(), "common_net".to_string())];
+        let modules = crate_modules(&crates, &[], &renames).unwrap();
+        let modules: Vec<_> = modules.iter().map(|m| m.module.as_str()).collect();
+        assert_eq!(modules, ["common_net", "common"]);
+
+        // a crate named by its root is left out of the renames
+        let at = vec![(PathBuf::from("a"), "common_a".to_string())];
+        let modules = crate_modules(&crates, &at, &renames).unwrap();
+        let modules: Vec<_> = modules.iter().map(|m| m.module.as_str()).collect();
+        assert_eq!(modules, ["common_a", "common_net"]);
+
+        let unmatched = vec![("other".to_string(), "x".to_string())];
+        assert!(crate_modules(&crates[..1], &[], &unmatched).is_err());
+    }
+
+    #[test]
+    fn crates_colliding_with_binary_modules_can_be_named() {
+        let dir = out_dir("collision");
+        fs::create_dir_all(dir.join("pkg/src")).unwrap();
+        fs::create_dir_all(dir.join("util/src")).unwrap();
+        let manifest = "[package]\nname = \"collides\"\nversion = \"0.1.0\"\nedition = \"2021\"\n";
+        fs::write(dir.join("pkg/Cargo.toml"), manifest).unwrap();
+        let main = "mod util;\nfn main() {\n    util::local();\n    ::util::shared();\n}\n";
+        fs::write(dir.join("pkg/src/main.rs"), main).unwrap();
+        fs::write(dir.join("pkg/src/util.rs"), "pub fn local() {}\n").unwrap();
+        fs::write(dir.join("util/src/lib.rs"), "pub fn shared() {}\n").unwrap();
+        let bundler = || Bundler::new_in_memory("src/main.rs", dir.join("pkg")).unwrap();
+
+        let err = bundler()
+            .with_crate_at("util", dir.join("util/src/lib.rs"))
+            .bundle_to_string()
+            .unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("Inlined crate util would become module util"));
+        assert!(err.contains("Bundler::with_crate_at_as"));
+
+        let text = bundler()
+            .with_crate_at_as("util", dir.join("util/src/lib.rs"), "bundled_util")
+            .bundle_to_string()
+            .unwrap();
+        assert!(text.contains("pub mod bundled_util {"));
+        assert!(text.contains("crate::bundled_util::shared();"));
+        assert!(text.contains("    util::local();"));
+    }
+
+    #[test]
+    fn nested_crates_bundle_the_fixture() {
+        let report = Bundler::new_with_dir("src/main.rs", out_dir("nest"), fixture("simple"))
+            .unwrap()
+            .with_lib()
+            .nest_under("__bundled")
+            .bundle(Path::new("simple.rs"))
+            .unwrap();
+        let output = fs::read_to_string(&report.output).unwrap();
+        assert!(output.contains("crate::__bundled::simple_fixture::run()"));
+        assert!(output.contains("use crate::__bundled::simple_fixture::greeting::Greeting;"));
+        assert!(output.contains("-> crate::__bundled::simple_fixture::greeting::Greeting"));
+        let file = syn::parse_file(&output).unwrap();
+        assert!(file.items.iter().any(|item| matches!(
+            item,
+            syn::Item::Mod(m) if m.ident == "__bundled"
+        )));
+    }
+
+    #[test]
+    fn dependency_reexported_by_the_lib_stays_reachable() {
+        let report = Bundler::new_with_dir("src/main.rs", out_dir("reexport"), fixture("reexport"))
+            .unwrap()
+            .with_lib()
+            .bundle(Path::new("reexport.rs"))
+            .unwrap();
+        let output = fs::read_to_string(&report.output).unwrap();
+        assert!(output.contains("pub use ::anyhow;"));
+        assert!(output.contains("crate::reexport_fixture::anyhow::Result"));
+        assert!(output.contains("anyhow = \"1.0\""));
+    }
+
+    #[test]
+    fn check_writes_nothing() {
+        let out = out_dir("check");
+        let report = Bundler::new_with_dir("src/main.rs", &out, fixture("simple"))
+            .unwrap()
+            .with_lib()
+            .check()
+            .unwrap();
+        assert!(report.output_size > 0);
+        assert!(report
+            .inputs
+            .contains(&fixture("simple").join("src/greeting.rs")));
+        assert_eq!(fs::read_dir(&out).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn sources_and_foreign_files_are_not_overwritten() {
+        let main = fixture("simple").join("src/main.rs");
+        let before = fs::read_to_string(&main).unwrap();
+        let err = Bundler::new_with_dir("src/main.rs", fixture("simple"), fixture("simple"))
+            .unwrap()
+            .bundle(Path::new("src/main.rs"))
+            .unwrap_err();
+        assert!(err.to_string().contains("Refusing to overwrite"));
+        assert_eq!(fs::read_to_string(&main).unwrap(), before);
+
+        let out = out_dir("overwrite");
+        fs::write(out.join("notes.rs"), "// hand written\n").unwrap();
+        let bundler = || Bundler::new_with_dir("src/main.rs", &out, fixture("simple")).unwrap();
+        assert!(bundler().bundle(Path::new("notes.rs")).is_err());
+        bundler()
+            .allow_overwrite(true)
+            .bundle(Path::new("notes.rs"))
+            .unwrap();
+        // what we wrote ourselves is fine to replace
+        bundler().bundle(Path::new("notes.rs")).unwrap();
+    }
+
+    #[test]
+    fn playground_flavor_drops_the_header() {
+        let bundler =
+            Bundler::new_with_dir("src/main.rs", out_dir("playground"), fixture("simple"))
+                .unwrap()
+                .with_lib()
+                .output_flavor(OutputFlavor::Playground);
+        // anyhow is available on the playground
+        assert!(bundler.check().unwrap().warnings.is_empty());
+        let report = bundler.bundle(Path::new("simple.rs")).unwrap();
+        let script = fs::read_to_string(&report.output).unwrap();
+        assert!(!script.starts_with("#!"));
+        assert!(!script.contains("```cargo"));
+        assert!(report.playground_url.unwrap().contains("edition=2021"));
+    }
+
+    #[test]
+    fn export_env_directives() {
+        let report = BundleReport {
+            output: fixture("simple").join("Cargo.toml"),
+            outputs: vec![],
+            written: true,
+            inputs: vec![fixture("simple").join("src/main.rs")],
+            playground_url: None,
+            renamed: vec![],
+            deps_graph: None,
+            passes: vec![],
+            clippy_diagnostics: vec![],
+            auto_allowed: vec![],
+            deps_trim: None,
+            warnings: vec![],
+            stats: Default::default(),
+        };
+        let directives = report.env_directives("TOOL_BUNDLE").unwrap();
+        let mut lines = directives.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("cargo:rustc-env=TOOL_BUNDLE=/"));
+        assert!(lines.next().unwrap().starts_with("cargo:rerun-if-changed="));
+        assert!(report.env_directives("TOOL-BUNDLE").is_err());
+        assert!(report.env_directives("1TOOL").is_err());
+
+        let report = BundleReport {
+            inputs: vec![PathBuf::from("src/evil\ncargo:rustc-cfg=x.rs")],
+            ..report
+        };
+        assert!(report.env_directives("TOOL_BUNDLE").is_err());
+    }
+
+    #[test]
+    fn depfile_lists_every_input() {
+        let dir = out_dir("dep file");
+        let report = Bundler::new_with_dir("src/main.rs", &dir, fixture("simple"))
+            .unwrap()
+            .with_lib()
+            .bundle(Path::new("simple.rs"))
+            .unwrap();
+        let path = dir.join("simple.rs.d");
+        report.write_depfile(&path).unwrap();
+
+        // split on whitespace that is not escaped
+        let depfile = fs::read_to_string(&path).unwrap().replace("\\\n", " ");
+        let mut words = vec![String::new()];
+        let mut chars = depfile.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => words.last_mut().unwrap().extend(chars.next()),
+                '$' => words.last_mut().unwrap().extend(chars.next()),
+                c if c.is_whitespace() => words.push(String::new()),
+                c => words.last_mut().unwrap().push(c),
+            }
+        }
+        words.retain(|w| !w.is_empty());
+        let target = words.remove(0);
+        assert_eq!(target, format!("{}:", report.output.display()));
+        let inputs: Vec<_> = words.iter().map(PathBuf::from).collect();
+        assert_eq!(inputs, report.inputs);
+    }
+
+    #[test]
+    fn polyglot_trampoline_still_parses() {
+        let bundler =
+            Bundler::new_with_dir("src/main.rs", out_dir("trampoline"), fixture("simple"))
+                .unwrap()
+                .with_lib()
+                .shebang_style(ShebangStyle::PolyglotTrampoline)
+                .unwrap();
+        let text = bundler.render(None).unwrap().text;
+        assert!(text.starts_with("#!/bin/sh\n//usr/bin/env rust-script"));
+        // the manifest still comes first for rust-script
+        assert!(text.lines().nth(2).unwrap().starts_with("//!"));
+        syn::parse_file(&text).unwrap();
+
+        assert!(
+            Bundler::new_with_dir("src/main.rs", out_dir("trampoline"), fixture("simple"))
+                .unwrap()
+                .shebang_style(ShebangStyle::Direct(Some("rust-script".into())))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn custom_and_omitted_shebangs() {
+        let bundler =
+            || Bundler::new_with_dir("src/main.rs", out_dir("shebang"), fixture("simple")).unwrap();
+        let text = bundler().no_shebang().render(None).unwrap().text;
+        assert!(text.starts_with("//! ```cargo\n"));
+        syn::parse_file(&text).unwrap();
+
+        let line = "#!/usr/local/bin/rust-script-wrapper --toolchain-version nightly";
+        let text = bundler().shebang(line).unwrap().render(None).unwrap().text;
+        assert!(text.starts_with(&format!("{}\n//!", line)));
+        assert!(bundler().shebang("/usr/bin/rust-script").is_err());
+        assert!(bundler()
+            .shebang("#!/usr/bin/rust-script\nfn x() {}")
+            .is_err());
+    }
+
+    #[test]
+    fn bundles_are_pretty_printed_in_process() {
+        let bundler = || {
+            Bundler::new_with_dir("src/main.rs", out_dir("pretty"), fixture("simple"))
+                .unwrap()
+                .with_lib()
+        };
+        let report = bundler().bundle(Path::new("simple.rs")).unwrap();
+        let script = fs::read_to_string(&report.output).unwrap();
+        assert!(script.starts_with("#!/usr/bin/env -S rust-script\n//! ```cargo\n"));
+        assert!(script.ends_with("\n// vim: ft=rust syntax=rust\n"));
+        assert!(script.contains("\nfn main() -> anyhow::Result<()> {\n"));
+        syn::parse_file(&script).unwrap();
+
+        let rendered = bundler().render(None).unwrap().text;
+        let report = bundler()
+            .formatting(Formatting::None)
+            .bundle(Path::new("unformatted.rs"))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&report.output).unwrap(), rendered);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rustfmt_is_run_on_the_script() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = out_dir("rustfmt");
+        fs::create_dir_all(&dir).unwrap();
+        let fake = |name: &str, script: &str| {
+            let path = dir.join(name);
+            fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        let bundle = |rustfmt: PathBuf, required: bool| {
+            Bundler::new_with_dir("src/main.rs", &dir, fixture("simple"))
+                .unwrap()
+                .formatting(Formatting::Rustfmt)
+                .formatting_required(required)
+                .rustfmt(rustfmt)
+                .bundle(Path::new("simple.rs"))
+        };
+
+        let args = dir.join("args");
+        let record = format!("echo \"$@\" > {}; cat", args.display());
+        bundle(fake("rustfmt-ok", &record), false).unwrap();
+        assert_eq!(fs::read_to_string(args).unwrap(), "--edition 2021\n");
+
+        let failing = fake("rustfmt-fail", "echo 'error: expected item' >&2; exit 1");
+        let err = bundle(failing, false).unwrap_err();
+        assert!(format!("{:#}", err).contains("error: expected item"));
+
+        let missing = dir.join("no-such-rustfmt");
+        let report = bundle(missing.clone(), false).unwrap();
+        assert!(report
+            .warnings
+            .iter()
+            .any(|w| w.kind == WarningKind::Formatting));
+        assert!(bundle(missing, true).is_err());
+    }
+
+    #[test]
+    fn bundles_can_stay_in_memory() {
+        let bundler = Bundler::new_in_memory("src/main.rs", fixture("simple"))
+            .unwrap()
+            .with_lib();
+        let text = bundler.bundle_to_string().unwrap();
+        assert!(text.starts_with("#!/usr/bin/env -S rust-script\n//! ```cargo\n"));
+        syn::parse_file(&text).unwrap();
+
+        let mut written = vec![];
+        bundler.bundle_to_writer(&mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), text);
+
+        // printed straight to the writer
+        let bundler = bundler.formatting(Formatting::None).skip_self_check();
+        let mut written = vec![];
+        bundler.bundle_to_writer(&mut written).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            bundler.bundle_to_string().unwrap()
+        );
+    }
+
+    #[test]
+    fn unchanged_bundles_are_not_rewritten() {
+        let bundle = || {
+            Bundler::new_with_dir("src/main.rs", out_dir("unchanged"), fixture("simple"))
+                .unwrap()
+                .with_lib()
+                .bundle(Path::new("simple.rs"))
+                .unwrap()
+        };
+        let first = bundle();
+        let modified = || fs::metadata(&first.output).unwrap().modified().unwrap();
+        let before = modified();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let second = bundle();
+        assert!(!second.written);
+        assert_eq!(modified(), before);
+
+        // anything else is overwritten, even if it only differs in formatting
+        fs::write(
+            &first.output,
+            fs::read_to_string(&first.output).unwrap() + "\n",
+        )
+        .unwrap();
+        assert!(bundle().written);
+    }
+
+    #[test]
+    fn watching_picks_up_new_modules() {
+        let dir = out_dir("watch");
+        fs::create_dir_all(dir.join("pkg/src")).unwrap();
+        let manifest = "[package]\nname = \"watched\"\nversion = \"0.1.0\"\nedition = \"2021\"\n";
+        fs::write(dir.join("pkg/Cargo.toml"), manifest).unwrap();
+        fs::write(dir.join("pkg/src/main.rs"), "fn main() {}\n").unwrap();
+
+        let mut bundles = 0;
+        Bundler::new_with_dir("src/main.rs", &dir, dir.join("pkg"))
+            .unwrap()
+            .watch(
+                Path::new("watched.rs"),
+                Duration::from_millis(50),
+                |result| {
+                    bundles += 1;
+                    let inputs = &result.as_ref().unwrap().inputs;
+                    match bundles {
+                        1 => {
+                            assert_eq!(inputs.len(), 2);
+                            let main = "mod extra;\nfn main() { extra::run() }\n";
+                            fs::write(dir.join("pkg/src/extra.rs"), "pub fn run() {}\n").unwrap();
+                            fs::write(dir.join("pkg/src/main.rs"), main).unwrap();
+                            ControlFlow::Continue(())
+                        }
+                        _ => {
+                            assert!(inputs.contains(&dir.join("pkg/src/extra.rs")));
+                            ControlFlow::Break(())
+                        }
+                    }
+                },
+            );
+        assert_eq!(bundles, 2);
+    }
+
+    #[test]
+    fn cfg_gated_module_variants_are_kept() {
+        let bundler = Bundler::new_in_memory("src/main.rs", fixture("cfg-split"))
+            .unwrap()
+            .with_lib()
+            .strip_tests(true);
+        let text = bundler.bundle_to_string().unwrap();
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(text.contains("#[cfg(unix)] mod imp { pub const NAME: &str = \"unix\";"));
+        assert!(text.contains("#[cfg(windows)] #[path = \"imp_windows.rs\"] mod imp {"));
+        assert!(text.contains("pub const NAME: &str = \"windows\";"));
+    }
+
+    #[test]
+    fn lenient_bundles_stub_broken_modules() {
+        let bundler = || Bundler::new_in_memory("src/main.rs", fixture("cfg-mods")).unwrap();
+        let err = bundler().bundle_to_string().unwrap_err();
+        assert!(err.to_string().contains("as mod freebsd_impl"));
+
+        let dir = out_dir("lenient");
+        let report = bundler()
+            .lenient(true)
+            .bundle(&dir.join("lenient.rs"))
+            .unwrap();
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].message.contains("as mod freebsd_impl"));
+        assert_eq!(report.warnings[0].kind, WarningKind::ModuleNotLoaded);
+        let script = fs::read_to_string(&report.output).unwrap();
+        assert!(script.contains("mod freebsd_impl {}"));
+    }
+
+    #[test]
+    fn comments_are_preserved_on_request() {
+        let bundler = || {
+            Bundler::new_in_memory("src/main.rs", fixture("comments"))
+                .unwrap()
+                .with_lib()
+        };
+        let text = bundler()
+            .preserve_comments(true)
+            .bundle_to_string()
+            .unwrap();
+        assert!(text.contains("    /* width times height,\n       nothing fancy */\n"));
+        assert!(text.contains("pub width: u32, // note: in pixels\n"));
+        assert!(text.contains("// the only computation there is\npub fn area("));
+        syn::parse_file(&text).unwrap();
+
+        let text = bundler().bundle_to_string().unwrap();
+        assert!(!text.contains("width times height"));
+        assert!(!text.contains("// note"));
+    }
+
+    #[test]
+    fn unused_items_are_pruned_on_request() {
+        let render = |prune: bool| {
+            Bundler::new_with_dir("src/main.rs", out_dir("prune"), fixture("prune"))
+                .unwrap()
+                .with_lib()
+                .prune_unused(prune)
+                .render(None)
+                .unwrap()
+        };
+        let rendered = render(true);
+        let text = &rendered.text;
+        assert!(text.contains("pub fn used ( )"));
+        assert!(text.contains("pub fn helper ( count : u32 )"));
+        assert!(text.contains("impl Counter"));
+        assert!(!text.contains("fn unused"));
+        assert!(!text.contains("fn orphan"));
+        let pruned = rendered
+            .passes
+            .iter()
+            .find(|p| p.name == "prune-unused")
+            .unwrap();
+        assert_eq!(pruned.changes, 2);
+        assert_eq!(rendered.stats.stripped.pruned, 2);
+
+        let text = render(false).text;
+        assert!(text.contains("fn unused") && text.contains("fn orphan"));
+    }
+
+    #[test]
+    fn report_has_stats() {
+        let report = Bundler::new_with_dir("src/main.rs", out_dir("stats"), fixture("simple"))
+            .unwrap()
+            .with_lib()
+            .bundle(Path::new("simple.rs"))
+            .unwrap();
+        let stats = &report.stats;
+        let script = fs::read_to_string(&report.output).unwrap();
+        assert_eq!(stats.bytes, script.len());
+        assert_eq!(stats.lines, script.lines().count());
+        assert_eq!(stats.crates.len(), 1);
+        let lib = &stats.crates[0];
+        assert_eq!(
+            (lib.name.as_str(), lib.module.as_str()),
+            ("simple_fixture", "simple_fixture")
+        );
+        let sources: Vec<_> = lib
+            .sources
+            .iter()
+            .filter_map(|(path, _)| path.file_name())
+            .collect();
+        assert_eq!(sources, ["lib.rs", "greeting.rs"]);
+        assert!(lib.sources.iter().all(|(_, size)| *size > 0));
+        assert_eq!(lib.items, 6);
+        assert_eq!(
+            stats.dependencies,
+            [("anyhow".to_string(), "1.0".to_string())]
+        );
+        assert_eq!(stats.stripped, StrippedItems::default());
+        let phases: Vec<_> = stats.phases.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            phases,
+            ["inline", "passes", "manifest", "print", "format", "write"]
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["stats"]["crates"][0]["items"], 6);
+    }
+
+    #[test]
+    fn exported_macros_keep_resolving() {
+        let text = Bundler::new_with_dir("src/main.rs", out_dir("macros"), fixture("macros"))
+            .unwrap()
+            .with_lib()
+            .render(None)
+            .unwrap()
+            .text;
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        // the macro reaches into the crate, which is a module now
+        assert!(text.contains("$ crate :: macros_fixture :: errors :: fail ("));
+        // and the binary reaches the macro through the module
+        assert!(text.contains("pub ( crate ) use crate :: ensure_ok ;"));
+        assert!(text.contains("use crate :: macros_fixture :: ensure_ok ;"));
+        assert!(text.contains("crate :: macros_fixture :: ensure_ok ! ("));
+    }
+
+    #[test]
+    fn macros_are_reached_in_either_order() {
+        let bundle = |order: ItemOrder| {
+            let text = Bundler::new_in_memory("src/bin/bare.rs", fixture("macros"))
+                .unwrap()
+                .with_lib()
+                .item_order(order)
+                .bundle_to_string()
+                .unwrap();
+            let file = syn::parse_file(&text).unwrap();
+            let position = |wanted: fn(&syn::Item) -> bool| file.items.iter().position(wanted);
+            let module = position(|item| matches!(item, syn::Item::Mod(_)));
+            let main = position(|item| matches!(item, syn::Item::Fn(f) if f.sig.ident == "main"));
+            (
+                text.split_whitespace().collect::<String>(),
+                module.unwrap(),
+                main.unwrap(),
+            )
+        };
+        for order in [ItemOrder::LibsFirst, ItemOrder::LibsLast] {
+            let (text, module, main) = bundle(order.clone());
+            assert_eq!(module < main, order == ItemOrder::LibsFirst);
+            // the crate is a module now and its exported macro is at the root of the bundle
+            assert!(!text.contains("externcrate"));
+            assert!(text.contains("#[macro_export]macro_rules!ensure_ok"));
+            assert!(text.contains("letn:u32=ensure_ok!(\"42\".parse::<u32>());"));
+        }
+    }
+
+    #[test]
+    fn minify_shrinks_the_bundle() {
+        let bundler = || {
+            Bundler::new_in_memory("src/main.rs", fixture("docs"))
+                .unwrap()
+                .with_lib()
+                .formatting(Formatting::None)
+        };
+        let full = bundler().bundle_to_string().unwrap();
+        let minified = bundler().minify(true).bundle_to_string().unwrap();
+        assert!(minified.len() < full.len());
+        assert!(minified.starts_with("#!/usr/bin/env -S rust-script\n//! ```cargo\n"));
+        assert!(!minified.contains("///") && !minified.contains("Prints a distance"));
+        assert!(!minified.contains("deny") && !minified.contains("allow"));
+        assert!(!minified.contains("deprecated") && !minified.contains("vim:"));
+        assert!(minified.contains("doc ( hidden )"));
+        assert!(!minified.contains("\n\n"));
+        syn::parse_file(&minified).unwrap();
+    }
+
+    #[test]
+    fn relative_includes_are_inlined() {
+        let bundler = || {
+            Bundler::new_with_dir("src/main.rs", out_dir("includes"), fixture("includes")).unwrap()
+        };
+        let rendered = bundler().render(None).unwrap();
+        let text = &rendered.text;
+        assert!(text.contains(r#"pub const SCHEMA : & str = "CREATE TABLE t (id INTEGER);\n""#));
+        assert!(text.contains(r#"b"SELECT 1;\n""#));
+        assert!(text.contains("pub fn count ( ) -> usize"));
+        assert!(!text.contains("\"generated.rs\""));
+        assert!(rendered
+            .inputs
+            .contains(&fixture("includes").join("data/schema.sql")));
+        // the generated file is only known when the script is built
+        assert_eq!(rendered.warnings.len(), 1);
+        let warning = &rendered.warnings[0];
+        assert!(warning
+            .message
+            .starts_with("src/main.rs:4: include! without a literal path"));
+        assert_eq!(warning.kind, WarningKind::Include);
+        assert_eq!(warning.path.as_deref(), Some(Path::new("src/main.rs")));
+        assert_eq!(warning.line, Some(4));
+
+        let text = bundler().inline_includes(false).render(None).unwrap().text;
+        assert!(text.contains("include_str !"));
+    }
+
+    #[test]
+    fn module_files_outside_the_crate() {
+        let app = fixture("escape").join("app");
+        let bundler = |bin: &str| {
+            Bundler::new_with_dir("src/main.rs", out_dir("escape"), &app)
+                .unwrap()
+                .with_bin(bin)
+                .containment(ContainmentPolicy::WorkspaceOnly)
+        };
+        // shared code elsewhere in the workspace is fine
+        let report = bundler("app").check().unwrap();
+        assert!(report
+            .inputs
+            .contains(&fixture("escape").join("shared/ring.rs")));
+        // but not beyond
+        let err = bundler("leak").check().unwrap_err();
+        assert!(err.to_string().contains("outside the workspace"));
+        Bundler::new_with_dir("src/main.rs", out_dir("escape"), &app)
+            .unwrap()
+            .with_bin("leak")
+            .check()
+            .unwrap();
+    }
+
+    #[test]
+    fn header_and_footer_templates() {
+        let bundler =
+            || Bundler::new_with_dir("src/main.rs", out_dir("templates"), fixture("simple"));
+        assert!(bundler().unwrap().with_header_template("{nope}").is_err());
+
+        let report = bundler()
+            .unwrap()
+            .with_header_template("Generated from {bin_name} {version}\nDo not edit")
+            .unwrap()
+            .with_footer_template("end of {bin_name}")
+            .unwrap()
+            .bundle(Path::new("simple.rs"))
+            .unwrap();
+        let script = fs::read_to_string(&report.output).unwrap();
+        // the manifest stays first
+        let manifest = script.find("```cargo").unwrap();
+        let header = script
+            .find("// Generated from simple-fixture 0.1.0\n// Do not edit\n")
+            .unwrap();
+        assert!(manifest < header);
+        assert!(script.ends_with("// end of simple-fixture\n"));
+    }
+
+    #[test]
+    fn header_comment_and_footer_can_be_removed() {
+        let bundler = || {
+            Bundler::new_with_dir("src/main.rs", out_dir("header-comment"), fixture("simple"))
+                .unwrap()
+        };
+        let text = bundler().render(None).unwrap().text;
+        assert!(text.ends_with("// vim: ft=rust syntax=rust\n"));
+
+        let text = bundler()
+            .header_comment(Some("DO NOT EDIT\ngenerated from src/main.rs".into()))
+            .footer(None)
+            .render(None)
+            .unwrap()
+            .text;
+        let banner = "// DO NOT EDIT\n// generated from src/main.rs\n//! ```cargo\n";
+        assert!(text.starts_with(&format!("#!/usr/bin/env -S rust-script\n{}", banner)));
+        assert!(!text.contains("vim:"));
+        syn::parse_file(&text).unwrap();
+    }
+
+    #[test]
+    fn license_goes_before_the_manifest() {
+        let dir = out_dir("license");
+        let license = "Copyright (c) 2021 The Authors\r\n\r\n\
+            Permission is hereby granted, free of charge, to any person obtaining a copy of this \
+            software, to deal in the Software without restriction.\r\n\r\n\
+            THE SOFTWARE IS PROVIDED \"AS IS\", WITHOUT WARRANTY OF ANY KIND.\r\n";
+        fs::write(dir.join("LICENSE"), license).unwrap();
+        let text = Bundler::new_with_dir("src/main.rs", &dir, fixture("simple"))
+            .unwrap()
+            .license_header_from_file(dir.join("LICENSE"))
+            .header_comment(Some("DO NOT EDIT".into()))
+            .render(None)
+            .unwrap()
+            .text;
+        let expected = "#!/usr/bin/env -S rust-script\n\
+            // Copyright (c) 2021 The Authors\n\
+            //\n\
+            // Permission is hereby granted, free of charge, to any person obtaining a copy of \
+            this software, to deal in the Software without restriction.\n\
+            //\n\
+            // THE SOFTWARE IS PROVIDED \"AS IS\", WITHOUT WARRANTY OF ANY KIND.\n\
+            \n\
+            // DO NOT EDIT\n\
+            //! ```cargo\n";
+        assert!(text.starts_with(expected));
+        let file = syn::parse_file(&text).unwrap();
+        assert!(file.shebang.is_some());
+        let docs = file
+            .attrs
+            .iter()
+            .filter(|attr| attr.path.is_ident("doc"))
+            .count();
+        assert!(docs > 0);
+        assert!(file
+            .attrs
+            .iter()
+            .all(|attr| !attr.tokens.to_string().contains("Copyright")));
+
+        let err = Bundler::new_with_dir("src/main.rs", &dir, fixture("simple"))
+            .unwrap()
+            .license_header_from_manifest()
+            .render(None)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("no license-file"));
+    }
+
+    #[test]
+    fn provenance_is_recorded_on_request() {
+        let bundler = || Bundler::new_in_memory("src/main.rs", fixture("simple")).unwrap();
+        let provenance = Provenance::new()
+            .timestamp(false)
+            .consts(true)
+            .commit("abc1234", false);
+        let text = bundler()
+            .provenance(Some(provenance))
+            .bundle_to_string()
+            .unwrap();
+        let comment = format!(
+            "#!/usr/bin/env -S rust-script\n\
+            // Generated by rust-script-bundler v{} from simple-fixture v0.1.0 (abc1234)\n\
+            //! ```cargo\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(text.starts_with(&comment));
+        assert!(text.contains("pub const BUNDLE_VERSION: &str = \"0.1.0\";"));
+        assert!(text.contains("pub const BUNDLE_COMMIT: &str = \"abc1234\";"));
+        assert!(!text.contains("BUNDLE_DATE"));
+
+        let text = bundler().bundle_to_string().unwrap();
+        assert!(!text.contains("Generated by rust-script-bundler"));
+    }
+
+    #[test]
+    fn embedded_inputs_hash_checks_freshness() {
+        let report = Bundler::new_with_dir("src/main.rs", out_dir("stamp"), fixture("simple"))
+            .unwrap()
+            .with_lib()
+            .embed_inputs_hash(true)
+            .bundle(Path::new("simple.rs"))
+            .unwrap();
+        let script = fs::read_to_string(&report.output).unwrap();
+        assert!(script.contains("// bundler-input: src/main.rs\n"));
+        assert_eq!(
+            check_freshness(&report.output).unwrap(),
+            Freshness::UpToDate
+        );
+
+        // tampering with the listed inputs shows
+        let tampered = out_dir("stamp").join("tampered.rs");
+        fs::write(
+            &tampered,
+            script.replace("// bundler-input: src/main.rs\n", ""),
+        )
+        .unwrap();
+        assert!(matches!(
+            check_freshness(&tampered).unwrap(),
+            Freshness::Stale(_)
+        ));
+
+        let plain = out_dir("stamp").join("plain.rs");
+        fs::write(&plain, "fn main() {}\n").unwrap();
+        assert!(matches!(
+            check_freshness(&plain).unwrap(),
+            Freshness::Unverifiable(_)
+        ));
+    }
+
+    #[test]
+    fn edition_override_reaches_the_manifest() {
+        let report = Bundler::new_with_dir("src/main.rs", out_dir("edition"), fixture("simple"))
+            .unwrap()
+            .with_lib()
+            .edition(Edition::E2018)
+            .check()
+            .unwrap();
+        assert!(report.warnings.iter().any(|w| w
+            .message
+            .ends_with("declares edition 2021, but the bundle is for 2018")));
+
+        let rendered = Bundler::new_with_dir("src/main.rs", out_dir("edition"), fixture("simple"))
+            .unwrap()
+            .edition(Edition::E2018)
+            .render(None)
+            .unwrap();
+        assert_eq!(rendered.edition, "2018");
+        assert!(rendered.text.contains("//! edition = \"2018\"\n"));
+    }
+
+    #[test]
+    fn streamed_crates_render_the_same() {
+        let render = |nest: bool, stream: bool| {
+            let mut bundler =
+                Bundler::new_with_dir("src/main.rs", out_dir("stream"), fixture("simple"))
+                    .unwrap()
+                    .with_lib()
+                    .strip_tests(true)
+                    .stream_crates(stream);
+            if nest {
+                bundler = bundler.nest_under("__bundled");
+            }
+            let rendered = bundler.render(None).unwrap();
+            let file = syn::parse_file(&rendered.text).unwrap();
+            (quote!(#file).to_string(), rendered.passes)
+        };
+        for nest in [false, true] {
+            let (streamed, streamed_passes) = render(nest, true);
+            let (whole, whole_passes) = render(nest, false);
+            assert_eq!(streamed, whole);
+            let changes = |passes: &[PassReport]| -> Vec<_> {
+                passes.iter().map(|p| (p.name.clone(), p.changes)).collect()
+            };
+            assert_eq!(changes(&streamed_passes), changes(&whole_passes));
+        }
+    }
+
+    #[test]
+    fn cached_crates_render_the_same() {
+        let cache = out_dir("cache");
+        let render = || {
+            Bundler::new_with_dir("src/main.rs", out_dir("cache-out"), fixture("simple"))
+                .unwrap()
+                .with_lib()
+                .cache_dir(&cache)
+                .render(None)
+                .unwrap()
+        };
+        let fresh = render();
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+        let cached = render();
+        assert_eq!(fresh.text, cached.text);
+        assert_eq!(fresh.inputs, cached.inputs);
+    }
+
+    #[test]
+    fn excluded_modules_are_dropped() {
+        let bundler = || {
+            Bundler::new_with_dir("src/main.rs", out_dir("globs"), fixture("globs"))
+                .unwrap()
+                .with_lib()
+                .with_crate_features("globs_fixture", &[])
+        };
+        // also off by cfg, dropping it first must not trip up cfg stripping
+        let report = bundler().respect_package_globs(true).check().unwrap();
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].message.contains("experimental"));
+        assert_eq!(report.warnings[0].kind, WarningKind::ExcludedModule);
+        // it was still read
+        assert!(report
+            .inputs
+            .iter()
+            .any(|input| input.ends_with("src/experimental/mod.rs")));
+
+        // cfg stripping alone removes it silently
+        let report = bundler().check().unwrap();
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn missing_files_of_disabled_modules_are_fine() {
+        let bundler =
+            || Bundler::new_with_dir("src/main.rs", out_dir("cfg-mods"), fixture("cfg-mods"));
+        // undecided, so the file is required as before
+        assert!(bundler().unwrap().check().is_err());
+
+        let linux = CfgSet::new().with_value("target_os", "linux");
+        let report = bundler()
+            .unwrap()
+            .resolve_target_cfgs(linux)
+            .bundle(Path::new("cfg-mods.rs"))
+            .unwrap();
+        let output = fs::read_to_string(&report.output).unwrap();
+        assert!(!output.contains("freebsd_impl"));
+        assert!(output.contains("mod linux_impl"));
+    }
+
+    /// Slow and needs the fixtures' dependencies in the local registry, so only runs with
+    /// `RUST_SCRIPT_BUNDLER_COMPILE_CHECK` set.
+    #[test]
+    fn fixtures_compile() {
+        if env::var_os("RUST_SCRIPT_BUNDLER_COMPILE_CHECK").is_none() {
+            return;
+        }
+        let fixtures = [("simple", "src/main.rs"), ("reexport", "src/main.rs")];
+        for (name, bin) in fixtures {
+            let report = Bundler::new_with_dir(bin, out_dir("compile"), fixture(name))
+                .unwrap()
+                .with_lib()
+                .bundle(Path::new(&format!("{}.rs", name)))
+                .unwrap();
+            testing::CompileCheck::new().run(&report.output).unwrap();
+        }
+    }
+
+    #[test]
+    fn path_dependency_features_are_inferred() {
+        let helpers = fixture("features").join("helpers/src/lib.rs");
+        let report = Bundler::new_with_dir("src/main.rs", out_dir("features"), fixture("features"))
+            .unwrap()
+            .with_crate_at("helpers", &helpers)
+            .bundle(Path::new("features.rs"))
+            .unwrap();
+        let output = fs::read_to_string(&report.output).unwrap();
+        // default features are off, so the heavy dependency stays out
+        assert!(!output.contains("regex"));
+        assert!(output.contains("fn fast"));
+        assert!(!output.contains("feature ="));
+        // the inlined crate is no dependency of the script anymore
+        assert!(!output.contains("path = \"helpers\""));
+
+        let report = Bundler::new_with_dir("src/main.rs", out_dir("features"), fixture("features"))
+            .unwrap()
+            .with_crate_at("helpers", &helpers)
+            .with_crate_features("helpers", &["heavy"])
+            .bundle(Path::new("features-heavy.rs"))
+            .unwrap();
+        let output = fs::read_to_string(&report.output).unwrap();
+        assert!(output.contains("regex"));
+    }
+
+    #[test]
+    fn streaming_leaves_the_bundle_the_same() {
+        let bundle = |stream: bool| {
+            Bundler::new_in_memory("src/main.rs", fixture("pathdeps"))
+                .unwrap()
+                .with_path_deps()
+                .stream_crates(stream)
+                .formatting(Formatting::None)
+                .bundle_to_string()
+                .unwrap()
+        };
+        assert_eq!(bundle(true), bundle(false));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_failures_are_reported_together() {
+        let dir = out_dir("parallel-failures");
+        for name in ["a", "b"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join("lib.rs"), "fn broken( {}\n").unwrap();
+        }
+        let err = Bundler::new_in_memory("src/main.rs", fixture("simple"))
+            .unwrap()
+            .with_crate_at("a", dir.join("a/lib.rs"))
+            .with_crate_at("b", dir.join("b/lib.rs"))
+            .bundle_to_string()
+            .unwrap_err();
+        assert!(format!("{:#}", err).starts_with("2 crates failed to inline:\n"));
+    }
+
+    #[test]
+    fn scripts_are_written_whole_and_executable() {
+        let dir = out_dir("atomic write");
+        let bundler = || {
+            Bundler::new_with_dir("src/main.rs", &dir, fixture("simple"))
+                .unwrap()
+                .with_lib()
+        };
+        bundler().bundle(Path::new("simple.rs")).unwrap();
+        bundler()
+            .no_shebang()
+            .bundle(Path::new("plain.rs"))
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = |name: &str| fs::metadata(dir.join(name)).unwrap().permissions().mode();
+            assert_eq!(mode("simple.rs") & 0o777, 0o755);
+            assert_eq!(mode("plain.rs") & 0o111, 0);
+        }
+
+        // fails rendering
+        let missing = dir.join("missing.rs");
+        assert!(bundler()
+            .with_crate_at("missing", missing)
+            .bundle(Path::new("a.rs"))
+            .is_err());
+        // fails renaming over the directory in the way
+        fs::create_dir_all(dir.join("taken.rs")).unwrap();
+        let err = bundler()
+            .allow_overwrite(true)
+            .bundle(Path::new("taken.rs"))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Failed to write"));
+
+        let tmp = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".tmp"))
+            .count();
+        assert_eq!(tmp, 0);
     }
 }
@@ -0,0 +1,896 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use toml::Value;
+use toml_edit::{value, Array, Document, InlineTable, Item, Table, TableLike};
+
+use crate::cfg::CfgSet;
+use crate::edition::Edition;
+use crate::features::EnabledFeatures;
+use crate::paths;
+use crate::resolve::{self, Dependency};
+use crate::warning::{Warning, WarningKind};
+
+const DEP_TABLES: &[&str] = &["dependencies", "dev-dependencies", "build-dependencies"];
+/// Tables of a manifest that mean something for a script
+const SCRIPT_TABLES: &[&str] = &["package", "dependencies", "features", "profile", "target"];
+/// Keys of `[package]` that mean something for a script
+const SCRIPT_PACKAGE_KEYS: &[&str] = &["name", "version", "edition"];
+
+/// Resolve `[target.'cfg(...)'.*dependencies]` tables of the manifest `text` that `cfgs`
+/// decides: tables whose predicate is false are dropped, and those whose predicate is true are
+/// merged into the plain dependency tables. Tables keyed by a target triple or an undecided
+/// predicate are kept, as is the formatting of the manifest.
+pub fn resolve_target_cfgs(text: &str, cfgs: &CfgSet) -> Result<String> {
+    let mut doc: Document = text.parse().context("Invalid manifest")?;
+    let root = doc.as_table_mut();
+    let mut targets = match root.remove("target") {
+        Some(targets) => targets,
+        None => return Ok(text.into()),
+    };
+    let kept = targets
+        .as_table_like_mut()
+        .context("Manifest key `target` is not a table")?;
+
+    let keys: Vec<_> = kept.iter().map(|(key, _)| key.to_string()).collect();
+    for key in keys {
+        let decided = if key.starts_with("cfg(") {
+            cfgs.eval_str(&key)?
+        } else {
+            None
+        };
+        match decided {
+            None => {}
+            Some(false) => {
+                log::info!("Dropping dependencies for target {}", key);
+                kept.remove(&key);
+            }
+            Some(true) => {
+                log::info!("Promoting dependencies for target {}", key);
+                let tables = kept.remove(&key).unwrap_or_default();
+                for table in DEP_TABLES {
+                    let deps = match tables
+                        .as_table_like()
+                        .and_then(|tables| tables.get(table))
+                        .and_then(Item::as_table_like)
+                    {
+                        Some(deps) => deps,
+                        None => continue,
+                    };
+                    let plain = root
+                        .entry(table)
+                        .or_insert(Item::Table(Table::new()))
+                        .as_table_like_mut()
+                        .with_context(|| format!("Manifest key `{}` is not a table", table))?;
+                    for (name, spec) in deps.iter() {
+                        match plain.get(name) {
+                            Some(existing) if plain_value(existing) != plain_value(spec) => bail!(
+                                "Dependency {} for target {} conflicts with its declaration in [{}]",
+                                name,
+                                key,
+                                table
+                            ),
+                            Some(_) => {}
+                            None => {
+                                plain.insert(name, spec.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if !kept.is_empty() {
+        root.insert("target", targets);
+    }
+    Ok(doc.to_string())
+}
+
+/// `item` without its formatting, to compare declarations by what they say
+fn plain_value(item: &Item) -> Option<Value> {
+    let mut doc = Document::new();
+    doc.as_table_mut().insert("item", item.clone());
+    let mut value: Value = toml::from_str(&doc.to_string()).ok()?;
+    value.as_table_mut()?.remove("item")
+}
+
+/// Set the edition of the package in the manifest `text` to `edition`, keeping its formatting
+pub fn set_edition(text: &str, edition: Edition) -> Result<String> {
+    let mut doc: Document = text.parse().context("Invalid manifest")?;
+    let package = match doc
+        .as_table_mut()
+        .get_mut("package")
+        .and_then(Item::as_table_like_mut)
+    {
+        Some(package) => package,
+        None => bail!("Manifest has no [package] to set the edition in"),
+    };
+    package.insert("edition", value(edition.to_string()));
+    Ok(doc.to_string())
+}
+
+/// Fix the features of the package in the manifest `text` to `enabled`, keeping its formatting:
+/// optional dependencies that are off are removed, those that are on become plain dependencies,
+/// the features enabled on dependencies are added to them, and `[features]` is removed. The
+/// script then builds the same whatever features it is built with.
+pub fn select_features(text: &str, enabled: &EnabledFeatures) -> Result<String> {
+    let mut doc: Document = text.parse().context("Invalid manifest")?;
+    doc.as_table_mut().remove("features");
+    for_each_dep_table(&mut doc, |entries, kind, _| {
+        if kind != "dependencies" {
+            return Ok(());
+        }
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.to_string()).collect();
+        for name in names {
+            let item = match entries.get_mut(&name) {
+                Some(item) => item,
+                None => continue,
+            };
+            let optional = item
+                .as_table_like()
+                .and_then(|spec| spec.get("optional"))
+                .and_then(Item::as_bool)
+                == Some(true);
+            if optional && !enabled.deps.contains(&name) {
+                log::debug!("Removing disabled optional dependency {}", name);
+                entries.remove(&name);
+                continue;
+            }
+            if let Some(spec) = item.as_table_like_mut().filter(|_| optional) {
+                spec.remove("optional");
+            }
+            if let Some(features) = enabled.dep_features.get(&name) {
+                add_features(item, &features.iter().cloned().collect::<Vec<_>>());
+            }
+        }
+        Ok(())
+    })?;
+    Ok(doc.to_string())
+}
+
+/// Remove what only makes sense for a package from the manifest `text`, keeping its formatting:
+/// targets like `[lib]` and `[[bin]]`, dev and build dependencies, `[workspace]`, and all of
+/// `[package]` but its name, version and edition. Dependencies, features, profiles and
+/// target-specific dependencies are kept.
+pub fn scrub(text: &str) -> Result<String> {
+    let mut doc: Document = text.parse().context("Invalid manifest")?;
+    let root = doc.as_table_mut();
+    let removed: Vec<_> = root
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| !SCRIPT_TABLES.contains(&key.as_str()))
+        .collect();
+    for key in removed {
+        log::debug!("Removing {} from the embedded manifest", key);
+        root.remove(&key);
+    }
+    if let Some(package) = root.get_mut("package").and_then(Item::as_table_like_mut) {
+        let removed: Vec<_> = package
+            .iter()
+            .map(|(key, _)| key.to_string())
+            .filter(|key| !SCRIPT_PACKAGE_KEYS.contains(&key.as_str()))
+            .collect();
+        for key in removed {
+            package.remove(&key);
+        }
+    }
+    if let Some(targets) = root.get_mut("target").and_then(Item::as_table_like_mut) {
+        let keys: Vec<_> = targets.iter().map(|(key, _)| key.to_string()).collect();
+        for key in keys {
+            if let Some(target) = targets.get_mut(&key).and_then(Item::as_table_like_mut) {
+                target.remove("dev-dependencies");
+                target.remove("build-dependencies");
+            }
+        }
+    }
+    Ok(doc.to_string())
+}
+
+/// Remove the `[dev-dependencies]` tables of the manifest `text`, plain and platform specific
+pub fn drop_dev_dependencies(text: &str) -> Result<String> {
+    let mut doc: Document = text.parse().context("Invalid manifest")?;
+    let root = doc.as_table_mut();
+    root.remove("dev-dependencies");
+    if let Some(targets) = root.get_mut("target").and_then(Item::as_table_like_mut) {
+        let keys: Vec<_> = targets.iter().map(|(key, _)| key.to_string()).collect();
+        for key in keys {
+            if let Some(target) = targets.get_mut(&key).and_then(Item::as_table_like_mut) {
+                target.remove("dev-dependencies");
+            }
+        }
+    }
+    Ok(doc.to_string())
+}
+
+/// Add the dependencies of inlined crates to the manifest `text` of the package `package`.
+/// `crates` pairs each crate's name with its dependencies. A dependency already there must be
+/// the same package with the same version requirement, and gets the union of the features.
+pub fn merge_dependencies(
+    text: &str,
+    package: &str,
+    crates: &[(String, Vec<Dependency>)],
+) -> Result<String> {
+    if crates.is_empty() {
+        return Ok(text.into());
+    }
+    let mut doc: Document = text.parse().context("Invalid manifest")?;
+    // which crate added a dependency, by table and name, for messages
+    let mut origins: BTreeMap<(Option<String>, String), String> = BTreeMap::new();
+    for (krate, deps) in crates {
+        for dep in deps {
+            let key = (dep.target.clone(), dep.name.clone());
+            let entries = dep_table(&mut doc, dep.target.as_deref())?;
+            if !entries.contains_key(&dep.name) {
+                entries.insert(&dep.name, dependency_item(dep));
+                origins.insert(key, format!("crate {}", krate));
+                continue;
+            }
+            let item = match entries.get_mut(&dep.name) {
+                Some(item) => item,
+                None => continue,
+            };
+            let (other_package, other_req) = requirement(&dep.name, item);
+            if other_package != dep.package || resolve::normalize_req(&other_req) != dep.req {
+                let origin = origins
+                    .get(&key)
+                    .cloned()
+                    .unwrap_or_else(|| format!("package {}", package));
+                bail!(
+                    "Dependency {} can not be merged: crate {} requires {} {} \
+                    but {} requires {} {}",
+                    dep.name,
+                    krate,
+                    dep.package,
+                    dep.req,
+                    origin,
+                    other_package,
+                    other_req
+                );
+            }
+            add_features(item, &dep.features);
+        }
+    }
+    Ok(doc.to_string())
+}
+
+/// The `[dependencies]` table of the manifest `doc`, or that of `[target.<target>]`, created if
+/// missing
+fn dep_table<'a>(doc: &'a mut Document, target: Option<&str>) -> Result<&'a mut dyn TableLike> {
+    let implicit = || {
+        let mut table = Table::new();
+        table.set_implicit(true);
+        Item::Table(table)
+    };
+    let root = doc.as_table_mut();
+    let parent = match target {
+        None => root,
+        Some(target) => root
+            .entry("target")
+            .or_insert_with(implicit)
+            .as_table_mut()
+            .context("Manifest key `target` is not a table")?
+            .entry(target)
+            .or_insert_with(implicit)
+            .as_table_mut()
+            .with_context(|| format!("Manifest key `target.{}` is not a table", target))?,
+    };
+    parent
+        .entry("dependencies")
+        .or_insert_with(|| Item::Table(Table::new()))
+        .as_table_like_mut()
+        .context("Dependencies are not a table")
+}
+
+/// The dependencies the manifest `text` declares, plain and platform specific, as name and
+/// version requirement, in the order they appear
+pub fn dependency_requirements(text: &str) -> Result<Vec<(String, String)>> {
+    let doc: Document = text.parse().context("Invalid manifest")?;
+    let root = doc.as_table();
+    let mut tables: Vec<&dyn TableLike> = root
+        .get("dependencies")
+        .and_then(Item::as_table_like)
+        .into_iter()
+        .collect();
+    if let Some(targets) = root.get("target").and_then(Item::as_table_like) {
+        for (_, target) in targets.iter() {
+            tables.extend(target.get("dependencies").and_then(Item::as_table_like));
+        }
+    }
+    Ok(tables
+        .into_iter()
+        .flat_map(|table| table.iter())
+        .map(|(name, item)| (name.to_string(), requirement(name, item).1))
+        .collect())
+}
+
+/// The package and version requirement of the dependency `name` declared as `item`
+fn requirement(name: &str, item: &Item) -> (String, String) {
+    if let Some(req) = item.as_str() {
+        return (name.into(), req.into());
+    }
+    let field = |key: &str| {
+        item.as_table_like()
+            .and_then(|spec| spec.get(key))
+            .and_then(Item::as_str)
+            .map(String::from)
+    };
+    (
+        field("package").unwrap_or_else(|| name.into()),
+        field("version").unwrap_or_else(|| "*".into()),
+    )
+}
+
+fn dependency_item(dep: &Dependency) -> Item {
+    let plain = dep.package == dep.name
+        && dep.default_features
+        && dep.features.is_empty()
+        && dep.path.is_none();
+    if plain {
+        return value(dep.req.as_str());
+    }
+    let mut spec = InlineTable::new();
+    if dep.req != "*" || dep.path.is_none() {
+        spec.insert("version", dep.req.as_str().into());
+    }
+    if let Some(path) = &dep.path {
+        spec.insert("path", path.display().to_string().into());
+    }
+    if dep.package != dep.name {
+        spec.insert("package", dep.package.as_str().into());
+    }
+    if !dep.default_features {
+        spec.insert("default-features", false.into());
+    }
+    if !dep.features.is_empty() {
+        let features: Array = dep.features.iter().map(String::as_str).collect();
+        spec.insert("features", features.into());
+    }
+    spec.fmt();
+    Item::Value(spec.into())
+}
+
+/// Enable `features` on the dependency declared as `item`, on top of those it has
+fn add_features(item: &mut Item, features: &[String]) {
+    if features.is_empty() {
+        return;
+    }
+    if let Some(req) = item.as_str().map(String::from) {
+        let mut spec = InlineTable::new();
+        spec.insert("version", req.into());
+        *item = Item::Value(spec.into());
+    }
+    let spec = match item.as_table_like_mut() {
+        Some(spec) => spec,
+        None => return,
+    };
+    if !spec.contains_key("features") {
+        spec.insert("features", value(Array::new()));
+    }
+    if let Some(list) = spec.get_mut("features").and_then(Item::as_array_mut) {
+        for feature in features {
+            if !list.iter().any(|f| f.as_str() == Some(feature.as_str())) {
+                list.push(feature.as_str());
+            }
+        }
+    }
+}
+
+/// Make the dependencies in the manifest `text` usable from a script, keeping its formatting:
+/// - dependencies on the `inlined` crates are removed;
+/// - dependencies inherited with `workspace = true` are resolved against
+///   `[workspace.dependencies]` of the manifest in `workspace_root`;
+/// - `path` is removed from dependencies that also have a `version` or `git`, like `cargo
+///   publish` does. Other path dependencies are an error, unless they are dev or build
+///   dependencies, which scripts do not build and are dropped.
+pub fn embed_dependencies(
+    text: &str,
+    inlined: &[String],
+    workspace_root: &Path,
+    warnings: &mut Vec<Warning>,
+) -> Result<String> {
+    let mut doc: Document = text.parse().context("Invalid manifest")?;
+    let mut deps = Deps {
+        inlined: inlined.iter().map(|name| name.replace('-', "_")).collect(),
+        workspace_root,
+        workspace: None,
+        member_dir: None,
+        warnings: vec![],
+    };
+    for_each_dep_table(&mut doc, |entries, kind, table| {
+        deps.embed(entries, kind, table)
+    })?;
+    warnings.extend(deps.warnings);
+    Ok(doc.to_string())
+}
+
+/// Resolve what the manifest `text` of the package at `manifest_dir` inherits from its
+/// workspace, keeping its formatting: `[package]` keys like `version.workspace = true` take their
+/// value from `[workspace.package]`, and dependencies with `workspace = true` their spec from
+/// `[workspace.dependencies]`, with the features the package adds. Paths in there are made
+/// relative to the package. The workspace root is where `package.workspace` points, or the
+/// closest directory at or above the package whose manifest declares `[workspace]`.
+pub fn inherit_workspace(text: &str, manifest_dir: &Path) -> Result<String> {
+    let mut doc: Document = text.parse().context("Invalid manifest")?;
+    let keys: Vec<_> = doc
+        .get("package")
+        .and_then(Item::as_table_like)
+        .into_iter()
+        .flat_map(|package| package.iter())
+        .filter(|(_, item)| is_inherited(item))
+        .map(|(key, _)| key.to_string())
+        .collect();
+    let mut inherited_deps = vec![];
+    for_each_dep_table(&mut doc, |entries, _, table| {
+        let inherited = entries.iter().filter(|(_, item)| is_inherited(item));
+        inherited_deps.extend(inherited.map(|(name, _)| format!("{} in {}", name, table)));
+        Ok(())
+    })?;
+    let first = match (keys.first(), inherited_deps.first()) {
+        (Some(key), _) => format!("package.{}", key),
+        (None, Some(dep)) => format!("dependency {}", dep),
+        (None, None) => return Ok(text.into()),
+    };
+    let root = find_workspace(&doc, manifest_dir).with_context(|| {
+        format!(
+            "The manifest in {} inherits {} from its workspace, but no workspace root declaring \
+            [workspace] was found",
+            manifest_dir.display(),
+            first
+        )
+    })?;
+
+    let mut deps = Deps {
+        inlined: vec![],
+        workspace_root: &root,
+        workspace: None,
+        member_dir: Some(manifest_dir),
+        warnings: vec![],
+    };
+    let workspace = deps.workspace()?.clone();
+    if let Some(package) = doc.get_mut("package").and_then(Item::as_table_like_mut) {
+        for key in keys {
+            let value = workspace
+                .get("workspace")
+                .and_then(|workspace| workspace.get("package"))
+                .and_then(|package| package.get(&key))
+                .and_then(Item::as_value)
+                .with_context(|| {
+                    format!(
+                        "package.{} is inherited from [workspace.package] in {}, which does not \
+                        declare it",
+                        key,
+                        root.join("Cargo.toml").display()
+                    )
+                })?;
+            let value = match (key.as_str(), value.as_str()) {
+                ("license-file" | "readme", Some(path)) => {
+                    let path = paths::relative_from(&root.join(path), manifest_dir);
+                    path.display().to_string().into()
+                }
+                _ => value.clone(),
+            };
+            package.insert(&key, Item::Value(value));
+        }
+    }
+    for_each_dep_table(&mut doc, |entries, _, _| deps.inherit_all(entries))?;
+    Ok(doc.to_string())
+}
+
+/// The root of the workspace the package with the manifest `doc` at `manifest_dir` belongs to,
+/// if there is a manifest declaring `[workspace]` there
+fn find_workspace(doc: &Document, manifest_dir: &Path) -> Option<PathBuf> {
+    let root = match doc
+        .get("package")
+        .and_then(|package| package.get("workspace"))
+        .and_then(Item::as_str)
+    {
+        Some(path) => manifest_dir.join(path),
+        None => paths::workspace_root(manifest_dir),
+    };
+    let declares = fs::read_to_string(root.join("Cargo.toml"))
+        .ok()
+        .and_then(|text| text.parse::<Document>().ok())
+        .map_or(false, |manifest| manifest.contains_key("workspace"));
+    declares.then(|| root)
+}
+
+/// Whether the manifest entry `item` is `{ workspace = true, ... }`
+fn is_inherited(item: &Item) -> bool {
+    item.as_table_like()
+        .and_then(|spec| spec.get("workspace"))
+        .and_then(Item::as_bool)
+        == Some(true)
+}
+
+/// Call `f` with each dependency table in `doc`, its kind, and how messages call it
+fn for_each_dep_table(
+    doc: &mut Document,
+    mut f: impl FnMut(&mut dyn TableLike, &str, &str) -> Result<()>,
+) -> Result<()> {
+    for table in DEP_TABLES {
+        if let Some(entries) = doc.get_mut(table).and_then(Item::as_table_like_mut) {
+            f(entries, table, &format!("[{}]", table))?;
+        }
+    }
+    if let Some(targets) = doc.get_mut("target").and_then(Item::as_table_like_mut) {
+        let keys: Vec<_> = targets.iter().map(|(key, _)| key.to_string()).collect();
+        for key in keys {
+            let target = match targets.get_mut(&key).and_then(Item::as_table_like_mut) {
+                Some(target) => target,
+                None => continue,
+            };
+            for table in DEP_TABLES {
+                if let Some(entries) = target.get_mut(table).and_then(Item::as_table_like_mut) {
+                    f(entries, table, &format!("[target.{}.{}]", key, table))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Deps<'a> {
+    inlined: Vec<String>,
+    workspace_root: &'a Path,
+    /// the workspace manifest, read on first use
+    workspace: Option<Document>,
+    /// the package's directory, which inherited paths are made relative to, if they are kept
+    member_dir: Option<&'a Path>,
+    /// dependencies dropped
+    warnings: Vec<Warning>,
+}
+
+impl Deps<'_> {
+    /// Embed the dependencies `entries` of the kind `kind`, from the table called `table` in
+    /// messages
+    fn embed(&mut self, entries: &mut dyn TableLike, kind: &str, table: &str) -> Result<()> {
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.to_string()).collect();
+        for name in names {
+            if self.inlined.contains(&name.replace('-', "_")) {
+                log::info!("Removing {} from {}, as it is inlined", name, table);
+                entries.remove(&name);
+                continue;
+            }
+            let item = match entries.get_mut(&name) {
+                Some(item) => item,
+                None => continue,
+            };
+            if is_inherited(item) {
+                let spec = self.inherit(&name, item)?;
+                *item = Item::Value(spec.into());
+            }
+
+            let spec = match item.as_table_like_mut() {
+                Some(spec) if spec.contains_key("path") => spec,
+                _ => continue,
+            };
+            if spec.contains_key("version") || spec.contains_key("git") {
+                log::info!(
+                    "Dropping the path of {} in {}, keeping its other source",
+                    name,
+                    table
+                );
+                spec.remove("path");
+            } else if kind == "dependencies" {
+                bail!(
+                    "Dependency {} in {} is only available by path, which does not work from a \
+                    script. Inline it, or give it a version or git source",
+                    name,
+                    table
+                );
+            } else {
+                let warning = format!(
+                    "Dropping {} from {}, as it is only available by path",
+                    name, table
+                );
+                log::warn!("{}", warning);
+                self.warnings
+                    .push(Warning::new(WarningKind::Dependency, warning));
+                entries.remove(&name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the inherited dependencies in `entries` by their specs
+    fn inherit_all(&mut self, entries: &mut dyn TableLike) -> Result<()> {
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.to_string()).collect();
+        for name in names {
+            if let Some(item) = entries.get_mut(&name).filter(|item| is_inherited(item)) {
+                let spec = self.inherit(&name, item)?;
+                *item = Item::Value(spec.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The workspace manifest
+    fn workspace(&mut self) -> Result<&Document> {
+        if self.workspace.is_none() {
+            let path = self.workspace_root.join("Cargo.toml");
+            let text = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read workspace manifest {}", path.display()))?;
+            let doc = text
+                .parse()
+                .with_context(|| format!("Invalid workspace manifest {}", path.display()))?;
+            self.workspace = Some(doc);
+        }
+        Ok(self.workspace.as_ref().expect("Just read"))
+    }
+
+    /// The spec of the dependency `name` inherited by `member` from `[workspace.dependencies]`,
+    /// with the features and optionality `member` adds
+    fn inherit(&mut self, name: &str, member: &Item) -> Result<InlineTable> {
+        let (root, member_dir) = (self.workspace_root, self.member_dir);
+        let workspace = self
+            .workspace()?
+            .get("workspace")
+            .and_then(|workspace| workspace.get("dependencies"))
+            .and_then(|deps| deps.get(name))
+            .with_context(|| {
+                format!(
+                    "Dependency {} is inherited from [workspace.dependencies], which does not \
+                    declare it",
+                    name
+                )
+            })?;
+
+        let mut spec = InlineTable::new();
+        if let Some(version) = workspace.as_str() {
+            spec.insert("version", version.into());
+        } else if let Some(table) = workspace.as_table_like() {
+            for (key, item) in table.iter() {
+                let path = item.as_str().filter(|_| key == "path");
+                match (item.as_value(), path, member_dir) {
+                    // relative to the workspace root
+                    (_, Some(path), Some(dir)) => {
+                        let path = paths::relative_from(&root.join(path), dir);
+                        spec.insert(key, path.display().to_string().into());
+                    }
+                    (Some(value), _, _) => {
+                        spec.insert(key, value.clone());
+                    }
+                    (None, _, _) => {}
+                }
+            }
+        } else {
+            bail!(
+                "Dependency {} in [workspace.dependencies] is neither a version nor a table",
+                name
+            );
+        }
+        let member = member
+            .as_table_like()
+            .into_iter()
+            .flat_map(|table| table.iter());
+        for (key, item) in member {
+            match (key, item.as_value()) {
+                ("workspace", _) | (_, None) => {}
+                ("features", Some(features)) => {
+                    let mut all = spec
+                        .get("features")
+                        .and_then(|features| features.as_array())
+                        .cloned()
+                        .unwrap_or_else(Array::new);
+                    for feature in features.as_array().into_iter().flat_map(Array::iter) {
+                        all.push_formatted(feature.clone());
+                    }
+                    spec.insert("features", all.into());
+                }
+                (key, Some(value)) => {
+                    spec.insert(key, value.clone());
+                }
+            }
+        }
+        spec.fmt();
+        Ok(spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_tables_are_dropped_or_promoted() {
+        let manifest = r#"
+            [package]
+            name = "tool"
+            version = "0.1.0"
+
+            [dependencies]
+            anyhow = "1.0"
+
+            [target.'cfg(windows)'.dependencies]
+            winapi = "0.3"
+
+            [target.'cfg(unix)'.dependencies]
+            nix = "0.23"
+
+            [target.'cfg(debug_assertions)'.dependencies]
+            log = "0.4"
+
+            [target.x86_64-pc-windows-msvc.dependencies]
+            widestring = "0.5"
+        "#;
+        let cfgs = CfgSet::new().with_name("unix").with_known("windows");
+        let resolved = resolve_target_cfgs(manifest, &cfgs).unwrap();
+        let resolved: Value = toml::from_str(&resolved).unwrap();
+
+        let deps = resolved["dependencies"].as_table().unwrap();
+        let names: Vec<_> = deps.keys().map(String::as_str).collect();
+        assert_eq!(names, ["anyhow", "nix"]);
+
+        let targets = resolved["target"].as_table().unwrap();
+        let keys: Vec<_> = targets.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["cfg(debug_assertions)", "x86_64-pc-windows-msvc"]);
+    }
+
+    #[test]
+    fn resolving_targets_and_setting_the_edition_keep_comments() {
+        let manifest = r#"
+            [package]
+            name = "tool"
+            version = "0.1.0"
+            # bumped with the bundle
+            edition = "2018"
+
+            [dependencies]
+            # for errors
+            anyhow = "1.0"
+
+            [target.'cfg(unix)'.dependencies]
+            anyhow = "1.0" # the same declaration
+            nix = "0.23"
+        "#;
+        let cfgs = CfgSet::new().with_name("unix");
+        let resolved = resolve_target_cfgs(manifest, &cfgs).unwrap();
+        let resolved = set_edition(&resolved, Edition::E2021).unwrap();
+        assert!(resolved.contains("# bumped with the bundle\n"));
+        assert!(resolved.contains("# for errors\n"));
+        let resolved: Value = toml::from_str(&resolved).unwrap();
+        assert_eq!(resolved["package"]["edition"].as_str(), Some("2021"));
+        let deps = resolved["dependencies"].as_table().unwrap();
+        let names: Vec<_> = deps.keys().map(String::as_str).collect();
+        assert_eq!(names, ["anyhow", "nix"]);
+        assert!(resolved.get("target").is_none());
+
+        let conflicting = manifest.replace(r#""1.0" # the same"#, r#""1.0.100" # not the same"#);
+        assert!(resolve_target_cfgs(&conflicting, &cfgs).is_err());
+    }
+
+    #[test]
+    fn package_only_sections_are_scrubbed() {
+        let manifest = r#"
+            [package]
+            name = "tool"
+            version = "0.1.0"
+            edition = "2021" # for let-else
+            build = "build.rs"
+            publish = false
+
+            [lib]
+            path = "src/lib.rs"
+
+            [[bin]]
+            name = "tool"
+
+            [[bench]]
+            name = "speed"
+
+            [dependencies]
+            # the only one
+            anyhow = "1.0"
+
+            [dev-dependencies]
+            quote = "1.0"
+
+            [build-dependencies]
+            cc = "1.0"
+
+            [target.'cfg(unix)'.dependencies]
+            nix = "0.23"
+
+            [target.'cfg(unix)'.dev-dependencies]
+            tempfile = "3"
+
+            [features]
+            fast = []
+
+            [profile.release]
+            lto = true
+
+            [workspace]
+        "#;
+        let scrubbed = scrub(manifest).unwrap();
+        assert!(scrubbed.contains("edition = \"2021\" # for let-else\n"));
+        assert!(scrubbed.contains("# the only one\n"));
+
+        let scrubbed: Value = toml::from_str(&scrubbed).unwrap();
+        let tables: Vec<_> = scrubbed
+            .as_table()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            tables,
+            ["dependencies", "features", "package", "profile", "target"]
+        );
+        let package: Vec<_> = scrubbed["package"].as_table().unwrap().keys().collect();
+        assert_eq!(package, ["edition", "name", "version"]);
+        let unix = scrubbed["target"]["cfg(unix)"].as_table().unwrap();
+        assert_eq!(unix.keys().collect::<Vec<_>>(), ["dependencies"]);
+    }
+
+    #[test]
+    fn dependencies_are_made_embeddable() {
+        let workspace = std::env::temp_dir().join(format!("bundler-embed-{}", std::process::id()));
+        fs::create_dir_all(&workspace).unwrap();
+        fs::write(
+            workspace.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"tool\"]\n\n[workspace.dependencies]\n\
+            common = { version = \"0.2\", features = [\"std\"] }\nlog = \"0.4\"\n",
+        )
+        .unwrap();
+        let manifest = r#"
+            [package]
+            name = "tool"
+            version = "0.1.0"
+
+            [dependencies]
+            # parsing
+            mylib = { path = "../mylib" }
+            common = { workspace = true, features = ["fast"] }
+            log.workspace = true
+            shared = { path = "../shared", version = "1.2" }
+
+            [dev-dependencies]
+            fixtures = { path = "../fixtures" }
+        "#;
+        let inlined = ["mylib".to_string()];
+        let mut warnings = vec![];
+        let embedded = embed_dependencies(manifest, &inlined, &workspace, &mut warnings).unwrap();
+        assert!(embedded.contains("# parsing\n"));
+        let embedded: Value = toml::from_str(&embedded).unwrap();
+        let deps = embedded["dependencies"].as_table().unwrap();
+        let names: Vec<_> = deps.keys().map(String::as_str).collect();
+        assert_eq!(names, ["common", "log", "shared"]);
+        assert_eq!(deps["common"]["version"].as_str(), Some("0.2"));
+        let features: Vec<_> = deps["common"]["features"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        assert_eq!(features, ["std", "fast"]);
+        assert_eq!(deps["log"]["version"].as_str(), Some("0.4"));
+        assert!(deps["shared"].get("path").is_none());
+        assert!(embedded["dev-dependencies"].as_table().unwrap().is_empty());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::Dependency);
+        assert!(warnings[0]
+            .message
+            .starts_with("Dropping fixtures from [dev-dependencies]"));
+
+        let only_path = "[dependencies]\nother = { path = \"../other\" }\n";
+        let err = embed_dependencies(only_path, &inlined, &workspace, &mut vec![]).unwrap_err();
+        assert!(err.to_string().contains("only available by path"));
+    }
+
+    #[test]
+    fn inheritance_needs_a_workspace() {
+        let dir = std::env::temp_dir().join(format!("bundler-orphan-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let manifest = "[package]\nname = \"tool\"\nversion.workspace = true\n";
+        let err = inherit_workspace(manifest, &dir).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("inherits package.version from its workspace"));
+
+        let plain = "[package]\nname = \"tool\"\nversion = \"0.1.0\"\n";
+        assert_eq!(inherit_workspace(plain, &dir).unwrap(), plain);
+    }
+}
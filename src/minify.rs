@@ -0,0 +1,85 @@
+use syn::visit_mut::{self, VisitMut};
+
+use crate::attrs;
+use crate::cfg::item_attrs;
+
+/// Drop the doc strings and lint level attributes in `file`, see [`attrs::is_minifiable`].
+/// Returns how many were dropped.
+pub fn strip(file: &mut syn::File) -> usize {
+    let mut stripper = Stripper(0);
+    stripper.visit_file_mut(file);
+    stripper.0
+}
+
+/// Counts the attributes dropped
+struct Stripper(usize);
+
+impl Stripper {
+    fn strip(&mut self, attrs: &mut Vec<syn::Attribute>) {
+        let len = attrs.len();
+        attrs.retain(|attr| !attrs::is_minifiable(attr));
+        self.0 += len - attrs.len();
+    }
+}
+
+impl VisitMut for Stripper {
+    fn visit_file_mut(&mut self, file: &mut syn::File) {
+        self.strip(&mut file.attrs);
+        visit_mut::visit_file_mut(self, file);
+    }
+
+    fn visit_item_mut(&mut self, item: &mut syn::Item) {
+        if let Some(attrs) = item_attrs(item) {
+            self.strip(attrs);
+        }
+        visit_mut::visit_item_mut(self, item);
+    }
+
+    fn visit_impl_item_mut(&mut self, item: &mut syn::ImplItem) {
+        match item {
+            syn::ImplItem::Const(i) => self.strip(&mut i.attrs),
+            syn::ImplItem::Method(i) => self.strip(&mut i.attrs),
+            syn::ImplItem::Type(i) => self.strip(&mut i.attrs),
+            syn::ImplItem::Macro(i) => self.strip(&mut i.attrs),
+            _ => {}
+        }
+        visit_mut::visit_impl_item_mut(self, item);
+    }
+
+    fn visit_trait_item_mut(&mut self, item: &mut syn::TraitItem) {
+        match item {
+            syn::TraitItem::Const(i) => self.strip(&mut i.attrs),
+            syn::TraitItem::Method(i) => self.strip(&mut i.attrs),
+            syn::TraitItem::Type(i) => self.strip(&mut i.attrs),
+            syn::TraitItem::Macro(i) => self.strip(&mut i.attrs),
+            _ => {}
+        }
+        visit_mut::visit_trait_item_mut(self, item);
+    }
+
+    fn visit_foreign_item_mut(&mut self, item: &mut syn::ForeignItem) {
+        match item {
+            syn::ForeignItem::Fn(i) => self.strip(&mut i.attrs),
+            syn::ForeignItem::Static(i) => self.strip(&mut i.attrs),
+            syn::ForeignItem::Type(i) => self.strip(&mut i.attrs),
+            syn::ForeignItem::Macro(i) => self.strip(&mut i.attrs),
+            _ => {}
+        }
+        visit_mut::visit_foreign_item_mut(self, item);
+    }
+
+    fn visit_field_mut(&mut self, field: &mut syn::Field) {
+        self.strip(&mut field.attrs);
+        visit_mut::visit_field_mut(self, field);
+    }
+
+    fn visit_variant_mut(&mut self, variant: &mut syn::Variant) {
+        self.strip(&mut variant.attrs);
+        visit_mut::visit_variant_mut(self, variant);
+    }
+
+    fn visit_local_mut(&mut self, local: &mut syn::Local) {
+        self.strip(&mut local.attrs);
+        visit_mut::visit_local_mut(self, local);
+    }
+}
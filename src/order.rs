@@ -0,0 +1,326 @@
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Result};
+use syn::visit::Visit;
+
+use crate::warning::{Warning, WarningKind};
+
+/// Where the inlined crates go relative to the binary's own items.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemOrder {
+    /// The binary's `use` and `extern crate` items, then the inlined crates, then the rest of
+    /// the binary, so that `fn main` comes last and macros of the crates are defined before the
+    /// binary invokes them.
+    LibsFirst,
+    /// All of the binary's items, then the inlined crates, the order of older bundles.
+    LibsLast,
+    /// Full control over the sequence. Anything not covered by a section is appended in
+    /// [`ItemOrder::LibsLast`] order.
+    Interleaved(Vec<Section>),
+}
+
+impl Default for ItemOrder {
+    fn default() -> Self {
+        ItemOrder::LibsFirst
+    }
+}
+
+/// A run of items in the bundle, for [`ItemOrder::Interleaved`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Section {
+    /// The binary's `use` and `extern crate` items
+    BinaryImports,
+    /// The binary's remaining items
+    BinaryItems,
+    /// The inlined crate with the given module name
+    Lib(String),
+    /// All inlined crates not placed by an explicit [`Section::Lib`]
+    Libs,
+}
+
+/// A modulized crate to arrange, with what ordering depends on collected up front, so the
+/// module's items can be printed and dropped before
+pub struct Lib {
+    pub module: syn::ItemMod,
+    /// see [`textual_macros`]
+    textual_macros: BTreeSet<String>,
+}
+
+impl From<syn::ItemMod> for Lib {
+    fn from(module: syn::ItemMod) -> Self {
+        Lib {
+            textual_macros: textual_macros(&module),
+            module,
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl Lib {
+    /// The module as text, without its items, and what ordering needs to know about them, which
+    /// unlike the syntax tree can be sent to another thread, see [`Lib::from_parts`]
+    pub fn into_parts(mut self) -> (String, BTreeSet<String>) {
+        // with content, so that the inner attributes are printed
+        self.module.content = Some((Default::default(), vec![]));
+        let module = quote::ToTokens::into_token_stream(self.module).to_string();
+        (module, self.textual_macros)
+    }
+
+    /// The module [`Lib::into_parts`] gave, with `items`
+    pub fn from_parts(
+        module: &str,
+        textual_macros: BTreeSet<String>,
+        items: Vec<syn::Item>,
+    ) -> Result<Self> {
+        let mut module: syn::ItemMod = syn::parse_str(module)?;
+        module.content = Some((Default::default(), items));
+        Ok(Lib {
+            module,
+            textual_macros,
+        })
+    }
+}
+
+/// Arrange the binary's items and the modulized crates according to `order`.
+///
+/// Crates that make textually scoped macros available to the binary (through `#[macro_use]`)
+/// must precede the binary's items that invoke them. When `order` would break that, it is
+/// overridden with [`ItemOrder::LibsFirst`] and a warning is logged and added to `warnings`.
+pub fn arrange(
+    order: &ItemOrder,
+    binary: Vec<syn::Item>,
+    libs: Vec<impl Into<Lib>>,
+    warnings: &mut Vec<Warning>,
+) -> Result<Vec<syn::Item>> {
+    let libs: Vec<Lib> = libs.into_iter().map(Into::into).collect();
+    let libs_first = ItemOrder::LibsFirst;
+    let order = if !textual_macros_ok(order, &binary, &libs) {
+        let warning = format!(
+            "Inlined crates provide macros by textual scope to the binary, emitting them first instead of {:?}",
+            order
+        );
+        log::warn!("{}", warning);
+        warnings.push(Warning::new(WarningKind::ItemOrder, warning));
+        &libs_first
+    } else {
+        order
+    };
+
+    let (imports, rest): (Vec<_>, Vec<_>) = binary
+        .into_iter()
+        .partition(|item| matches!(item, syn::Item::Use(_) | syn::Item::ExternCrate(_)));
+    let mut sections = Sections {
+        imports: Some(imports),
+        rest: Some(rest),
+        libs: libs.into_iter().map(|lib| Some(lib.module)).collect(),
+        libs_placed: false,
+    };
+
+    let sequence = match order {
+        ItemOrder::LibsFirst => vec![Section::BinaryImports, Section::Libs, Section::BinaryItems],
+        ItemOrder::LibsLast => vec![Section::BinaryImports, Section::BinaryItems, Section::Libs],
+        ItemOrder::Interleaved(sequence) => sequence.clone(),
+    };
+
+    let mut items = vec![];
+    for section in &sequence {
+        items.extend(sections.take(section)?);
+    }
+    for section in &[Section::BinaryImports, Section::BinaryItems, Section::Libs] {
+        items.extend(sections.take(section).unwrap_or_default());
+    }
+    Ok(items)
+}
+
+struct Sections {
+    imports: Option<Vec<syn::Item>>,
+    rest: Option<Vec<syn::Item>>,
+    libs: Vec<Option<syn::ItemMod>>,
+    libs_placed: bool,
+}
+
+impl Sections {
+    fn take(&mut self, section: &Section) -> Result<Vec<syn::Item>> {
+        let items = match section {
+            Section::BinaryImports => self.imports.take(),
+            Section::BinaryItems => self.rest.take(),
+            Section::Lib(name) => {
+                let lib = self
+                    .libs
+                    .iter_mut()
+                    .find(|lib| matches!(lib, Some(lib) if lib.ident == name));
+                match lib {
+                    Some(lib) => lib.take().map(|lib| vec![lib.into()]),
+                    None => bail!("No inlined crate {} to place, or it is placed twice", name),
+                }
+            }
+            Section::Libs if self.libs_placed => None,
+            Section::Libs => {
+                self.libs_placed = true;
+                Some(
+                    self.libs
+                        .iter_mut()
+                        .flat_map(Option::take)
+                        .map(Into::into)
+                        .collect(),
+                )
+            }
+        };
+        match items {
+            Some(items) => Ok(items),
+            None => bail!("Section {:?} is placed more than once", section),
+        }
+    }
+}
+
+/// Whether `order` puts every `#[macro_use]` crate before the binary items invoking its macros.
+fn textual_macros_ok(order: &ItemOrder, binary: &[syn::Item], libs: &[Lib]) -> bool {
+    let invoked = {
+        let mut collector = MacroCalls::default();
+        for item in binary {
+            collector.visit_item(item);
+        }
+        collector.0
+    };
+    let needed: Vec<_> = libs
+        .iter()
+        .filter(|lib| {
+            lib.module
+                .attrs
+                .iter()
+                .any(|a| a.path.is_ident("macro_use"))
+        })
+        .filter(|lib| lib.textual_macros.iter().any(|name| invoked.contains(name)))
+        .map(|lib| lib.module.ident.to_string())
+        .collect();
+    if needed.is_empty() {
+        return true;
+    }
+
+    match order {
+        ItemOrder::LibsFirst => true,
+        ItemOrder::LibsLast => false,
+        ItemOrder::Interleaved(sequence) => {
+            let binary_at = sequence
+                .iter()
+                .position(|s| *s == Section::BinaryItems)
+                .unwrap_or(sequence.len());
+            needed.iter().all(|name| {
+                sequence[..binary_at]
+                    .iter()
+                    .any(|s| *s == Section::Libs || *s == Section::Lib(name.clone()))
+            })
+        }
+    }
+}
+
+/// Names of `macro_rules!` defined at the top level of `lib` without `#[macro_export]`
+fn textual_macros(lib: &syn::ItemMod) -> BTreeSet<String> {
+    let items = lib
+        .content
+        .as_ref()
+        .map_or(&[][..], |(_, items)| items.as_slice());
+    items
+        .iter()
+        .filter_map(|item| match item {
+            syn::Item::Macro(m) if !m.attrs.iter().any(|a| a.path.is_ident("macro_export")) => {
+                m.ident.as_ref().map(ToString::to_string)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Bare names of invoked macros
+#[derive(Default)]
+struct MacroCalls(BTreeSet<String>);
+
+impl<'ast> Visit<'ast> for MacroCalls {
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        if let Some(ident) = mac.path.get_ident() {
+            self.0.insert(ident.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn binary() -> Vec<syn::Item> {
+        let file: syn::File = parse_quote!(
+            use std::io;
+            fn main() {}
+        );
+        file.items
+    }
+
+    fn libs() -> Vec<syn::ItemMod> {
+        vec![
+            parse_quote!(
+                pub mod a {}
+            ),
+            parse_quote!(
+                pub mod b {}
+            ),
+        ]
+    }
+
+    fn names(items: &[syn::Item]) -> Vec<String> {
+        items
+            .iter()
+            .map(|item| match item {
+                syn::Item::Use(_) => "use".into(),
+                syn::Item::Fn(f) => f.sig.ident.to_string(),
+                syn::Item::Mod(m) => m.ident.to_string(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn libs_first() {
+        let items = arrange(&ItemOrder::LibsFirst, binary(), libs(), &mut vec![]).unwrap();
+        assert_eq!(names(&items), ["use", "a", "b", "main"]);
+    }
+
+    #[test]
+    fn libs_last() {
+        let items = arrange(&ItemOrder::LibsLast, binary(), libs(), &mut vec![]).unwrap();
+        assert_eq!(names(&items), ["use", "main", "a", "b"]);
+    }
+
+    #[test]
+    fn interleaved() {
+        let order = ItemOrder::Interleaved(vec![
+            Section::BinaryImports,
+            Section::Lib("b".into()),
+            Section::BinaryItems,
+        ]);
+        let items = arrange(&order, binary(), libs(), &mut vec![]).unwrap();
+        assert_eq!(names(&items), ["use", "b", "main", "a"]);
+
+        let twice = ItemOrder::Interleaved(vec![Section::Libs, Section::Libs]);
+        assert!(arrange(&twice, binary(), libs(), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn textual_macros_override_order() {
+        let binary: syn::File = parse_quote!(
+            fn main() {
+                hello!();
+            }
+        );
+        let libs = vec![parse_quote! {
+            #[macro_use]
+            pub mod a {
+                macro_rules! hello { () => {} }
+            }
+        }];
+        let mut warnings = vec![];
+        let items = arrange(&ItemOrder::LibsLast, binary.items, libs, &mut warnings).unwrap();
+        assert_eq!(names(&items), ["a", "main"]);
+        assert_eq!(warnings.len(), 1);
+    }
+}
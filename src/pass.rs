@@ -0,0 +1,455 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use quote::ToTokens;
+use serde::Serialize;
+
+use crate::attrs;
+use crate::minify;
+use crate::prune;
+use crate::rewrite::{self, CrateModule, ReexportMode};
+use crate::srcmap::SourceMap;
+use crate::testcode;
+
+/// A transformation of the whole bundle, run after the crates are inlined next to the binary's
+/// own items and before the manifest is added. See
+/// [`Bundler::with_pass`](crate::Bundler::with_pass) for where the built-in ones run.
+pub trait Pass: Send + Sync {
+    /// The name to refer to the pass by, unique within the pipeline
+    fn name(&self) -> &str;
+
+    fn run(&self, ctx: &PassContext, file: &mut syn::File) -> Result<PassOutcome>;
+}
+
+/// What a [`Pass`] can know about the bundle besides the syntax tree
+pub struct PassContext {
+    bin_name: String,
+    crates: Vec<CrateModule>,
+    nest: Option<String>,
+    /// where the binary's code came from, for errors
+    sources: SourceMap,
+}
+
+impl PassContext {
+    pub(crate) fn new(bin_name: String, crates: Vec<CrateModule>, nest: Option<String>) -> Self {
+        PassContext {
+            bin_name,
+            crates,
+            nest,
+            sources: SourceMap::default(),
+        }
+    }
+
+    /// Point errors about the binary at its sources with `sources`
+    pub(crate) fn with_sources(mut self, sources: SourceMap) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Name of the binary being bundled
+    pub fn bin_name(&self) -> &str {
+        &self.bin_name
+    }
+
+    /// Each inlined crate, as the name the binary refers to it by and the module it becomes
+    pub fn crates(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.crates
+            .iter()
+            .map(|c| (c.name.as_str(), c.module.as_str()))
+    }
+
+    /// The container module of the inlined crates, see
+    /// [`Bundler::nest_under`](crate::Bundler::nest_under)
+    pub fn nest(&self) -> Option<&str> {
+        self.nest.as_deref()
+    }
+
+    /// Whether `item` at the bundle root is one of the inlined crates, or their container, rather
+    /// than an item of the binary
+    pub fn is_inlined(&self, item: &syn::Item) -> bool {
+        match (item, &self.nest) {
+            (syn::Item::Mod(m), Some(nest)) => m.ident == nest,
+            (syn::Item::Mod(m), None) => self.crates.iter().any(|c| m.ident == c.module),
+            _ => false,
+        }
+    }
+}
+
+/// What a [`Pass`] did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PassOutcome {
+    /// How many things the pass changed, in whatever unit fits it
+    pub changes: usize,
+}
+
+impl PassOutcome {
+    pub fn changes(changes: usize) -> Self {
+        PassOutcome { changes }
+    }
+}
+
+/// How a pass went, as listed in the reports
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PassReport {
+    pub name: String,
+    pub duration: Duration,
+    pub changes: usize,
+}
+
+/// Where to insert a custom pass
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PassPosition {
+    First,
+    Last,
+    Before(String),
+    After(String),
+}
+
+impl PassPosition {
+    pub fn before(name: impl Into<String>) -> Self {
+        PassPosition::Before(name.into())
+    }
+
+    pub fn after(name: impl Into<String>) -> Self {
+        PassPosition::After(name.into())
+    }
+}
+
+/// The built-in passes and what was asked of the pipeline, put together when bundling
+#[derive(Default)]
+pub struct Pipeline {
+    custom: Vec<(PassPosition, Box<dyn Pass>)>,
+    disabled: BTreeSet<String>,
+}
+
+/// Options of the built-in passes
+pub struct BuiltinOptions {
+    pub strip_tests: bool,
+    pub reexport: ReexportMode,
+    pub prune_unused: bool,
+    pub merge_lint_attrs: bool,
+    pub minify: bool,
+}
+
+impl Pipeline {
+    pub fn insert(&mut self, position: PassPosition, pass: Box<dyn Pass>) {
+        self.custom.push((position, pass));
+    }
+
+    pub fn disable(&mut self, name: String) {
+        self.disabled.insert(name);
+    }
+
+    /// Whether any pass besides the built-in ones is in the pipeline
+    pub fn has_custom(&self) -> bool {
+        !self.custom.is_empty()
+    }
+
+    /// Run every enabled pass in order over `file`
+    pub fn run(
+        &self,
+        options: &BuiltinOptions,
+        ctx: &PassContext,
+        file: &mut syn::File,
+    ) -> Result<Vec<PassReport>> {
+        // built-ins that are off still count as positions to insert at
+        let builtins: Vec<(Box<dyn Pass>, bool)> = vec![
+            (Box::new(StripTests), options.strip_tests),
+            (Box::new(RewritePaths(options.reexport)), true),
+            (Box::new(PruneUnused), options.prune_unused),
+            (Box::new(DedupeUses), true),
+            (Box::new(DedupeAttrs(options.merge_lint_attrs)), true),
+            (Box::new(Minify), options.minify),
+        ];
+        let mut passes: Vec<(&dyn Pass, bool)> =
+            builtins.iter().map(|(p, on)| (p.as_ref(), *on)).collect();
+        for (position, pass) in &self.custom {
+            if passes.iter().any(|(p, _)| p.name() == pass.name()) {
+                bail!("There is already a pass named {}", pass.name());
+            }
+            let find = |name: &str| {
+                passes
+                    .iter()
+                    .position(|(p, _)| p.name() == name)
+                    .ok_or_else(|| anyhow!("No pass named {} to place {} at", name, pass.name()))
+            };
+            let idx = match position {
+                PassPosition::First => 0,
+                PassPosition::Last => passes.len(),
+                PassPosition::Before(name) => find(name)?,
+                PassPosition::After(name) => find(name)? + 1,
+            };
+            passes.insert(idx, (pass.as_ref(), true));
+        }
+        for name in &self.disabled {
+            if !passes.iter().any(|(p, _)| p.name() == name) {
+                bail!("No pass named {} to disable", name);
+            }
+        }
+
+        let mut reports = vec![];
+        for (pass, _) in passes
+            .into_iter()
+            .filter(|(p, on)| *on && !self.disabled.contains(p.name()))
+        {
+            let start = Instant::now();
+            let outcome = pass.run(ctx, file)?;
+            let duration = start.elapsed();
+            log::info!(
+                "Pass {} made {} changes in {:?}",
+                pass.name(),
+                outcome.changes,
+                duration
+            );
+            reports.push(PassReport {
+                name: pass.name().into(),
+                duration,
+                changes: outcome.changes,
+            });
+        }
+        Ok(reports)
+    }
+}
+
+/// Add the `reports` of running the pipeline over one part of the bundle to `total`, the
+/// reports over the parts before
+pub fn merge_reports(total: &mut Vec<PassReport>, reports: Vec<PassReport>) {
+    for report in reports {
+        match total.iter_mut().find(|r| r.name == report.name) {
+            Some(r) => {
+                r.duration += report.duration;
+                r.changes += report.changes;
+            }
+            None => total.push(report),
+        }
+    }
+}
+
+/// `strip-tests`, see [`Bundler::strip_tests`](crate::Bundler::strip_tests)
+struct StripTests;
+
+impl Pass for StripTests {
+    fn name(&self) -> &str {
+        "strip-tests"
+    }
+
+    fn run(&self, _: &PassContext, file: &mut syn::File) -> Result<PassOutcome> {
+        Ok(PassOutcome::changes(testcode::strip(file)))
+    }
+}
+
+/// `rewrite-paths`, keeping the binary's references to the inlined crates resolving, see
+/// [`ReexportMode`]. Only the binary's own items are touched, apart from `extern crate` items
+/// naming inlined crates, see [`rewrite::replace_extern_crates`].
+struct RewritePaths(ReexportMode);
+
+impl Pass for RewritePaths {
+    fn name(&self) -> &str {
+        "rewrite-paths"
+    }
+
+    fn run(&self, ctx: &PassContext, file: &mut syn::File) -> Result<PassOutcome> {
+        let (mut libs, mut items): (Vec<_>, Vec<_>) = std::mem::take(&mut file.items)
+            .into_iter()
+            .partition(|item| ctx.is_inlined(item));
+        // the crates can not be referred to as crates anymore, by the binary or each other
+        let (crates, nest) = (&ctx.crates, ctx.nest());
+        let mut replaced = rewrite::replace_extern_crates(&mut items, crates, nest, false, true);
+        for lib in &mut libs {
+            if let syn::Item::Mod(syn::ItemMod {
+                content: Some((_, content)),
+                ..
+            }) = lib
+            {
+                replaced += rewrite::replace_extern_crates(content, crates, nest, true, false);
+            }
+        }
+        let before: Vec<_> = items
+            .iter()
+            .map(|item| item.to_token_stream().to_string())
+            .collect();
+        let mut binary = syn::File {
+            shebang: None,
+            attrs: std::mem::take(&mut file.attrs),
+            items,
+        };
+        let result = match self.0 {
+            ReexportMode::Rewrite => {
+                rewrite::rewrite_crate_refs(&mut binary, &ctx.crates, ctx.nest(), &ctx.sources)
+            }
+            ReexportMode::Glob => {
+                rewrite::glob_reexport(&mut binary, &ctx.crates, ctx.nest(), &ctx.sources)
+            }
+        };
+        // the order of the binary's items and the crates is only decided later
+        file.attrs = binary.attrs;
+        file.items = binary.items;
+        let changes = file
+            .items
+            .iter()
+            .filter(|item| !before.contains(&item.to_token_stream().to_string()))
+            .count()
+            + replaced;
+        file.items.extend(libs);
+        result?;
+        Ok(PassOutcome::changes(changes))
+    }
+}
+
+/// `prune-unused`, see [`Bundler::prune_unused`](crate::Bundler::prune_unused)
+struct PruneUnused;
+
+impl Pass for PruneUnused {
+    fn name(&self) -> &str {
+        "prune-unused"
+    }
+
+    fn run(&self, ctx: &PassContext, file: &mut syn::File) -> Result<PassOutcome> {
+        let removed = prune::prune(ctx, file);
+        log::info!("Pruned {} unused items of the inlined crates", removed);
+        Ok(PassOutcome::changes(removed))
+    }
+}
+
+/// `minify`, dropping doc strings and lint attributes, see
+/// [`Bundler::minify`](crate::Bundler::minify)
+struct Minify;
+
+impl Pass for Minify {
+    fn name(&self) -> &str {
+        "minify"
+    }
+
+    fn run(&self, _: &PassContext, file: &mut syn::File) -> Result<PassOutcome> {
+        Ok(PassOutcome::changes(minify::strip(file)))
+    }
+}
+
+/// `dedupe-uses`, dropping `use` items identical to an earlier one in the same module
+struct DedupeUses;
+
+impl DedupeUses {
+    fn dedupe(items: &mut Vec<syn::Item>) -> usize {
+        let mut seen = BTreeSet::new();
+        let len = items.len();
+        items.retain(|item| match item {
+            syn::Item::Use(u) => seen.insert(u.to_token_stream().to_string()),
+            _ => true,
+        });
+        let mut removed = len - items.len();
+        for item in items.iter_mut() {
+            if let syn::Item::Mod(syn::ItemMod {
+                content: Some((_, content)),
+                ..
+            }) = item
+            {
+                removed += Self::dedupe(content);
+            }
+        }
+        removed
+    }
+}
+
+impl Pass for DedupeUses {
+    fn name(&self) -> &str {
+        "dedupe-uses"
+    }
+
+    fn run(&self, _: &PassContext, file: &mut syn::File) -> Result<PassOutcome> {
+        Ok(PassOutcome::changes(Self::dedupe(&mut file.items)))
+    }
+}
+
+/// `dedupe-attrs`, normalizing the bundle root's inner attributes, see
+/// [`Bundler::merge_lint_attrs`](crate::Bundler::merge_lint_attrs)
+struct DedupeAttrs(bool);
+
+impl Pass for DedupeAttrs {
+    fn name(&self) -> &str {
+        "dedupe-attrs"
+    }
+
+    fn run(&self, _: &PassContext, file: &mut syn::File) -> Result<PassOutcome> {
+        let len = file.attrs.len();
+        attrs::dedup(&mut file.attrs, self.0);
+        Ok(PassOutcome::changes(len - file.attrs.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    struct Rename;
+
+    impl Pass for Rename {
+        fn name(&self) -> &str {
+            "rename"
+        }
+
+        fn run(&self, _: &PassContext, file: &mut syn::File) -> Result<PassOutcome> {
+            let mut changes = 0;
+            for item in &mut file.items {
+                if let syn::Item::Fn(f) = item {
+                    if f.sig.ident == "helper" {
+                        f.sig.ident = parse_quote!(h0);
+                        changes += 1;
+                    }
+                }
+            }
+            Ok(PassOutcome::changes(changes))
+        }
+    }
+
+    #[test]
+    fn custom_passes_run_in_position() {
+        let ctx = PassContext::new(
+            "app".into(),
+            vec![CrateModule {
+                name: "mylib".into(),
+                module: "mylib".into(),
+            }],
+            None,
+        );
+        let options = BuiltinOptions {
+            strip_tests: false,
+            reexport: ReexportMode::Rewrite,
+            prune_unused: false,
+            merge_lint_attrs: false,
+            minify: false,
+        };
+        let mut file: syn::File = parse_quote! {
+            use std::fmt;
+            use std::fmt;
+            fn helper() {}
+            fn main() { mylib::run(); }
+            pub mod mylib { pub fn run() {} }
+        };
+
+        let mut pipeline = Pipeline::default();
+        pipeline.insert(PassPosition::before("rewrite-paths"), Box::new(Rename));
+        pipeline.disable("dedupe-uses".into());
+        let reports = pipeline.run(&options, &ctx, &mut file).unwrap();
+        let names: Vec<_> = reports.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["rename", "rewrite-paths", "dedupe-attrs"]);
+        assert_eq!(reports[0].changes, 1);
+        assert_eq!(reports[1].changes, 1);
+        let expected: syn::File = parse_quote! {
+            use std::fmt;
+            use std::fmt;
+            fn h0() {}
+            fn main() { crate::mylib::run(); }
+            pub mod mylib { pub fn run() {} }
+        };
+        assert_eq!(
+            file.to_token_stream().to_string(),
+            expected.to_token_stream().to_string()
+        );
+
+        let mut pipeline = Pipeline::default();
+        pipeline.insert(PassPosition::after("nope"), Box::new(Rename));
+        assert!(pipeline.run(&options, &ctx, &mut file).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+/// Where the files of inlined modules may live, see [`Bundler::containment`].
+///
+/// [`Bundler::containment`]: crate::Bundler::containment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainmentPolicy {
+    /// Follow `#[path]` attributes wherever they lead
+    Anywhere,
+    /// Reject module files outside the workspace, e.g. absolute paths into `$HOME`
+    WorkspaceOnly,
+}
+
+impl Default for ContainmentPolicy {
+    fn default() -> Self {
+        ContainmentPolicy::Anywhere
+    }
+}
+
+/// Resolve `.` and `..` components of `path` without touching the file system, so
+/// `src/../../shared/ring.rs` becomes `../shared/ring.rs`.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                // can not go above the root
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                Some(Component::ParentDir) | Some(Component::CurDir) | None => {
+                    normalized.push("..")
+                }
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// `path` made absolute and normalized
+fn absolute(path: &Path) -> PathBuf {
+    match env::current_dir() {
+        Ok(cwd) if path.is_relative() => normalize(&cwd.join(path)),
+        _ => normalize(path),
+    }
+}
+
+/// The root of the workspace the crate at `crate_dir` belongs to, i.e. the closest directory
+/// above it with a manifest declaring `[workspace]`, or the crate directory itself.
+pub fn workspace_root(crate_dir: &Path) -> PathBuf {
+    let crate_dir = absolute(crate_dir);
+    crate_dir
+        .ancestors()
+        .find(|dir| {
+            fs::read_to_string(dir.join("Cargo.toml"))
+                .ok()
+                .and_then(|text| toml::from_str::<toml::Value>(&text).ok())
+                .map_or(false, |manifest| manifest.get("workspace").is_some())
+        })
+        .unwrap_or(crate_dir.as_path())
+        .to_path_buf()
+}
+
+/// `path` relative to `root` if it is inside, for messages and markers that should not depend
+/// on where the checkout is.
+pub fn relative_to(path: &Path, root: &Path) -> PathBuf {
+    let path = absolute(path);
+    path.strip_prefix(absolute(root))
+        .map(Path::to_path_buf)
+        .unwrap_or(path)
+}
+
+/// `path` relative to the directory `base`, going up with `..` as needed, e.g. `../src/main.rs`
+/// for `/pkg/src/main.rs` from `/pkg/scripts`. Paths on different Windows drives have no
+/// relative path, those stay absolute.
+pub fn relative_from(path: &Path, base: &Path) -> PathBuf {
+    let (path, base) = (absolute(path), absolute(base));
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path;
+    }
+    let mut relative: PathBuf = base.components().skip(common).map(|_| "..").collect();
+    relative.extend(path.components().skip(common));
+    relative
+}
+
+/// Check `sources` against `policy`, with `root` the workspace root
+pub fn check_containment(
+    policy: ContainmentPolicy,
+    sources: &[PathBuf],
+    root: &Path,
+) -> Result<()> {
+    if policy == ContainmentPolicy::Anywhere {
+        return Ok(());
+    }
+    // symlinks may point anywhere, so compare real paths where possible
+    let real = |path: &Path| path.canonicalize().unwrap_or_else(|_| absolute(path));
+    let root = real(root);
+    let escaped: Vec<_> = sources
+        .iter()
+        .filter(|source| !real(source).starts_with(&root))
+        .map(|source| source.display().to_string())
+        .collect();
+    if !escaped.is_empty() {
+        bail!(
+            "Module files outside the workspace at {}:\n{}",
+            root.display(),
+            escaped.join("\n")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_lexically() {
+        assert_eq!(
+            normalize(Path::new("/ws/app/src/../../shared/./ring.rs")),
+            Path::new("/ws/shared/ring.rs")
+        );
+        assert_eq!(
+            normalize(Path::new("src/../../shared/ring.rs")),
+            Path::new("../shared/ring.rs")
+        );
+        assert_eq!(normalize(Path::new("/../etc")), Path::new("/etc"));
+        assert_eq!(
+            relative_to(Path::new("/ws/app/../shared/ring.rs"), Path::new("/ws")),
+            Path::new("shared/ring.rs")
+        );
+        assert_eq!(
+            relative_from(Path::new("/pkg/src/main.rs"), Path::new("/pkg/scripts")),
+            Path::new("../src/main.rs")
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Result;
+use proc_macro2::{Group, TokenStream, TokenTree};
+use quote::quote;
+use syn::punctuated::Punctuated;
+use syn::visit_mut::{self, VisitMut};
+
+use crate::resolve;
+
+/// Values of the `CARGO_PKG_*` and `CARGO_CRATE_NAME` variables cargo sets when compiling a
+/// crate, so that `env!` of them can be frozen into the bundle.
+#[derive(Debug, Clone, Default)]
+pub struct PkgEnv(BTreeMap<&'static str, String>);
+
+impl PkgEnv {
+    pub fn new(package: &cargo_toml::Package, crate_name: &str) -> Self {
+        let mut vars = BTreeMap::new();
+        let version = &package.version;
+        let (core, pre) = version
+            .split('+')
+            .next()
+            .map(|v| v.split_once('-').unwrap_or((v, "")))
+            .unwrap_or_default();
+        let mut numbers = core.split('.');
+        vars.insert("CARGO_PKG_VERSION", version.clone());
+        vars.insert(
+            "CARGO_PKG_VERSION_MAJOR",
+            numbers.next().unwrap_or("").into(),
+        );
+        vars.insert(
+            "CARGO_PKG_VERSION_MINOR",
+            numbers.next().unwrap_or("").into(),
+        );
+        vars.insert(
+            "CARGO_PKG_VERSION_PATCH",
+            numbers.next().unwrap_or("").into(),
+        );
+        vars.insert("CARGO_PKG_VERSION_PRE", pre.into());
+        vars.insert("CARGO_PKG_NAME", package.name.clone());
+        vars.insert("CARGO_PKG_AUTHORS", package.authors.join(":"));
+        let optional = [
+            ("CARGO_PKG_DESCRIPTION", &package.description),
+            ("CARGO_PKG_HOMEPAGE", &package.homepage),
+            ("CARGO_PKG_REPOSITORY", &package.repository),
+            ("CARGO_PKG_LICENSE", &package.license),
+        ];
+        for (var, value) in optional {
+            vars.insert(var, value.clone().unwrap_or_default());
+        }
+        vars.insert("CARGO_CRATE_NAME", crate_name.replace('-', "_"));
+        PkgEnv(vars)
+    }
+
+    /// The environment of the crate rooted at `root`, from the nearest `Cargo.toml` above it.
+    pub fn for_crate_root(root: &Path, crate_name: &str) -> Result<Option<Self>> {
+        Ok(
+            resolve::find_crate_manifest(root)?.and_then(|(manifest, _)| {
+                manifest
+                    .package
+                    .as_ref()
+                    .map(|package| Self::new(package, crate_name))
+            }),
+        )
+    }
+
+    /// The literal an `env!` or `option_env!` invocation with `args` expands to, if it reads one
+    /// of our variables.
+    fn expansion(&self, name: &str, args: TokenStream) -> Option<TokenStream> {
+        let parser = Punctuated::<syn::LitStr, syn::Token![,]>::parse_terminated;
+        let args = syn::parse::Parser::parse2(parser, args).ok()?;
+        let key = args.first()?;
+        let value = self.0.get(key.value().as_str())?;
+        let lit = syn::LitStr::new(value, key.span());
+        match name {
+            "env" => Some(quote!(#lit)),
+            "option_env" if args.len() == 1 => Some(quote!(::core::option::Option::Some(#lit))),
+            _ => None,
+        }
+    }
+
+    /// Expand invocations within the tokens of other macros, which syn does not parse
+    fn expand_tokens(&self, tokens: TokenStream) -> TokenStream {
+        let tokens: Vec<_> = tokens.into_iter().collect();
+        let mut expanded = TokenStream::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            if let [TokenTree::Ident(name), TokenTree::Punct(bang), TokenTree::Group(args), ..] =
+                &tokens[i..]
+            {
+                let name = name.to_string();
+                if bang.as_char() == '!' && (name == "env" || name == "option_env") {
+                    if let Some(lit) = self.expansion(&name, args.stream()) {
+                        expanded.extend(lit);
+                        i += 3;
+                        continue;
+                    }
+                }
+            }
+            match &tokens[i] {
+                TokenTree::Group(group) => {
+                    let mut new = Group::new(group.delimiter(), self.expand_tokens(group.stream()));
+                    new.set_span(group.span());
+                    expanded.extend(Some(TokenTree::Group(new)));
+                }
+                token => expanded.extend(Some(token.clone())),
+            }
+            i += 1;
+        }
+        expanded
+    }
+}
+
+/// Replace `env!`/`option_env!` of the variables in `env` by literals throughout `file`. Other
+/// keys are left alone.
+pub fn expand(file: &mut syn::File, env: &PkgEnv) {
+    Expander(env).visit_file_mut(file);
+}
+
+struct Expander<'a>(&'a PkgEnv);
+
+impl VisitMut for Expander<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut syn::Expr) {
+        if let syn::Expr::Macro(syn::ExprMacro { mac, .. }) = expr {
+            let name = mac.path.get_ident().map(ToString::to_string);
+            if let Some(lit) = name.and_then(|name| self.0.expansion(&name, mac.tokens.clone())) {
+                *expr = syn::parse_quote!(#lit);
+                return;
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+
+    fn visit_macro_mut(&mut self, mac: &mut syn::Macro) {
+        mac.tokens = self.0.expand_tokens(std::mem::take(&mut mac.tokens));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cargo_toml::Manifest;
+    use syn::parse_quote;
+
+    fn env() -> PkgEnv {
+        let manifest = Manifest::from_str(
+            "[package]\nname = \"my-lib\"\nversion = \"1.2.3-beta.1\"\nauthors = [\"a\", \"b\"]\n",
+        )
+        .unwrap();
+        PkgEnv::new(manifest.package.as_ref().unwrap(), "my-lib")
+    }
+
+    #[test]
+    fn version_parts() {
+        let env = env();
+        assert_eq!(env.0["CARGO_PKG_VERSION_MINOR"], "2");
+        assert_eq!(env.0["CARGO_PKG_VERSION_PRE"], "beta.1");
+        assert_eq!(env.0["CARGO_PKG_AUTHORS"], "a:b");
+        assert_eq!(env.0["CARGO_CRATE_NAME"], "my_lib");
+    }
+
+    #[test]
+    fn expands_expressions_and_macro_arguments() {
+        let mut file: syn::File = parse_quote! {
+            const VERSION: &str = env!("CARGO_PKG_VERSION");
+            fn main() {
+                let name = option_env!("CARGO_PKG_NAME");
+                println!("{} {}", env!("CARGO_PKG_NAME"), env!("HOME"));
+            }
+        };
+        expand(&mut file, &env());
+        let expected: syn::File = parse_quote! {
+            const VERSION: &str = "1.2.3-beta.1";
+            fn main() {
+                let name = ::core::option::Option::Some("my-lib");
+                println!("{} {}", "my-lib", env!("HOME"));
+            }
+        };
+        assert_eq!(quote!(#file).to_string(), quote!(#expected).to_string());
+    }
+}
@@ -0,0 +1,100 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use anyhow::{Context, Result};
+
+use crate::flavor;
+use crate::warning::{Warning, WarningKind};
+
+/// How [`Bundler::bundle`](crate::Bundler::bundle) makes the script readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Formatting {
+    /// Pretty-print in process with prettyplease, which needs no external tools
+    PrettyPlease,
+    /// Pipe the script through `rustfmt` before writing it. rustfmt is taken from the `RUSTFMT`
+    /// environment variable, else from `rustup which rustfmt`, else from the `PATH`. Without one
+    /// the script is left unformatted with a warning, unless
+    /// [`Bundler::formatting_required`](crate::Bundler::formatting_required).
+    Rustfmt,
+    /// Leave the script as printed from the syntax tree
+    None,
+}
+
+impl Default for Formatting {
+    fn default() -> Self {
+        Formatting::PrettyPlease
+    }
+}
+
+/// Pretty-print the code in the bundle `text`. The leading and trailing lines that are not code,
+/// i.e. the shebang, the manifest in frontmatter or inner attributes, other inner attributes, and
+/// plain comments, are kept as they are, as prettyplease would drop the shebang and comments.
+/// Code prettyplease can not print is left as it is, adding to `warnings`.
+pub fn format(text: &str, warnings: &mut Vec<Warning>) -> Result<String> {
+    let lines: Vec<_> = text.split_inclusive('\n').collect();
+    let start = flavor::frontmatter_lines(&lines).map_or(0, |lines| lines.end);
+    let start = start
+        + lines[start..]
+            .iter()
+            .take_while(|line| is_frame(line))
+            .count();
+    let end = lines.len()
+        - lines[start..]
+            .iter()
+            .rev()
+            .take_while(|line| is_frame(line))
+            .count();
+    let body = lines[start..end].concat();
+    let file = syn::parse_file(&body).context("The bundled script does not parse")?;
+    // prettyplease panics on syntax it does not know how to print
+    let code = match panic::catch_unwind(AssertUnwindSafe(|| prettyplease::unparse(&file))) {
+        Ok(code) => code,
+        Err(_) => {
+            let warning = "Failed to pretty-print the bundle, leaving it unformatted";
+            log::warn!("{}", warning);
+            warnings.push(Warning::new(WarningKind::Formatting, warning));
+            body
+        }
+    };
+    Ok([lines[..start].concat(), code, lines[end..].concat()].concat())
+}
+
+/// Whether `line` is blank, a plain or inner doc comment, a shebang or an inner attribute, all of
+/// which the printer puts on lines of their own
+fn is_frame(line: &str) -> bool {
+    let line = line.trim();
+    let comment = line.starts_with("//") && !line.starts_with("///");
+    line.is_empty() || line.starts_with("#!") || comment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_is_kept() {
+        let text = "#!/usr/bin/env -S rust-script\n\
+            // DO NOT EDIT\n\
+            //! ```cargo\n\
+            //! ```\n\
+            #![allow(dead_code)]\n\
+            \n\
+            /// Entry\n\
+            fn main () { let x = 1 ; println ! (\"{}\" , x) ; }\n\
+            \n\
+            // vim: ft=rust syntax=rust\n";
+        let formatted = format(text, &mut vec![]).unwrap();
+        assert_eq!(
+            formatted,
+            "#!/usr/bin/env -S rust-script\n\
+            // DO NOT EDIT\n\
+            //! ```cargo\n\
+            //! ```\n\
+            #![allow(dead_code)]\n\
+            \n\
+            /// Entry\n\
+            fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n\
+            \n\
+            // vim: ft=rust syntax=rust\n"
+        );
+    }
+}
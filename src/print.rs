@@ -1,67 +1,350 @@
-use std::fmt::Display;
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::iter::Peekable;
 
-use proc_macro2::{Delimiter, Spacing, TokenStream, TokenTree};
-use quote::ToTokens;
+use proc_macro2::{token_stream, Delimiter, Literal, Spacing, TokenStream, TokenTree};
+use quote::{quote, ToTokens};
 use syn::Lit;
 
+use crate::verbatim;
+
+/// Printing a syntax tree as source, see [`FilePrinter`]
 pub trait SynFilePrint {
     fn print(&self) -> FilePrinter;
 }
 
 impl SynFilePrint for syn::File {
     fn print(&self) -> FilePrinter {
-        FilePrinter(&self)
+        FilePrinter {
+            file: self,
+            preamble: "",
+            frontmatter: "",
+            banner: "",
+            header: "",
+            fragments: &[],
+            preserve_comments: false,
+            compact: false,
+        }
+    }
+}
+
+/// Prints a file the way [`Bundler`](crate::Bundler) does, with doc attributes as `///` and
+/// `//!` comments, and room for the lines a script needs before its code. Write it to any
+/// [`io::Write`] with [`FilePrinter::write_to`], or use its [`Display`], which fails where
+/// `write_to` returns an error.
+pub struct FilePrinter<'a> {
+    file: &'a syn::File,
+    /// written right after the shebang
+    preamble: &'a str,
+    /// written after the shebang and preamble, shebang or not
+    frontmatter: &'a str,
+    /// written before the inner attributes
+    banner: &'a str,
+    /// written between the inner attributes and the items
+    header: &'a str,
+    /// printed items, in place of the markers [`take_fragment`] leaves
+    fragments: &'a [String],
+    preserve_comments: bool,
+    compact: bool,
+}
+
+impl<'a> FilePrinter<'a> {
+    /// Write `preamble` as is right after the shebang, if there is one, for lines that have to come before
+    /// anything else but are not part of the syntax tree.
+    pub fn with_preamble(mut self, preamble: &'a str) -> Self {
+        self.preamble = preamble;
+        self
+    }
+
+    /// Write `frontmatter` as is first thing in the file, or after the shebang and preamble if
+    /// there is a shebang, where the frontmatter of a cargo script goes.
+    pub fn with_frontmatter(mut self, frontmatter: &'a str) -> Self {
+        self.frontmatter = frontmatter;
+        self
+    }
+
+    /// Write `banner` as is before the inner attributes, after the shebang and preamble if any.
+    pub fn with_banner(mut self, banner: &'a str) -> Self {
+        self.banner = banner;
+        self
+    }
+
+    /// Write `header` as is after the inner attributes, which keeps the manifest first.
+    pub fn with_header(mut self, header: &'a str) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Write `fragments[idx]` in place of the marker [`take_fragment`] left with `idx`.
+    pub(crate) fn with_fragments(mut self, fragments: &'a [String]) -> Self {
+        self.fragments = fragments;
+        self
+    }
+
+    /// Copy items from their original source where nothing changed them, which keeps the plain
+    /// comments in and between them. Items that were changed are printed from their tokens as
+    /// usual, and modules made of several files are printed item by item.
+    pub fn preserve_comments(mut self, preserve: bool) -> Self {
+        self.preserve_comments = preserve;
+        self
+    }
+
+    /// Leave out the blank line between items.
+    pub fn compact(mut self, compact: bool) -> Self {
+        self.compact = compact;
+        self
+    }
+}
+
+/// Name of the macro marking where a fragment goes, which no real code invokes
+const FRAGMENT_MARKER: &str = "__rust_script_bundler_fragment";
+
+/// Print the items of `module` now, leaving a marker with `idx` in their place. The printed
+/// items are returned to be passed to [`FilePrinter::with_fragments`] at index `idx`, which lets
+/// the syntax tree of a module be dropped long before the whole file is printed.
+/// `preserve` and `compact` are as in [`FilePrinter::preserve_comments`] and
+/// [`FilePrinter::compact`].
+pub fn take_fragment(
+    module: &mut syn::ItemMod,
+    idx: usize,
+    preserve: bool,
+    compact: bool,
+) -> String {
+    let items = match &mut module.content {
+        Some((_, items)) => std::mem::take(items),
+        None => vec![],
+    };
+    let mut fragment = vec![];
+    Items {
+        items: &items,
+        preserve,
+        compact,
+    }
+    .write(&mut fragment, &[])
+    .expect("Writing to a Vec does not fail");
+    let fragment = String::from_utf8(fragment).expect("Printed tokens are UTF-8");
+    module.content = Some((Default::default(), vec![placeholder(idx)]));
+    fragment
+}
+
+/// The marker [`take_fragment`] leaves for the fragment `idx`
+pub fn placeholder(idx: usize) -> syn::Item {
+    let marker = syn::Ident::new(FRAGMENT_MARKER, proc_macro2::Span::call_site());
+    let idx = Literal::usize_unsuffixed(idx);
+    syn::Item::Verbatim(quote!(#marker!(#idx);))
+}
+
+/// Items as they are written to the file, one after the other
+struct Items<'a> {
+    items: &'a [syn::Item],
+    /// see [`FilePrinter::preserve_comments`]
+    preserve: bool,
+    /// see [`FilePrinter::compact`]
+    compact: bool,
+}
+
+impl Items<'_> {
+    fn write(&self, f: &mut dyn Write, fragments: &[String]) -> io::Result<()> {
+        let mut prev = None;
+        for item in self.items {
+            if let Some(comments) = prev
+                .filter(|_| self.preserve)
+                .and_then(|p| between(p, item))
+            {
+                writeln!(f, "{}", comments)?;
+            }
+            self.write_item(f, item, fragments)?;
+            prev = Some(item);
+        }
+        Ok(())
+    }
+
+    fn write_item(
+        &self,
+        f: &mut dyn Write,
+        item: &syn::Item,
+        fragments: &[String],
+    ) -> io::Result<()> {
+        // whether what was written ends the line, as printed tokens do after their last `;` or `}`
+        let ends_line = match item {
+            syn::Item::Verbatim(tokens) if is_fragment_marker(tokens) => {
+                write_tokens_normalized(f, tokens.clone(), fragments)?;
+                true
+            }
+            // no normalization, we do not know what is in there
+            syn::Item::Verbatim(tokens) => {
+                match verbatim::source_text(tokens) {
+                    Some(text) => write!(f, "{}", text)?,
+                    None => write!(f, "{}", tokens)?,
+                }
+                false
+            }
+            item if self.preserve => match original_text(item) {
+                Some(text) => {
+                    write!(f, "{}", text)?;
+                    false
+                }
+                None => match item {
+                    syn::Item::Mod(syn::ItemMod {
+                        attrs,
+                        vis,
+                        mod_token,
+                        ident,
+                        content: Some((_, items)),
+                        ..
+                    }) => {
+                        let (inner, outer): (Vec<_>, Vec<_>) = attrs
+                            .iter()
+                            .partition(|a| matches!(a.style, syn::AttrStyle::Inner(_)));
+                        let head = quote!(#(#outer)* #vis #mod_token #ident);
+                        write_tokens_normalized(f, head, fragments)?;
+                        writeln!(f, " {{")?;
+                        write_tokens_normalized(f, quote!(#(#inner)*), fragments)?;
+                        writeln!(f)?;
+                        Items {
+                            items,
+                            preserve: true,
+                            compact: self.compact,
+                        }
+                        .write(f, fragments)?;
+                        write!(f, "}}")?;
+                        false
+                    }
+                    item => {
+                        write_tokens_normalized(f, item.to_token_stream(), fragments)?;
+                        true
+                    }
+                },
+            },
+            item => {
+                write_tokens_normalized(f, item.to_token_stream(), fragments)?;
+                true
+            }
+        };
+        match (self.compact, ends_line) {
+            (true, true) => Ok(()),
+            (true, false) => writeln!(f),
+            (false, _) => writeln!(f, "\n"),
+        }
+    }
+}
+
+/// The source text of `item`, if its tokens are exactly what the source has there, i.e. nothing
+/// changed the item since it was parsed
+fn original_text(item: &syn::Item) -> Option<String> {
+    let tokens = item.to_token_stream();
+    let text = verbatim::source_text(&tokens)?;
+    let original: TokenStream = text.parse().ok()?;
+    (original.to_string() == tokens.to_string()).then(|| text)
+}
+
+/// The comments in the source between the consecutive items `prev` and `next`, if both come from
+/// the same file and only comments are between them there
+fn between(prev: &syn::Item, next: &syn::Item) -> Option<String> {
+    let (prev, next) = (prev.to_token_stream(), next.to_token_stream());
+    let (prev_first, prev_last) = (prev.clone().into_iter().next()?, prev.into_iter().last()?);
+    let (next_first, next_last) = (next.clone().into_iter().next()?, next.into_iter().last()?);
+    let (prev_end, next_start) = (prev_last.span().end(), next_first.span().start());
+    if (prev_end.line, prev_end.column) > (next_start.line, next_start.column) {
+        return None;
     }
+    let before = prev_first.span().join(prev_last.span())?.source_text()?;
+    let after = next_first.span().join(next_last.span())?.source_text()?;
+    let all = prev_first.span().join(next_last.span())?.source_text()?;
+    let gap = all.get(before.len()..all.len().checked_sub(after.len())?)?;
+    // anything but comments there would be code the bundle no longer has
+    let tokens: TokenStream = gap.parse().ok()?;
+    let gap = gap.trim();
+    (tokens.is_empty() && !gap.is_empty()).then(|| gap.to_string())
 }
 
-pub struct FilePrinter<'a>(&'a syn::File);
+impl FilePrinter<'_> {
+    /// Print the file to `out` as it goes, in many small writes, so wrap files in a
+    /// [`io::BufWriter`]. Fails if `out` does, or for an outer attribute on the file, which can
+    /// only have inner ones.
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        self.write(out)
+    }
 
-impl<'a> Display for FilePrinter<'a> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let file = self.0;
+    fn write(&self, f: &mut dyn Write) -> io::Result<()> {
+        let file = self.file;
+        if let Some(attr) = file
+            .attrs
+            .iter()
+            .find(|a| matches!(a.style, syn::AttrStyle::Outer))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File can only have inner attributes at top level, found {}",
+                    attr.to_token_stream()
+                ),
+            ));
+        }
         if let Some(shebang) = &file.shebang {
             writeln!(f, "{}", shebang)?;
+            if !self.preamble.is_empty() {
+                writeln!(f, "{}", self.preamble)?;
+            }
+        }
+        if !self.frontmatter.is_empty() {
+            writeln!(f, "{}", self.frontmatter)?;
         }
+        f.write_all(self.banner.as_bytes())?;
 
         // write inner attributes, we do two passes,
         // first are all doc attributes
         for attr in file.attrs.iter().filter(|a| a.path.is_ident("doc")) {
-            assert!(
-                matches!(attr.style, syn::AttrStyle::Inner(_)),
-                "File can only have inner attributes at top level"
-            );
             let mut it = attr.tokens.clone().into_iter();
-            if let (Some(_), Some(TokenTree::Literal(lit)), None) = (it.next(), it.next(), it.next()) {
-                if let Lit::Str(lit) = Lit::new(lit) {
-                    writeln!(f, "//!{}", lit.value())?;
-                }
+            let value = match (it.next(), it.next(), it.next()) {
+                (Some(_), Some(TokenTree::Literal(lit)), None) => match Lit::new(lit) {
+                    Lit::Str(lit) => Some(lit.value()),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match value.and_then(|value| doc_lines("//!", &value)) {
+                Some(lines) => f.write_all(lines.as_bytes())?,
+                None => writeln!(f, "#![{}{}]", attr.path.to_token_stream(), attr.tokens)?,
             }
         }
         // then others
         for attr in file.attrs.iter().filter(|a| !a.path.is_ident("doc")) {
-            assert!(
-                matches!(attr.style, syn::AttrStyle::Inner(_)),
-                "File can only have inner attributes at top level"
-            );
             writeln!(f, "#![{}{}]", attr.path.to_token_stream(), attr.tokens)?;
         }
 
-        // write items as is
-        for item in file.items.iter() {
-            write_tokens_normalized(f, item.to_token_stream())?;
-            writeln!(f, "\n")?;
+        if !self.header.is_empty() {
+            writeln!(f, "{}", self.header)?;
         }
 
-        Ok(())
+        // write items as is
+        let items = Items {
+            items: &file.items,
+            preserve: self.preserve_comments,
+            compact: self.compact,
+        };
+        items.write(f, self.fragments)
+    }
+}
+
+impl Display for FilePrinter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = vec![];
+        self.write(&mut text).map_err(|_| fmt::Error)?;
+        f.write_str(&String::from_utf8_lossy(&text))
     }
 }
 
 /// Write tokens same way as `TokenStream::to_string` would do, but with normalization of doc
-/// attributes into `///`.
+/// attributes into `///`, and fragment markers replaced by the matching one of `fragments`.
 ///
 /// Adapted from sourcegen cli @ commit 1492a97e86eee5e69a959c4347efb3c8c58e1a7e
 /// https://github.com/commure/sourcegen
-fn write_tokens_normalized(f: &mut std::fmt::Formatter, tokens: TokenStream) -> std::fmt::Result {
+fn write_tokens_normalized(
+    f: &mut dyn Write,
+    tokens: TokenStream,
+    fragments: &[String],
+) -> io::Result<()> {
     let mut tokens = tokens.into_iter().peekable();
     let mut joint = false;
     let mut first = true;
@@ -76,9 +359,19 @@ fn write_tokens_normalized(f: &mut std::fmt::Formatter, tokens: TokenStream) ->
         if let Some(comment) = tokens
             .peek()
             .and_then(|lookahead| as_doc_comment(&tt, lookahead))
+            .and_then(|comment| doc_lines("///", &comment))
         {
             let _ignore = tokens.next();
-            writeln!(f, "///{}", comment)?;
+            f.write_all(comment.as_bytes())?;
+            continue;
+        }
+        if matches!(&tt, TokenTree::Ident(ident) if ident == FRAGMENT_MARKER) {
+            let fragment = fragment_index(&mut tokens).and_then(|idx| fragments.get(idx));
+            let fragment = fragment.expect("Every fragment marker has its fragment");
+            write!(f, "\n{}", fragment)?;
+            if !fragment.ends_with('\n') {
+                writeln!(f)?;
+            }
             continue;
         }
         // write tt recursively
@@ -94,7 +387,7 @@ fn write_tokens_normalized(f: &mut std::fmt::Formatter, tokens: TokenStream) ->
                     write!(f, "{} {}", start, end)?
                 } else {
                     write!(f, "{} ", start)?;
-                    write_tokens_normalized(f, tt.stream())?;
+                    write_tokens_normalized(f, tt.stream(), fragments)?;
                     write!(f, " {}", end)?
                 }
             }
@@ -116,6 +409,43 @@ fn write_tokens_normalized(f: &mut std::fmt::Formatter, tokens: TokenStream) ->
     Ok(())
 }
 
+fn is_fragment_marker(tokens: &TokenStream) -> bool {
+    let first = tokens.clone().into_iter().next();
+    matches!(first, Some(TokenTree::Ident(ident)) if ident == FRAGMENT_MARKER)
+}
+
+/// The index in the rest of a fragment marker, `!(idx);`, consuming it
+fn fragment_index(tokens: &mut Peekable<token_stream::IntoIter>) -> Option<usize> {
+    let _bang = tokens.next();
+    let idx = match tokens.next() {
+        Some(TokenTree::Group(group)) => group.stream().to_string().parse().ok(),
+        _ => None,
+    };
+    let _semi = tokens.next();
+    idx
+}
+
+/// The doc string `value` as line comments starting with `prefix`, `///` or `//!`, one per line
+/// of it. `None` if some line would not read back the same, which the attribute is then kept
+/// for: a bare carriage return is not allowed in comments, and `////` is no doc comment.
+fn doc_lines(prefix: &str, value: &str) -> Option<String> {
+    let mut lines = String::new();
+    for line in value.split('\n') {
+        if line.contains('\r') || (prefix == "///" && line.starts_with('/')) {
+            return None;
+        }
+        lines.push_str(prefix);
+        lines.push_str(line);
+        lines.push('\n');
+    }
+    Some(lines)
+}
+
+/// The doc string of `first` and `second` if they are a plain outer `#[doc = "..."]`, with
+/// nothing but a string literal without suffix as the value. Anything else that looks like it,
+/// e.g. `#[doc(hidden)]`, `#[doc = include_str!(..)]` or `#[cfg_attr(.., doc = "...")]`, is
+/// `None` and printed as is, and so are inner attributes, whose `#` is followed by `!`.
+///
 /// Adapted from sourcegen cli @ commit 1492a97e86eee5e69a959c4347efb3c8c58e1a7e
 /// https://github.com/commure/sourcegen
 fn as_doc_comment(first: &TokenTree, second: &TokenTree) -> Option<String> {
@@ -124,15 +454,18 @@ fn as_doc_comment(first: &TokenTree, second: &TokenTree) -> Option<String> {
             if first.as_char() == '#' && group.delimiter() == Delimiter::Bracket =>
         {
             let mut it = group.stream().into_iter();
-            match (it.next(), it.next(), it.next()) {
+            match (it.next(), it.next(), it.next(), it.next()) {
                 (
                     Some(TokenTree::Ident(ident)),
                     Some(TokenTree::Punct(punct)),
                     Some(TokenTree::Literal(lit)),
+                    None,
                 ) => {
                     if ident == "doc" && punct.as_char() == '=' {
                         if let Lit::Str(lit) = Lit::new(lit) {
-                            return Some(lit.value());
+                            if lit.suffix().is_empty() {
+                                return Some(lit.value());
+                            }
                         }
                     }
                 }
@@ -143,3 +476,124 @@ fn as_doc_comment(first: &TokenTree, second: &TokenTree) -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The doc strings in `attrs`, as rustdoc joins them
+    fn docs(attrs: &[syn::Attribute]) -> String {
+        let values: Vec<_> = attrs
+            .iter()
+            .filter(|attr| attr.path.is_ident("doc"))
+            .map(|attr| match attr.parse_meta() {
+                Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                    lit: Lit::Str(lit), ..
+                })) => lit.value(),
+                _ => panic!("Unexpected doc attribute {}", attr.to_token_stream()),
+            })
+            .collect();
+        values.join("\n")
+    }
+
+    #[test]
+    fn doc_strings_roundtrip() {
+        let file: syn::File = syn::parse_quote! {
+            #![doc = "inner\n  continued"]
+            #![doc = "carriage\r return"]
+
+            #[doc = "first\n  second\n"]
+            #[doc = "/ not a fourth slash"]
+            #[doc = "carriage\r return"]
+            #[doc = " ends */ here"]
+            fn documented() {}
+        };
+        let printed = file.print().to_string();
+        assert!(printed.starts_with("//!inner\n//!  continued\n"));
+        assert!(printed.contains("///first\n///  second\n///\n"));
+
+        let parsed = syn::parse_file(&printed).unwrap();
+        assert_eq!(docs(&parsed.attrs), docs(&file.attrs));
+        match (&parsed.items[0], &file.items[0]) {
+            (syn::Item::Fn(parsed), syn::Item::Fn(original)) => {
+                assert_eq!(docs(&parsed.attrs), docs(&original.attrs))
+            }
+            _ => panic!("Expected a function"),
+        }
+    }
+
+    #[test]
+    fn doc_look_alikes_are_kept() {
+        let file: syn::File = syn::parse_quote! {
+            #[cfg_attr(feature = "docs", doc = "extra docs")]
+            #[doc(hidden)]
+            pub struct Hidden;
+
+            mod nested {
+                #![doc = "inner docs"]
+                #![allow(dead_code)]
+
+                #[doc = "outer docs"]
+                fn documented() {}
+            }
+        };
+        let printed = file.print().to_string();
+        let text: String = printed.split_whitespace().collect();
+        assert!(text.contains("#[cfg_attr(feature=\"docs\",doc=\"extradocs\")]"));
+        assert!(text.contains("#[doc(hidden)]"));
+        assert!(text.contains("#![doc=\"innerdocs\"]"));
+        assert!(printed.contains("///outer docs\n"));
+
+        let parsed = syn::parse_file(&printed).unwrap();
+        assert_eq!(
+            parsed.to_token_stream().to_string(),
+            file.to_token_stream().to_string()
+        );
+    }
+
+    #[test]
+    fn only_plain_doc_strings_become_comments() {
+        let doc = |attr: TokenStream| {
+            let mut tokens = attr.into_iter();
+            as_doc_comment(&tokens.next().unwrap(), &tokens.next().unwrap())
+        };
+        assert_eq!(doc(quote!(#[doc = "plain"])), Some("plain".into()));
+        assert_eq!(doc(quote!(#[doc = r"raw"])), Some("raw".into()));
+        assert_eq!(doc(quote!(#[doc = include_str!("README.md")])), None);
+        assert_eq!(doc("#[doc = \"suffixed\"x]".parse().unwrap()), None);
+        assert_eq!(doc(quote!(#[doc = "first" "second"])), None);
+        assert_eq!(doc(quote!(#[cfg_attr(docs, doc = "extra")])), None);
+        assert_eq!(doc(quote!(#![doc = "inner"])), None);
+    }
+
+    #[test]
+    fn files_print_to_any_writer() {
+        let file: syn::File = syn::parse_quote! {
+            #![allow(dead_code)]
+
+            #[doc = "documented"]
+            fn documented() {}
+        };
+        let mut sink = vec![];
+        file.print()
+            .with_header("// header")
+            .write_to(&mut sink)
+            .unwrap();
+        let text = String::from_utf8(sink).unwrap();
+        assert!(text.starts_with("#![allow(dead_code)]\n// header\n///documented\n"));
+        assert_eq!(text, file.print().with_header("// header").to_string());
+    }
+
+    #[test]
+    fn outer_file_attributes_are_an_error() {
+        let mut file: syn::File = syn::parse_quote!(
+            fn main() {}
+        );
+        file.attrs.push(syn::parse_quote!(#[allow(dead_code)]));
+        let err = file.print().write_to(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err
+            .to_string()
+            .starts_with("File can only have inner attributes"));
+    }
+}
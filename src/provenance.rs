@@ -0,0 +1,170 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::template;
+
+/// What [`Bundler::provenance`](crate::Bundler::provenance) records about where a bundle comes
+/// from: the package and its version, the commit checked out and whether the tree had changes,
+/// and when the bundle was made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    timestamp: bool,
+    consts: bool,
+    /// the commit and whether the tree was dirty, instead of asking git
+    commit: Option<(String, bool)>,
+}
+
+impl Default for Provenance {
+    fn default() -> Self {
+        Provenance {
+            timestamp: false,
+            consts: false,
+            commit: None,
+        }
+    }
+}
+
+impl Provenance {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Include the date the bundle was made. Off by default, so that bundles only change when
+    /// their sources do.
+    pub fn timestamp(mut self, timestamp: bool) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Also emit the provenance as `pub const BUNDLE_CRATE`, `BUNDLE_VERSION`, `BUNDLE_COMMIT`
+    /// and, with the timestamp, `BUNDLE_DATE`, all `&str`, at the root of the bundle, so the
+    /// script can tell where it comes from, e.g. for `--version`. Off by default.
+    pub fn consts(mut self, consts: bool) -> Self {
+        self.consts = consts;
+        self
+    }
+
+    /// Record `hash` as the commit, `dirty` if the tree had changes, rather than asking git
+    pub fn commit(mut self, hash: impl Into<String>, dirty: bool) -> Self {
+        self.commit = Some((hash.into(), dirty));
+        self
+    }
+
+    /// What to record for the package `name` at `version` in `dir`
+    pub(crate) fn facts(&self, name: &str, version: &str, dir: &Path) -> Facts {
+        Facts {
+            name: name.into(),
+            version: version.into(),
+            commit: self.commit.clone().or_else(|| git_commit(dir)),
+            date: self.timestamp.then(template::today),
+            consts: self.consts,
+        }
+    }
+}
+
+/// The provenance of one bundle, see [`Provenance::facts`]
+pub(crate) struct Facts {
+    name: String,
+    version: String,
+    /// `None` outside of a git checkout
+    commit: Option<(String, bool)>,
+    date: Option<String>,
+    consts: bool,
+}
+
+impl Facts {
+    /// The comment line to put at the top of the bundle
+    pub fn comment(&self) -> String {
+        let mut comment = format!(
+            "// Generated by rust-script-bundler v{} from {} v{}",
+            env!("CARGO_PKG_VERSION"),
+            self.name,
+            self.version
+        );
+        if let Some((hash, dirty)) = &self.commit {
+            let dirty = if *dirty { ", dirty" } else { "" };
+            comment.push_str(&format!(" ({}{})", hash, dirty));
+        }
+        if let Some(date) = &self.date {
+            comment.push_str(&format!(" on {}", date));
+        }
+        comment.push('\n');
+        comment
+    }
+
+    /// The consts to add to the root of the bundle, if asked for
+    pub fn items(&self) -> Vec<syn::Item> {
+        if !self.consts {
+            return vec![];
+        }
+        let commit = match &self.commit {
+            Some((hash, true)) => format!("{}-dirty", hash),
+            Some((hash, false)) => hash.clone(),
+            None => "unknown".into(),
+        };
+        let mut consts = vec![
+            ("BUNDLE_CRATE", self.name.clone()),
+            ("BUNDLE_VERSION", self.version.clone()),
+            ("BUNDLE_COMMIT", commit),
+        ];
+        consts.extend(self.date.clone().map(|date| ("BUNDLE_DATE", date)));
+        consts
+            .into_iter()
+            .map(|(name, value)| {
+                let name = syn::Ident::new(name, proc_macro2::Span::call_site());
+                syn::parse_quote! {
+                    #[allow(dead_code)]
+                    pub const #name: &str = #value;
+                }
+            })
+            .collect()
+    }
+}
+
+/// Short hash of the commit checked out at `dir` and whether the tree has changes, `None` if git
+/// is missing or `dir` is not in a checkout
+fn git_commit(dir: &Path) -> Option<(String, bool)> {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let hash = git(&["rev-parse", "--short", "HEAD"])?;
+    let dirty = git(&["status", "--porcelain"]).map_or(false, |status| !status.is_empty());
+    Some((hash, dirty))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::quote;
+
+    #[test]
+    fn facts_describe_the_bundle() {
+        let provenance = Provenance::new().timestamp(false).commit("abc1234", true);
+        let facts = provenance.facts("tool", "1.2.3", Path::new("."));
+        let expected = format!(
+            "// Generated by rust-script-bundler v{} from tool v1.2.3 (abc1234, dirty)\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(facts.comment(), expected);
+        assert!(facts.items().is_empty());
+
+        let facts = provenance
+            .consts(true)
+            .facts("tool", "1.2.3", Path::new("."));
+        let items: Vec<_> = facts
+            .items()
+            .iter()
+            .map(|i| quote!(#i).to_string())
+            .collect();
+        assert_eq!(items.len(), 3);
+        assert!(items[2].contains("pub const BUNDLE_COMMIT : & str = \"abc1234-dirty\""));
+    }
+}
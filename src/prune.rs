@@ -0,0 +1,239 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::UseTree;
+
+use crate::pass::PassContext;
+use crate::rewrite::item_ident;
+
+/// Drop the items of the inlined crates nothing in the bundle refers to, returning how many.
+///
+/// The analysis only goes by names: every identifier in the binary's items counts as a
+/// reference, and an item of the crates is kept if its name is referenced, after which the
+/// identifiers in it count as well. Several items of the same name are all kept, and so is
+/// anything that can be reached without naming it: items without a name, `#[macro_export]`
+/// macros, `#[macro_use]` crates, exported symbols and glob imports. Impls are kept with their
+/// self type, or with their trait if the self type is generic. Modules are never dropped, only
+/// their items.
+pub fn prune(ctx: &PassContext, file: &mut syn::File) -> usize {
+    let mut nodes = vec![];
+    let mut referenced = BTreeSet::new();
+    collect_idents(
+        file.attrs.iter().map(ToTokens::to_token_stream),
+        &mut referenced,
+    );
+    for (idx, item) in file.items.iter().enumerate() {
+        match item {
+            syn::Item::Mod(syn::ItemMod {
+                content: Some((_, content)),
+                ..
+            }) if ctx.is_inlined(item) => collect_nodes(content, &mut vec![idx], &mut nodes),
+            item => collect_idents(Some(item.to_token_stream()), &mut referenced),
+        }
+    }
+
+    let mut by_name: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (idx, (_, node)) in nodes.iter().enumerate() {
+        for name in &node.names {
+            by_name.entry(name).or_default().push(idx);
+        }
+    }
+    let mut kept = vec![false; nodes.len()];
+    let mut pending: Vec<String> = referenced.into_iter().collect();
+    let mut keep = |idx: usize, pending: &mut Vec<String>| {
+        if !kept[idx] {
+            kept[idx] = true;
+            pending.extend(nodes[idx].1.idents.iter().cloned());
+        }
+    };
+    for idx in (0..nodes.len()).filter(|idx| nodes[*idx].1.always) {
+        keep(idx, &mut pending);
+    }
+    let mut seen = BTreeSet::new();
+    while let Some(name) = pending.pop() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        for idx in by_name.get(name.as_str()).into_iter().flatten() {
+            keep(*idx, &mut pending);
+        }
+    }
+
+    let dropped: BTreeSet<_> = nodes
+        .iter()
+        .zip(&kept)
+        .filter(|(_, kept)| !**kept)
+        .map(|((path, _), _)| path.clone())
+        .collect();
+    for path in &dropped {
+        log::debug!("Pruned unused item at {:?}", path);
+    }
+    for (idx, item) in file.items.iter_mut().enumerate() {
+        if !ctx.is_inlined(item) {
+            continue;
+        }
+        if let syn::Item::Mod(syn::ItemMod {
+            content: Some((_, content)),
+            ..
+        }) = item
+        {
+            remove(content, &mut vec![idx], &dropped);
+        }
+    }
+    dropped.len()
+}
+
+/// An item of the inlined crates that may be dropped
+struct Node {
+    /// what referring to the item takes
+    names: Vec<String>,
+    /// every identifier in the item, which keeping it refers to
+    idents: BTreeSet<String>,
+    /// whether the item is kept whatever refers to it
+    always: bool,
+}
+
+/// Add a node for each item in `items` and their inline modules to `nodes`, with the indices
+/// leading to it from the root, `path` being those of the module of `items`
+fn collect_nodes(items: &[syn::Item], path: &mut Vec<usize>, nodes: &mut Vec<(Vec<usize>, Node)>) {
+    for (idx, item) in items.iter().enumerate() {
+        path.push(idx);
+        match item {
+            syn::Item::Mod(syn::ItemMod {
+                content: Some((_, content)),
+                ..
+            }) => collect_nodes(content, path, nodes),
+            item => nodes.push((path.clone(), node(item))),
+        }
+        path.pop();
+    }
+}
+
+fn node(item: &syn::Item) -> Node {
+    let mut idents = BTreeSet::new();
+    collect_idents(Some(item.to_token_stream()), &mut idents);
+    let (names, always) = match item {
+        syn::Item::Use(u) => {
+            let mut names = vec![];
+            let glob = use_names(&u.tree, None, &mut names);
+            (names, glob)
+        }
+        syn::Item::Impl(i) => {
+            let generics: BTreeSet<_> = i
+                .generics
+                .type_params()
+                .map(|param| param.ident.to_string())
+                .collect();
+            let mut names = BTreeSet::new();
+            collect_idents(Some(i.self_ty.to_token_stream()), &mut names);
+            let mut names: Vec<_> = names.difference(&generics).cloned().collect();
+            if let (true, Some((_, trait_, _))) = (names.is_empty(), &i.trait_) {
+                names.extend(trait_.segments.last().map(|s| s.ident.to_string()));
+            }
+            let always = names.is_empty();
+            (names, always)
+        }
+        item => {
+            let exported = item_attrs(item).iter().any(|attr| {
+                [
+                    "macro_export",
+                    "macro_use",
+                    "no_mangle",
+                    "export_name",
+                    "used",
+                ]
+                .iter()
+                .any(|name| attr.path.is_ident(name))
+            });
+            match item_ident(item) {
+                Some(ident) => (vec![ident.to_string()], exported),
+                None => (vec![], true),
+            }
+        }
+    };
+    Node {
+        names,
+        idents,
+        always,
+    }
+}
+
+/// Add the names `tree` brings into scope to `names`, `parent` being the segment before it.
+/// Returns whether it has a glob, which brings in names that can not be told.
+fn use_names(tree: &UseTree, parent: Option<&syn::Ident>, names: &mut Vec<String>) -> bool {
+    match tree {
+        UseTree::Path(p) => use_names(&p.tree, Some(&p.ident), names),
+        UseTree::Name(n) if n.ident == "self" => {
+            names.extend(parent.map(ToString::to_string));
+            false
+        }
+        UseTree::Name(n) => {
+            names.push(n.ident.to_string());
+            false
+        }
+        UseTree::Rename(r) => {
+            names.push(r.rename.to_string());
+            false
+        }
+        UseTree::Glob(_) => true,
+        UseTree::Group(g) => g
+            .items
+            .iter()
+            .fold(false, |glob, tree| use_names(tree, parent, names) || glob),
+    }
+}
+
+fn item_attrs(item: &syn::Item) -> &[syn::Attribute] {
+    use syn::Item::*;
+    match item {
+        Const(i) => &i.attrs,
+        Enum(i) => &i.attrs,
+        ExternCrate(i) => &i.attrs,
+        Fn(i) => &i.attrs,
+        Macro(i) => &i.attrs,
+        Macro2(i) => &i.attrs,
+        Static(i) => &i.attrs,
+        Struct(i) => &i.attrs,
+        Trait(i) => &i.attrs,
+        TraitAlias(i) => &i.attrs,
+        Type(i) => &i.attrs,
+        Union(i) => &i.attrs,
+        _ => &[],
+    }
+}
+
+fn collect_idents(streams: impl IntoIterator<Item = TokenStream>, out: &mut BTreeSet<String>) {
+    for stream in streams {
+        for token in stream {
+            match token {
+                TokenTree::Ident(ident) => {
+                    out.insert(ident.to_string().trim_start_matches("r#").to_string());
+                }
+                TokenTree::Group(group) => collect_idents(Some(group.stream()), out),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Remove the items at `dropped` from `items`, those of the module at `path`
+fn remove(items: &mut Vec<syn::Item>, path: &mut Vec<usize>, dropped: &BTreeSet<Vec<usize>>) {
+    let mut idx = 0;
+    items.retain_mut(|item| {
+        path.push(idx);
+        idx += 1;
+        let keep = match item {
+            syn::Item::Mod(syn::ItemMod {
+                content: Some((_, content)),
+                ..
+            }) => {
+                remove(content, path, dropped);
+                true
+            }
+            _ => !dropped.contains(path),
+        };
+        path.pop();
+        keep
+    });
+}
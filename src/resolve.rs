@@ -0,0 +1,762 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use cargo_toml::Manifest;
+
+use crate::edition::Edition;
+use crate::manifest;
+use crate::paths;
+
+/// How the package's targets and dependencies are resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Interpret `Cargo.toml` ourselves via `cargo_toml`. Works without cargo on PATH.
+    CargoToml,
+    /// Ask cargo via `cargo metadata`, which is authoritative for exotic manifests.
+    CargoMetadata,
+}
+
+impl Default for Resolution {
+    fn default() -> Self {
+        Resolution::CargoToml
+    }
+}
+
+/// Everything the bundler needs to know about a package, independent of how it was resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    pub edition: String,
+    pub lib: Option<Target>,
+    pub bins: Vec<Target>,
+    pub examples: Vec<Target>,
+    /// The bin target `cargo run` picks, from `default-run`
+    pub default_run: Option<String>,
+    pub features: BTreeMap<String, Vec<String>>,
+    pub dependencies: Vec<Dependency>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target {
+    /// Target name, for lib targets this is the crate name as used in code
+    pub name: String,
+    /// Absolute path to the crate root
+    pub path: PathBuf,
+    pub required_features: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DepKind {
+    Normal,
+    Dev,
+    Build,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Dependency {
+    pub kind: DepKind,
+    /// `cfg(...)` or target triple for platform specific dependencies
+    pub target: Option<String>,
+    /// The name used in code, i.e. after any renaming
+    pub name: String,
+    /// The actual package name
+    pub package: String,
+    /// Version requirement, normalized the way cargo prints it
+    pub req: String,
+    pub optional: bool,
+    pub default_features: bool,
+    pub features: Vec<String>,
+    /// Absolute path for path dependencies
+    pub path: Option<PathBuf>,
+}
+
+/// Resolve the package at `manifest_dir`, whose already parsed and completed manifest is `manifest`.
+pub fn resolve_package(
+    resolution: Resolution,
+    manifest: &Manifest,
+    manifest_dir: &Path,
+) -> Result<Package> {
+    let package = match resolution {
+        Resolution::CargoToml => from_cargo_toml(manifest, manifest_dir)?,
+        Resolution::CargoMetadata => from_cargo_metadata(manifest_dir)?,
+    };
+    Ok(sorted(package))
+}
+
+/// `package` with its targets and dependencies in a fixed order, which makes results comparable
+/// regardless of backend
+fn sorted(mut package: Package) -> Package {
+    package.bins.sort();
+    package.examples.sort();
+    package.dependencies.sort();
+    package
+}
+
+/// The manifest of the crate rooted at the file `root`, i.e. the nearest `Cargo.toml` above it,
+/// parsed and completed, along with its directory.
+pub fn find_crate_manifest(root: &Path) -> Result<Option<(Manifest, PathBuf)>> {
+    let manifest_path = match root
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join("Cargo.toml"))
+        .find(|path| path.is_file())
+    {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let manifest = read_manifest(&manifest_path)?;
+    let dir = manifest_path.parent().unwrap().to_path_buf();
+    Ok(Some((manifest, dir)))
+}
+
+fn read_manifest(manifest_path: &Path) -> Result<Manifest> {
+    let content = std::fs::read_to_string(manifest_path)
+        .with_context(|| format!("Failed to read manifest at {}", manifest_path.display()))?;
+    let dir = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+    let content = manifest::inherit_workspace(&content, dir)?;
+    let mut manifest = Manifest::from_str(&content)
+        .with_context(|| format!("Invalid manifest at {}", manifest_path.display()))?;
+    manifest.complete_from_path(manifest_path)?;
+    Ok(manifest)
+}
+
+/// The libs of the path dependencies of `package` at `manifest_dir`, and of theirs in turn, as
+/// the name code refers to each by and its crate root. Dependencies come before the crates that
+/// depend on them, and crates reached several ways appear once. The dependencies are resolved
+/// with `resolution`, for [`Resolution::CargoMetadata`] by a single `cargo metadata` run with
+/// dependencies.
+pub fn path_dependencies(
+    resolution: Resolution,
+    package: &Package,
+    manifest_dir: &Path,
+) -> Result<Vec<(String, PathBuf)>> {
+    let dir = manifest_dir
+        .canonicalize()
+        .with_context(|| format!("Package directory {} not found", manifest_dir.display()))?;
+    let resolved = match resolution {
+        Resolution::CargoToml => None,
+        Resolution::CargoMetadata => Some(metadata_packages(&dir, true)?),
+    };
+    let mut chain = vec![(package.name.clone(), dir)];
+    let mut found = vec![];
+    walk_path_deps(
+        package,
+        resolved.as_ref(),
+        &mut chain,
+        &mut BTreeSet::new(),
+        &mut found,
+    )?;
+    Ok(found)
+}
+
+/// `chain` are the packages depended through to get to `package`, with their directories.
+/// The packages found are looked up in `resolved` if given, and read from their manifests
+/// otherwise.
+fn walk_path_deps(
+    package: &Package,
+    resolved: Option<&BTreeMap<PathBuf, Package>>,
+    chain: &mut Vec<(String, PathBuf)>,
+    visited: &mut BTreeSet<PathBuf>,
+    found: &mut Vec<(String, PathBuf)>,
+) -> Result<()> {
+    for dep in package
+        .dependencies
+        .iter()
+        .filter(|d| d.kind == DepKind::Normal)
+    {
+        // the bundle is for every platform, so published platform-specific dependencies are
+        // left to the script's manifest, in the same target table
+        if dep.target.is_some() && dep.req != "*" {
+            continue;
+        }
+        let dir = match &dep.path {
+            Some(dir) => dir.canonicalize().with_context(|| {
+                format!(
+                    "Path dependency {} of {} not found at {}",
+                    dep.name,
+                    package.name,
+                    dir.display()
+                )
+            })?,
+            None => continue,
+        };
+        if let Some(start) = chain.iter().position(|(_, seen)| *seen == dir) {
+            let cycle: Vec<_> = chain[start..]
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .chain(Some(dep.package.as_str()))
+                .collect();
+            bail!("Path dependencies form a cycle: {}", cycle.join(" -> "));
+        }
+        if !visited.insert(dir.clone()) {
+            continue;
+        }
+
+        let dep_package = match resolved {
+            Some(packages) => packages.get(&dir).cloned().ok_or_else(|| {
+                anyhow!(
+                    "cargo metadata did not report path dependency {} of {}",
+                    dep.name,
+                    package.name
+                )
+            })?,
+            None => sorted(from_cargo_toml(
+                &read_manifest(&dir.join("Cargo.toml"))?,
+                &dir,
+            )?),
+        };
+        let lib = dep_package.lib.clone().ok_or_else(|| {
+            anyhow!(
+                "Path dependency {} of {} has no lib target",
+                dep.name,
+                package.name
+            )
+        })?;
+        chain.push((dep_package.name.clone(), dir));
+        walk_path_deps(&dep_package, resolved, chain, visited, found)?;
+        chain.pop();
+        found.push((dep.name.replace('-', "_"), lib.path));
+    }
+    Ok(())
+}
+
+fn from_cargo_toml(manifest: &Manifest, manifest_dir: &Path) -> Result<Package> {
+    let package = manifest
+        .package
+        .as_ref()
+        .ok_or_else(|| anyhow!("Manifest at {} has no [package]", manifest_dir.display()))?;
+
+    let target = |product: &cargo_toml::Product, default_name: &str| -> Option<Target> {
+        Some(Target {
+            name: product.name.clone().unwrap_or_else(|| default_name.into()),
+            path: manifest_dir.join(product.path.as_ref()?),
+            required_features: product.required_features.clone(),
+        })
+    };
+
+    // cargo's conventions fill in what `[lib]` leaves out, or the whole table
+    let lib_path = match manifest.lib.as_ref().and_then(|lib| lib.path.as_ref()) {
+        Some(path) => Some(manifest_dir.join(path)),
+        None => Some(manifest_dir.join("src/lib.rs")).filter(|path| path.is_file()),
+    };
+    let lib = lib_path.map(|path| {
+        let lib = manifest.lib.as_ref();
+        Target {
+            name: lib
+                .and_then(|lib| lib.name.as_deref())
+                .unwrap_or(&package.name)
+                .replace('-', "_"),
+            path,
+            required_features: lib.map_or(vec![], |lib| lib.required_features.clone()),
+        }
+    });
+    let mut bins: Vec<_> = manifest
+        .bin
+        .iter()
+        .filter_map(|bin| target(bin, &package.name))
+        .collect();
+    if package.autobins {
+        for bin in discover_dir_bins(manifest_dir)? {
+            if !bins
+                .iter()
+                .any(|b| b.name == bin.name || b.path == bin.path)
+            {
+                bins.push(bin);
+            }
+        }
+    }
+
+    let mut examples = vec![];
+    for example in &manifest.example {
+        let name = match &example.name {
+            Some(name) => name,
+            None => continue,
+        };
+        let path = match &example.path {
+            Some(path) => manifest_dir.join(path),
+            None => example_path(manifest_dir, name),
+        };
+        examples.push(Target {
+            name: name.clone(),
+            path,
+            required_features: example.required_features.clone(),
+        });
+    }
+    if package.autoexamples {
+        for example in discover_examples(manifest_dir)? {
+            if !examples
+                .iter()
+                .any(|e| e.name == example.name || e.path == example.path)
+            {
+                examples.push(example);
+            }
+        }
+    }
+
+    let dependencies = manifest_dependencies(manifest, manifest_dir);
+
+    // cargo adds an implicit feature for every optional dependency not referred to as `dep:name`
+    let mut features = manifest.features.clone();
+    let explicit: BTreeSet<_> = features
+        .values()
+        .flatten()
+        .filter_map(|f| f.strip_prefix("dep:"))
+        .map(String::from)
+        .collect();
+    for dep in dependencies.iter().filter(|d| d.optional) {
+        if !explicit.contains(&dep.name) {
+            features
+                .entry(dep.name.clone())
+                .or_insert_with(|| vec![format!("dep:{}", dep.name)]);
+        }
+    }
+
+    Ok(Package {
+        name: package.name.clone(),
+        version: package.version.clone(),
+        edition: Edition::from(package.edition).to_string(),
+        lib,
+        bins,
+        examples,
+        default_run: package.default_run.clone(),
+        features,
+        dependencies,
+    })
+}
+
+/// The crate root of the binary to bundle when none is named, the way `cargo run` picks it: the
+/// `default-run` bin target, the only bin target, or `src/main.rs` if it exists.
+pub fn default_bin(package: &Package, manifest_dir: &Path) -> Result<PathBuf> {
+    if let Some(name) = &package.default_run {
+        let bin = package
+            .bins
+            .iter()
+            .find(|bin| &bin.name == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "default-run of package {} is {}, which is no bin target",
+                    package.name,
+                    name
+                )
+            })?;
+        return Ok(bin.path.clone());
+    }
+    if let [bin] = package.bins.as_slice() {
+        return Ok(bin.path.clone());
+    }
+    let main = manifest_dir.join("src/main.rs");
+    if let Ok(main) = main.canonicalize() {
+        // not necessarily a target with autobins off, but what the binary is by default
+        let is_main = |bin: &&Target| bin.path.canonicalize().map_or(false, |p| p == main);
+        return Ok(package
+            .bins
+            .iter()
+            .find(is_main)
+            .map_or(main, |bin| bin.path.clone()));
+    }
+    let names: Vec<_> = package.bins.iter().map(|bin| bin.name.as_str()).collect();
+    if names.is_empty() {
+        bail!(
+            "Package {} has no bin targets and no src/main.rs",
+            package.name
+        );
+    }
+    bail!(
+        "Package {} has several bin targets and no default-run, pick one of: {}",
+        package.name,
+        names.join(", ")
+    )
+}
+
+/// Bins laid out as `src/bin/<name>/main.rs`, which `cargo_toml` does not discover by itself.
+fn discover_dir_bins(manifest_dir: &Path) -> Result<Vec<Target>> {
+    let bin_dir = manifest_dir.join("src/bin");
+    if !bin_dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut bins = vec![];
+    for entry in std::fs::read_dir(&bin_dir)
+        .with_context(|| format!("Failed to list bin targets in {}", bin_dir.display()))?
+    {
+        let entry = entry?;
+        let main = entry.path().join("main.rs");
+        if let (true, Some(name)) = (main.is_file(), entry.file_name().to_str()) {
+            bins.push(Target {
+                name: name.into(),
+                path: main,
+                required_features: vec![],
+            });
+        }
+    }
+    Ok(bins)
+}
+
+/// Where the example `name` is by convention: `examples/<name>/main.rs` if that exists, else
+/// `examples/<name>.rs`
+fn example_path(manifest_dir: &Path, name: &str) -> PathBuf {
+    let main = manifest_dir.join("examples").join(name).join("main.rs");
+    if main.is_file() {
+        main
+    } else {
+        manifest_dir.join("examples").join(format!("{}.rs", name))
+    }
+}
+
+/// Examples laid out as `examples/<name>.rs` or `examples/<name>/main.rs`
+fn discover_examples(manifest_dir: &Path) -> Result<Vec<Target>> {
+    let dir = manifest_dir.join("examples");
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut examples = vec![];
+    for entry in std::fs::read_dir(&dir)
+        .with_context(|| format!("Failed to list examples in {}", dir.display()))?
+    {
+        let path = entry?.path();
+        let found = if path.is_dir() {
+            let name = path.file_name().and_then(|name| name.to_str());
+            name.map(|name| (name, path.join("main.rs")))
+        } else if path.extension().map_or(false, |ext| ext == "rs") {
+            let name = path.file_stem().and_then(|name| name.to_str());
+            name.map(|name| (name, path.clone()))
+        } else {
+            None
+        };
+        if let Some((name, path)) = found.filter(|(_, path)| path.is_file()) {
+            examples.push(Target {
+                name: name.into(),
+                path,
+                required_features: vec![],
+            });
+        }
+    }
+    Ok(examples)
+}
+
+/// All the dependencies `manifest` lists, path dependencies being relative to `manifest_dir`
+pub fn manifest_dependencies(manifest: &Manifest, manifest_dir: &Path) -> Vec<Dependency> {
+    let mut dependencies = vec![];
+    let mut add_deps = |kind, target: Option<&String>, deps: &cargo_toml::DepsSet| {
+        for (name, dep) in deps {
+            dependencies.push(toml_dependency(
+                kind,
+                target.cloned(),
+                name,
+                dep,
+                manifest_dir,
+            ));
+        }
+    };
+    add_deps(DepKind::Normal, None, &manifest.dependencies);
+    add_deps(DepKind::Dev, None, &manifest.dev_dependencies);
+    add_deps(DepKind::Build, None, &manifest.build_dependencies);
+    for (cfg, deps) in &manifest.target {
+        add_deps(DepKind::Normal, Some(cfg), &deps.dependencies);
+        add_deps(DepKind::Dev, Some(cfg), &deps.dev_dependencies);
+        add_deps(DepKind::Build, Some(cfg), &deps.build_dependencies);
+    }
+    dependencies
+}
+
+fn toml_dependency(
+    kind: DepKind,
+    target: Option<String>,
+    name: &str,
+    dep: &cargo_toml::Dependency,
+    manifest_dir: &Path,
+) -> Dependency {
+    let detail = dep.detail();
+    Dependency {
+        kind,
+        target,
+        name: name.into(),
+        package: detail
+            .and_then(|d| d.package.clone())
+            .unwrap_or_else(|| name.into()),
+        req: normalize_req(dep.req()),
+        optional: dep.optional(),
+        default_features: detail.and_then(|d| d.default_features).unwrap_or(true),
+        features: dep.req_features().to_vec(),
+        // cargo reports `../dep` as `dep` next to the package
+        path: detail
+            .and_then(|d| d.path.as_ref())
+            .map(|p| paths::normalize(&manifest_dir.join(p))),
+    }
+}
+
+/// Print a version requirement the way `semver::VersionReq` does, which is what cargo reports.
+pub fn normalize_req(req: &str) -> String {
+    let req = req.trim();
+    if req.is_empty() || req == "*" {
+        return "*".into();
+    }
+    req.split(',')
+        .map(str::trim)
+        .map(|part| match part.chars().next() {
+            Some(c) if c.is_ascii_digit() => format!("^{}", part),
+            _ => part.into(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn from_cargo_metadata(manifest_dir: &Path) -> Result<Package> {
+    let manifest_path = manifest_dir.join("Cargo.toml");
+    let dir = manifest_dir.canonicalize()?;
+    metadata_packages(manifest_dir, false)?
+        .remove(&dir)
+        .ok_or_else(|| anyhow!("cargo metadata did not report {}", manifest_path.display()))
+}
+
+/// The packages `cargo metadata` reports for the package at `manifest_dir` by their canonical
+/// directories: its workspace, and with `with_deps` the path dependencies of the whole
+/// dependency graph as well. Packages from registries and git are left out.
+fn metadata_packages(manifest_dir: &Path, with_deps: bool) -> Result<BTreeMap<PathBuf, Package>> {
+    let manifest_path = manifest_dir.join("Cargo.toml");
+    let mut command = cargo_metadata::MetadataCommand::new();
+    command.manifest_path(&manifest_path);
+    if !with_deps {
+        command.no_deps();
+    }
+    let metadata = command.exec().with_context(|| {
+        format!(
+            "Failed to run cargo metadata on {}",
+            manifest_path.display()
+        )
+    })?;
+
+    let mut packages = BTreeMap::new();
+    for package in metadata.packages.iter().filter(|p| p.source.is_none()) {
+        let manifest_path = package.manifest_path.as_std_path();
+        let dir = match manifest_path.parent().map(Path::canonicalize) {
+            Some(Ok(dir)) => dir,
+            _ => continue,
+        };
+        packages.insert(dir, sorted(from_metadata_package(package)));
+    }
+    Ok(packages)
+}
+
+fn from_metadata_package(package: &cargo_metadata::Package) -> Package {
+    let target = |t: &cargo_metadata::Target| Target {
+        name: t.name.clone(),
+        path: t.src_path.clone().into(),
+        required_features: t.required_features.clone(),
+    };
+    let is_lib = |t: &cargo_metadata::Target| {
+        t.kind.iter().any(|k| {
+            matches!(
+                k.as_str(),
+                "lib" | "rlib" | "dylib" | "cdylib" | "staticlib" | "proc-macro"
+            )
+        })
+    };
+
+    let lib = package.targets.iter().find(|t| is_lib(t)).map(|t| Target {
+        name: t.name.replace('-', "_"),
+        ..target(t)
+    });
+    let bins = package
+        .targets
+        .iter()
+        .filter(|t| t.kind.iter().any(|k| k == "bin"))
+        .map(target)
+        .collect();
+    let examples = package
+        .targets
+        .iter()
+        .filter(|t| t.kind.iter().any(|k| k == "example"))
+        .map(target)
+        .collect();
+
+    let dependencies = package
+        .dependencies
+        .iter()
+        .map(|d| Dependency {
+            kind: match d.kind {
+                cargo_metadata::DependencyKind::Development => DepKind::Dev,
+                cargo_metadata::DependencyKind::Build => DepKind::Build,
+                _ => DepKind::Normal,
+            },
+            target: d.target.as_ref().map(ToString::to_string),
+            name: d.rename.clone().unwrap_or_else(|| d.name.clone()),
+            package: d.name.clone(),
+            req: d.req.to_string(),
+            optional: d.optional,
+            default_features: d.uses_default_features,
+            features: d.features.clone(),
+            path: d.path.clone().map(Into::into),
+        })
+        .collect();
+
+    Package {
+        name: package.name.clone(),
+        version: package.version.to_string(),
+        edition: package.edition.clone(),
+        lib,
+        bins,
+        examples,
+        default_run: package.default_run.clone(),
+        features: package
+            .features
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        dependencies,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn fixture(name: &str) -> (Manifest, PathBuf) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        let manifest_path = dir.join("Cargo.toml");
+        let manifest_str = std::fs::read_to_string(&manifest_path).unwrap();
+        let mut manifest = Manifest::from_str(&manifest_str).unwrap();
+        manifest.complete_from_path(&manifest_path).unwrap();
+        (manifest, dir)
+    }
+
+    #[test]
+    fn normalize_req_matches_semver_display() {
+        assert_eq!(normalize_req(""), "*");
+        assert_eq!(normalize_req("1.0"), "^1.0");
+        assert_eq!(normalize_req(">=1.2,<2"), ">=1.2, <2");
+        assert_eq!(normalize_req("=0.3.1"), "=0.3.1");
+    }
+
+    #[test]
+    fn default_bin_follows_cargo_run() {
+        let (manifest, dir) = fixture("multibin");
+        let mut package = resolve_package(Resolution::CargoToml, &manifest, &dir).unwrap();
+        let err = default_bin(&package, &dir).unwrap_err().to_string();
+        assert!(err.contains("several bin targets"));
+        assert!(err.contains("flat") && err.contains("mytool"));
+
+        package.default_run = Some("flat".into());
+        assert_eq!(
+            default_bin(&package, &dir).unwrap(),
+            dir.join("src/bin/flat.rs")
+        );
+        package.default_run = Some("nope".into());
+        assert!(default_bin(&package, &dir).is_err());
+
+        let (manifest, dir) = fixture("simple");
+        let package = resolve_package(Resolution::CargoToml, &manifest, &dir).unwrap();
+        assert_eq!(
+            default_bin(&package, &dir).unwrap(),
+            dir.join("src/main.rs")
+        );
+    }
+
+    #[test]
+    fn lib_follows_cargo_conventions() {
+        let dir = std::env::temp_dir().join(format!("bundler-conventions-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/lib.rs"), "").unwrap();
+        let package = "[package]\nname = \"my-tool\"\nversion = \"0.1.0\"\n";
+        let lib = |lib_table: &str| {
+            let manifest = Manifest::from_str(&format!("{}{}", package, lib_table)).unwrap();
+            let package = resolve_package(Resolution::CargoToml, &manifest, &dir).unwrap();
+            package.lib.map(|lib| (lib.name, lib.path))
+        };
+        let conventional = Some(("my_tool".to_string(), dir.join("src/lib.rs")));
+        assert_eq!(lib(""), conventional);
+        assert_eq!(lib("[lib]\npath = \"src/lib.rs\"\n"), conventional);
+        let named = Some(("tool_core".to_string(), dir.join("src/lib.rs")));
+        assert_eq!(lib("[lib]\nname = \"tool-core\"\n"), named);
+        let moved = Some(("my_tool".to_string(), dir.join("lib/tool.rs")));
+        assert_eq!(lib("[lib]\npath = \"lib/tool.rs\"\n"), moved);
+
+        fs::remove_file(dir.join("src/lib.rs")).unwrap();
+        assert_eq!(lib(""), None);
+    }
+
+    #[test]
+    fn path_dependency_cycles_are_reported() {
+        let dir = std::env::temp_dir().join(format!("bundler-cycle-{}", std::process::id()));
+        for (name, dep) in [("a", "b"), ("b", "a")] {
+            fs::create_dir_all(dir.join(name).join("src")).unwrap();
+            let manifest = format!(
+                "[package]\nname = \"{}\"\nversion = \"0.1.0\"\n\n\
+                [dependencies]\n{} = {{ path = \"../{}\" }}\n",
+                name, dep, dep
+            );
+            fs::write(dir.join(name).join("Cargo.toml"), manifest).unwrap();
+            fs::write(dir.join(name).join("src/lib.rs"), "").unwrap();
+        }
+        let manifest = read_manifest(&dir.join("a/Cargo.toml")).unwrap();
+        let package = resolve_package(Resolution::CargoToml, &manifest, &dir.join("a")).unwrap();
+        let err = path_dependencies(Resolution::CargoToml, &package, &dir.join("a")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Path dependencies form a cycle: a -> b -> a"
+        );
+    }
+
+    #[test]
+    fn backends_agree_on_fixtures() {
+        fn manifests(dir: &Path, found: &mut Vec<PathBuf>) {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    manifests(&path, found);
+                } else if path.ends_with("Cargo.toml") {
+                    found.push(path);
+                }
+            }
+        }
+        let mut found = vec![];
+        manifests(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures"),
+            &mut found,
+        );
+        found.sort();
+
+        let mut compared = vec![];
+        for manifest_path in found {
+            let manifest = read_manifest(&manifest_path).unwrap();
+            // virtual workspace manifests, whose members are compared instead
+            if manifest.package.is_none() {
+                continue;
+            }
+            let dir = manifest_path.parent().unwrap();
+            let from_toml = resolve_package(Resolution::CargoToml, &manifest, dir).unwrap();
+            let from_metadata = resolve_package(Resolution::CargoMetadata, &manifest, dir).unwrap();
+            assert_eq!(
+                from_toml,
+                from_metadata,
+                "backends disagree on {}",
+                dir.display()
+            );
+            compared.push(from_toml.name);
+        }
+        for name in [
+            "tool",
+            "multibin",
+            "examples-fixture",
+            "features-fixture",
+            "engine",
+        ] {
+            assert!(
+                compared.iter().any(|compared| compared == name),
+                "{} not compared",
+                name
+            );
+        }
+
+        let (manifest, dir) = fixture("pathdeps");
+        let package = resolve_package(Resolution::CargoToml, &manifest, &dir).unwrap();
+        assert_eq!(
+            path_dependencies(Resolution::CargoToml, &package, &dir).unwrap(),
+            path_dependencies(Resolution::CargoMetadata, &package, &dir).unwrap()
+        );
+    }
+}
@@ -0,0 +1,1246 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{bail, Result};
+use proc_macro2::{Group, Ident, Spacing, Span, TokenStream, TokenTree};
+use quote::{format_ident, ToTokens};
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, UsePath, UseTree};
+
+use crate::srcmap::SourceMap;
+
+/// How references to inlined crates in the binary are kept resolving after bundling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReexportMode {
+    /// Rewrite every path rooted at an inlined crate into `crate::<module>::...`. Thorough, but
+    /// touches many places of the binary.
+    Rewrite,
+    /// Leave the binary's paths alone and add `use` glue instead: `pub use <module>::*;` at the
+    /// crate root, and `use crate::<module> as <name>;` in each nested module of the binary that
+    /// refers to the crate.
+    ///
+    /// Limitations:
+    /// - glob imports from several crates may conflict, which rustc only reports when an
+    ///   ambiguous name is actually used;
+    /// - paths inside macro invocations are not inspected, so nested modules referring to a
+    ///   crate only from within a macro do not get the glue;
+    /// - absolute paths like `::mylib::Foo` can never resolve to a module and are reported as
+    ///   an error, as are nested modules that define their own item with the crate's name.
+    Glob,
+}
+
+impl Default for ReexportMode {
+    fn default() -> Self {
+        ReexportMode::Rewrite
+    }
+}
+
+/// The name an item introduces into its module's namespace, if any.
+pub fn item_ident(item: &syn::Item) -> Option<&Ident> {
+    use syn::Item::*;
+    match item {
+        Const(i) => Some(&i.ident),
+        Enum(i) => Some(&i.ident),
+        ExternCrate(i) => Some(i.rename.as_ref().map_or(&i.ident, |(_, r)| r)),
+        Fn(i) => Some(&i.sig.ident),
+        Macro(i) => i.ident.as_ref(),
+        Macro2(i) => Some(&i.ident),
+        Mod(i) => Some(&i.ident),
+        Static(i) => Some(&i.ident),
+        Struct(i) => Some(&i.ident),
+        Trait(i) => Some(&i.ident),
+        TraitAlias(i) => Some(&i.ident),
+        Type(i) => Some(&i.ident),
+        Union(i) => Some(&i.ident),
+        // the tokens are not looked into, see `verbatim::check`
+        Verbatim(_) => None,
+        _ => None,
+    }
+}
+
+/// An inlined crate as seen from the binary: the name the binary refers to it by, and the name
+/// of the module it becomes in the bundle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrateModule {
+    pub name: String,
+    pub module: String,
+}
+
+/// What to do when the module of an inlined crate would get the name of an item at the root of
+/// the binary, e.g. its own `mod utils` and the crate `utils`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Fail, so the crate can be renamed by hand
+    Error,
+    /// Rename the crate's module to `<module>_lib`, or `<module>_lib<n>` counting from 2 if that
+    /// is taken as well. References to the crate follow, as with an explicit rename.
+    AutoRename,
+}
+
+impl Default for CollisionPolicy {
+    fn default() -> Self {
+        CollisionPolicy::Error
+    }
+}
+
+/// Check the modules of `crates` against the items at the root of `binary`, renaming modules
+/// that collide if `policy` allows. Only the type namespace is looked at, which is the one
+/// modules live in; imports are not considered. Returns the crates renamed.
+pub fn resolve_collisions(
+    binary: &syn::File,
+    crates: &mut [CrateModule],
+    policy: CollisionPolicy,
+) -> Result<Vec<CrateModule>> {
+    let binary_names: BTreeSet<_> = binary
+        .items
+        .iter()
+        .filter(|item| {
+            use syn::Item::*;
+            matches!(
+                item,
+                Mod(_) | Struct(_) | Enum(_) | Union(_) | Trait(_) | TraitAlias(_) | Type(_)
+            )
+        })
+        .filter_map(|item| item_ident(item).map(ToString::to_string))
+        .collect();
+    let mut taken: BTreeSet<_> = crates.iter().map(|c| c.module.clone()).collect();
+    taken.extend(binary_names.iter().cloned());
+
+    let mut renamed = vec![];
+    for c in crates.iter_mut() {
+        if !binary_names.contains(&c.module) {
+            continue;
+        }
+        if policy == CollisionPolicy::Error {
+            bail!(
+                "Inlined crate {} would become module {}, which the binary already defines, use Bundler::with_crate_at_as, Bundler::with_rename or Bundler::nest_under",
+                c.name,
+                c.module
+            );
+        }
+        let module = std::iter::once(format!("{}_lib", c.module))
+            .chain((2..).map(|n| format!("{}_lib{}", c.module, n)))
+            .find(|candidate| !taken.contains(candidate))
+            .expect("Candidates are endless");
+        log::warn!(
+            "Renamed the module of inlined crate {} from {} to {}, which the binary already defines",
+            c.name,
+            c.module,
+            module
+        );
+        taken.insert(module.clone());
+        c.module = module;
+        renamed.push(c.clone());
+    }
+    Ok(renamed)
+}
+
+fn is_crate(crates: &[CrateModule], ident: &Ident) -> bool {
+    crates.iter().any(|c| ident == &c.name)
+}
+
+/// The module replacing references to the crate `ident`
+fn module_of(crates: &[CrateModule], ident: &Ident) -> Option<Ident> {
+    crates
+        .iter()
+        .find(|c| ident == &c.name)
+        .map(|c| Ident::new(&c.module, ident.span()))
+}
+
+/// Whether `path` is rooted at one of `crates`. Single segment paths are local names such as
+/// variables, so they are never considered.
+fn crate_rooted(crates: &[CrateModule], path: &syn::Path) -> bool {
+    (path.leading_colon.is_some() || path.segments.len() > 1)
+        && path
+            .segments
+            .first()
+            .map_or(false, |first| is_crate(crates, &first.ident))
+}
+
+/// Idents at the root of a use tree, looking through groups
+fn use_roots(tree: &UseTree) -> Vec<&Ident> {
+    match tree {
+        UseTree::Path(p) => vec![&p.ident],
+        UseTree::Name(n) => vec![&n.ident],
+        UseTree::Rename(r) => vec![&r.ident],
+        UseTree::Glob(_) => vec![],
+        UseTree::Group(g) => g.items.iter().flat_map(use_roots).collect(),
+    }
+}
+
+/// Fail if `file` refers to a crate name that several inlined crates share, since there is no
+/// telling which one is meant. `sources` tells where the references are.
+fn check_ambiguous(file: &syn::File, crates: &[CrateModule], sources: &SourceMap) -> Result<()> {
+    let mut modules: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for c in crates {
+        modules.entry(&c.name).or_default().insert(&c.module);
+    }
+    modules.retain(|_, modules| modules.len() > 1);
+    if modules.is_empty() {
+        return Ok(());
+    }
+
+    let ambiguous: Vec<_> = crates
+        .iter()
+        .filter(|c| modules.contains_key(c.name.as_str()))
+        .cloned()
+        .collect();
+    let mut refs = RefCollector::new(&ambiguous, sources, vec![], true);
+    refs.visit_file(file);
+    if let Some((module, span)) = refs.first {
+        let names: Vec<_> = modules
+            .iter()
+            .map(|(name, modules)| {
+                let modules: Vec<_> = modules.iter().copied().collect();
+                format!("`{}` ({})", name, modules.join(", "))
+            })
+            .collect();
+        bail!(
+            "References to {} are ambiguous, as several inlined crates share the name. \
+            Only one crate of a name can be referred to from the binary, rename the references \
+            to the others by hand. The first is at {}:\n{}",
+            names.join(", "),
+            sources.locate(&module, span),
+            sources.excerpt(&module, span)
+        );
+    }
+    Ok(())
+}
+
+/// Rewrite paths in `file` rooted at any of `crates` into `crate::<module>::...`, or
+/// `crate::<nest>::<module>::...` if the crates are nested in a container module. `sources`
+/// points errors at the original sources.
+///
+/// Within a nested module that defines or imports an item of its own with a crate's name, paths
+/// starting with that name refer to the item and are left alone, unless they start with `::`.
+pub fn rewrite_crate_refs(
+    file: &mut syn::File,
+    crates: &[CrateModule],
+    nest: Option<&str>,
+    sources: &SourceMap,
+) -> Result<()> {
+    check_ambiguous(file, crates, sources)?;
+    // a module of the binary's own shadows the crate, which the binary then reaches as `::name`
+    let shadowed = file
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            syn::Item::Mod(m) if is_crate(crates, &m.ident) => Some(m.ident.to_string()),
+            _ => None,
+        })
+        .collect();
+    CrateRefRewriter {
+        crates,
+        nest,
+        shadowed,
+    }
+    .visit_file_mut(file);
+    Ok(())
+}
+
+struct CrateRefRewriter<'a> {
+    crates: &'a [CrateModule],
+    nest: Option<&'a str>,
+    /// crate names the current module has items of its own for
+    shadowed: BTreeSet<String>,
+}
+
+/// Names the items of a module bring into its namespace, imports included, apart from imports
+/// of inlined crates, which are rewritten rather than shadowing them
+fn module_names(items: &[syn::Item], crates: &[CrateModule]) -> BTreeSet<String> {
+    fn use_names(tree: &UseTree, names: &mut BTreeSet<String>) {
+        match tree {
+            UseTree::Path(p) => use_names(&p.tree, names),
+            UseTree::Name(n) if n.ident != "self" => {
+                names.insert(n.ident.to_string());
+            }
+            UseTree::Rename(r) => {
+                names.insert(r.rename.to_string());
+            }
+            UseTree::Group(g) => g.items.iter().for_each(|tree| use_names(tree, names)),
+            UseTree::Name(_) | UseTree::Glob(_) => {}
+        }
+    }
+
+    let mut names = BTreeSet::new();
+    for item in items {
+        match item {
+            syn::Item::Use(item)
+                if item.leading_colon.is_some()
+                    || !use_roots(&item.tree)
+                        .into_iter()
+                        .any(|root| is_crate(crates, root)) =>
+            {
+                use_names(&item.tree, &mut names)
+            }
+            item => names.extend(item_ident(item).map(ToString::to_string)),
+        }
+    }
+    names
+}
+
+impl CrateRefRewriter<'_> {
+    /// The module replacing references to the crate `ident`, if it is one and not shadowed
+    fn module_for(&self, ident: &Ident, absolute: bool) -> Option<Ident> {
+        if !absolute && self.shadowed.contains(&ident.to_string()) {
+            return None;
+        }
+        module_of(self.crates, ident)
+    }
+
+    fn rewrite_use_tree(&self, tree: &mut UseTree, absolute: bool) {
+        let module = match tree {
+            UseTree::Group(group) => {
+                for tree in group.items.iter_mut() {
+                    self.rewrite_use_tree(tree, absolute);
+                }
+                return;
+            }
+            UseTree::Glob(_) => return,
+            UseTree::Path(syn::UsePath { ident, .. })
+            | UseTree::Name(syn::UseName { ident })
+            | UseTree::Rename(syn::UseRename { ident, .. }) => {
+                match self.module_for(ident, absolute) {
+                    Some(module) => module,
+                    None => return,
+                }
+            }
+        };
+
+        let span = module.span();
+        let placeholder = UseTree::Glob(syn::UseGlob {
+            star_token: Default::default(),
+        });
+        let renamed = match std::mem::replace(tree, placeholder) {
+            UseTree::Path(p) => UseTree::Path(UsePath { ident: module, ..p }),
+            UseTree::Name(n) if n.ident == module => UseTree::Name(n),
+            UseTree::Name(n) => UseTree::Rename(syn::UseRename {
+                ident: module,
+                as_token: Default::default(),
+                rename: n.ident,
+            }),
+            UseTree::Rename(r) => UseTree::Rename(syn::UseRename { ident: module, ..r }),
+            _ => unreachable!("groups and globs are handled above"),
+        };
+        *tree = self
+            .prefix(span)
+            .into_iter()
+            .rev()
+            .fold(renamed, |tree, ident| {
+                UseTree::Path(UsePath {
+                    ident,
+                    colon2_token: Default::default(),
+                    tree: Box::new(tree),
+                })
+            });
+    }
+
+    /// Segments leading to the inlined modules: `crate`, then the container if any
+    fn prefix(&self, span: proc_macro2::Span) -> Vec<Ident> {
+        std::iter::once("crate")
+            .chain(self.nest)
+            .map(|name| Ident::new(name, span))
+            .collect()
+    }
+}
+
+impl VisitMut for CrateRefRewriter<'_> {
+    fn visit_item_mod_mut(&mut self, item: &mut syn::ItemMod) {
+        let shadowed = match &item.content {
+            Some((_, items)) => module_names(items, self.crates)
+                .into_iter()
+                .filter(|name| self.crates.iter().any(|c| c.name == *name))
+                .collect(),
+            None => BTreeSet::new(),
+        };
+        // modules do not see the items of the module around them
+        let outer = std::mem::replace(&mut self.shadowed, shadowed);
+        visit_mut::visit_item_mod_mut(self, item);
+        self.shadowed = outer;
+    }
+
+    fn visit_item_use_mut(&mut self, item: &mut syn::ItemUse) {
+        let absolute = item.leading_colon.is_some();
+        let roots_crate = use_roots(&item.tree)
+            .into_iter()
+            .any(|root| self.module_for(root, absolute).is_some());
+        if roots_crate {
+            item.leading_colon = None;
+        }
+        self.rewrite_use_tree(&mut item.tree, absolute);
+    }
+
+    fn visit_path_mut(&mut self, path: &mut syn::Path) {
+        let absolute = path.leading_colon.is_some();
+        let module = path
+            .segments
+            .first()
+            .and_then(|first| self.module_for(&first.ident, absolute));
+        if let (true, Some(module)) = (crate_rooted(self.crates, path), module) {
+            path.segments[0].ident = module;
+            let span = path.segments[0].ident.span();
+            path.leading_colon = None;
+            for (i, ident) in self.prefix(span).into_iter().enumerate() {
+                path.segments.insert(i, ident.into());
+            }
+        }
+        visit_mut::visit_path_mut(self, path);
+    }
+}
+
+/// `use crate::<nest>::<module> as <name>;`, leaving out the container if there is none and
+/// the rename if the names are the same
+fn glue_use(name: &str, module: &str, nest: Option<&str>) -> syn::Item {
+    let (name, module) = (format_ident!("{}", name), format_ident!("{}", module));
+    let nest = nest.map(|nest| format_ident!("{}", nest)).into_iter();
+    if name == module {
+        parse_quote!(use crate::#(#nest::)*#module;)
+    } else {
+        parse_quote!(use crate::#(#nest::)*#module as #name;)
+    }
+}
+
+/// Add `use` glue to `file` so that paths rooted at any of `crates` keep resolving once the
+/// crates are inlined as modules at the crate root, or in the container module `nest`. See
+/// [`ReexportMode::Glob`]. `sources` points errors at the original sources.
+pub fn glob_reexport(
+    file: &mut syn::File,
+    crates: &[CrateModule],
+    nest: Option<&str>,
+    sources: &SourceMap,
+) -> Result<()> {
+    check_ambiguous(file, crates, sources)?;
+    let mut unresolved = vec![];
+    glue_items(
+        &mut file.items,
+        crates,
+        nest,
+        sources,
+        &mut vec![],
+        &mut unresolved,
+    );
+    if !unresolved.is_empty() {
+        bail!(
+            "Glob re-export can not make these references resolve, use ReexportMode::Rewrite instead:\n{}",
+            unresolved.join("\n")
+        );
+    }
+
+    let modules: BTreeSet<_> = crates.iter().map(|c| c.module.as_str()).collect();
+    let nest = nest.map(|nest| format_ident!("{}", nest));
+    for module in modules {
+        let ident = format_ident!("{}", module);
+        let nest = nest.iter();
+        file.items.push(parse_quote!(pub use #(#nest::)*#ident::*;));
+    }
+    Ok(())
+}
+
+/// Add the glue to `items`, those of the module at `module` in the binary, and its nested
+/// modules, adding what can not be made to resolve to `unresolved`
+fn glue_items(
+    items: &mut Vec<syn::Item>,
+    crates: &[CrateModule],
+    nest: Option<&str>,
+    sources: &SourceMap,
+    module: &mut Vec<String>,
+    unresolved: &mut Vec<String>,
+) {
+    let mut refs = RefCollector::new(crates, sources, module.clone(), false);
+    for item in items.iter_mut() {
+        match item {
+            syn::Item::Mod(syn::ItemMod {
+                ident,
+                content: Some((_, nested)),
+                ..
+            }) => {
+                module.push(ident.to_string());
+                glue_items(nested, crates, nest, sources, module, unresolved);
+                module.pop();
+            }
+            item => refs.visit_item(item),
+        }
+    }
+    unresolved.extend(refs.absolute);
+
+    for name in refs.used {
+        let inlined = match crates.iter().find(|c| c.name == name) {
+            Some(c) => &c.module,
+            None => continue,
+        };
+        if module.is_empty() && nest.is_none() && inlined == name {
+            // the inlined module is directly visible at the crate root
+            continue;
+        }
+        if let Some(own) = items
+            .iter()
+            .filter_map(item_ident)
+            .find(|ident| **ident == name)
+        {
+            unresolved.push(format!(
+                "{}: module defines its own `{}`, which shadows the inlined crate",
+                sources.locate(module, own.span()),
+                name
+            ));
+            continue;
+        }
+        items.insert(0, glue_use(&name, inlined, nest));
+    }
+}
+
+/// Turn `pub extern crate X;` in an inlined crate into `pub use ::X;`, which keeps re-exporting
+/// the dependency as `<module>::X` once the crate is a module. `#[macro_use]` crates are left
+/// alone since their macros can not be imported this way.
+pub fn reexport_extern_crates(file: &mut syn::File) {
+    ExternCrateRewriter.visit_file_mut(file);
+}
+
+struct ExternCrateRewriter;
+
+impl VisitMut for ExternCrateRewriter {
+    fn visit_item_mut(&mut self, item: &mut syn::Item) {
+        let krate = match item {
+            syn::Item::ExternCrate(krate)
+                if !matches!(krate.vis, syn::Visibility::Inherited)
+                    && krate.ident != "self"
+                    && !krate.attrs.iter().any(|a| a.path.is_ident("macro_use")) =>
+            {
+                krate
+            }
+            item => return visit_mut::visit_item_mut(self, item),
+        };
+        let syn::ItemExternCrate {
+            attrs,
+            vis,
+            ident,
+            rename,
+            ..
+        } = krate.clone();
+        let rename = rename.map(|(_, rename)| quote::quote!(as #rename));
+        *item = parse_quote! {
+            #(#attrs)*
+            #vis use ::#ident #rename;
+        };
+    }
+}
+
+/// Replace the `extern crate` items in `items` naming one of `crates`, which are modules of the
+/// bundle now, looking into inline modules as well. Where the name still matters, i.e. the crate
+/// is renamed or re-exported, or the items belong to an inlined crate (`lib`) whose paths are not
+/// rewritten, the item becomes a `use` of the module; elsewhere it is dropped. `#[macro_use]` is
+/// dropped with it, the crate's exported macros being at the root of the bundle. `root` is
+/// whether `items` are at the root of the bundle. Returns how many items were replaced.
+///
+/// In inlined crates, `pub extern crate` has become `pub use ::name` by now, see
+/// [`reexport_extern_crates`], so those are replaced as well.
+pub fn replace_extern_crates(
+    items: &mut Vec<syn::Item>,
+    crates: &[CrateModule],
+    nest: Option<&str>,
+    lib: bool,
+    root: bool,
+) -> usize {
+    let mut replaced = 0;
+    items.retain_mut(|item| {
+        let (attrs, vis, ident, rename) = match item {
+            syn::Item::Mod(syn::ItemMod {
+                content: Some((_, content)),
+                ..
+            }) => {
+                replaced += replace_extern_crates(content, crates, nest, lib, false);
+                return true;
+            }
+            syn::Item::ExternCrate(krate) => {
+                let rename = krate.rename.as_ref().map(|(_, rename)| rename);
+                (&krate.attrs, &krate.vis, &krate.ident, rename)
+            }
+            syn::Item::Use(u) if lib && u.leading_colon.is_some() => match &u.tree {
+                UseTree::Name(n) => (&u.attrs, &u.vis, &n.ident, None),
+                UseTree::Rename(r) => (&u.attrs, &u.vis, &r.ident, Some(&r.rename)),
+                _ => return true,
+            },
+            _ => return true,
+        };
+        let module = match crates.iter().find(|c| ident == &c.name) {
+            Some(c) => &c.module,
+            None => return true,
+        };
+        replaced += 1;
+        let name = rename.unwrap_or(ident).to_string();
+        let public = !matches!(vis, syn::Visibility::Inherited);
+        // the module itself is there under the name already
+        let defined = root && nest.is_none() && name == *module;
+        if defined || !(lib || public || rename.is_some()) {
+            return false;
+        }
+        let attrs: Vec<_> = attrs
+            .iter()
+            .filter(|attr| !attr.path.is_ident("macro_use"))
+            .cloned()
+            .collect();
+        let vis = vis.clone();
+        let mut glue = glue_use(&name, module, nest);
+        if let syn::Item::Use(u) = &mut glue {
+            u.attrs = attrs;
+            u.vis = vis;
+        }
+        *item = glue;
+        true
+    });
+    replaced
+}
+
+/// Rewrite the paths in the modulized crate `lib` that start at its own root, `crate::...` and
+/// `::<name>::...`, into `crate::<prefix>::...`, where `prefix` are the modules leading to it in
+/// the bundle. Macro invocations and `macro_rules!` bodies are rewritten token by token, with
+/// `$crate::...` included, as that is the bundle's root too.
+///
+/// Paths to the crate's `#[macro_export]` macros are left alone, as those end up at the root of
+/// the bundle as well. Only paths naming such a macro directly are recognized, not ones inside
+/// `use` groups.
+pub fn rewrite_self_refs(lib: &mut syn::ItemMod, name: &str, prefix: &[String]) {
+    let mut exported = ExportedMacros::default();
+    exported.visit_item_mod(lib);
+    SelfRefRewriter {
+        name: name.replace('-', "_"),
+        prefix,
+        exported: exported.0,
+    }
+    .visit_item_mod_mut(lib);
+}
+
+/// Re-export the `#[macro_export]` macros of the modulized crate `lib` from its module, with
+/// `pub(crate) use crate::name;`. The macros end up at the root of the bundle, so this is what
+/// keeps paths to them through the crate, like `mylib::name!` or `use mylib::name;` in the
+/// binary, resolving once rewritten to go through the module. Macros the module imports under
+/// the same name already are left out.
+pub fn reexport_macros(lib: &mut syn::ItemMod) {
+    let mut exported = ExportedMacros::default();
+    exported.visit_item_mod(lib);
+    let items = match &mut lib.content {
+        Some((_, items)) => items,
+        None => return,
+    };
+    let uses: Vec<_> = items
+        .iter()
+        .filter(|item| matches!(item, syn::Item::Use(_)))
+        .cloned()
+        .collect();
+    let imported = module_names(&uses, &[]);
+    let reexports = exported
+        .0
+        .iter()
+        .filter(|name| !imported.contains(*name))
+        .map(|name| -> syn::Item {
+            let name = format_ident!("{}", name);
+            parse_quote! {
+                #[allow(unused_imports)]
+                pub(crate) use crate::#name;
+            }
+        });
+    let _: Vec<_> = items.splice(0..0, reexports).collect();
+}
+
+#[derive(Default)]
+struct ExportedMacros(BTreeSet<String>);
+
+impl<'ast> Visit<'ast> for ExportedMacros {
+    fn visit_item_macro(&mut self, item: &'ast syn::ItemMacro) {
+        if let Some(ident) = &item.ident {
+            if item.attrs.iter().any(|a| a.path.is_ident("macro_export")) {
+                self.0.insert(ident.to_string());
+            }
+        }
+    }
+}
+
+struct SelfRefRewriter<'a> {
+    name: String,
+    prefix: &'a [String],
+    exported: BTreeSet<String>,
+}
+
+impl SelfRefRewriter<'_> {
+    fn prefix(&self, span: proc_macro2::Span) -> impl Iterator<Item = Ident> + '_ {
+        self.prefix
+            .iter()
+            .map(move |module| Ident::new(module, span))
+    }
+
+    fn is_exported(&self, ident: &Ident) -> bool {
+        self.exported.contains(&ident.to_string())
+    }
+
+    /// Rewrite `tree`, the root of a `use`, returning whether it was an absolute path to the crate
+    fn rewrite_use_tree(&self, tree: &mut UseTree, absolute: bool) -> bool {
+        let path = match tree {
+            UseTree::Group(group) if !absolute => {
+                for tree in group.items.iter_mut() {
+                    self.rewrite_use_tree(tree, false);
+                }
+                return false;
+            }
+            UseTree::Path(path) => path,
+            _ => return false,
+        };
+        let from_root = if absolute {
+            path.ident == self.name
+        } else {
+            path.ident == "crate"
+        };
+        let to_exported = matches!(
+            &*path.tree,
+            UseTree::Name(syn::UseName { ident }) | UseTree::Rename(syn::UseRename { ident, .. })
+                if self.is_exported(ident)
+        );
+        if !from_root || to_exported {
+            return false;
+        }
+
+        let span = path.ident.span();
+        path.ident = Ident::new("crate", span);
+        let placeholder = UseTree::Glob(syn::UseGlob {
+            star_token: Default::default(),
+        });
+        let rest = std::mem::replace(&mut *path.tree, placeholder);
+        let segments: Vec<_> = self.prefix(span).collect();
+        *path.tree = segments.into_iter().rev().fold(rest, |tree, ident| {
+            UseTree::Path(UsePath {
+                ident,
+                colon2_token: Default::default(),
+                tree: Box::new(tree),
+            })
+        });
+        absolute
+    }
+
+    fn rewrite_tokens(&self, tokens: TokenStream) -> TokenStream {
+        let tokens: Vec<_> = tokens.into_iter().collect();
+        let is_path_sep = |i: usize| {
+            matches!(
+                (tokens.get(i), tokens.get(i + 1)),
+                (Some(TokenTree::Punct(a)), Some(TokenTree::Punct(b)))
+                    if a.as_char() == ':' && a.spacing() == Spacing::Joint && b.as_char() == ':'
+            )
+        };
+        let names_exported = |i: usize| matches!(tokens.get(i), Some(TokenTree::Ident(ident)) if self.is_exported(ident));
+        let mut rewritten: Vec<TokenTree> = vec![];
+        for (idx, token) in tokens.iter().enumerate() {
+            match token {
+                TokenTree::Group(group) => {
+                    let stream = self.rewrite_tokens(group.stream());
+                    let mut new = Group::new(group.delimiter(), stream);
+                    new.set_span(group.span());
+                    rewritten.push(new.into());
+                    continue;
+                }
+                TokenTree::Ident(ident) if is_path_sep(idx + 1) && !names_exported(idx + 3) => {
+                    // a leading `::` does not follow a segment, or the `>` of a qualified path
+                    let follows_segment = idx >= 3
+                        && match &tokens[idx - 3] {
+                            TokenTree::Ident(_) => true,
+                            TokenTree::Punct(p) => p.as_char() == '>',
+                            _ => false,
+                        };
+                    let absolute = idx >= 2 && is_path_sep(idx - 2) && !follows_segment;
+                    let from_root = if absolute {
+                        *ident == self.name
+                    } else {
+                        ident == "crate"
+                    };
+                    if from_root {
+                        if absolute {
+                            rewritten.truncate(rewritten.len() - 2);
+                        }
+                        let span = ident.span();
+                        rewritten.push(Ident::new("crate", span).into());
+                        for module in self.prefix(span) {
+                            rewritten.extend(quote::quote_spanned!(span=> :: #module));
+                        }
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            rewritten.push(token.clone());
+        }
+        rewritten.into_iter().collect()
+    }
+}
+
+impl VisitMut for SelfRefRewriter<'_> {
+    fn visit_item_use_mut(&mut self, item: &mut syn::ItemUse) {
+        if self.rewrite_use_tree(&mut item.tree, item.leading_colon.is_some()) {
+            item.leading_colon = None;
+        }
+    }
+
+    fn visit_path_mut(&mut self, path: &mut syn::Path) {
+        if path.segments.len() > 1 {
+            let first = &path.segments[0].ident;
+            let from_root = if path.leading_colon.is_some() {
+                *first == self.name
+            } else {
+                first == "crate"
+            };
+            let to_exported = path.segments.len() == 2 && self.is_exported(&path.segments[1].ident);
+            if from_root && !to_exported {
+                let span = first.span();
+                path.leading_colon = None;
+                path.segments[0].ident = Ident::new("crate", span);
+                let segments: Vec<_> = self.prefix(span).collect();
+                for (i, ident) in segments.into_iter().enumerate() {
+                    path.segments.insert(i + 1, ident.into());
+                }
+            }
+        }
+        visit_mut::visit_path_mut(self, path);
+    }
+
+    fn visit_macro_mut(&mut self, mac: &mut syn::Macro) {
+        mac.tokens = self.rewrite_tokens(mac.tokens.clone());
+        visit_mut::visit_macro_mut(self, mac);
+    }
+}
+
+/// Collects references to inlined crates within one module, descending into nested modules
+/// only if `nested` is set
+struct RefCollector<'a> {
+    crates: &'a [CrateModule],
+    sources: &'a SourceMap,
+    /// the module in the binary being visited
+    module: Vec<String>,
+    used: BTreeSet<String>,
+    absolute: Vec<String>,
+    /// the module and span of the first reference
+    first: Option<(Vec<String>, Span)>,
+    nested: bool,
+}
+
+impl<'a> RefCollector<'a> {
+    fn new(
+        crates: &'a [CrateModule],
+        sources: &'a SourceMap,
+        module: Vec<String>,
+        nested: bool,
+    ) -> Self {
+        RefCollector {
+            crates,
+            sources,
+            module,
+            used: Default::default(),
+            absolute: vec![],
+            first: None,
+            nested,
+        }
+    }
+
+    fn record(&mut self, ident: &Ident, absolute: bool, tokens: &dyn ToTokens) {
+        if self.first.is_none() {
+            self.first = Some((self.module.clone(), ident.span()));
+        }
+        if absolute {
+            self.absolute.push(format!(
+                "{}: absolute path `{}`",
+                self.sources.locate(&self.module, ident.span()),
+                tokens.to_token_stream()
+            ));
+        } else {
+            self.used.insert(ident.to_string());
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for RefCollector<'_> {
+    fn visit_item_mod(&mut self, item: &'ast syn::ItemMod) {
+        if self.nested {
+            self.module.push(item.ident.to_string());
+            visit::visit_item_mod(self, item);
+            self.module.pop();
+        }
+    }
+
+    fn visit_item_use(&mut self, item: &'ast syn::ItemUse) {
+        for root in use_roots(&item.tree) {
+            if is_crate(self.crates, root) {
+                self.record(root, item.leading_colon.is_some(), item);
+            }
+        }
+    }
+
+    fn visit_path(&mut self, path: &'ast syn::Path) {
+        if crate_rooted(self.crates, path) {
+            self.record(&path.segments[0].ident, path.leading_colon.is_some(), path);
+        }
+        visit::visit_path(self, path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crates() -> Vec<CrateModule> {
+        vec![CrateModule {
+            name: "mylib".into(),
+            module: "mylib".into(),
+        }]
+    }
+
+    #[test]
+    fn collisions_rename_deterministically() {
+        let binary: syn::File = parse_quote! {
+            mod mylib {}
+            struct mylib_lib;
+            fn utils() {}
+        };
+        let mut crates = vec![
+            CrateModule {
+                name: "mylib".into(),
+                module: "mylib".into(),
+            },
+            CrateModule {
+                name: "utils".into(),
+                module: "utils".into(),
+            },
+        ];
+        let err = resolve_collisions(&binary, &mut crates.clone(), CollisionPolicy::Error);
+        assert!(err.unwrap_err().to_string().contains("mylib"));
+
+        let renamed =
+            resolve_collisions(&binary, &mut crates, CollisionPolicy::AutoRename).unwrap();
+        // functions live in the value namespace, so utils is fine
+        assert_eq!(renamed.len(), 1);
+        assert_eq!(crates[0].module, "mylib_lib2");
+        assert_eq!(crates[1].module, "utils");
+    }
+
+    #[test]
+    fn rewrite_prefixes_crate_paths() {
+        let mut file: syn::File = parse_quote! {
+            use mylib::{a, b::c};
+            fn main() {
+                let mylib = 1;
+                mylib::run(mylib);
+            }
+        };
+        rewrite_crate_refs(&mut file, &crates(), None, &SourceMap::default()).unwrap();
+        let expected: syn::File = parse_quote! {
+            use crate::mylib::{a, b::c};
+            fn main() {
+                let mylib = 1;
+                crate::mylib::run(mylib);
+            }
+        };
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn glob_adds_glue_to_nested_modules() {
+        let mut file: syn::File = parse_quote! {
+            mod cli {
+                pub fn run() -> mylib::Config {
+                    todo!()
+                }
+            }
+            fn main() {}
+        };
+        glob_reexport(&mut file, &crates(), None, &SourceMap::default()).unwrap();
+        let expected: syn::File = parse_quote! {
+            mod cli {
+                use crate::mylib;
+                pub fn run() -> mylib::Config {
+                    todo!()
+                }
+            }
+            fn main() {}
+            pub use mylib::*;
+        };
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn glob_rejects_absolute_paths() {
+        let mut file: syn::File = parse_quote! {
+            fn main() {
+                ::mylib::run();
+            }
+        };
+        let err = glob_reexport(&mut file, &crates(), None, &SourceMap::default()).unwrap_err();
+        assert!(err.to_string().contains("absolute path"));
+    }
+
+    #[test]
+    fn rewrite_skips_modules_shadowing_the_crate() {
+        let mut file: syn::File = parse_quote! {
+            mod cli {
+                mod mylib { pub fn run() {} }
+                fn start() {
+                    mylib::run();
+                    ::mylib::run();
+                }
+                mod inner {
+                    use other::mylib as _;
+                    fn start() { mylib::run(); }
+                }
+            }
+            mod ui {
+                use helpers::mylib;
+                fn start() { mylib::run(); }
+            }
+        };
+        rewrite_crate_refs(&mut file, &crates(), None, &SourceMap::default()).unwrap();
+        let expected: syn::File = parse_quote! {
+            mod cli {
+                mod mylib { pub fn run() {} }
+                fn start() {
+                    mylib::run();
+                    crate::mylib::run();
+                }
+                mod inner {
+                    use other::mylib as _;
+                    fn start() { crate::mylib::run(); }
+                }
+            }
+            mod ui {
+                use helpers::mylib;
+                fn start() { mylib::run(); }
+            }
+        };
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn rewrite_maps_renamed_crates() {
+        let crates = vec![CrateModule {
+            name: "common".into(),
+            module: "common_net".into(),
+        }];
+        let mut file: syn::File = parse_quote! {
+            use common;
+            use common::Config;
+            fn main() {
+                common::run();
+            }
+        };
+        rewrite_crate_refs(&mut file, &crates, None, &SourceMap::default()).unwrap();
+        let expected: syn::File = parse_quote! {
+            use crate::common_net as common;
+            use crate::common_net::Config;
+            fn main() {
+                crate::common_net::run();
+            }
+        };
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn ambiguous_crate_names_error() {
+        let crates = vec![
+            CrateModule {
+                name: "common".into(),
+                module: "common_net".into(),
+            },
+            CrateModule {
+                name: "common".into(),
+                module: "common".into(),
+            },
+        ];
+        let mut file: syn::File = parse_quote! {
+            fn main() {
+                common::run();
+            }
+        };
+        let err = rewrite_crate_refs(&mut file, &crates, None, &SourceMap::default()).unwrap_err();
+        assert!(err.to_string().contains("ambiguous"));
+
+        let mut unrelated: syn::File = parse_quote!(
+            fn main() {}
+        );
+        rewrite_crate_refs(&mut unrelated, &crates, None, &SourceMap::default()).unwrap();
+    }
+
+    #[test]
+    fn nested_crates_are_reached_through_the_container() {
+        let mut file: syn::File = parse_quote! {
+            use mylib::Config;
+            fn main() {
+                mylib::run();
+            }
+        };
+        rewrite_crate_refs(
+            &mut file,
+            &crates(),
+            Some("__bundled"),
+            &SourceMap::default(),
+        )
+        .unwrap();
+        let expected: syn::File = parse_quote! {
+            use crate::__bundled::mylib::Config;
+            fn main() {
+                crate::__bundled::mylib::run();
+            }
+        };
+        assert_eq!(file, expected);
+
+        let mut file: syn::File = parse_quote! {
+            fn main() {
+                mylib::run();
+            }
+        };
+        glob_reexport(
+            &mut file,
+            &crates(),
+            Some("__bundled"),
+            &SourceMap::default(),
+        )
+        .unwrap();
+        let expected: syn::File = parse_quote! {
+            use crate::__bundled::mylib;
+            fn main() {
+                mylib::run();
+            }
+            pub use __bundled::mylib::*;
+        };
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn self_refs_go_through_the_module() {
+        let mut lib: syn::ItemMod = parse_quote! {
+            pub mod mylib {
+                use crate::types::{Config, Mode};
+                use ::my_lib::types::Id;
+                use crate::log;
+                pub(crate) fn run(c: crate::types::Config) -> Vec<::my_lib::types::Id> {
+                    crate::log!("{:?}", crate::types::default());
+                    vec![]
+                }
+                #[macro_export]
+                macro_rules! log { ($($t:tt)*) => { $crate::util::print(format!($($t)*)) } }
+            }
+        };
+        let prefix = ["__bundled".to_string(), "mylib".to_string()];
+        rewrite_self_refs(&mut lib, "my-lib", &prefix);
+        let expected: syn::ItemMod = parse_quote! {
+            pub mod mylib {
+                use crate::__bundled::mylib::types::{Config, Mode};
+                use crate::__bundled::mylib::types::Id;
+                use crate::log;
+                pub(crate) fn run(
+                    c: crate::__bundled::mylib::types::Config,
+                ) -> Vec<crate::__bundled::mylib::types::Id> {
+                    crate::log!("{:?}", crate::__bundled::mylib::types::default());
+                    vec![]
+                }
+                #[macro_export]
+                macro_rules! log {
+                    ($($t:tt)*) => { $crate::__bundled::mylib::util::print(format!($($t)*)) }
+                }
+            }
+        };
+        assert_eq!(
+            lib.to_token_stream().to_string(),
+            expected.to_token_stream().to_string()
+        );
+    }
+
+    #[test]
+    fn exported_macros_are_reexported_from_the_module() {
+        let mut lib: syn::ItemMod = parse_quote! {
+            pub mod mylib {
+                use crate::log;
+                #[macro_export]
+                macro_rules! log { () => {} }
+                pub mod errors {
+                    #[macro_export]
+                    macro_rules! ensure_ok { ($e:expr) => { $crate::mylib::errors::fail($e) } }
+                }
+                macro_rules! private { () => {} }
+            }
+        };
+        reexport_macros(&mut lib);
+        let expected: syn::ItemMod = parse_quote! {
+            pub mod mylib {
+                #[allow(unused_imports)]
+                pub(crate) use crate::ensure_ok;
+                use crate::log;
+                #[macro_export]
+                macro_rules! log { () => {} }
+                pub mod errors {
+                    #[macro_export]
+                    macro_rules! ensure_ok { ($e:expr) => { $crate::mylib::errors::fail($e) } }
+                }
+                macro_rules! private { () => {} }
+            }
+        };
+        assert_eq!(lib, expected);
+    }
+
+    #[test]
+    fn extern_crates_of_inlined_crates_are_replaced() {
+        let mut crates = crates();
+        crates.push(CrateModule {
+            name: "utils".into(),
+            module: "utils".into(),
+        });
+        let mut file: syn::File = parse_quote! {
+            #[macro_use]
+            extern crate mylib;
+            extern crate mylib as ml;
+            pub extern crate utils;
+            #[macro_use]
+            extern crate serde_derive;
+            mod nested {
+                #[macro_use]
+                extern crate utils as u;
+            }
+        };
+        assert_eq!(
+            replace_extern_crates(&mut file.items, &crates, None, false, true),
+            4
+        );
+        let expected: syn::File = parse_quote! {
+            use crate::mylib as ml;
+            #[macro_use]
+            extern crate serde_derive;
+            mod nested {
+                use crate::utils as u;
+            }
+        };
+        assert_eq!(file, expected);
+
+        let mut lib: syn::File = parse_quote! {
+            extern crate mylib;
+            pub use ::utils;
+            pub use ::serde;
+        };
+        replace_extern_crates(&mut lib.items, &crates, Some("__bundled"), true, false);
+        let expected: syn::File = parse_quote! {
+            use crate::__bundled::mylib;
+            pub use crate::__bundled::utils;
+            pub use ::serde;
+        };
+        assert_eq!(lib, expected);
+    }
+
+    #[test]
+    fn pub_extern_crates_become_uses() {
+        let mut file: syn::File = parse_quote! {
+            pub extern crate serde;
+            pub(crate) extern crate serde_json as json;
+            extern crate log;
+            #[macro_use]
+            pub extern crate lazy_static;
+        };
+        reexport_extern_crates(&mut file);
+        let expected: syn::File = parse_quote! {
+            pub use ::serde;
+            pub(crate) use ::serde_json as json;
+            extern crate log;
+            #[macro_use]
+            pub extern crate lazy_static;
+        };
+        assert_eq!(file, expected);
+    }
+}
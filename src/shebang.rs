@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+/// How the bundle makes itself executable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShebangStyle {
+    /// `#!/usr/bin/env -S rust-script`, which needs an `env` supporting `-S`
+    EnvSplit,
+    /// `#!` followed by the absolute path of the interpreter, `/usr/bin/rust-script` if none is
+    /// given
+    Direct(Option<String>),
+    /// A `/bin/sh` shebang followed by a line that is a shell command running rust-script on the
+    /// script and a plain comment to Rust. Works wherever there is a POSIX shell and rust-script
+    /// on the `PATH`.
+    PolyglotTrampoline,
+    /// This line as is, which has to start with `#!`, e.g. for wrappers taking extra flags
+    Custom(String),
+    /// No shebang, for scripts only ever run as `rust-script <script>`
+    Omitted,
+}
+
+impl Default for ShebangStyle {
+    fn default() -> Self {
+        ShebangStyle::EnvSplit
+    }
+}
+
+const DIRECT_INTERPRETER: &str = "/usr/bin/rust-script";
+
+/// `//usr/bin/env` is a valid path for the shell, and a comment for Rust. The `exit` makes sure
+/// the shell never gets to the Rust code after it.
+const TRAMPOLINE: &str = r#"//usr/bin/env rust-script "$0" "$@"; exit $?"#;
+
+impl ShebangStyle {
+    pub fn validate(&self) -> Result<()> {
+        if let ShebangStyle::Direct(Some(path)) = self {
+            if !Path::new(path).is_absolute() {
+                bail!(
+                    "The interpreter in a shebang has to be an absolute path, got {}",
+                    path
+                );
+            }
+            if path.contains(char::is_whitespace) {
+                bail!("The interpreter path {:?} can not contain whitespace", path);
+            }
+        }
+        if let ShebangStyle::Custom(line) = self {
+            // rustc takes `#![` for an inner attribute rather than a shebang
+            if !line.starts_with("#!") || line.starts_with("#![") {
+                bail!("A shebang has to start with #!, got {:?}", line);
+            }
+            if line.contains(['\n', '\r']) {
+                bail!("A shebang has to be a single line, got {:?}", line);
+            }
+        }
+        Ok(())
+    }
+
+    /// The `#!` line, if any
+    pub fn shebang(&self) -> Option<String> {
+        let line = match self {
+            ShebangStyle::EnvSplit => "#!/usr/bin/env -S rust-script".into(),
+            ShebangStyle::Direct(path) => {
+                format!("#!{}", path.as_deref().unwrap_or(DIRECT_INTERPRETER))
+            }
+            ShebangStyle::PolyglotTrampoline => "#!/bin/sh".into(),
+            ShebangStyle::Custom(line) => line.clone(),
+            ShebangStyle::Omitted => return None,
+        };
+        Some(line)
+    }
+
+    /// Lines that go right after the shebang, before even the manifest. They are not attributes
+    /// or items, so the printer has to write them.
+    pub fn preamble(&self) -> &'static str {
+        match self {
+            ShebangStyle::PolyglotTrampoline => TRAMPOLINE,
+            _ => "",
+        }
+    }
+
+    /// A shebang that runs the same interpreter directly, for deriving launchers. Without a
+    /// shebang that is plain rust-script.
+    pub fn interpreter(&self) -> String {
+        match self {
+            ShebangStyle::PolyglotTrampoline | ShebangStyle::Omitted => {
+                ShebangStyle::EnvSplit.shebang().unwrap_or_default()
+            }
+            style => style.shebang().unwrap_or_default(),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use proc_macro2::Span;
+use quote::ToTokens;
+use syn::spanned::Spanned;
+
+use crate::diagnostic;
+use crate::rewrite;
+
+/// Where the items of a bundle came from, at the granularity of items: line numbers do not
+/// survive inlining and formatting, but item paths do. Used to make diagnostics about the
+/// bundle point at the original sources.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    /// module path in the bundle of each inlined crate, and its root file; the binary has the
+    /// empty path
+    roots: Vec<(Vec<String>, PathBuf)>,
+    /// every file read, to find the files of non-inline modules
+    sources: Vec<PathBuf>,
+}
+
+impl SourceMap {
+    /// Record that the crate rooted at `root` lives at `module` in the bundle
+    pub fn add_root(&mut self, module: Vec<String>, root: PathBuf) {
+        self.roots.push((module, root));
+    }
+
+    pub fn set_sources(&mut self, sources: Vec<PathBuf>) {
+        self.sources = sources;
+    }
+
+    /// The file the item at `path` in the bundle came from
+    fn file_of(&self, path: &[String]) -> Option<&Path> {
+        // the last segment is the item itself
+        self.module_file(&path[..path.len().saturating_sub(1)])
+    }
+
+    /// The file the code of the module at `module` in the bundle came from
+    pub fn module_file(&self, module: &[String]) -> Option<&Path> {
+        let (prefix, root) = self
+            .roots
+            .iter()
+            .filter(|(prefix, _)| module.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())?;
+        let dir = root.parent()?;
+        // the innermost module with a file of its own
+        let modules = &module[prefix.len()..];
+        for depth in (1..=modules.len()).rev() {
+            let rel: PathBuf = modules[..depth].iter().collect();
+            let candidates = [
+                dir.join(&rel).with_extension("rs"),
+                dir.join(&rel).join("mod.rs"),
+            ];
+            if let Some(file) = candidates.iter().find(|c| self.sources.contains(c)) {
+                return self
+                    .sources
+                    .iter()
+                    .find(|s| *s == file)
+                    .map(PathBuf::as_path);
+            }
+        }
+        Some(root)
+    }
+
+    /// Where `span`, of code in the module at `module` in the bundle, is in the sources, see
+    /// [`diagnostic::location`]. Only the line is known if the file is not.
+    pub fn locate(&self, module: &[String], span: Span) -> String {
+        match self.module_file(module) {
+            Some(file) => diagnostic::location(file, span),
+            None => format!("line {}:{}", span.start().line, span.start().column + 1),
+        }
+    }
+
+    /// The lines of the sources around `span`, of code in the module at `module` in the bundle,
+    /// see [`diagnostic::snippet`]. Empty if they can not be told.
+    pub fn excerpt(&self, module: &[String], span: Span) -> String {
+        match (self.module_file(module), span.start().line) {
+            (Some(file), line) if line > 0 => fs::read_to_string(file)
+                .map(|text| diagnostic::snippet(&text, line))
+                .unwrap_or_default(),
+            _ => String::new(),
+        }
+    }
+
+    /// Rewrite references to lines of `file` in `diagnostics`, like `src/main.rs:12:5`, into the
+    /// item the line belongs to and the file that item came from. `text` is the content of
+    /// `file`, which has the same items as the bundle.
+    pub fn translate(&self, diagnostics: &str, file: &str, text: &str) -> String {
+        let items = match syn::parse_file(text) {
+            Ok(parsed) => {
+                let mut items = vec![];
+                collect_items(&parsed.items, &mut vec![], &mut items);
+                items
+            }
+            // nothing to map with, leave the diagnostics alone
+            Err(_) => return diagnostics.into(),
+        };
+
+        let needle = format!("{}:", file);
+        let mut translated = String::new();
+        let mut rest = diagnostics;
+        while let Some(idx) = rest.find(&needle) {
+            let after = &rest[idx + needle.len()..];
+            let digits = after.bytes().take_while(u8::is_ascii_digit).count();
+            let line = after[..digits].parse::<usize>().ok();
+            // the innermost item containing the line
+            let item = line.and_then(|line| {
+                items
+                    .iter()
+                    .filter(|(start, end, _)| (*start..=*end).contains(&line))
+                    .max_by_key(|(start, _, _)| *start)
+            });
+            translated.push_str(&rest[..idx + needle.len() + digits]);
+            rest = &after[digits..];
+            if let Some((_, _, path)) = item {
+                // skip the column
+                if let Some(col) = rest.strip_prefix(':') {
+                    let digits = col.bytes().take_while(u8::is_ascii_digit).count();
+                    translated.push_str(&rest[..1 + digits]);
+                    rest = &col[digits..];
+                }
+                match self.file_of(path) {
+                    Some(source) => translated.push_str(&format!(
+                        " (in item `{}` from {})",
+                        path.join("::"),
+                        source.display()
+                    )),
+                    None => translated.push_str(&format!(" (in item `{}`)", path.join("::"))),
+                }
+            }
+        }
+        translated.push_str(rest);
+        translated
+    }
+}
+
+/// Collect the line range and path of `items` and everything in their inline modules
+fn collect_items(
+    items: &[syn::Item],
+    module: &mut Vec<String>,
+    out: &mut Vec<(usize, usize, Vec<String>)>,
+) {
+    for item in items {
+        let name = match item {
+            syn::Item::Impl(i) => Some(format!("impl {}", i.self_ty.to_token_stream())),
+            item => rewrite::item_ident(item).map(ToString::to_string),
+        };
+        let span = item.span();
+        let mut path = module.clone();
+        path.extend(name.clone());
+        out.push((span.start().line, span.end().line, path));
+
+        if let (syn::Item::Mod(m), Some(name)) = (item, name) {
+            if let Some((_, content)) = &m.content {
+                module.push(name);
+                collect_items(content, module, out);
+                module.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_map_to_items_and_files() {
+        let text = "fn main() {
+    foo::util::bar();
+}
+
+pub mod foo {
+    pub mod util {
+        pub fn bar() {
+            nope
+        }
+    }
+}
+";
+        let mut map = SourceMap::default();
+        map.set_sources(vec![
+            PathBuf::from("/app/src/main.rs"),
+            PathBuf::from("/foo/src/lib.rs"),
+            PathBuf::from("/foo/src/util.rs"),
+        ]);
+        map.add_root(vec![], PathBuf::from("/app/src/main.rs"));
+        map.add_root(vec!["foo".into()], PathBuf::from("/foo/src/lib.rs"));
+
+        let translated = map.translate(
+            "src/main.rs:8:13: error: cannot find value `nope`",
+            "src/main.rs",
+            text,
+        );
+        assert_eq!(
+            translated,
+            "src/main.rs:8:13 (in item `foo::util::bar` from /foo/src/util.rs): error: cannot find value `nope`"
+        );
+        let translated = map.translate("src/main.rs:2:5: warning", "src/main.rs", text);
+        assert!(translated.contains("in item `main` from /app/src/main.rs"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::paths;
+
+const HASH: &str = "// bundler-inputs-sha256: ";
+const OPTIONS: &str = "// bundler-options: ";
+const ROOT: &str = "// bundler-root: ";
+const INPUT: &str = "// bundler-input: ";
+
+/// The inputs of a bundle and a hash over them, embedded in its header as comment lines so it
+/// can be checked for staleness without bundling again, see [`check_freshness`].
+///
+/// The hash does not change between releases. It is the SHA-256, in lowercase hex, of the text
+/// made of these lines, each ending in `\n`:
+/// - `rust-script-bundler inputs v1`;
+/// - for each input, sorted by path: its path relative to the package root with `/` separators,
+///   then the SHA-256 of its content in lowercase hex;
+/// - `options`, then the options line as embedded.
+pub struct Stamp {
+    /// the package root, relative to the directory of the bundle
+    root: String,
+    /// relative to the root
+    inputs: Vec<String>,
+    options: String,
+    hash: String,
+}
+
+impl Stamp {
+    /// The stamp of a bundle written to `bundle_dir` from `inputs` of the package at `root`
+    pub fn new(
+        bundle_dir: &Path,
+        root: &Path,
+        inputs: &[PathBuf],
+        options: String,
+    ) -> Result<Self> {
+        let mut relative: Vec<_> = inputs
+            .iter()
+            .map(|input| slash_path(&paths::relative_from(input, root)))
+            .collect();
+        relative.sort();
+        relative.dedup();
+        let hash = hash(root, &relative, &options)?;
+        Ok(Stamp {
+            root: slash_path(&paths::relative_from(root, bundle_dir)),
+            inputs: relative,
+            options,
+            hash,
+        })
+    }
+
+    /// The comment lines, each ending in a newline
+    pub fn render(&self) -> String {
+        let mut lines = format!(
+            "{}{}\n{}{}\n{}{}\n",
+            HASH, self.hash, OPTIONS, self.options, ROOT, self.root
+        );
+        for input in &self.inputs {
+            lines.push_str(&format!("{}{}\n", INPUT, input));
+        }
+        lines
+    }
+
+    /// The stamp embedded in the bundle `text`, if any
+    fn parse(text: &str) -> Option<Self> {
+        let mut stamp = Stamp {
+            root: String::new(),
+            inputs: vec![],
+            options: String::new(),
+            hash: String::new(),
+        };
+        for line in text.lines() {
+            if let Some(hash) = line.strip_prefix(HASH) {
+                stamp.hash = hash.trim().into();
+            } else if let Some(options) = line.strip_prefix(OPTIONS) {
+                stamp.options = options.into();
+            } else if let Some(root) = line.strip_prefix(ROOT) {
+                stamp.root = root.into();
+            } else if let Some(input) = line.strip_prefix(INPUT) {
+                stamp.inputs.push(input.into());
+            }
+        }
+        (!stamp.hash.is_empty() && !stamp.root.is_empty()).then_some(stamp)
+    }
+}
+
+/// `path` with `/` separators on every platform, `.` if empty
+fn slash_path(path: &Path) -> String {
+    let parts: Vec<_> = path
+        .components()
+        .map(|c| match c {
+            // joins into a leading `/`
+            Component::RootDir => "".into(),
+            c => c.as_os_str().to_string_lossy(),
+        })
+        .collect();
+    if parts.is_empty() {
+        ".".into()
+    } else {
+        parts.join("/")
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// See [`Stamp`] for the definition
+fn hash(root: &Path, inputs: &[String], options: &str) -> Result<String> {
+    let mut text = String::from("rust-script-bundler inputs v1\n");
+    for input in inputs {
+        let path = root.join(input);
+        let content =
+            fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        text.push_str(&format!("{}\n{}\n", input, sha256_hex(&content)));
+    }
+    text.push_str(&format!("options\n{}\n", options));
+    Ok(sha256_hex(text.as_bytes()))
+}
+
+/// Whether a bundle still matches the sources it was made from, see [`check_freshness`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Freshness {
+    UpToDate,
+    /// An input changed or is gone, with what was noticed
+    Stale(String),
+    /// The bundle has no stamp, or its inputs can not be located, with why
+    Unverifiable(String),
+}
+
+/// Check the bundle at `bundle` against the current content of the inputs listed in its stamp,
+/// which [`Bundler::embed_inputs_hash`](crate::Bundler::embed_inputs_hash) adds. Much cheaper
+/// than bundling again, but files that would newly become inputs, like a module added since,
+/// are not noticed.
+pub fn check_freshness(bundle: impl AsRef<Path>) -> Result<Freshness> {
+    let bundle = bundle.as_ref();
+    let text = fs::read_to_string(bundle)
+        .with_context(|| format!("Failed to read bundle {}", bundle.display()))?;
+    let stamp = match Stamp::parse(&text) {
+        Some(stamp) => stamp,
+        None => {
+            return Ok(Freshness::Unverifiable(format!(
+                "{} has no embedded inputs hash",
+                bundle.display()
+            )))
+        }
+    };
+    let root = bundle.parent().unwrap_or(Path::new(".")).join(&stamp.root);
+    if !root.is_dir() {
+        return Ok(Freshness::Unverifiable(format!(
+            "package root {} of {} does not exist",
+            root.display(),
+            bundle.display()
+        )));
+    }
+    if let Some(gone) = stamp
+        .inputs
+        .iter()
+        .find(|input| !root.join(input).is_file())
+    {
+        return Ok(Freshness::Stale(format!("input {} is gone", gone)));
+    }
+    if hash(&root, &stamp.inputs, &stamp.options)? == stamp.hash {
+        Ok(Freshness::UpToDate)
+    } else {
+        Ok(Freshness::Stale("inputs changed since bundling".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamp_round_trip() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/simple");
+        let inputs = [root.join("src/main.rs"), root.join("Cargo.toml")];
+        let stamp = Stamp::new(&root.join("out"), &root, &inputs, "bin=simple".into()).unwrap();
+        let rendered = stamp.render();
+        assert!(rendered.contains("// bundler-root: ..\n"));
+        assert!(rendered.ends_with("// bundler-input: Cargo.toml\n// bundler-input: src/main.rs\n"));
+
+        let parsed = Stamp::parse(&format!("fn main() {{}}\n{}", rendered)).unwrap();
+        assert_eq!(parsed.inputs, stamp.inputs);
+        assert_eq!(
+            hash(&root, &parsed.inputs, &parsed.options).unwrap(),
+            stamp.hash
+        );
+        assert_ne!(
+            hash(&root, &parsed.inputs, "bin=other").unwrap(),
+            stamp.hash
+        );
+        assert!(Stamp::parse("fn main() {}").is_none());
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Figures about what went into a bundle, to follow its size over time, see
+/// [`BundleReport::stats`](crate::BundleReport::stats)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BundleStats {
+    /// Size of the script in bytes
+    pub bytes: usize,
+    pub lines: usize,
+    /// The inlined crates, in the order they are in the bundle
+    pub crates: Vec<CrateStats>,
+    /// The dependencies in the embedded manifest, plain and platform specific, as name and
+    /// version requirement
+    pub dependencies: Vec<(String, String)>,
+    pub stripped: StrippedItems,
+    /// How long each phase of bundling took, in order
+    pub phases: Vec<(String, Duration)>,
+}
+
+/// What one inlined crate brought into a bundle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CrateStats {
+    pub name: String,
+    /// The module it became
+    pub module: String,
+    /// Its source files with their size in bytes
+    pub sources: Vec<(PathBuf, u64)>,
+    /// How many items it has, those of its modules included, before the passes. Items the
+    /// passes drop are counted in [`BundleStats::stripped`].
+    pub items: usize,
+    /// Whether it was processed before, by an earlier bundle of
+    /// [`Bundler::bundle_all`](crate::Bundler::bundle_all) or another run with the same
+    /// [`Bundler::cache_dir`](crate::Bundler::cache_dir), rather than read anew
+    pub cached: bool,
+}
+
+/// How many items were left out of a bundle, and why
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StrippedItems {
+    /// Test code, with [`Bundler::strip_tests`](crate::Bundler::strip_tests)
+    pub tests: usize,
+    /// Items, fields, statements and the like whose `#[cfg]` is false for the selected features
+    pub cfg: usize,
+    /// Unused items of the inlined crates, with
+    /// [`Bundler::prune_unused`](crate::Bundler::prune_unused)
+    pub pruned: usize,
+}
+
+impl CrateStats {
+    pub(crate) fn new(
+        name: &str,
+        module: &str,
+        sources: &[PathBuf],
+        items: &[syn::Item],
+        cached: bool,
+    ) -> Self {
+        CrateStats {
+            name: name.into(),
+            module: module.into(),
+            sources: sources
+                .iter()
+                .map(|path| {
+                    (
+                        path.clone(),
+                        fs::metadata(path).map_or(0, |meta| meta.len()),
+                    )
+                })
+                .collect(),
+            items: count_items(items),
+            cached,
+        }
+    }
+}
+
+/// The number of `items`, counting those in inline modules as well
+fn count_items(items: &[syn::Item]) -> usize {
+    items
+        .iter()
+        .map(|item| match item {
+            syn::Item::Mod(syn::ItemMod {
+                content: Some((_, content)),
+                ..
+            }) => 1 + count_items(content),
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Times the phases of bundling one after the other
+pub(crate) struct Phases {
+    start: Instant,
+    done: Vec<(String, Duration)>,
+}
+
+impl Phases {
+    pub fn start() -> Self {
+        Phases {
+            start: Instant::now(),
+            done: vec![],
+        }
+    }
+
+    /// End the phase `name`, starting the next one
+    pub fn end(&mut self, name: &str) {
+        self.done.push((name.into(), self.start.elapsed()));
+        self.start = Instant::now();
+    }
+
+    pub fn finish(self) -> Vec<(String, Duration)> {
+        self.done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_items_are_counted() {
+        let file: syn::File = syn::parse_quote! {
+            fn a() {}
+            mod b {
+                struct C;
+                mod d {
+                    fn e() {}
+                }
+            }
+            mod f;
+        };
+        assert_eq!(count_items(&file.items), 6);
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+
+/// Variables a header or footer template can use, as `{name}`
+pub const VARIABLES: &[&str] = &["version", "bin_name", "date", "git_hash", "inputs_hash"];
+
+/// The footer every bundle gets unless replaced
+pub const DEFAULT_FOOTER: &str = "vim: ft=rust syntax=rust";
+
+/// The header of playground bundles, which have no shebang or manifest to tell they were generated
+pub const PLAYGROUND_HEADER: &str = "@generated by rust-script-bundler";
+
+enum Piece {
+    Text(String),
+    Var(String),
+}
+
+/// A multi-line text with `{variable}` placeholders, emitted as `//` comment lines. Braces are
+/// escaped by doubling them.
+pub struct Template(Vec<Piece>);
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut pieces = vec![];
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        bail!(
+                            "Unclosed {{ in template {:?}, write {{{{ for a literal one",
+                            text
+                        );
+                    }
+                    if !VARIABLES.contains(&name.as_str()) {
+                        bail!(
+                            "Unknown template variable {{{}}}, supported are: {}",
+                            name,
+                            VARIABLES.join(", ")
+                        );
+                    }
+                    pieces.push(Piece::Text(std::mem::take(&mut literal)));
+                    pieces.push(Piece::Var(name));
+                }
+                '}' => bail!(
+                    "Unmatched }} in template {:?}, write }}}} for a literal one",
+                    text
+                ),
+                c => literal.push(c),
+            }
+        }
+        pieces.push(Piece::Text(literal));
+        Ok(Template(pieces))
+    }
+
+    /// A template of `text` as is, braces and all
+    pub fn literal(text: impl Into<String>) -> Self {
+        Template(vec![Piece::Text(text.into())])
+    }
+
+    /// The comment lines, each ending in a newline, with variables looked up through `var`
+    pub fn render(&self, mut var: impl FnMut(&str) -> Result<String>) -> Result<String> {
+        let mut text = String::new();
+        for piece in &self.0 {
+            match piece {
+                Piece::Text(t) => text.push_str(t),
+                Piece::Var(name) => text.push_str(&var(name)?),
+            }
+        }
+        Ok(text
+            .lines()
+            .map(|line| match line {
+                "" => "//\n".to_string(),
+                line => format!("// {}\n", line),
+            })
+            .collect())
+    }
+}
+
+/// A digest of the content of `inputs`, as 16 hex digits. FNV-1a, so it is stable across
+/// platforms and Rust versions.
+pub fn inputs_hash(inputs: &[PathBuf]) -> Result<String> {
+    let mut content = vec![];
+    for input in inputs {
+        content.extend(
+            fs::read(input).with_context(|| format!("Failed to read {}", input.display()))?,
+        );
+    }
+    Ok(fnv1a(&content))
+}
+
+/// FNV-1a of `bytes`, as 16 hex digits
+pub fn fnv1a(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Today's date in UTC, as `YYYY-MM-DD`
+pub fn today() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    // days since the epoch to a civil date, after Howard Hinnant's algorithm
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_comment_lines() {
+        let template = Template::parse("{bin_name} v{version}\n\n{{not a var}}").unwrap();
+        let rendered = template.render(|name| Ok(format!("<{}>", name))).unwrap();
+        assert_eq!(rendered, "// <bin_name> v<version>\n//\n// {not a var}\n");
+
+        let literal = Template::literal("DO NOT EDIT {version}").render(|_| unreachable!());
+        assert_eq!(literal.unwrap(), "// DO NOT EDIT {version}\n");
+
+        let err = Template::parse("built on {host}").err().unwrap();
+        assert!(err.to_string().contains("inputs_hash"));
+    }
+}
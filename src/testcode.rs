@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+
+use crate::cfg::{self, CfgSet};
+
+/// What [`Bundler::tests`](crate::Bundler::tests) does with the test code of the bundle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tests {
+    /// Remove it, along with the imports only it used
+    Strip,
+    /// Keep it, with the modules nested as they were, so `rust-script --test` runs the tests.
+    /// Their dev-dependencies are left out of the embedded manifest.
+    Keep,
+    /// Keep it, and merge the `[dev-dependencies]` of the package and of the crates whose
+    /// manifests are merged into `[dependencies]`, as script manifests have no dev-dependencies
+    KeepAndMergeDevDeps,
+}
+
+impl Default for Tests {
+    fn default() -> Self {
+        Tests::Keep
+    }
+}
+
+/// Remove test code from `file`: items under `#[cfg(test)]`, `#[test]` functions, and the
+/// `cfg_attr(test, ...)` attributes, resolved as not testing. Private imports that were only
+/// used by removed code go as well, so the bundle stays clean under `-D unused`. Returns how many
+/// items and imported names were removed.
+pub fn strip(file: &mut syn::File) -> usize {
+    let mut before = BTreeMap::new();
+    used_idents(&file.items, &mut vec![], &mut before);
+    let items = count_items(&file.items);
+
+    remove_test_fns(&mut file.items, &mut vec![]);
+    cfg::strip(file, &CfgSet::new().with_known("test"));
+
+    let mut after = BTreeMap::new();
+    used_idents(&file.items, &mut vec![], &mut after);
+    let removed = items - count_items(&file.items);
+    removed + prune_imports(&mut file.items, &mut vec![], &before, &after)
+}
+
+/// Number of items, including those in inline modules
+fn count_items(items: &[syn::Item]) -> usize {
+    items
+        .iter()
+        .map(|item| match item {
+            syn::Item::Mod(syn::ItemMod {
+                content: Some((_, content)),
+                ..
+            }) => 1 + count_items(content),
+            _ => 1,
+        })
+        .sum()
+}
+
+fn is_test_attr(attr: &syn::Attribute) -> bool {
+    attr.path
+        .segments
+        .last()
+        .map_or(false, |s| s.ident == "test" || s.ident == "bench")
+}
+
+fn remove_test_fns(items: &mut Vec<syn::Item>, module: &mut Vec<String>) {
+    items.retain_mut(|item| match item {
+        syn::Item::Fn(f) if f.attrs.iter().any(is_test_attr) => {
+            log::debug!(
+                "Removed test function {}::{}",
+                module.join("::"),
+                f.sig.ident
+            );
+            false
+        }
+        syn::Item::Mod(m) => {
+            if let Some((_, content)) = &mut m.content {
+                module.push(m.ident.to_string());
+                remove_test_fns(content, module);
+                module.pop();
+            }
+            true
+        }
+        _ => true,
+    });
+}
+
+/// Collect, per module path, the identifiers appearing anywhere in items that are not imports,
+/// including nested modules
+fn used_idents(
+    items: &[syn::Item],
+    module: &mut Vec<String>,
+    out: &mut BTreeMap<Vec<String>, BTreeSet<String>>,
+) {
+    let mut used = BTreeSet::new();
+    for item in items {
+        if !matches!(item, syn::Item::Use(_)) {
+            collect_idents(item.to_token_stream(), &mut used);
+        }
+        if let syn::Item::Mod(syn::ItemMod {
+            ident,
+            content: Some((_, content)),
+            ..
+        }) = item
+        {
+            module.push(ident.to_string());
+            used_idents(content, module, out);
+            module.pop();
+        }
+    }
+    // cfg-gated variants of a module share its path
+    out.entry(module.clone()).or_default().extend(used);
+}
+
+fn collect_idents(tokens: TokenStream, out: &mut BTreeSet<String>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => {
+                out.insert(ident.to_string());
+            }
+            TokenTree::Group(group) => collect_idents(group.stream(), out),
+            _ => {}
+        }
+    }
+}
+
+/// Remove names from private imports that were used before stripping but are not anymore,
+/// returning how many
+fn prune_imports(
+    items: &mut Vec<syn::Item>,
+    module: &mut Vec<String>,
+    before: &BTreeMap<Vec<String>, BTreeSet<String>>,
+    after: &BTreeMap<Vec<String>, BTreeSet<String>>,
+) -> usize {
+    let empty = BTreeSet::new();
+    let used_before = before.get(module).unwrap_or(&empty);
+    let used_after = after.get(module).unwrap_or(&empty);
+    let unused = |name: &str| used_before.contains(name) && !used_after.contains(name);
+    let path = module.join("::");
+    let removed = std::cell::Cell::new(0);
+    items.retain_mut(|item| match item {
+        syn::Item::Use(u) if matches!(u.vis, syn::Visibility::Inherited) => {
+            prune_tree(&mut u.tree, &|name| {
+                let unused = unused(name);
+                if unused {
+                    log::debug!(
+                        "Removed import of {} in {}, only test code used it",
+                        name,
+                        path
+                    );
+                    removed.set(removed.get() + 1);
+                }
+                unused
+            })
+        }
+        syn::Item::Mod(m) => {
+            if let Some((_, content)) = &mut m.content {
+                module.push(m.ident.to_string());
+                removed.set(removed.get() + prune_imports(content, module, before, after));
+                module.pop();
+            }
+            true
+        }
+        _ => true,
+    });
+    removed.get()
+}
+
+/// Drop the names `remove` asks for from `tree`, returning whether anything is left
+fn prune_tree(tree: &mut syn::UseTree, remove: &dyn Fn(&str) -> bool) -> bool {
+    match tree {
+        syn::UseTree::Path(p) => prune_tree(&mut p.tree, remove),
+        syn::UseTree::Name(n) => n.ident == "self" || !remove(&n.ident.to_string()),
+        syn::UseTree::Rename(r) => !remove(&r.rename.to_string()),
+        syn::UseTree::Glob(_) => true,
+        syn::UseTree::Group(g) => {
+            let items = std::mem::take(&mut g.items);
+            g.items = items
+                .into_pairs()
+                .map(|pair| pair.into_value())
+                .filter_map(|mut tree| {
+                    let keep = prune_tree(&mut tree, remove);
+                    keep.then_some(tree)
+                })
+                .collect();
+            !g.items.is_empty()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn strips_tests_and_their_imports() {
+        let mut file: syn::File = parse_quote! {
+            use std::collections::{BTreeMap, HashMap};
+            use std::fmt::Debug;
+
+            #[cfg_attr(test, derive(PartialEq))]
+            #[derive(Clone)]
+            pub struct Config(BTreeMap<String, String>);
+
+            pub fn show(c: &dyn Debug) {}
+
+            #[test]
+            fn loose_test() {
+                let _ = HashMap::<u8, u8>::new();
+            }
+
+            #[cfg(test)]
+            mod tests {
+                use super::*;
+            }
+        };
+        // the test function, the test module with its import, and HashMap
+        assert_eq!(strip(&mut file), 4);
+        let expected: syn::File = parse_quote! {
+            use std::collections::{BTreeMap};
+            use std::fmt::Debug;
+
+            #[derive(Clone)]
+            pub struct Config(BTreeMap<String, String>);
+
+            pub fn show(c: &dyn Debug) {}
+        };
+        assert_eq!(
+            file.to_token_stream().to_string(),
+            expected.to_token_stream().to_string()
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Check that a bundled script actually compiles, not just parses.
+//!
+//! The script is turned back into a cargo project with [`unbundle`](crate::unbundle) in a
+//! temporary directory, and `cargo check --offline` is run on it. Dependencies therefore have to
+//! be in the local registry cache already, e.g. by building the original crate first. With
+//! [`CompileCheck::frozen`], `--frozen` is passed as well, for vendored registries.
+//!
+//! Enable the `test-util` feature to use this from a build pipeline:
+//!
+//! ```ignore
+//! # fn main() -> anyhow::Result<()> {
+//! use rust_script_bundler::testing::CompileCheck;
+//!
+//! CompileCheck::new().run("target/scripts/mytool.rs")?;
+//! # Ok(())
+//! # }
+//! ```
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+
+/// The script does not compile. Returned through [`anyhow::Error`], so downcast to inspect it.
+#[derive(Debug, thiserror::Error)]
+#[error("Bundled script {} does not compile:\n{diagnostics}", .script.display())]
+pub struct CompileError {
+    pub script: PathBuf,
+    /// cargo's output, in short message format
+    pub diagnostics: String,
+}
+
+/// Runs `cargo check` on bundled scripts
+#[derive(Debug, Clone, Default)]
+pub struct CompileCheck {
+    frozen: bool,
+    target_dir: Option<PathBuf>,
+}
+
+impl CompileCheck {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Pass `--frozen`, so cargo neither touches the network nor updates the lock file.
+    pub fn frozen(mut self, frozen: bool) -> Self {
+        self.frozen = frozen;
+        self
+    }
+
+    /// Share a target directory across checks, which saves rebuilding dependencies each time.
+    pub fn target_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.target_dir = Some(dir.into());
+        self
+    }
+
+    /// Compile check the script at `script`, failing with a [`CompileError`] carrying the
+    /// diagnostics if it does not compile.
+    pub fn run(&self, script: impl AsRef<Path>) -> Result<()> {
+        let script = script.as_ref();
+        let project = crate::verify::temp_project(script, "check")?;
+        let manifest_path = project.join("Cargo.toml");
+
+        let mut cmd = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()));
+        cmd.arg("check")
+            .arg("--offline")
+            .arg("--message-format=short")
+            .arg("--manifest-path")
+            .arg(&manifest_path)
+            .stdin(Stdio::null());
+        if self.frozen {
+            cmd.arg("--frozen");
+        }
+        if let Some(dir) = &self.target_dir {
+            cmd.arg("--target-dir").arg(dir);
+        }
+        let output = cmd.output().context("Failed to run cargo")?;
+        if !output.status.success() {
+            return Err(CompileError {
+                script: script.into(),
+                diagnostics: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+            .into());
+        }
+        fs::remove_dir_all(&project)?;
+        Ok(())
+    }
+}
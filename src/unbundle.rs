@@ -0,0 +1,259 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use cargo_toml::Manifest;
+use syn::spanned::Spanned;
+
+use crate::print::SynFilePrint;
+
+/// Comment placed on the line right before a top-level module that came from its own file
+pub const SOURCE_MARKER: &str = "// source: ";
+
+/// A cargo project reconstructed from a script
+struct Project {
+    manifest: String,
+    /// files relative to the project root, including `src/main.rs`
+    files: Vec<(PathBuf, String)>,
+}
+
+/// Reconstruct a cargo project in `out_dir` from the rust-script at `script`, returning the paths
+/// of all files written.
+///
+/// The embedded ```` ```cargo ```` block becomes `Cargo.toml`, other fenced blocks in the header
+/// are kept as `header-<n>.<lang>` files, and the rest of the script becomes `src/main.rs`.
+/// Top-level inline modules preceded by a [`SOURCE_MARKER`] comment are split back into
+/// `src/<name>.rs`.
+pub fn unbundle(script: impl AsRef<Path>, out_dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let (script, out_dir) = (script.as_ref(), out_dir.as_ref());
+    let text = fs::read_to_string(script)
+        .with_context(|| format!("Failed to read script at {}", script.display()))?;
+    let name = script
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("script");
+
+    let project = split_script(&text, name)?;
+
+    let mut written = vec![];
+    let mut write = |path: &Path, content: &str| -> Result<()> {
+        let path = out_dir.join(path);
+        if let Some(p) = path.parent() {
+            fs::create_dir_all(p)?;
+        }
+        fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+        written.push(path);
+        Ok(())
+    };
+    write(Path::new("Cargo.toml"), &project.manifest)?;
+    for (path, content) in &project.files {
+        write(path, content)?;
+    }
+    Ok(written)
+}
+
+fn split_script(text: &str, name: &str) -> Result<Project> {
+    let mut lines = text.lines().peekable();
+    if let Some(first) = lines.peek() {
+        if first.starts_with("#!") && !first.starts_with("#![") {
+            lines.next();
+        }
+    }
+
+    // the header is the leading run of inner doc comments
+    let mut docs = vec![];
+    let mut fences: Vec<(String, Vec<String>)> = vec![];
+    let mut in_fence = false;
+    while let Some(&line) = lines.peek() {
+        let content = match line.trim_start().strip_prefix("//!") {
+            Some(content) => content.strip_prefix(' ').unwrap_or(content),
+            // blank lines and plain comments before the manifest
+            None if fences.is_empty() && docs.is_empty() && skippable(line) => {
+                lines.next();
+                continue;
+            }
+            None => break,
+        };
+        match content.trim_start().strip_prefix("```") {
+            Some(info) if !in_fence => {
+                in_fence = true;
+                fences.push((info.trim().into(), vec![]));
+            }
+            Some(_) => in_fence = false,
+            None if in_fence => fences.last_mut().unwrap().1.push(content.into()),
+            None => docs.push(line.to_string()),
+        }
+        lines.next();
+    }
+
+    let mut body: Vec<_> = lines.collect();
+    while let Some(last) = body.last() {
+        if last.trim().is_empty() || last.starts_with("// vim:") {
+            body.pop();
+        } else {
+            break;
+        }
+    }
+
+    let mut manifest = None;
+    let mut files = vec![];
+    for (i, (info, content)) in fences.into_iter().enumerate() {
+        let content = content.join("\n") + "\n";
+        if info == "cargo" && manifest.is_none() {
+            manifest = Some(content);
+            continue;
+        }
+        let ext = if !info.is_empty() && info.chars().all(|c| c.is_ascii_alphanumeric()) {
+            info.as_str()
+        } else {
+            "txt"
+        };
+        files.push((PathBuf::from(format!("header-{}.{}", i, ext)), content));
+    }
+    let manifest = complete_manifest(manifest, name);
+
+    let main = docs
+        .into_iter()
+        .chain(body.into_iter().map(String::from))
+        .collect::<Vec<_>>()
+        .join("\n")
+        + "\n";
+    let (main, modules) = split_modules(&main)?;
+    files.push((PathBuf::from("src/main.rs"), main));
+    files.extend(modules);
+
+    Ok(Project { manifest, files })
+}
+
+/// Make sure the manifest has a `[package]`, which scripts typically leave out
+fn complete_manifest(manifest: Option<String>, name: &str) -> String {
+    let manifest = manifest.unwrap_or_default();
+    let has_package = Manifest::from_str(&manifest)
+        .map(|m| m.package.is_some())
+        .unwrap_or(false);
+    if has_package {
+        return manifest;
+    }
+    format!(
+        "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n{}",
+        name, manifest
+    )
+}
+
+/// Split top-level modules preceded by a source marker into their own files. Without any marker
+/// the text is kept verbatim.
+fn split_modules(main: &str) -> Result<(String, Vec<(PathBuf, String)>)> {
+    let lines: Vec<_> = main.lines().collect();
+    if !lines
+        .iter()
+        .any(|l| l.trim_start().starts_with(SOURCE_MARKER))
+    {
+        return Ok((main.into(), vec![]));
+    }
+
+    let mut file = syn::parse_file(main).context("Failed to parse the script body")?;
+    let mut modules = vec![];
+    for item in file.items.iter_mut() {
+        let module = match item {
+            syn::Item::Mod(module) if module.content.is_some() => module,
+            _ => continue,
+        };
+        // span lines are 1-based, so this indexes the line before the item
+        let marker_at = module.span().start().line.saturating_sub(2);
+        let marked = lines
+            .get(marker_at)
+            .map_or(false, |l| l.trim_start().starts_with(SOURCE_MARKER));
+        if !marked {
+            continue;
+        }
+
+        let (_, items) = module.content.take().unwrap();
+        let (inner, outer): (Vec<_>, Vec<_>) = std::mem::take(&mut module.attrs)
+            .into_iter()
+            .partition(|a| matches!(a.style, syn::AttrStyle::Inner(_)));
+        module.attrs = outer;
+        module.semi = Some(Default::default());
+        let split = syn::File {
+            shebang: None,
+            attrs: inner,
+            items,
+        };
+        modules.push((
+            PathBuf::from(format!("src/{}.rs", module.ident)),
+            print(&split)?,
+        ));
+    }
+    Ok((print(&file)?, modules))
+}
+
+/// `file` as source
+fn print(file: &syn::File) -> Result<String> {
+    let mut text = vec![];
+    file.print().write_to(&mut text)?;
+    Ok(String::from_utf8(text)?)
+}
+
+/// Whether `line` before the manifest is blank or a plain comment, like a header comment
+fn skippable(line: &str) -> bool {
+    line.trim().is_empty() || (line.starts_with("//") && !line.starts_with("///"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_header_and_footer() {
+        let script = "#!/usr/bin/env -S rust-script\n\
+            // DO NOT EDIT\n\
+            //! ```cargo\n\
+            //! [dependencies]\n\
+            //! anyhow = \"1.0\"\n\
+            //! ```\n\
+            //! ```sh\n\
+            //! echo hi\n\
+            //! ```\n\
+            //! Crate docs\n\
+            fn main() {}\n\
+            // vim: ft=rust syntax=rust\n";
+        let project = split_script(script, "tool").unwrap();
+        assert!(project.manifest.starts_with("[package]\nname = \"tool\""));
+        assert!(project
+            .manifest
+            .ends_with("[dependencies]\nanyhow = \"1.0\"\n"));
+        assert_eq!(
+            project.files,
+            [
+                (PathBuf::from("header-1.sh"), "echo hi\n".to_string()),
+                (
+                    PathBuf::from("src/main.rs"),
+                    "//! Crate docs\nfn main() {}\n".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn plain_script_becomes_single_main() {
+        let script = "fn main() {\n    // hi\n}\n";
+        let project = split_script(script, "plain").unwrap();
+        assert_eq!(
+            project.files,
+            [(PathBuf::from("src/main.rs"), script.to_string())]
+        );
+    }
+
+    #[test]
+    fn marked_modules_are_split() {
+        let script = "fn main() {}\n// source: src/util.rs\npub mod util {\n    pub fn f() {}\n}\n";
+        let project = split_script(script, "tool").unwrap();
+        let main = syn::parse_file(&project.files[0].1).unwrap();
+        assert!(matches!(
+            &main.items[1],
+            syn::Item::Mod(m) if m.ident == "util" && m.content.is_none()
+        ));
+        assert_eq!(project.files[1].0, PathBuf::from("src/util.rs"));
+        let util = syn::parse_file(&project.files[1].1).unwrap();
+        assert_eq!(util.items.len(), 1);
+    }
+}
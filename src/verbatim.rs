@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use proc_macro2::TokenStream;
+use syn::visit::{self, Visit};
+
+use crate::warning::{Warning, WarningKind};
+
+/// Report the verbatim nodes in `file`, i.e. code syn could not parse into its syntax tree. No
+/// pass rewrites inside them, so they reach the bundle unchanged, which may leave them
+/// inconsistent with the code around them. Each is logged and added to `warnings`, or is an
+/// error if `strict` is set. `source` identifies the crate for the messages.
+pub fn check(
+    file: &syn::File,
+    source: &Path,
+    strict: bool,
+    warnings: &mut Vec<Warning>,
+) -> Result<()> {
+    let mut collector = Collector(vec![]);
+    collector.visit_file(file);
+    let found: Vec<_> = collector
+        .0
+        .into_iter()
+        .map(|tokens| {
+            let line = tokens
+                .clone()
+                .into_iter()
+                .next()
+                .map(|tt| tt.span().start().line);
+            (describe(&tokens, source), line)
+        })
+        .collect();
+    if strict && !found.is_empty() {
+        let found: Vec<_> = found.into_iter().map(|(warning, _)| warning).collect();
+        bail!(
+            "Can not process these parts of the code, which would be bundled verbatim:\n{}",
+            found.join("\n")
+        );
+    }
+    for (warning, line) in found {
+        log::warn!("{}", warning);
+        warnings.push(Warning::new(WarningKind::Verbatim, warning).at(source, line));
+    }
+    Ok(())
+}
+
+/// The original source text of `tokens`, if their spans cover a contiguous piece of it.
+pub fn source_text(tokens: &TokenStream) -> Option<String> {
+    let mut iter = tokens.clone().into_iter();
+    let first = iter.next()?.span();
+    let last = iter.last().map_or(first, |tt| tt.span());
+    first.join(last)?.source_text()
+}
+
+fn describe(tokens: &TokenStream, source: &Path) -> String {
+    let start = tokens
+        .clone()
+        .into_iter()
+        .next()
+        .map(|tt| tt.span().start());
+    let mut text = tokens.to_string();
+    if text.chars().count() > 60 {
+        text = text.chars().take(60).collect::<String>() + "...";
+    }
+    match start {
+        Some(start) => format!(
+            "{} (line {}:{}): verbatim `{}`",
+            source.display(),
+            start.line,
+            start.column,
+            text
+        ),
+        None => format!("{}: empty verbatim node", source.display()),
+    }
+}
+
+struct Collector(Vec<TokenStream>);
+
+impl<'ast> Visit<'ast> for Collector {
+    fn visit_item(&mut self, item: &'ast syn::Item) {
+        match item {
+            syn::Item::Verbatim(tokens) => self.0.push(tokens.clone()),
+            item => visit::visit_item(self, item),
+        }
+    }
+
+    fn visit_impl_item(&mut self, item: &'ast syn::ImplItem) {
+        match item {
+            syn::ImplItem::Verbatim(tokens) => self.0.push(tokens.clone()),
+            item => visit::visit_impl_item(self, item),
+        }
+    }
+
+    fn visit_trait_item(&mut self, item: &'ast syn::TraitItem) {
+        match item {
+            syn::TraitItem::Verbatim(tokens) => self.0.push(tokens.clone()),
+            item => visit::visit_trait_item(self, item),
+        }
+    }
+
+    fn visit_foreign_item(&mut self, item: &'ast syn::ForeignItem) {
+        match item {
+            syn::ForeignItem::Verbatim(tokens) => self.0.push(tokens.clone()),
+            item => visit::visit_foreign_item(self, item),
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &'ast syn::Expr) {
+        match expr {
+            syn::Expr::Verbatim(tokens) => self.0.push(tokens.clone()),
+            expr => visit::visit_expr(self, expr),
+        }
+    }
+
+    fn visit_type(&mut self, ty: &'ast syn::Type) {
+        match ty {
+            syn::Type::Verbatim(tokens) => self.0.push(tokens.clone()),
+            ty => visit::visit_type(self, ty),
+        }
+    }
+
+    fn visit_pat(&mut self, pat: &'ast syn::Pat) {
+        match pat {
+            syn::Pat::Verbatim(tokens) => self.0.push(tokens.clone()),
+            pat => visit::visit_pat(self, pat),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbatim_items_warn_or_fail() {
+        // syn keeps functions without a body as verbatim items
+        let file = syn::parse_file("fn declared();\nfn main() {}\n").unwrap();
+        let mut warnings = vec![];
+        check(&file, Path::new("lib.rs"), false, &mut warnings).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].message.contains("lib.rs (line 1:0)"));
+        assert_eq!(warnings[0].line, Some(1));
+
+        assert!(check(&file, Path::new("lib.rs"), true, &mut vec![]).is_err());
+        let tokens = match &file.items[0] {
+            syn::Item::Verbatim(tokens) => tokens,
+            _ => panic!("expected a verbatim item"),
+        };
+        assert_eq!(source_text(tokens).as_deref(), Some("fn declared();"));
+    }
+}
@@ -0,0 +1,386 @@
+use std::env;
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use crate::diagnostic;
+use crate::flavor;
+use crate::srcmap::SourceMap;
+
+/// How a script run by [`Bundler::verify_run`](crate::Bundler::verify_run) has to behave.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunExpectation {
+    exit_code: i32,
+    stdout_contains: Option<String>,
+    timeout: Duration,
+}
+
+impl Default for RunExpectation {
+    fn default() -> Self {
+        RunExpectation {
+            exit_code: 0,
+            stdout_contains: None,
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RunExpectation {
+    /// Exit successfully within 5 minutes, which includes compiling the script.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn exit_code(mut self, code: i32) -> Self {
+        self.exit_code = code;
+        self
+    }
+
+    /// Require `text` somewhere in the standard output.
+    pub fn stdout_contains(mut self, text: impl Into<String>) -> Self {
+        self.stdout_contains = Some(text.into());
+        self
+    }
+
+    /// Kill the script and fail if it is not done after `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// How [`Bundler::bundle`](crate::Bundler::bundle) makes sure the written script compiles, see
+/// [`Bundler::verify`](crate::Bundler::verify).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verify {
+    /// `cargo check` a cargo project made from the script, which is kept next to it to be
+    /// reused
+    CargoCheck,
+    /// Build the script with `rust-script --build-only`, or [`Verify::CargoCheck`] if
+    /// rust-script is not installed
+    RustScript,
+    /// Trust the script
+    None,
+}
+
+impl Default for Verify {
+    fn default() -> Self {
+        Verify::None
+    }
+}
+
+/// Check the bundle `text` parses back, frontmatter aside, as a guard against printing code
+/// rustc would reject. Fails with the lines around where parsing stopped. `name` is how the
+/// message refers to the bundle.
+pub fn self_check(text: &str, name: &str) -> Result<()> {
+    let code = flavor::blank_frontmatter(text);
+    let err = match syn::parse_file(&code) {
+        Ok(_) => return Ok(()),
+        Err(err) => err,
+    };
+    let start = err.span().start();
+    bail!(
+        "{} does not parse at line {}, column {}: {}\n{}",
+        name,
+        start.line,
+        start.column + 1,
+        err,
+        diagnostic::snippet(&code, start.line)
+    )
+}
+
+/// Check `script` compiles as `verify` says, pointing compile errors at the original sources
+/// with `map`.
+pub fn compile(script: &Path, verify: Verify, map: &SourceMap) -> Result<()> {
+    match verify {
+        Verify::None => Ok(()),
+        Verify::CargoCheck => cargo_check(script, map),
+        Verify::RustScript => {
+            let output = Command::new("rust-script")
+                .arg("--build-only")
+                .arg(script)
+                .stdin(Stdio::null())
+                .output();
+            match output {
+                Ok(output) => {
+                    let text = fs::read_to_string(script)?;
+                    let label = script.display().to_string();
+                    check_output(script, output, |stderr| {
+                        map.translate(stderr, &label, &text)
+                    })
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    log::info!(
+                        "rust-script not found, checking {} with cargo",
+                        script.display()
+                    );
+                    cargo_check(script, map)
+                }
+                Err(e) => Err(e).context("Failed to run rust-script"),
+            }
+        }
+    }
+}
+
+fn cargo_check(script: &Path, map: &SourceMap) -> Result<()> {
+    let project = check_project(script)?;
+    let output = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+        .arg("check")
+        .arg("--quiet")
+        .arg("--message-format=short")
+        .arg("--manifest-path")
+        .arg(project.join("Cargo.toml"))
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run cargo")?;
+    let text = fs::read_to_string(project.join("src/main.rs"))?;
+    check_output(script, output, |stderr| {
+        map.translate(stderr, "src/main.rs", &text)
+    })
+}
+
+fn check_output(script: &Path, output: Output, translate: impl Fn(&str) -> String) -> Result<()> {
+    if !output.status.success() {
+        bail!(
+            "Bundled script {} does not compile:\n{}",
+            script.display(),
+            translate(&String::from_utf8_lossy(&output.stderr))
+        );
+    }
+    Ok(())
+}
+
+/// The cargo project [`Verify::CargoCheck`] checks `script` in: `.rust-script-bundler/<name>`
+/// next to it, updated to the script. It is kept, so its dependencies are only built once.
+pub fn check_project(script: &Path) -> Result<PathBuf> {
+    let name = script
+        .file_stem()
+        .map_or_else(|| "script".into(), |s| s.to_string_lossy());
+    let project = script
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(".rust-script-bundler")
+        .join(&*name);
+    materialize(script, &project)?;
+    Ok(project)
+}
+
+/// Run `script` with `args` through rust-script, or through cargo in a temporary project if
+/// rust-script is not installed, and check it behaves as `expected`. Compile errors are pointed
+/// at the original sources with `map`.
+pub fn run(
+    script: &Path,
+    args: &[String],
+    expected: &RunExpectation,
+    map: &SourceMap,
+) -> Result<()> {
+    let spawned = Command::new("rust-script")
+        .arg(script)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    match spawned {
+        Ok(child) => {
+            let text = fs::read_to_string(script)?;
+            let label = script.display().to_string();
+            check_child(script, child, expected, &|stderr| {
+                map.translate(stderr, &label, &text)
+            })
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let project = temp_project(script, "run")?;
+            let manifest_path = project.join("Cargo.toml");
+            let child = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+                .arg("run")
+                .arg("--quiet")
+                .arg("--manifest-path")
+                .arg(&manifest_path)
+                .arg("--")
+                .args(args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .context("Failed to run cargo")?;
+            let text = fs::read_to_string(project.join("src/main.rs"))?;
+            let result = check_child(script, child, expected, &|stderr| {
+                map.translate(stderr, "src/main.rs", &text)
+            });
+            fs::remove_dir_all(&project)?;
+            result
+        }
+        Err(e) => Err(e).context("Failed to run rust-script"),
+    }
+}
+
+/// Turn `script` back into a cargo project in a fresh temporary directory, named after `purpose`
+/// so concurrent uses do not clash. The caller removes it when done.
+pub fn temp_project(script: &Path, purpose: &str) -> Result<PathBuf> {
+    let name = script
+        .file_stem()
+        .map_or_else(|| "script".into(), |s| s.to_string_lossy());
+    let project = env::temp_dir().join(format!(
+        "rust-script-bundler-{}-{}-{}",
+        purpose,
+        name,
+        std::process::id()
+    ));
+    if project.exists() {
+        fs::remove_dir_all(&project)?;
+    }
+    materialize(script, &project)?;
+    Ok(project)
+}
+
+/// Turn `script` into a cargo project at `project`
+fn materialize(script: &Path, project: &Path) -> Result<()> {
+    crate::unbundle(script, project)?;
+
+    // keep the project out of any workspace its directory happens to be in
+    let manifest_path = project.join("Cargo.toml");
+    let mut manifest = fs::read_to_string(&manifest_path)?;
+    if !manifest.contains("[workspace]") {
+        manifest.push_str("\n[workspace]\n");
+        fs::write(&manifest_path, manifest)?;
+    }
+    Ok(())
+}
+
+fn check_child(
+    script: &Path,
+    mut child: Child,
+    expected: &RunExpectation,
+    translate: &dyn Fn(&str) -> String,
+) -> Result<()> {
+    // drain the pipes while waiting, a full pipe would block the child
+    let capture = |pipe: Option<Box<dyn Read + Send>>| {
+        thread::spawn(move || {
+            let mut buf = vec![];
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            String::from_utf8_lossy(&buf).into_owned()
+        })
+    };
+    let stdout = capture(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = capture(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let deadline = Instant::now() + expected.timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            break None;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = translate(&stderr.join().unwrap_or_default());
+
+    let problem = match status {
+        None => format!("did not finish within {:?}", expected.timeout),
+        Some(status) if status.code() != Some(expected.exit_code) => format!(
+            "exited with {}, expected code {}",
+            status, expected.exit_code
+        ),
+        Some(_) => match &expected.stdout_contains {
+            Some(text) if !stdout.contains(text.as_str()) => {
+                format!("did not print {:?}", text)
+            }
+            _ => return Ok(()),
+        },
+    };
+    bail!(
+        "Bundled script {} {}\n--- stdout\n{}\n--- stderr\n{}",
+        script.display(),
+        problem,
+        stdout,
+        stderr
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Child {
+        Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    #[test]
+    fn checks_exit_code_output_and_timeout() {
+        let script = Path::new("script.rs");
+        let keep = |stderr: &str| stderr.to_string();
+        let expected = RunExpectation::new().stdout_contains("all good");
+        assert!(check_child(script, sh("echo all good"), &expected, &keep).is_ok());
+
+        let err = check_child(script, sh("echo oops >&2; exit 3"), &expected, &keep).unwrap_err();
+        assert!(err.to_string().contains("oops"));
+
+        let quick = RunExpectation::new().timeout(Duration::from_millis(200));
+        assert!(check_child(script, sh("sleep 10"), &quick, &keep).is_err());
+    }
+
+    #[test]
+    fn self_check_points_at_the_problem() {
+        let text = "#!/usr/bin/env cargo\n---\n[package]\nname = \"tool\"\n---\n\
+            /// Runs\n\
+            fn main() {}\n\
+            // vim: ft=rust syntax=rust\n";
+        self_check(text, "tool.rs").unwrap();
+
+        // a doc string continued without its `///`
+        let text = "/// Runs\nthe tool\nfn main() {}\n";
+        let err = self_check(text, "tool.rs").unwrap_err().to_string();
+        assert!(err.starts_with("tool.rs does not parse at line 2"));
+        assert!(err.contains(">     2 | the tool\n"));
+    }
+
+    #[test]
+    fn check_projects_are_kept_next_to_the_script() {
+        let dir = env::temp_dir().join(format!("bundler-check-project-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("tool.rs");
+        let text = "#!/usr/bin/env -S rust-script\n\
+            //! ```cargo\n\
+            //! [dependencies]\n\
+            //! anyhow = \"1.0\"\n\
+            //! ```\n\
+            fn main() {}\n";
+        fs::write(&script, text).unwrap();
+
+        let project = check_project(&script).unwrap();
+        assert_eq!(project, dir.join(".rust-script-bundler/tool"));
+        let manifest = fs::read_to_string(project.join("Cargo.toml")).unwrap();
+        assert!(manifest.contains("anyhow = \"1.0\""));
+        assert!(manifest.ends_with("\n[workspace]\n"));
+        let main = fs::read_to_string(project.join("src/main.rs")).unwrap();
+        assert_eq!(main, "fn main() {}\n");
+
+        // the same project is updated, keeping what cargo built in it
+        fs::create_dir_all(project.join("target")).unwrap();
+        fs::write(&script, text.replace("fn main() {}", "fn main() { run() }")).unwrap();
+        assert_eq!(check_project(&script).unwrap(), project);
+        assert!(project.join("target").exists());
+        assert!(fs::read_to_string(project.join("src/main.rs"))
+            .unwrap()
+            .contains("run()"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// What a [`Warning`] is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WarningKind {
+    /// A module failed to load and was left out, see
+    /// [`Bundler::lenient`](crate::Bundler::lenient)
+    ModuleNotLoaded,
+    /// A module file was skipped for the package's include/exclude lists
+    ExcludedModule,
+    /// Code syn can not parse, which is bundled as is
+    Verbatim,
+    /// An `include_str!`, `include_bytes!` or `include!` was left alone
+    Include,
+    /// The code may not work in the edition of the bundle
+    Edition,
+    /// The requested item order was overridden
+    ItemOrder,
+    /// A dependency was dropped from the manifest or will not be available
+    Dependency,
+    /// The script was left unformatted
+    Formatting,
+}
+
+/// A problem that did not stop bundling, but may make the bundle behave differently from the
+/// package. Displays as its message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Warning {
+    pub kind: WarningKind,
+    /// The file it is about, relative to the package if in it
+    pub path: Option<PathBuf>,
+    /// The line of `path` it is about, from 1
+    pub line: Option<usize>,
+    /// What happened, naming where as well
+    pub message: String,
+}
+
+impl Warning {
+    pub(crate) fn new(kind: WarningKind, message: impl Into<String>) -> Self {
+        Warning {
+            kind,
+            path: None,
+            line: None,
+            message: message.into(),
+        }
+    }
+
+    /// The same warning, about `line` of `path`
+    pub(crate) fn at(mut self, path: impl AsRef<Path>, line: Option<usize>) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self.line = line;
+        self
+    }
+
+    /// The `cargo:warning` lines a build script prints to have cargo show the warning, one per
+    /// line of the message
+    pub fn cargo_directives(&self) -> String {
+        self.message
+            .lines()
+            .map(|line| format!("cargo:warning={}\n", line))
+            .collect()
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_line_is_a_directive() {
+        let message = "Error when parsing a.rs\n    more";
+        let warning = Warning::new(WarningKind::ModuleNotLoaded, message).at("src/a.rs", None);
+        assert_eq!(
+            warning.cargo_directives(),
+            "cargo:warning=Error when parsing a.rs\ncargo:warning=    more\n"
+        );
+        assert_eq!(warning.path.as_deref(), Some(Path::new("src/a.rs")));
+    }
+}
@@ -0,0 +1,64 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often watched files are looked at
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The modification times and sizes of files, see [`snapshot`]
+pub type Snapshot = Vec<Option<(SystemTime, u64)>>;
+
+/// The state of `paths` to look for changes against, `None` for those that can not be read
+pub fn snapshot(paths: &[PathBuf]) -> Snapshot {
+    paths
+        .iter()
+        .map(|path| {
+            let meta = fs::metadata(path).ok()?;
+            Some((meta.modified().ok()?, meta.len()))
+        })
+        .collect()
+}
+
+/// Block until one of `paths` differs from `initial`, their [`snapshot`], and then until none
+/// has changed for `debounce`, so a burst of saves only counts once.
+pub fn wait_for_change(paths: &[PathBuf], initial: Snapshot, debounce: Duration) {
+    let mut last = loop {
+        thread::sleep(POLL_INTERVAL);
+        let now = snapshot(paths);
+        if now != initial {
+            break now;
+        }
+    };
+    let mut quiet_since = Instant::now();
+    while quiet_since.elapsed() < debounce {
+        thread::sleep(POLL_INTERVAL.min(debounce));
+        let now = snapshot(paths);
+        if now != last {
+            last = now;
+            quiet_since = Instant::now();
+        }
+    }
+}
+
+/// What to watch before a bundle has found its actual inputs: the manifest of the package at
+/// `manifest_dir`, and every Rust file under its `src`
+pub fn package_files(manifest_dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![manifest_dir.join("Cargo.toml")];
+    let mut dirs = vec![manifest_dir.join("src")];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().map_or(false, |ext| ext == "rs") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
@@ -0,0 +1,135 @@
+//! Runs `rust-script-bundle` the way users do, from a working directory of its own
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+fn work_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!(
+        "rust-script-bundle-cli-{}-{}",
+        name,
+        std::process::id()
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(dir: &Path, args: &[&OsStr]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust-script-bundle"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn a_bare_file_name_is_written_to_the_working_directory() {
+    let dir = work_dir("bare");
+    let simple = fixture("simple");
+    let output = run(
+        &dir,
+        &[
+            simple.as_os_str(),
+            "src/main.rs".as_ref(),
+            "out.rs".as_ref(),
+        ],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let script = fs::read_to_string(dir.join("out.rs")).unwrap();
+    assert!(script.contains("fn main"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn a_target_naming_no_file_is_an_error() {
+    let dir = work_dir("no-file");
+    let simple = fixture("simple");
+    for target in ["..", "/"] {
+        let output = run(
+            &dir,
+            &[simple.as_os_str(), "src/main.rs".as_ref(), target.as_ref()],
+        );
+        // 1 for an error, rather than 101 for a panic
+        assert_eq!(output.status.code(), Some(1), "{}", target);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("does not name a file"), "{}", stderr);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn flags_picking_what_to_bundle_are_an_error_with_positional_arguments() {
+    let dir = work_dir("positional-flags");
+    let simple = fixture("simple");
+    let output = run(
+        &dir,
+        &[
+            simple.as_os_str(),
+            "src/main.rs".as_ref(),
+            "out.rs".as_ref(),
+            "--output".as_ref(),
+            "other.rs".as_ref(),
+        ],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--output can not be used"), "{}", stderr);
+    assert!(!dir.join("out.rs").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn options_needing_a_file_are_an_error_for_stdout() {
+    let dir = work_dir("stdout");
+    let simple = fixture("simple");
+    let options: &[&[&str]] = &[&["--embed-inputs-hash"], &["--verify-run", "--"]];
+    for option in options {
+        let mut args = vec![simple.as_os_str(), "src/main.rs".as_ref(), "-".as_ref()];
+        args.extend(option.iter().map(OsStr::new));
+        let output = run(&dir, &args);
+        assert_eq!(output.status.code(), Some(1), "{:?}", option);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("needs an --output file"), "{}", stderr);
+        assert!(output.stdout.is_empty());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn report_is_an_error_for_several_scripts() {
+    let dir = work_dir("report");
+    let simple = fixture("simple");
+    let output = run(
+        &dir,
+        &[
+            "--jobs".as_ref(),
+            "1".as_ref(),
+            "--all-bins".as_ref(),
+            simple.as_os_str(),
+            "out".as_ref(),
+            "--report".as_ref(),
+            "report.json".as_ref(),
+        ],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--report needs a single script"),
+        "{}",
+        stderr
+    );
+    assert!(!dir.join("out").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
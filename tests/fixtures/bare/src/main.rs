@@ -0,0 +1,3 @@
+fn main() {
+    println!("{}", bare_fixture::answer());
+}
@@ -0,0 +1,10 @@
+// freebsd_impl.rs lives in a downstream fork, not here
+#[cfg(target_os = "freebsd")]
+mod freebsd_impl;
+#[cfg(target_os = "linux")]
+mod linux_impl;
+
+fn main() {
+    #[cfg(target_os = "linux")]
+    linux_impl::run();
+}
@@ -0,0 +1,9 @@
+#[cfg(unix)]
+mod imp;
+#[cfg(windows)]
+#[path = "imp_windows.rs"]
+mod imp;
+
+pub fn platform() -> &'static str {
+    imp::NAME
+}
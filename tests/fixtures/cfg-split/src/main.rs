@@ -0,0 +1,3 @@
+fn main() {
+    println!("{}", cfg_split_fixture::platform());
+}
@@ -0,0 +1,10 @@
+mod shape;
+
+pub use shape::Shape;
+
+// the only computation there is
+pub fn area(shape: &Shape) -> u32 {
+    /* width times height,
+       nothing fancy */
+    shape.width * shape.height
+}
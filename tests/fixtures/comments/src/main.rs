@@ -0,0 +1,4 @@
+fn main() {
+    let shape = comments_fixture::Shape { width: 2, height: 3 };
+    println!("{}", comments_fixture::area(&shape));
+}
@@ -0,0 +1,4 @@
+pub struct Shape {
+    pub width: u32, // note: in pixels
+    pub height: u32,
+}
@@ -0,0 +1,36 @@
+//! A library documented far beyond what its size calls for.
+//!
+//! Everything here has a doc comment, which a bundle meant to be small does not need.
+
+#![deny(missing_docs)]
+
+/// A point on the plane.
+///
+/// Both coordinates are in the same unit, whatever it is.
+#[derive(Debug, Clone, Copy)]
+pub struct Point {
+    /// The horizontal coordinate
+    pub x: i32,
+    /// The vertical coordinate
+    pub y: i32,
+}
+
+impl Point {
+    /// The point at `x` and `y`
+    pub fn new(x: i32, y: i32) -> Self {
+        Point { x, y }
+    }
+
+    /// The Manhattan distance to `other`, the sum of the differences of the coordinates.
+    #[allow(clippy::cast_sign_loss)]
+    pub fn distance(&self, other: &Point) -> u32 {
+        ((self.x - other.x).abs() + (self.y - other.y).abs()) as u32
+    }
+}
+
+/// Old name of [`Point::new`]
+#[deprecated = "use Point::new"]
+#[doc(hidden)]
+pub fn point(x: i32, y: i32) -> Point {
+    Point::new(x, y)
+}
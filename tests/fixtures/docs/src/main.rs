@@ -0,0 +1,9 @@
+//! Prints a distance.
+
+use docs_fixture::Point;
+
+/// Entry point
+fn main() {
+    let origin = Point::new(0, 0);
+    println!("{}", origin.distance(&Point::new(3, 4)));
+}
@@ -0,0 +1,7 @@
+// reaches into another fixture, outside of this workspace
+#[path = "../../../../simple/src/greeting.rs"]
+mod greeting;
+
+fn main() {
+    println!("{}", greeting::hello());
+}
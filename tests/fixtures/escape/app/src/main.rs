@@ -0,0 +1,7 @@
+#[path = "../../shared/ring.rs"]
+mod ring;
+
+fn main() {
+    let mut ring = ring::Ring::<4>::new();
+    ring.push(1);
+}
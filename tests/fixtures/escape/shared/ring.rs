@@ -0,0 +1,18 @@
+pub struct Ring<const N: usize> {
+    items: [u32; N],
+    next: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    pub fn new() -> Self {
+        Ring {
+            items: [0; N],
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: u32) {
+        self.items[self.next] = item;
+        self.next = (self.next + 1) % N;
+    }
+}
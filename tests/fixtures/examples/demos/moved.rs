@@ -0,0 +1,3 @@
+fn main() {
+    println!("moved: {}", examples_fixture::greet());
+}
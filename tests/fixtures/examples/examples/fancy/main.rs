@@ -0,0 +1,3 @@
+fn main() {
+    println!("{}", examples_fixture::fancy());
+}
@@ -0,0 +1,5 @@
+use examples_fixture::greet;
+
+fn main() {
+    println!("{}", greet());
+}
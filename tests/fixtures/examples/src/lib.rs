@@ -0,0 +1,8 @@
+pub fn greet() -> &'static str {
+    "hello"
+}
+
+#[cfg(feature = "fancy")]
+pub fn fancy() -> String {
+    format!("*{}*", greet())
+}
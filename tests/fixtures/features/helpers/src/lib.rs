@@ -0,0 +1,7 @@
+#[cfg(feature = "heavy")]
+pub use regex::Regex;
+
+#[cfg(feature = "fast")]
+pub fn fast(x: u32) -> u32 {
+    x * 2
+}
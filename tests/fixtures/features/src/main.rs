@@ -0,0 +1,59 @@
+#[cfg(feature = "cli")]
+fn mode() -> &'static str {
+    "cli"
+}
+
+#[cfg(not(feature = "cli"))]
+fn mode() -> &'static str {
+    "plain"
+}
+
+#[cfg(feature = "tls")]
+fn secure() -> bool {
+    true
+}
+
+#[cfg(not(feature = "tls"))]
+fn secure() -> bool {
+    false
+}
+
+fn main() {
+    println!("{} {}", mode(), secure());
+}
+
+struct Config {
+    #[cfg(feature = "tls")]
+    cert: Option<String>,
+    verbose: bool,
+}
+
+enum Transport {
+    Plain,
+    #[cfg(feature = "tls")]
+    Tls,
+}
+
+impl Config {
+    #[cfg(feature = "cli")]
+    fn from_args() -> Self {
+        Config {
+            #[cfg(feature = "tls")]
+            cert: None,
+            verbose: std::env::args().any(|a| a == "-v"),
+        }
+    }
+}
+
+#[cfg(all(feature = "tls", unix))]
+fn socket_dir() -> &'static str {
+    "/run/tool"
+}
+
+fn describe(transport: &Transport) -> &'static str {
+    match transport {
+        Transport::Plain => "plain transport",
+        #[cfg(feature = "tls")]
+        Transport::Tls => "tls transport",
+    }
+}
@@ -0,0 +1,4 @@
+// generated.rs is made by a build script in real packages, the tests provide it
+mod generated;
+
+pub use generated::answer;
@@ -0,0 +1,3 @@
+#[cfg(feature = "experimental")]
+pub mod experimental;
+pub mod util;
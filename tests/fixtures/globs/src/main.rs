@@ -0,0 +1,3 @@
+fn main() {
+    globs_fixture::util::run();
+}
@@ -0,0 +1,3 @@
+pub fn count() -> usize {
+    LIST.len()
+}
@@ -0,0 +1,3 @@
+pub mod queries;
+
+pub const SCHEMA: &str = include_str!("../../data/schema.sql");
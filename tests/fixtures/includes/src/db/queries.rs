@@ -0,0 +1,3 @@
+pub const LIST: &[u8] = include_bytes!("list.sql");
+
+include!("generated.rs");
@@ -0,0 +1,10 @@
+mod db;
+
+mod tables {
+    include!(concat!(env!("OUT_DIR"), "/tables.rs"));
+}
+
+fn main() {
+    println!("{}", db::SCHEMA);
+    println!("{} queries", db::queries::count());
+}
@@ -0,0 +1,7 @@
+#[macro_use]
+extern crate macros_fixture;
+
+fn main() {
+    let n: u32 = ensure_ok!("42".parse::<u32>());
+    println!("{}", n);
+}
@@ -0,0 +1,15 @@
+pub mod errors {
+    pub fn fail(message: &str) -> ! {
+        panic!("{}", message)
+    }
+}
+
+#[macro_export]
+macro_rules! ensure_ok {
+    ($result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(e) => $crate::errors::fail(&e.to_string()),
+        }
+    };
+}
@@ -0,0 +1,7 @@
+use macros_fixture::ensure_ok;
+
+fn main() {
+    let n: u32 = ensure_ok!("42".parse::<u32>());
+    let m: u32 = macros_fixture::ensure_ok!("7".parse::<u32>());
+    println!("{}", n + m);
+}
@@ -0,0 +1,3 @@
+pub fn run() {
+    println!("{}", serde_json::json!({ "crate": "beta" }));
+}
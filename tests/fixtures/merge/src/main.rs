@@ -0,0 +1,5 @@
+fn main() -> anyhow::Result<()> {
+    alpha::run();
+    beta::run();
+    Ok(())
+}
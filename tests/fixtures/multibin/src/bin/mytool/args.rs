@@ -0,0 +1,12 @@
+#[derive(Debug)]
+pub struct Args {
+    pub rest: Vec<String>,
+}
+
+impl Args {
+    pub fn parse() -> Self {
+        Args {
+            rest: std::env::args().skip(1).collect(),
+        }
+    }
+}
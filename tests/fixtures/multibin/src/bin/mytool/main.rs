@@ -0,0 +1,6 @@
+mod args;
+
+fn main() {
+    let args = args::Args::parse();
+    println!("{:?}", args);
+}
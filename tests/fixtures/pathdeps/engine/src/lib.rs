@@ -0,0 +1,6 @@
+pub fn run(n: u32) -> String {
+    #[cfg(windows)]
+    winconsole::enable_colors();
+    let result = util::double(n);
+    serde_json::json!({ "result": result }).to_string()
+}
@@ -0,0 +1,4 @@
+fn main() {
+    let output = engine::run(util::double(2));
+    println!("{}", output);
+}
@@ -0,0 +1,3 @@
+pub fn double(n: u32) -> u32 {
+    n * 2
+}
@@ -0,0 +1 @@
+pub fn enable_colors() {}
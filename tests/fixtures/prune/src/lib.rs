@@ -0,0 +1,19 @@
+mod util;
+
+pub struct Counter {
+    count: u32,
+}
+
+impl Counter {
+    pub fn new() -> Self {
+        Counter { count: 0 }
+    }
+}
+
+pub fn used() -> u32 {
+    util::helper(Counter::new().count)
+}
+
+pub fn unused() -> u32 {
+    util::orphan()
+}
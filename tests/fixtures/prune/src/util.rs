@@ -0,0 +1,7 @@
+pub fn helper(count: u32) -> u32 {
+    count + 1
+}
+
+pub fn orphan() -> u32 {
+    0
+}
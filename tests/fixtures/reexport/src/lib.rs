@@ -0,0 +1,11 @@
+pub extern crate anyhow;
+
+pub use anyhow::anyhow as error;
+
+pub fn check(ok: bool) -> anyhow::Result<()> {
+    if ok {
+        Ok(())
+    } else {
+        Err(error!("not ok"))
+    }
+}
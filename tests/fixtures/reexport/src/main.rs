@@ -0,0 +1,4 @@
+// anyhow is only reached through the lib's re-export
+fn main() -> reexport_fixture::anyhow::Result<()> {
+    reexport_fixture::check(true)
+}
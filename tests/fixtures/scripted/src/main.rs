@@ -0,0 +1,15 @@
+#!/usr/bin/env run-cargo-script
+//! Greets, once a script of its own.
+//!
+//! ```cargo
+//! [dependencies]
+//! anyhow = "1.0"
+//! regex = "1"
+//! ```
+//!
+//! Run it without arguments.
+
+fn main() -> anyhow::Result<()> {
+    println!("hello");
+    Ok(())
+}
@@ -0,0 +1,5 @@
+pub struct Greeting(pub &'static str);
+
+pub fn hello() -> &'static str {
+    "hello"
+}
@@ -0,0 +1,12 @@
+pub mod greeting;
+
+use crate::greeting::Greeting;
+
+pub fn greeting() -> crate::greeting::Greeting {
+    Greeting(greeting::hello())
+}
+
+pub fn run() -> anyhow::Result<()> {
+    println!("{}", greeting().0);
+    Ok(())
+}
@@ -0,0 +1,16 @@
+pub mod math;
+
+pub fn double(x: u32) -> u32 {
+    crate::math::times(x, 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn doubles() {
+        assert_eq!(double(2), crate::math::times(2, 2));
+    }
+}
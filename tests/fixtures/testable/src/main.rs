@@ -0,0 +1,8 @@
+fn main() {
+    println!("{}", testable_fixture::double(21));
+}
+
+#[test]
+fn doubles_the_answer() {
+    assert_eq!(testable_fixture::double(21), 42);
+}
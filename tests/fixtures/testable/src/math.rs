@@ -0,0 +1,3 @@
+pub fn times(x: u32, n: u32) -> u32 {
+    x * n
+}